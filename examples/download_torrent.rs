@@ -0,0 +1,37 @@
+// Downloads a torrent into a directory and reports progress as the session's events come in.
+//
+//     cargo run --example download_torrent -- some.torrent downloads/
+use bit_torrent::logger::LogFormat;
+use bit_torrent::meta_info_file::MetaInfoFile;
+use bit_torrent::session::{Session, SessionEvent};
+use std::path::{Path, PathBuf};
+
+fn main() {
+    let usage = "usage: download_torrent <torrent file> [download dir]";
+    let args: Vec<String> = std::env::args().collect();
+    let meta_info = MetaInfoFile::from_path(Path::new(args.get(1).expect(usage)))
+        .expect("could not load torrent");
+
+    let mut session = Session::new("download_torrent.log", LogFormat::Human);
+    if let Some(dir) = args.get(2) {
+        session
+            .settings()
+            .update(|settings| settings.download_dir = PathBuf::from(dir));
+    }
+    let info_hash = session.add(meta_info);
+    loop {
+        match session.events().recv() {
+            Ok(SessionEvent::DownloadComplete { info_hash: done }) if done == info_hash => {
+                println!("download complete");
+                break;
+            }
+            Ok(SessionEvent::StorageFailed { error, .. }) => {
+                println!("could not write the files {:?}", error);
+                break;
+            }
+            Ok(event) => println!("{:?}", event),
+            Err(_) => break,
+        }
+    }
+    session.wait();
+}
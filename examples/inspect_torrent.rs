@@ -0,0 +1,29 @@
+// Prints what a .torrent holds: its info hash, trackers and files.
+//
+//     cargo run --example inspect_torrent -- some.torrent
+use bit_torrent::meta_info_file::MetaInfoFile;
+use std::path::Path;
+
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .expect("usage: inspect_torrent <torrent file>");
+    let meta_info = MetaInfoFile::from_path(Path::new(&path)).expect("could not load torrent");
+
+    println!("info hash {}", hex::encode(meta_info.info_hash));
+    println!("private {}", meta_info.is_private());
+    for (tier, trackers) in meta_info.tiers().iter().enumerate() {
+        println!("tier {}: {}", tier, trackers.join(" "));
+    }
+    for (host, port) in &meta_info.nodes {
+        println!("DHT node {}:{}", host, port);
+    }
+    for entry in meta_info.contents() {
+        println!("{:>14} {}", entry.length, entry.path.display());
+    }
+    let summary = meta_info.summary();
+    println!(
+        "{} files, {} bytes in {} pieces of {} bytes",
+        summary.files, summary.length, summary.pieces, summary.piece_length
+    );
+}
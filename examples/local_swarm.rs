@@ -0,0 +1,68 @@
+// A whole swarm on localhost: a tracker, a seed of some random data, and a session downloading it
+// from the seed and checking it arrived intact.
+//
+//     cargo run --example local_swarm
+use bit_torrent::logger::LogFormat;
+use bit_torrent::meta_info_file::MetaInfoFile;
+use bit_torrent::session::{Session, SessionEvent};
+use bit_torrent::test_seeder::{SeederProfile, TestSeeder};
+use bit_torrent::test_tracker::TestHttpTracker;
+use bit_torrent::torrent_builder::TorrentBuilder;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const SIZE: usize = 1024 * 1024 + 4321;
+const PIECE_LENGTH: u32 = 32 * 1024;
+
+fn main() {
+    let dir = std::env::temp_dir().join("bit_torrent_local_swarm");
+    let downloads = dir.join("downloads");
+    std::fs::create_dir_all(&downloads).unwrap();
+    let data: Vec<u8> = (0..SIZE).map(|i| (i % 251) as u8).collect();
+    let source = dir.join("swarm.bin");
+    std::fs::write(&source, &data).unwrap();
+
+    let tracker = TestHttpTracker::start().expect("could not start the tracker");
+    let bytes = TorrentBuilder::new(&source)
+        .piece_length(PIECE_LENGTH)
+        .announce(&tracker.announce_url())
+        .build()
+        .expect("could not build the torrent");
+    let meta_info = MetaInfoFile::from(bytes.as_slice());
+    let info_hash = meta_info.info_hash;
+
+    let seeder = TestSeeder::start_with_metadata(
+        data.clone(),
+        PIECE_LENGTH,
+        meta_info.info_bytes.clone(),
+        SeederProfile::default(),
+    )
+    .expect("could not start the seed");
+    if let SocketAddr::V4(addr) = seeder.addr() {
+        tracker.add_seed(info_hash, addr, seeder.peer_id());
+    }
+
+    let mut session = Session::new(
+        dir.join("local_swarm.log").to_str().unwrap(),
+        LogFormat::Human,
+    );
+    session
+        .settings()
+        .update(|settings| settings.download_dir = downloads.clone());
+    session.add(meta_info);
+    loop {
+        match session.events().recv_timeout(Duration::from_secs(60)) {
+            Ok(SessionEvent::DownloadComplete { info_hash: done }) if done == info_hash => break,
+            Ok(_) => {}
+            Err(_) => panic!("the download did not finish"),
+        }
+    }
+    let downloaded = std::fs::read(downloads.join("swarm.bin")).unwrap();
+    assert!(downloaded == data, "the download differs from the seed");
+    println!(
+        "downloaded {} bytes from {}, {} blocks served",
+        downloaded.len(),
+        seeder.addr(),
+        seeder.blocks_served()
+    );
+}
@@ -0,0 +1,23 @@
+// Makes a .torrent of a file or directory, announced to the given trackers, one tier each.
+//
+//     cargo run --example make_torrent -- some/dir out.torrent http://tracker.example/announce
+use bit_torrent::meta_info_file::MetaInfoFile;
+use bit_torrent::torrent_builder::TorrentBuilder;
+
+fn main() {
+    let usage = "usage: make_torrent <file or directory> <out.torrent> [announce url...]";
+    let args: Vec<String> = std::env::args().collect();
+    let mut builder = TorrentBuilder::new(args.get(1).expect(usage));
+    for url in args.iter().skip(3) {
+        builder = builder.announce_tier(&[url]);
+    }
+    let bytes = builder.build().expect("could not build the torrent");
+    std::fs::write(args.get(2).expect(usage), &bytes).expect("could not write the torrent");
+
+    let meta_info = MetaInfoFile::from(bytes.as_slice());
+    println!(
+        "wrote {} with info hash {}",
+        args[2],
+        hex::encode(meta_info.info_hash)
+    );
+}
@@ -0,0 +1,48 @@
+// Seeds a file to whoever connects, listed on an in-process tracker, until killed. Point another
+// client at the .torrent it writes.
+//
+//     cargo run --example serve_seed -- some.file seed.torrent
+use bit_torrent::meta_info_file::MetaInfoFile;
+use bit_torrent::test_seeder::{SeederProfile, TestSeeder};
+use bit_torrent::test_tracker::TestHttpTracker;
+use bit_torrent::torrent::PiecedContent;
+use bit_torrent::torrent_builder::TorrentBuilder;
+use std::net::SocketAddr;
+use std::thread::sleep;
+use std::time::Duration;
+
+fn main() {
+    let usage = "usage: serve_seed <file> <out.torrent>";
+    let args: Vec<String> = std::env::args().collect();
+    let path = args.get(1).expect(usage);
+    let data = std::fs::read(path).expect("could not read the file");
+
+    let tracker = TestHttpTracker::start().expect("could not start the tracker");
+    let bytes = TorrentBuilder::new(path)
+        .announce(&tracker.announce_url())
+        .build()
+        .expect("could not build the torrent");
+    std::fs::write(args.get(2).expect(usage), &bytes).expect("could not write the torrent");
+    let meta_info = MetaInfoFile::from(bytes.as_slice());
+
+    let seeder = TestSeeder::start_with_metadata(
+        data,
+        meta_info.piece_length(),
+        meta_info.info_bytes.clone(),
+        SeederProfile::default(),
+    )
+    .expect("could not start seeding");
+    if let SocketAddr::V4(addr) = seeder.addr() {
+        tracker.add_seed(meta_info.info_hash, addr, seeder.peer_id());
+    }
+    println!(
+        "seeding {} on {}, tracked at {}",
+        hex::encode(meta_info.info_hash),
+        seeder.addr(),
+        tracker.announce_url()
+    );
+    loop {
+        sleep(Duration::from_secs(5));
+        println!("blocks served: {}", seeder.blocks_served());
+    }
+}
@@ -1,4 +1,22 @@
 use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read};
+
+mod lazy;
+pub use lazy::LazyBencodable;
+mod events;
+pub use events::{BencodeEvent, EventParser};
+mod lenient;
+pub use lenient::{bdecode_lenient, Recovered};
+mod preserve;
+pub use preserve::Preserved;
+#[cfg(feature = "serde")]
+mod serde_impl;
+#[cfg(feature = "serde")]
+pub use serde_impl::{from_bencodable, from_bytes, to_bencodable, to_bytes, SerdeError};
+#[cfg(feature = "serde_json")]
+mod json;
+#[cfg(feature = "serde_json")]
+pub use json::JsonError;
 
 #[derive(Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct BencodableByteString(Vec<u8>);
@@ -14,11 +32,20 @@ impl std::fmt::Debug for BencodableByteString {
 #[derive(Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub enum Bencodable {
     ByteString(BencodableByteString),
-    Integer(u32),
+    Integer(i64),
     List(Vec<Bencodable>),
     Dictionary(BTreeMap<BencodableByteString, Bencodable>),
 }
 
+// Lets dictionaries be searched with a plain `&[u8]` (`dictionary.get(b"announce".as_slice())`),
+// so looking a key up doesn't allocate a `BencodableByteString` just to compare against. Sound
+// because the derived comparisons and hash are those of the bytes.
+impl std::borrow::Borrow<[u8]> for BencodableByteString {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl BencodableByteString {
     pub fn as_string(&self) -> Result<&str, std::str::Utf8Error> {
         std::str::from_utf8(&self.0)
@@ -53,6 +80,240 @@ impl From<&[u8]> for Bencodable {
     }
 }
 
+impl From<String> for BencodableByteString {
+    fn from(s: String) -> Self {
+        BencodableByteString(s.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for BencodableByteString {
+    fn from(b: Vec<u8>) -> Self {
+        BencodableByteString(b)
+    }
+}
+
+impl From<String> for Bencodable {
+    fn from(s: String) -> Self {
+        Bencodable::ByteString(BencodableByteString::from(s))
+    }
+}
+
+impl From<Vec<u8>> for Bencodable {
+    fn from(b: Vec<u8>) -> Self {
+        Bencodable::ByteString(BencodableByteString(b))
+    }
+}
+
+impl From<i64> for Bencodable {
+    fn from(i: i64) -> Self {
+        Bencodable::Integer(i)
+    }
+}
+
+impl From<u32> for Bencodable {
+    fn from(i: u32) -> Self {
+        Bencodable::Integer(i as i64)
+    }
+}
+
+impl From<Vec<Bencodable>> for Bencodable {
+    fn from(list: Vec<Bencodable>) -> Self {
+        Bencodable::List(list)
+    }
+}
+
+// The owned counterparts of the accessors on `Bencodable`, failing the same way they do
+impl TryFrom<Bencodable> for Vec<u8> {
+    type Error = AccessError;
+
+    fn try_from(b: Bencodable) -> Result<Self, Self::Error> {
+        match b {
+            Bencodable::ByteString(bs) => Ok(bs.0),
+            _ => Err(b.wrong_type("byte string")),
+        }
+    }
+}
+
+impl TryFrom<Bencodable> for String {
+    type Error = AccessError;
+
+    fn try_from(b: Bencodable) -> Result<Self, Self::Error> {
+        String::from_utf8(Vec::try_from(b)?).map_err(|_| AccessError::NotUtf8)
+    }
+}
+
+impl TryFrom<Bencodable> for i64 {
+    type Error = AccessError;
+
+    fn try_from(b: Bencodable) -> Result<Self, Self::Error> {
+        b.as_int()
+    }
+}
+
+impl TryFrom<Bencodable> for Vec<Bencodable> {
+    type Error = AccessError;
+
+    fn try_from(b: Bencodable) -> Result<Self, Self::Error> {
+        match b {
+            Bencodable::List(list) => Ok(list),
+            _ => Err(b.wrong_type("list")),
+        }
+    }
+}
+
+// Builds a `Bencodable::Dictionary` one entry at a time. Entries come out sorted by key however they
+// went in; inserting a key again replaces its value.
+#[derive(Debug, Default)]
+pub struct DictBuilder {
+    entries: BTreeMap<BencodableByteString, Bencodable>,
+}
+
+impl DictBuilder {
+    pub fn new() -> Self {
+        DictBuilder::default()
+    }
+
+    pub fn insert(
+        mut self,
+        key: impl Into<BencodableByteString>,
+        value: impl Into<Bencodable>,
+    ) -> Self {
+        self.entries.insert(key.into(), value.into());
+        self
+    }
+
+    // Leaves the key out altogether when there is no value
+    pub fn insert_some(
+        self,
+        key: impl Into<BencodableByteString>,
+        value: Option<impl Into<Bencodable>>,
+    ) -> Self {
+        match value {
+            Some(value) => self.insert(key, value),
+            None => self,
+        }
+    }
+
+    pub fn build(self) -> Bencodable {
+        Bencodable::Dictionary(self.entries)
+    }
+}
+
+// What went wrong reaching into a decoded value with the typed accessors below
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessError {
+    MissingKey(String),
+    WrongType {
+        expected: &'static str,
+        found: &'static str,
+    },
+    NotUtf8,
+}
+
+impl std::fmt::Display for AccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccessError::MissingKey(key) => write!(f, "missing key {:?}", key),
+            AccessError::WrongType { expected, found } => {
+                write!(f, "expected {} but found {}", expected, found)
+            }
+            AccessError::NotUtf8 => write!(f, "byte string is not UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+impl Bencodable {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Bencodable::ByteString(_) => "byte string",
+            Bencodable::Integer(_) => "integer",
+            Bencodable::List(_) => "list",
+            Bencodable::Dictionary(_) => "dictionary",
+        }
+    }
+
+    fn wrong_type(&self, expected: &'static str) -> AccessError {
+        AccessError::WrongType {
+            expected,
+            found: self.type_name(),
+        }
+    }
+
+    pub fn as_dict(&self) -> Result<&BTreeMap<BencodableByteString, Bencodable>, AccessError> {
+        match self {
+            Bencodable::Dictionary(dictionary) => Ok(dictionary),
+            _ => Err(self.wrong_type("dictionary")),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&[Bencodable], AccessError> {
+        match self {
+            Bencodable::List(list) => Ok(list),
+            _ => Err(self.wrong_type("list")),
+        }
+    }
+
+    pub fn as_int(&self) -> Result<i64, AccessError> {
+        match self {
+            Bencodable::Integer(i) => Ok(*i),
+            _ => Err(self.wrong_type("integer")),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Result<&[u8], AccessError> {
+        match self {
+            Bencodable::ByteString(bs) => Ok(bs.as_bytes()),
+            _ => Err(self.wrong_type("byte string")),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str, AccessError> {
+        std::str::from_utf8(self.as_bytes()?).map_err(|_| AccessError::NotUtf8)
+    }
+
+    // The value under `key`, for when this is a dictionary that must have it
+    pub fn get(&self, key: &str) -> Result<&Bencodable, AccessError> {
+        self.as_dict()?
+            .get(key.as_bytes())
+            .ok_or_else(|| AccessError::MissingKey(key.to_string()))
+    }
+
+    // Follows a path such as `info.files[2].path`: dots step into dictionaries by key and `[n]`
+    // into lists by position. None when anything along the way is missing or the wrong type, or
+    // the path is malformed. Keys containing `.` or `[` can't be reached this way; use `get`.
+    pub fn query(&self, path: &str) -> Option<&Bencodable> {
+        let mut current = self;
+        for segment in path.split('.') {
+            let (key, mut indices) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+            if !key.is_empty() {
+                current = current.as_dict().ok()?.get(key.as_bytes())?;
+            }
+            while !indices.is_empty() {
+                let (index, rest) = indices.strip_prefix('[')?.split_once(']')?;
+                current = current.as_list().ok()?.get(index.parse::<usize>().ok()?)?;
+                indices = rest;
+            }
+        }
+        Some(current)
+    }
+}
+
+// `bencodable["info"]["piece length"]`, for values already known to have that shape. Panics, with
+// the `AccessError` that `get` would have returned, when a key is missing or a value along the way
+// isn't a dictionary.
+impl std::ops::Index<&str> for Bencodable {
+    type Output = Bencodable;
+
+    fn index(&self, key: &str) -> &Bencodable {
+        match self.get(key) {
+            Ok(value) => value,
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum EncodeError {
     List,
@@ -60,6 +321,18 @@ pub enum EncodeError {
     DictValue,
 }
 
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodeError::List => write!(f, "could not encode a list item"),
+            EncodeError::DictKey => write!(f, "could not encode a dictionary key"),
+            EncodeError::DictValue => write!(f, "could not encode a dictionary value"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
 pub fn bencode(b: &Bencodable) -> Result<Vec<u8>, EncodeError> {
     match b {
         Bencodable::ByteString(bs) => {
@@ -122,6 +395,7 @@ pub fn bencode(b: &Bencodable) -> Result<Vec<u8>, EncodeError> {
 pub struct ParseResult {
     pub index: usize,
     pub bencodable: Bencodable,
+    pub span: Span,
 }
 
 impl From<(usize, Bencodable)> for ParseResult {
@@ -129,10 +403,45 @@ impl From<(usize, Bencodable)> for ParseResult {
         ParseResult {
             index: pr.0,
             bencodable: pr.1,
+            span: Span {
+                start: 0,
+                end: pr.0,
+                children: SpanChildren::None,
+            },
         }
     }
 }
 
+// Where a value sat in the buffer it was decoded from, `end` exclusive, along with the spans of
+// everything nested inside it. Slicing the original buffer with a span gives back the value's
+// bytes exactly as they were sent, even when they weren't canonically encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub children: SpanChildren,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpanChildren {
+    None,
+    List(Vec<Span>),
+    Dictionary(BTreeMap<BencodableByteString, Span>),
+}
+
+impl Span {
+    pub fn get(&self, key: &str) -> Option<&Span> {
+        match &self.children {
+            SpanChildren::Dictionary(spans) => spans.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn slice<'a>(&self, bencoded_bytes: &'a [u8]) -> &'a [u8] {
+        &bencoded_bytes[self.start..self.end]
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct BencodeParseError {
     index: usize,
@@ -140,7 +449,7 @@ pub struct BencodeParseError {
     error_type: BencodeParseErrorType,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BencodeParseErrorType {
     Integer,
     List,
@@ -150,6 +459,15 @@ pub enum BencodeParseErrorType {
     Initiate,
     End,
     Value,
+    // `DecodeLimits` exceeded
+    TooDeep,
+    StringTooLong,
+    TooManyElements,
+    // only reported by `bdecode_strict`
+    NonCanonicalInteger,
+    NonCanonicalLength,
+    UnsortedKey,
+    DuplicateKey,
 }
 
 impl From<(BencodeParseErrorType, usize, &[u8])> for BencodeParseError {
@@ -157,14 +475,119 @@ impl From<(BencodeParseErrorType, usize, &[u8])> for BencodeParseError {
         BencodeParseError {
             error_type: t.0,
             index: t.1,
-            original: std::str::from_utf8(t.2).unwrap_or("BYTES").to_string(),
+            original: std::str::from_utf8(t.2).unwrap_or(NOT_UTF8).to_string(),
+        }
+    }
+}
+
+// stands in for the input in `BencodeParseError::original` when it isn't UTF-8
+const NOT_UTF8: &str = "BYTES";
+// how much of the input either side of the error `Display` quotes
+const ERROR_CONTEXT: usize = 12;
+
+impl std::fmt::Display for BencodeParseErrorType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            BencodeParseErrorType::Integer => "malformed or unterminated integer",
+            BencodeParseErrorType::List => "unterminated list",
+            BencodeParseErrorType::Dictionary => "dictionary key is not a byte string",
+            BencodeParseErrorType::ByteString => "byte string runs past the end of the input",
+            BencodeParseErrorType::ByteStringLength => "malformed byte string length",
+            BencodeParseErrorType::Initiate => "expected the start of a value",
+            BencodeParseErrorType::End => "unexpected data after the value",
+            BencodeParseErrorType::Value => "input ended where a value was expected",
+            BencodeParseErrorType::TooDeep => "values nested deeper than the limit",
+            BencodeParseErrorType::StringTooLong => "byte string longer than the limit",
+            BencodeParseErrorType::TooManyElements => "more values than the limit",
+            BencodeParseErrorType::NonCanonicalInteger => "integer not in canonical form",
+            BencodeParseErrorType::NonCanonicalLength => "byte string length not in canonical form",
+            BencodeParseErrorType::UnsortedKey => "dictionary keys out of order",
+            BencodeParseErrorType::DuplicateKey => "dictionary key repeated",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl std::fmt::Display for BencodeParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at byte {}", self.error_type, self.index)?;
+        // quoted only when the input was text and the cut falls on character boundaries
+        let start = self.index.saturating_sub(ERROR_CONTEXT);
+        let end = (self.index + ERROR_CONTEXT).min(self.original.len());
+        match self.original.get(start..end) {
+            Some(context) if self.original != NOT_UTF8 && !context.is_empty() => {
+                write!(f, " near {:?}", context)
+            }
+            _ => Ok(()),
         }
     }
 }
 
+impl std::error::Error for BencodeParseError {}
+
+// Bounds on what the decoder takes from untrusted input. Nesting is bounded so a deep enough
+// tracker response can't overflow the stack, and string lengths so the declared length of a byte
+// string can't make the reader allocate gigabytes before finding out the data isn't there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    // lists and dictionaries inside one another; a bare integer or string is depth 1
+    pub max_depth: usize,
+    pub max_string_length: usize,
+    // every value counts, dictionary keys included
+    pub max_elements: usize,
+}
+
+impl Default for DecodeLimits {
+    // Far beyond any real .torrent or tracker response: a 100 GiB torrent with 256 KiB pieces
+    // carries 8 MiB of piece hashes
+    fn default() -> Self {
+        DecodeLimits {
+            max_depth: 64,
+            max_string_length: 64 * 1024 * 1024,
+            max_elements: 4 * 1024 * 1024,
+        }
+    }
+}
+
+struct ParseState {
+    strict: bool,
+    limits: DecodeLimits,
+    depth: usize,
+    elements: usize,
+    // whether to build the `Span` tree; left off unless a caller asks for spans, since it costs
+    // a copy of every dictionary key
+    spans: bool,
+}
+
+impl ParseState {
+    fn new(strict: bool, limits: DecodeLimits) -> Self {
+        ParseState {
+            strict,
+            limits,
+            depth: 0,
+            elements: 0,
+            spans: false,
+        }
+    }
+
+    // Called as each value starts; the caller steps back out of it with `depth -= 1`
+    fn enter(&mut self) -> Result<(), BencodeParseErrorType> {
+        if self.depth == self.limits.max_depth {
+            return Err(BencodeParseErrorType::TooDeep);
+        }
+        if self.elements == self.limits.max_elements {
+            return Err(BencodeParseErrorType::TooManyElements);
+        }
+        self.depth += 1;
+        self.elements += 1;
+        Ok(())
+    }
+}
+
 fn parse_byte_string(
     index: usize,
     bencoded_value: &[u8],
+    state: &mut ParseState,
 ) -> Result<ParseResult, BencodeParseError> {
     let mut i = index;
     let mut length_string = String::new();
@@ -181,6 +604,20 @@ fn parse_byte_string(
     let length = length_string.parse::<usize>().map_err(|_| {
         BencodeParseError::from((BencodeParseErrorType::ByteStringLength, i, bencoded_value))
     })?;
+    if state.strict && length.to_string() != length_string {
+        return Err(BencodeParseError::from((
+            BencodeParseErrorType::NonCanonicalLength,
+            index,
+            bencoded_value,
+        )));
+    }
+    if length > state.limits.max_string_length {
+        return Err(BencodeParseError::from((
+            BencodeParseErrorType::StringTooLong,
+            index,
+            bencoded_value,
+        )));
+    }
     let relevant_slice = bencoded_value.get(i + 1..i + 1 + length).ok_or_else(|| {
         BencodeParseError::from((BencodeParseErrorType::ByteString, i, bencoded_value))
     })?;
@@ -191,7 +628,11 @@ fn parse_byte_string(
     )))
 }
 
-fn parse_integer(index: usize, bencoded_value: &[u8]) -> Result<ParseResult, BencodeParseError> {
+fn parse_integer(
+    index: usize,
+    bencoded_value: &[u8],
+    state: &mut ParseState,
+) -> Result<ParseResult, BencodeParseError> {
     let mut i = index;
     let mut integer_string = String::new();
     let mut next_char = *bencoded_value.get(i).ok_or_else(|| {
@@ -204,22 +645,38 @@ fn parse_integer(index: usize, bencoded_value: &[u8]) -> Result<ParseResult, Ben
             BencodeParseError::from((BencodeParseErrorType::Integer, i, bencoded_value))
         })?;
     }
-    let integer = integer_string.parse::<u32>().map_err(|_| {
+    let integer = integer_string.parse::<i64>().map_err(|_| {
         BencodeParseError::from((BencodeParseErrorType::Integer, i, bencoded_value))
     })?;
+    // leading zeros, `-0` and a `+` sign all parse but aren't how the integer is spelled
+    if state.strict && integer.to_string() != integer_string {
+        return Err(BencodeParseError::from((
+            BencodeParseErrorType::NonCanonicalInteger,
+            index,
+            bencoded_value,
+        )));
+    }
     // +1 for the last character consumed as part of parsing the bencodable ("e")
     Ok(ParseResult::from((i + 1, Bencodable::Integer(integer))))
 }
 
-fn parse_list(index: usize, bencoded_value: &[u8]) -> Result<ParseResult, BencodeParseError> {
+fn parse_list(
+    index: usize,
+    bencoded_value: &[u8],
+    state: &mut ParseState,
+) -> Result<ParseResult, BencodeParseError> {
     let mut i = index;
     let mut bencodables = vec![];
+    let mut spans = vec![];
     let mut next_char = *bencoded_value
         .get(i)
         .ok_or_else(|| BencodeParseError::from((BencodeParseErrorType::List, i, bencoded_value)))?;
     while next_char != b'e' {
-        let item = parse_bencoded_value(i, bencoded_value)?;
+        let item = parse_bencoded_value(i, bencoded_value, state)?;
         bencodables.push(item.bencodable);
+        if state.spans {
+            spans.push(item.span);
+        }
         i = item.index;
         next_char = *bencoded_value.get(i).ok_or_else(|| {
             BencodeParseError::from((BencodeParseErrorType::List, i, bencoded_value))
@@ -227,18 +684,27 @@ fn parse_list(index: usize, bencoded_value: &[u8]) -> Result<ParseResult, Bencod
     }
     // +1 for the last character consumed as part of parsing the bencodable ("e")
     let result = (i + 1, Bencodable::List(bencodables));
-    Ok(ParseResult::from(result))
+    let mut result = ParseResult::from(result);
+    if state.spans {
+        result.span.children = SpanChildren::List(spans);
+    }
+    Ok(result)
 }
 
-fn parse_dictionary(index: usize, bencoded_value: &[u8]) -> Result<ParseResult, BencodeParseError> {
+fn parse_dictionary(
+    index: usize,
+    bencoded_value: &[u8],
+    state: &mut ParseState,
+) -> Result<ParseResult, BencodeParseError> {
     let mut i = index;
     let mut bencodables = BTreeMap::new();
+    let mut spans = BTreeMap::new();
     let mut next_char = *bencoded_value.get(i).ok_or_else(|| {
         BencodeParseError::from((BencodeParseErrorType::Dictionary, i, bencoded_value))
     })?;
     while next_char != b'e' {
         let byte_string_key =
-            parse_bencoded_value(i, bencoded_value).and_then(|pr| match pr.bencodable {
+            parse_bencoded_value(i, bencoded_value, state).and_then(|pr| match pr.bencodable {
                 Bencodable::ByteString(bs) => Ok((pr.index, bs.0)),
                 _ => Err(BencodeParseError::from((
                     BencodeParseErrorType::Dictionary,
@@ -246,9 +712,25 @@ fn parse_dictionary(index: usize, bencoded_value: &[u8]) -> Result<ParseResult,
                     bencoded_value,
                 ))),
             })?;
-        let result = parse_bencoded_value(byte_string_key.0, bencoded_value)?;
         let key = BencodableByteString(byte_string_key.1);
+        // keys have to be sorted as raw bytes and can't repeat
+        if state.strict {
+            if let Some(previous) = bencodables.keys().next_back() {
+                let error_type = match key.cmp(previous) {
+                    std::cmp::Ordering::Less => Some(BencodeParseErrorType::UnsortedKey),
+                    std::cmp::Ordering::Equal => Some(BencodeParseErrorType::DuplicateKey),
+                    std::cmp::Ordering::Greater => None,
+                };
+                if let Some(error_type) = error_type {
+                    return Err(BencodeParseError::from((error_type, i, bencoded_value)));
+                }
+            }
+        }
+        let result = parse_bencoded_value(byte_string_key.0, bencoded_value, state)?;
         let value = result.bencodable;
+        if state.spans {
+            spans.insert(key.clone(), result.span);
+        }
         bencodables.insert(key, value);
         i = result.index;
         next_char = *bencoded_value.get(i).ok_or_else(|| {
@@ -256,39 +738,94 @@ fn parse_dictionary(index: usize, bencoded_value: &[u8]) -> Result<ParseResult,
         })?;
     }
     // +1 for the last character consumed as part of parsing the bencodable ("e")
-    Ok(ParseResult::from((
-        i + 1,
-        Bencodable::Dictionary(bencodables),
-    )))
+    let mut result = ParseResult::from((i + 1, Bencodable::Dictionary(bencodables)));
+    if state.spans {
+        result.span.children = SpanChildren::Dictionary(spans);
+    }
+    Ok(result)
 }
 
 fn parse_bencoded_value(
     index: usize,
     bencoded_value: &[u8],
+    state: &mut ParseState,
 ) -> Result<ParseResult, BencodeParseError> {
     let i = index;
     let b = *bencoded_value.get(i).ok_or_else(|| {
         BencodeParseError::from((BencodeParseErrorType::Value, i, bencoded_value))
     })?;
-    if b.is_ascii_digit() {
-        parse_byte_string(i, bencoded_value)
+    if let Err(error_type) = state.enter() {
+        return Err(BencodeParseError::from((error_type, i, bencoded_value)));
+    }
+    let result = if b.is_ascii_digit() {
+        parse_byte_string(i, bencoded_value, state)
     } else if b == b'i' {
-        parse_integer(i + 1, bencoded_value)
+        parse_integer(i + 1, bencoded_value, state)
     } else if b == b'l' {
-        parse_list(i + 1, bencoded_value)
+        parse_list(i + 1, bencoded_value, state)
     } else if b == b'd' {
-        parse_dictionary(i + 1, bencoded_value)
+        parse_dictionary(i + 1, bencoded_value, state)
     } else {
         Err(BencodeParseError::from((
             BencodeParseErrorType::Initiate,
             i,
             bencoded_value,
         )))
-    }
+    };
+    state.depth -= 1;
+    result.map(|mut pr| {
+        pr.span.start = i;
+        pr
+    })
 }
 
 pub fn bdecode(bencoded_bytes: &[u8]) -> Result<Bencodable, BencodeParseError> {
-    parse_bencoded_value(0, bencoded_bytes)
+    decode(
+        bencoded_bytes,
+        ParseState::new(false, DecodeLimits::default()),
+    )
+    .map(|(bencodable, _)| bencodable)
+}
+
+// Like `bdecode`, but only accepts the one canonical encoding of each value: no leading zeros in
+// integers or lengths, no `-0`, dictionary keys sorted and unique. Whatever it accepts re-encodes
+// to exactly the input, so a hash of the input is a hash of the value.
+pub fn bdecode_strict(bencoded_bytes: &[u8]) -> Result<Bencodable, BencodeParseError> {
+    decode(
+        bencoded_bytes,
+        ParseState::new(true, DecodeLimits::default()),
+    )
+    .map(|(bencodable, _)| bencodable)
+}
+
+// `bdecode`, plus the span of every value in the input
+pub fn bdecode_with_span(bencoded_bytes: &[u8]) -> Result<(Bencodable, Span), BencodeParseError> {
+    let mut state = ParseState::new(false, DecodeLimits::default());
+    state.spans = true;
+    decode(bencoded_bytes, state)
+}
+
+// Decodes the value at the start of `bencoded_bytes` and returns it with the number of bytes it
+// took up, leaving whatever follows for the caller: another value, or raw data such as the piece
+// trailing a ut_metadata message
+pub fn bdecode_prefix(bencoded_bytes: &[u8]) -> Result<(Bencodable, usize), BencodeParseError> {
+    let mut state = ParseState::new(false, DecodeLimits::default());
+    parse_bencoded_value(0, bencoded_bytes, &mut state).map(|pr| (pr.bencodable, pr.index))
+}
+
+// `bdecode` with limits other than `DecodeLimits::default()`
+pub fn bdecode_with_limits(
+    bencoded_bytes: &[u8],
+    limits: DecodeLimits,
+) -> Result<Bencodable, BencodeParseError> {
+    decode(bencoded_bytes, ParseState::new(false, limits)).map(|(bencodable, _)| bencodable)
+}
+
+fn decode(
+    bencoded_bytes: &[u8],
+    mut state: ParseState,
+) -> Result<(Bencodable, Span), BencodeParseError> {
+    parse_bencoded_value(0, bencoded_bytes, &mut state)
         .and_then(|pr: ParseResult| {
             let next_index = pr.index;
             if bencoded_bytes.get(next_index).is_some() {
@@ -301,7 +838,196 @@ pub fn bdecode(bencoded_bytes: &[u8]) -> Result<Bencodable, BencodeParseError> {
                 Ok(pr)
             }
         })
-        .map(|b| b.bencodable)
+        .map(|b| (b.bencodable, b.span))
+}
+
+#[derive(Debug)]
+pub enum ReadDecodeError {
+    Io(std::io::Error),
+    // `original` is left empty since the input is never held in memory as a whole
+    Parse(BencodeParseError),
+}
+
+impl std::fmt::Display for ReadDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadDecodeError::Io(e) => write!(f, "could not read bencode: {}", e),
+            ReadDecodeError::Parse(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReadDecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReadDecodeError::Io(e) => Some(e),
+            ReadDecodeError::Parse(e) => Some(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for ReadDecodeError {
+    fn from(e: std::io::Error) -> Self {
+        ReadDecodeError::Io(e)
+    }
+}
+
+// Longest an integer or a string length can be spelled: `-9223372036854775808` for an `i64` and
+// twenty digits for a 64-bit `usize`. Anything longer can't parse, so there's no need to read on.
+const MAX_DIGITS: usize = 20;
+
+// Pulls bytes from the underlying reader only as the parser asks for them, keeping count of how
+// far into the input it is so errors can say where things went wrong
+struct StreamParser<R: Read> {
+    reader: BufReader<R>,
+    index: usize,
+    state: ParseState,
+}
+
+impl<R: Read> StreamParser<R> {
+    fn error(&self, error_type: BencodeParseErrorType) -> ReadDecodeError {
+        ReadDecodeError::Parse(BencodeParseError::from((error_type, self.index, &[][..])))
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, ReadDecodeError> {
+        Ok(self.reader.fill_buf()?.first().copied())
+    }
+
+    fn next(&mut self, error_type: BencodeParseErrorType) -> Result<u8, ReadDecodeError> {
+        match self.peek()? {
+            Some(b) => {
+                self.reader.consume(1);
+                self.index += 1;
+                Ok(b)
+            }
+            None => Err(self.error(error_type)),
+        }
+    }
+
+    // Reads up to the terminator, which is consumed but not returned, giving up with `error_type`
+    // once there are more than `MAX_DIGITS` bytes before it
+    fn read_until(
+        &mut self,
+        terminator: u8,
+        error_type: BencodeParseErrorType,
+    ) -> Result<String, ReadDecodeError> {
+        let mut s = String::new();
+        loop {
+            let b = self.next(error_type)?;
+            if b == terminator {
+                return Ok(s);
+            }
+            if s.len() == MAX_DIGITS {
+                return Err(self.error(error_type));
+            }
+            s.push(b as char);
+        }
+    }
+
+    fn parse_byte_string(&mut self) -> Result<Vec<u8>, ReadDecodeError> {
+        let length = self
+            .read_until(b':', BencodeParseErrorType::ByteStringLength)?
+            .parse::<usize>()
+            .map_err(|_| self.error(BencodeParseErrorType::ByteStringLength))?;
+        if length > self.state.limits.max_string_length {
+            return Err(self.error(BencodeParseErrorType::StringTooLong));
+        }
+        // grows with what actually arrives instead of trusting the declared length up front
+        let mut bytes = vec![];
+        (&mut self.reader)
+            .take(length as u64)
+            .read_to_end(&mut bytes)?;
+        self.index += bytes.len();
+        if bytes.len() != length {
+            return Err(self.error(BencodeParseErrorType::ByteString));
+        }
+        Ok(bytes)
+    }
+
+    fn parse_value(&mut self) -> Result<Bencodable, ReadDecodeError> {
+        let b = self
+            .peek()?
+            .ok_or_else(|| self.error(BencodeParseErrorType::Value))?;
+        if let Err(error_type) = self.state.enter() {
+            return Err(self.error(error_type));
+        }
+        let value = self.parse_entered_value(b);
+        self.state.depth -= 1;
+        value
+    }
+
+    fn parse_entered_value(&mut self, b: u8) -> Result<Bencodable, ReadDecodeError> {
+        if b.is_ascii_digit() {
+            return Ok(Bencodable::ByteString(BencodableByteString(
+                self.parse_byte_string()?,
+            )));
+        }
+        match b {
+            b'i' => {
+                self.next(BencodeParseErrorType::Value)?;
+                let integer = self
+                    .read_until(b'e', BencodeParseErrorType::Integer)?
+                    .parse::<i64>()
+                    .map_err(|_| self.error(BencodeParseErrorType::Integer))?;
+                Ok(Bencodable::Integer(integer))
+            }
+            b'l' => {
+                self.next(BencodeParseErrorType::Value)?;
+                let mut bencodables = vec![];
+                while self.peek()? != Some(b'e') {
+                    if self.peek()?.is_none() {
+                        return Err(self.error(BencodeParseErrorType::List));
+                    }
+                    bencodables.push(self.parse_value()?);
+                }
+                self.next(BencodeParseErrorType::List)?;
+                Ok(Bencodable::List(bencodables))
+            }
+            b'd' => {
+                self.next(BencodeParseErrorType::Value)?;
+                let mut bencodables = BTreeMap::new();
+                while self.peek()? != Some(b'e') {
+                    match self.peek()? {
+                        Some(b) if b.is_ascii_digit() => {}
+                        _ => return Err(self.error(BencodeParseErrorType::Dictionary)),
+                    }
+                    // keys count as elements like any other value
+                    if let Err(error_type) = self.state.enter() {
+                        return Err(self.error(error_type));
+                    }
+                    self.state.depth -= 1;
+                    let key = BencodableByteString(self.parse_byte_string()?);
+                    let value = self.parse_value()?;
+                    bencodables.insert(key, value);
+                }
+                self.next(BencodeParseErrorType::Dictionary)?;
+                Ok(Bencodable::Dictionary(bencodables))
+            }
+            _ => Err(self.error(BencodeParseErrorType::Initiate)),
+        }
+    }
+}
+
+// Same as `bdecode`, but reads the input incrementally so large .torrent files and tracker
+// responses never need to be buffered whole; anything after the value is still an error
+pub fn bdecode_from_reader(r: impl Read) -> Result<Bencodable, ReadDecodeError> {
+    bdecode_from_reader_with_limits(r, DecodeLimits::default())
+}
+
+pub fn bdecode_from_reader_with_limits(
+    r: impl Read,
+    limits: DecodeLimits,
+) -> Result<Bencodable, ReadDecodeError> {
+    let mut parser = StreamParser {
+        reader: BufReader::new(r),
+        index: 0,
+        state: ParseState::new(false, limits),
+    };
+    let bencodable = parser.parse_value()?;
+    if parser.peek()?.is_some() {
+        return Err(parser.error(BencodeParseErrorType::End));
+    }
+    Ok(bencodable)
 }
 
 #[cfg(test)]
@@ -410,6 +1136,19 @@ mod tests {
         assert_eq!(bdecode(b"i3e").unwrap(), Bencodable::Integer(3));
     }
 
+    #[test]
+    fn it_round_trips_integers_beyond_32_bits() {
+        for (bytes, i) in [
+            (&b"i5368709120e"[..], 5_368_709_120i64),
+            (b"i-42e", -42),
+            (b"i9223372036854775807e", i64::MAX),
+        ] {
+            assert_eq!(bdecode(bytes).unwrap(), Bencodable::Integer(i));
+            assert_eq!(bencode(&Bencodable::Integer(i)).unwrap(), bytes);
+        }
+        assert!(bdecode(b"i9223372036854775808e").is_err());
+    }
+
     #[test]
     fn it_decodes_heterogenous_lists() {
         assert_eq!(
@@ -495,6 +1234,134 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_decodes_values_from_the_front_of_a_buffer() {
+        let bytes = b"d1:ai1ee4:spamli1ei2eeraw";
+        let (first, consumed) = bdecode_prefix(bytes).unwrap();
+        assert_eq!(first, bdecode(b"d1:ai1ee").unwrap());
+        assert_eq!(consumed, 8);
+        let (second, more) = bdecode_prefix(&bytes[consumed..]).unwrap();
+        assert_eq!(second, Bencodable::from("spam"));
+        let (third, rest) = bdecode_prefix(&bytes[consumed + more..]).unwrap();
+        assert_eq!(
+            third,
+            Bencodable::List(vec![Bencodable::Integer(1), Bencodable::Integer(2)])
+        );
+        assert_eq!(&bytes[consumed + more + rest..], b"raw");
+        assert!(bdecode_prefix(b"d1:ai1e").is_err());
+    }
+
+    #[test]
+    fn it_builds_dictionaries() {
+        let built = DictBuilder::new()
+            .insert("b", Bencodable::Integer(1))
+            .insert(&b"a"[..], "first")
+            .insert_some("c", None::<Bencodable>)
+            .insert_some("d", Some(DictBuilder::new().build()))
+            .insert("b", Bencodable::Integer(2))
+            .build();
+        assert_eq!(bencode(&built).unwrap(), b"d1:a5:first1:bi2e1:ddee");
+    }
+
+    #[test]
+    fn it_converts_to_and_from_owned_values() {
+        assert_eq!(
+            Bencodable::from(String::from("spam")),
+            Bencodable::from("spam")
+        );
+        assert_eq!(
+            Bencodable::from(vec![0xffu8]),
+            Bencodable::from(&[0xffu8][..])
+        );
+        assert_eq!(Bencodable::from(u32::MAX), Bencodable::Integer(4294967295));
+        let dictionary = DictBuilder::new().insert("spam", 1_i64).build();
+        assert_eq!(
+            dictionary.as_dict().unwrap().get(b"spam".as_slice()),
+            Some(&Bencodable::Integer(1))
+        );
+        assert_eq!(Bencodable::from(-3i64), Bencodable::Integer(-3));
+
+        assert_eq!(
+            String::try_from(Bencodable::from("spam")),
+            Ok("spam".to_string())
+        );
+        assert_eq!(
+            String::try_from(Bencodable::from(vec![0xffu8])),
+            Err(AccessError::NotUtf8)
+        );
+        assert_eq!(
+            Vec::<u8>::try_from(Bencodable::from("ab")),
+            Ok(b"ab".to_vec())
+        );
+        assert_eq!(i64::try_from(Bencodable::Integer(7)), Ok(7));
+        assert_eq!(
+            Vec::<Bencodable>::try_from(Bencodable::from(vec![Bencodable::Integer(1)])),
+            Ok(vec![Bencodable::Integer(1)])
+        );
+        assert_eq!(
+            i64::try_from(Bencodable::from("7")),
+            Err(AccessError::WrongType {
+                expected: "integer",
+                found: "byte string"
+            })
+        );
+    }
+
+    #[test]
+    fn it_queries_nested_values_by_path() {
+        let torrent = bdecode(
+            b"d13:announce-listll3:oneel3:two5:threeee4:infod5:filesld6:lengthi1e4:pathl1:aeed6:lengthi2e4:pathl1:b1:ceee12:piece lengthi16eee",
+        )
+        .unwrap();
+        assert_eq!(
+            torrent.query("info.piece length"),
+            Some(&Bencodable::Integer(16))
+        );
+        assert_eq!(
+            torrent.query("info.files[1].path[1]"),
+            Some(&Bencodable::from("c"))
+        );
+        assert_eq!(
+            torrent.query("announce-list[1][0]"),
+            Some(&Bencodable::from("two"))
+        );
+        assert_eq!(torrent.query(""), Some(&torrent));
+        let missing = [
+            "info.files[2]",
+            "info.name",
+            "info.files.path",
+            "info[0]",
+            "announce-list[1",
+            "announce-list[x]",
+            "announce-list[0]junk",
+        ];
+        for path in missing {
+            assert_eq!(torrent.query(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn it_describes_errors_with_their_position() {
+        let error = bdecode(b"d3:key5:valuex3:abce").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "expected the start of a value at byte 13 near \"3:key5:valuex3:abce\""
+        );
+        assert_eq!(
+            bdecode(b"i1e\xff").unwrap_err().to_string(),
+            "unexpected data after the value at byte 3"
+        );
+
+        fn decode_boxed(bytes: &[u8]) -> Result<i64, Box<dyn std::error::Error>> {
+            Ok(bdecode(bytes)?.as_int()?)
+        }
+        assert_eq!(decode_boxed(b"i7e").unwrap(), 7);
+        assert_eq!(
+            decode_boxed(b"4:spam").unwrap_err().to_string(),
+            "expected integer but found byte string"
+        );
+    }
+
     #[test]
     fn it_decodes_incomplete_bencode() {
         assert_eq!(
@@ -600,4 +1467,222 @@ mod tests {
         let t = bdecode(example_string.as_bytes());
         assert_eq!(t.unwrap(), Bencodable::Dictionary(examples));
     }
+
+    #[test]
+    fn it_decodes_from_readers() {
+        let bytes: &[u8] = b"d4:spaml1:a1:bi42ee3:cow3:mooe";
+        assert_eq!(bdecode_from_reader(bytes).unwrap(), bdecode(bytes).unwrap());
+
+        // a reader that hands out one byte per read still decodes the same value
+        struct Trickle<'a>(&'a [u8]);
+        impl<'a> Read for Trickle<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                match self.0.split_first() {
+                    Some((b, rest)) if !buf.is_empty() => {
+                        buf[0] = *b;
+                        self.0 = rest;
+                        Ok(1)
+                    }
+                    _ => Ok(0),
+                }
+            }
+        }
+        assert_eq!(
+            bdecode_from_reader(Trickle(bytes)).unwrap(),
+            bdecode(bytes).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_rejects_bad_input_when_decoding_from_readers() {
+        for bytes in [
+            &b"l4:spam"[..],
+            b"d3:cowi1e",
+            b"10:short",
+            b"i12",
+            b"di1ei2ee",
+        ] {
+            assert!(bdecode(bytes).is_err());
+            assert!(
+                matches!(bdecode_from_reader(bytes), Err(ReadDecodeError::Parse(_))),
+                "{:?}",
+                bytes
+            );
+        }
+        match bdecode_from_reader(&b"4:spamx"[..]) {
+            Err(ReadDecodeError::Parse(e)) => {
+                assert_eq!(e.index, 6);
+                assert_eq!(e.error_type, BencodeParseErrorType::End);
+            }
+            other => panic!("expected trailing data to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_stops_reading_integers_and_lengths_that_never_end() {
+        let endless_integer = (&b"i"[..]).chain(std::io::repeat(b'1'));
+        match bdecode_from_reader(endless_integer) {
+            Err(ReadDecodeError::Parse(e)) => {
+                assert_eq!(e.error_type, BencodeParseErrorType::Integer);
+                assert_eq!(e.index, 1 + MAX_DIGITS + 1);
+            }
+            other => panic!("expected the integer to be rejected, got {:?}", other),
+        }
+        match bdecode_from_reader(std::io::repeat(b'1')) {
+            Err(ReadDecodeError::Parse(e)) => {
+                assert_eq!(e.error_type, BencodeParseErrorType::ByteStringLength);
+                assert_eq!(e.index, MAX_DIGITS + 1);
+            }
+            other => panic!("expected the length to be rejected, got {:?}", other),
+        }
+        // the longest values that fit still decode
+        assert_eq!(
+            bdecode_from_reader(&b"i-9223372036854775808e"[..]).unwrap(),
+            Bencodable::Integer(i64::MIN)
+        );
+        assert!(bdecode_from_reader(&b"00000000000000000004:spam"[..]).is_ok());
+    }
+
+    #[test]
+    fn it_records_the_span_of_every_value() {
+        let bytes = b"d3:cowl1:ai12ee4:spami042ee";
+        let (bencodable, span) = bdecode_with_span(bytes).unwrap();
+        assert_eq!(bencodable, bdecode(bytes).unwrap());
+        assert_eq!((span.start, span.end), (0, bytes.len()));
+
+        let cow = span.get("cow").unwrap();
+        assert_eq!(cow.slice(bytes), b"l1:ai12ee");
+        match &cow.children {
+            SpanChildren::List(items) => {
+                assert_eq!(items[0].slice(bytes), b"1:a");
+                assert_eq!(items[1].slice(bytes), b"i12e");
+            }
+            children => panic!("expected list children, got {:?}", children),
+        }
+        // non-canonical encodings come back exactly as they were sent
+        assert_eq!(span.get("spam").unwrap().slice(bytes), b"i042e");
+        assert!(span.get("missing").is_none());
+    }
+
+    #[test]
+    fn it_reaches_into_values_with_typed_accessors() {
+        let torrent = bdecode(b"d8:announce3:url4:infod6:lengthi5e5:filesl1:aeee").unwrap();
+        assert_eq!(
+            torrent.get("announce").and_then(Bencodable::as_str),
+            Ok("url")
+        );
+        let info = torrent.get("info").unwrap();
+        assert_eq!(info.as_dict().unwrap().len(), 2);
+        assert_eq!(info.get("length").and_then(Bencodable::as_int), Ok(5));
+        assert_eq!(
+            info.get("files").and_then(Bencodable::as_list).unwrap(),
+            &[Bencodable::from("a")]
+        );
+        assert_eq!(
+            info.get("pieces"),
+            Err(AccessError::MissingKey("pieces".to_string()))
+        );
+        assert_eq!(
+            info.get("length").and_then(Bencodable::as_bytes),
+            Err(AccessError::WrongType {
+                expected: "byte string",
+                found: "integer"
+            })
+        );
+        assert_eq!(
+            Bencodable::Integer(1).get("length"),
+            Err(AccessError::WrongType {
+                expected: "dictionary",
+                found: "integer"
+            })
+        );
+        assert_eq!(
+            Bencodable::from(&[0xffu8][..]).as_str(),
+            Err(AccessError::NotUtf8)
+        );
+        assert_eq!(torrent["info"]["length"], Bencodable::Integer(5));
+    }
+
+    #[test]
+    #[should_panic(expected = "missing key \"pieces\"")]
+    fn it_panics_indexing_a_missing_key() {
+        let torrent = bdecode(b"d4:infod6:lengthi5eee").unwrap();
+        let _ = &torrent["info"]["pieces"];
+    }
+
+    #[test]
+    fn it_rejects_non_canonical_input_in_strict_mode() {
+        let canonical: &[u8] = b"d1:ai-3e1:bli0e4:spame1:cdee";
+        assert_eq!(bdecode_strict(canonical), bdecode(canonical));
+        assert_eq!(
+            bencode(&bdecode_strict(canonical).unwrap()).unwrap(),
+            canonical
+        );
+
+        let rejected: &[(&[u8], BencodeParseErrorType, usize)] = &[
+            (b"i03e", BencodeParseErrorType::NonCanonicalInteger, 1),
+            (b"i-0e", BencodeParseErrorType::NonCanonicalInteger, 1),
+            (b"i+4e", BencodeParseErrorType::NonCanonicalInteger, 1),
+            (b"l04:spame", BencodeParseErrorType::NonCanonicalLength, 1),
+            (b"d1:bi1e1:ai2ee", BencodeParseErrorType::UnsortedKey, 7),
+            (b"d1:ai1e1:ai2ee", BencodeParseErrorType::DuplicateKey, 7),
+            (b"i1ei2e", BencodeParseErrorType::End, 3),
+        ];
+        for (input, error_type, index) in rejected {
+            // the lenient decoder takes all of these except trailing data
+            if *error_type != BencodeParseErrorType::End {
+                assert!(bdecode(input).is_ok());
+            }
+            let error = bdecode_strict(input).unwrap_err();
+            assert_eq!((error.error_type, error.index), (*error_type, *index));
+        }
+    }
+
+    #[test]
+    fn it_stops_at_the_decode_limits() {
+        let limits = DecodeLimits {
+            max_depth: 3,
+            max_string_length: 4,
+            max_elements: 6,
+        };
+        let error_type = |input: &[u8]| {
+            let from_slice = bdecode_with_limits(input, limits).map_err(|e| e.error_type);
+            let from_reader = bdecode_from_reader_with_limits(input, limits).map_err(|e| match e {
+                ReadDecodeError::Parse(e) => e.error_type,
+                ReadDecodeError::Io(e) => panic!("unexpected io error {:?}", e),
+            });
+            assert_eq!(from_slice, from_reader);
+            from_slice.err()
+        };
+
+        assert_eq!(error_type(b"lli1eee"), None);
+        assert_eq!(error_type(b"d4:spamli1eee"), None);
+        assert_eq!(
+            error_type(b"llli1eeee"),
+            Some(BencodeParseErrorType::TooDeep)
+        );
+        assert_eq!(
+            error_type(b"5:spams"),
+            Some(BencodeParseErrorType::StringTooLong)
+        );
+        // a declared length far past the end of the input fails on the length, not the read
+        assert_eq!(
+            error_type(b"9999999999:"),
+            Some(BencodeParseErrorType::StringTooLong)
+        );
+        assert_eq!(
+            error_type(b"li1ei2ei3ei4ei5ei6ee"),
+            Some(BencodeParseErrorType::TooManyElements)
+        );
+        assert_eq!(
+            error_type(b"d1:ai1e1:bi2e1:ci3ee"),
+            Some(BencodeParseErrorType::TooManyElements)
+        );
+
+        let deep = format!("{}{}", "l".repeat(100_000), "e".repeat(100_000));
+        assert_eq!(
+            bdecode(deep.as_bytes()).map_err(|e| e.error_type),
+            Err(BencodeParseErrorType::TooDeep)
+        );
+    }
 }
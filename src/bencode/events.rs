@@ -0,0 +1,201 @@
+// A pull parser: bencoded input as a stream of events instead of a tree, for callers after a few
+// fields of a large value. Nothing is copied; byte strings borrow from the input. Every
+// `DictStart` and `ListStart` is matched by an `End`, and within a dictionary each `Key` is
+// followed by the events of its value.
+use super::lazy::{byte_string_bounds, integer_at};
+use super::{BencodeParseError, BencodeParseErrorType, DecodeLimits};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BencodeEvent<'a> {
+    DictStart,
+    ListStart,
+    Key(&'a [u8]),
+    Bytes(&'a [u8]),
+    Int(i64),
+    End,
+}
+
+#[derive(Debug)]
+enum Container {
+    List,
+    Dictionary { expecting_key: bool },
+}
+
+#[derive(Debug)]
+pub struct EventParser<'a> {
+    bytes: &'a [u8],
+    index: usize,
+    containers: Vec<Container>,
+    // after an error, or once the whole value and nothing after it has been read
+    finished: bool,
+}
+
+impl<'a> EventParser<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        EventParser {
+            bytes,
+            index: 0,
+            containers: vec![],
+            finished: false,
+        }
+    }
+
+    // How many lists and dictionaries the next event is inside of
+    pub fn depth(&self) -> usize {
+        self.containers.len()
+    }
+
+    fn error(&self, error_type: BencodeParseErrorType) -> BencodeParseError {
+        BencodeParseError::from((error_type, self.index, self.bytes))
+    }
+
+    // A value just ended, so the dictionary it was in expects a key next
+    fn value_done(&mut self) {
+        if let Some(Container::Dictionary { expecting_key }) = self.containers.last_mut() {
+            *expecting_key = true;
+        }
+    }
+
+    fn next_event(&mut self) -> Result<Option<BencodeEvent<'a>>, BencodeParseError> {
+        if self.containers.is_empty() && self.index > 0 {
+            return match self.index < self.bytes.len() {
+                true => Err(self.error(BencodeParseErrorType::End)),
+                false => Ok(None),
+            };
+        }
+        let error_type = match self.containers.last() {
+            Some(Container::List) => BencodeParseErrorType::List,
+            Some(Container::Dictionary { .. }) => BencodeParseErrorType::Dictionary,
+            None => BencodeParseErrorType::Value,
+        };
+        let b = *self
+            .bytes
+            .get(self.index)
+            .ok_or_else(|| self.error(error_type))?;
+        if b == b'e' && !self.containers.is_empty() {
+            self.containers.pop();
+            self.index += 1;
+            self.value_done();
+            return Ok(Some(BencodeEvent::End));
+        }
+        if let Some(Container::Dictionary { expecting_key }) = self.containers.last_mut() {
+            if *expecting_key {
+                if !b.is_ascii_digit() {
+                    return Err(self.error(BencodeParseErrorType::Dictionary));
+                }
+                *expecting_key = false;
+                let (start, end) = byte_string_bounds(self.index, self.bytes)?;
+                self.index = end;
+                return Ok(Some(BencodeEvent::Key(&self.bytes[start..end])));
+            }
+        }
+        let event = match b {
+            b'0'..=b'9' => {
+                let (start, end) = byte_string_bounds(self.index, self.bytes)?;
+                self.index = end;
+                self.value_done();
+                BencodeEvent::Bytes(&self.bytes[start..end])
+            }
+            b'i' => {
+                let (integer, end) = integer_at(self.index, self.bytes)?;
+                self.index = end;
+                self.value_done();
+                BencodeEvent::Int(integer)
+            }
+            b'l' | b'd' => {
+                if self.containers.len() == DecodeLimits::default().max_depth {
+                    return Err(self.error(BencodeParseErrorType::TooDeep));
+                }
+                self.index += 1;
+                if b == b'l' {
+                    self.containers.push(Container::List);
+                    BencodeEvent::ListStart
+                } else {
+                    self.containers.push(Container::Dictionary {
+                        expecting_key: true,
+                    });
+                    BencodeEvent::DictStart
+                }
+            }
+            _ => return Err(self.error(BencodeParseErrorType::Initiate)),
+        };
+        Ok(Some(event))
+    }
+
+    // Steps over the rest of the value whose first event was just read, whatever its size
+    pub fn skip_value(&mut self, first: BencodeEvent<'a>) -> Result<(), BencodeParseError> {
+        if !matches!(first, BencodeEvent::DictStart | BencodeEvent::ListStart) {
+            return Ok(());
+        }
+        let depth = self.depth();
+        while self.depth() >= depth {
+            if self.next().transpose()?.is_none() {
+                return Err(self.error(BencodeParseErrorType::Value));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for EventParser<'a> {
+    type Item = Result<BencodeEvent<'a>, BencodeParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let event = self.next_event();
+        if !matches!(event, Ok(Some(_))) {
+            self.finished = true;
+        }
+        event.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_emits_events_in_order() {
+        let events: Vec<BencodeEvent> = EventParser::new(b"d1:ali1e2:xye1:bi-3ee")
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                BencodeEvent::DictStart,
+                BencodeEvent::Key(b"a"),
+                BencodeEvent::ListStart,
+                BencodeEvent::Int(1),
+                BencodeEvent::Bytes(b"xy"),
+                BencodeEvent::End,
+                BencodeEvent::Key(b"b"),
+                BencodeEvent::Int(-3),
+                BencodeEvent::End,
+            ]
+        );
+    }
+
+    #[test]
+    fn it_skips_values_and_stops_at_errors() {
+        let mut parser = EventParser::new(b"d1:ad1:xli1eee1:bi2ee");
+        assert_eq!(parser.next(), Some(Ok(BencodeEvent::DictStart)));
+        assert_eq!(parser.next(), Some(Ok(BencodeEvent::Key(b"a"))));
+        let first = parser.next().unwrap().unwrap();
+        parser.skip_value(first).unwrap();
+        assert_eq!(parser.next(), Some(Ok(BencodeEvent::Key(b"b"))));
+
+        let mut parser = EventParser::new(b"di1ei2ee");
+        assert_eq!(parser.next(), Some(Ok(BencodeEvent::DictStart)));
+        assert!(matches!(parser.next(), Some(Err(_))));
+        assert_eq!(parser.next(), None);
+        assert!(matches!(
+            EventParser::new(b"i1ei2e").last(),
+            Some(Err(BencodeParseError {
+                error_type: BencodeParseErrorType::End,
+                ..
+            }))
+        ));
+    }
+}
@@ -0,0 +1,127 @@
+// Converts between `Bencodable` and JSON for inspecting .torrent files and tracker responses.
+// Integers, lists and dictionaries map onto their JSON counterparts. Byte strings (dictionary keys
+// included) become JSON strings when they are UTF-8; anything else is written as "hex:" followed
+// by the bytes in hex. UTF-8 strings that happen to start with "hex:" are hex encoded too, so
+// every JSON string starting with "hex:" is binary and the conversion round-trips exactly.
+use super::{Bencodable, BencodableByteString};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+const HEX_PREFIX: &str = "hex:";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum JsonError {
+    // null, booleans and floats have no bencode equivalent
+    Unsupported(&'static str),
+    IntegerOutOfRange,
+    BadHex(String),
+}
+
+impl std::fmt::Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonError::Unsupported(what) => write!(f, "bencode has no way to represent {}", what),
+            JsonError::IntegerOutOfRange => write!(f, "integer does not fit in a bencode integer"),
+            JsonError::BadHex(s) => write!(f, "{:?} is not valid hex", s),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+fn bytes_to_json(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) if !s.starts_with(HEX_PREFIX) => s.to_string(),
+        _ => format!("{}{}", HEX_PREFIX, hex::encode(bytes)),
+    }
+}
+
+fn bytes_from_json(s: &str) -> Result<Vec<u8>, JsonError> {
+    match s.strip_prefix(HEX_PREFIX) {
+        Some(encoded) => hex::decode(encoded).map_err(|_| JsonError::BadHex(s.to_string())),
+        None => Ok(s.as_bytes().to_vec()),
+    }
+}
+
+impl Bencodable {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Bencodable::ByteString(bs) => Value::String(bytes_to_json(bs.as_bytes())),
+            Bencodable::Integer(i) => Value::from(*i),
+            Bencodable::List(list) => Value::Array(list.iter().map(Bencodable::to_json).collect()),
+            Bencodable::Dictionary(dictionary) => Value::Object(
+                dictionary
+                    .iter()
+                    .map(|(key, value)| (bytes_to_json(key.as_bytes()), value.to_json()))
+                    .collect::<Map<String, Value>>(),
+            ),
+        }
+    }
+
+    pub fn from_json(value: &Value) -> Result<Bencodable, JsonError> {
+        match value {
+            Value::String(s) => Ok(Bencodable::from(bytes_from_json(s)?.as_slice())),
+            Value::Number(n) => n.as_i64().map(Bencodable::Integer).ok_or(if n.is_f64() {
+                JsonError::Unsupported("floats")
+            } else {
+                JsonError::IntegerOutOfRange
+            }),
+            Value::Array(values) => values
+                .iter()
+                .map(Bencodable::from_json)
+                .collect::<Result<Vec<Bencodable>, JsonError>>()
+                .map(Bencodable::List),
+            Value::Object(object) => object
+                .iter()
+                .map(|(key, value)| {
+                    Ok((
+                        BencodableByteString::from(bytes_from_json(key)?.as_slice()),
+                        Bencodable::from_json(value)?,
+                    ))
+                })
+                .collect::<Result<BTreeMap<BencodableByteString, Bencodable>, JsonError>>()
+                .map(Bencodable::Dictionary),
+            Value::Bool(_) => Err(JsonError::Unsupported("booleans")),
+            Value::Null => Err(JsonError::Unsupported("null")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::bdecode;
+
+    #[test]
+    fn it_round_trips_bencode_through_json() {
+        let original = bdecode(
+            b"d8:announce3:url4:infod6:lengthi-5e7:literal8:hex:abcd6:pieces3:\x00\xff\x10e4:listli1e0:ee",
+        )
+        .unwrap();
+        let json = original.to_json();
+        assert_eq!(
+            json.to_string(),
+            r#"{"announce":"url","info":{"length":-5,"literal":"hex:6865783a61626364","pieces":"hex:00ff10"},"list":[1,""]}"#
+        );
+        assert_eq!(Bencodable::from_json(&json), Ok(original));
+    }
+
+    #[test]
+    fn it_rejects_json_bencode_cannot_represent() {
+        let parse = |s: &str| Bencodable::from_json(&serde_json::from_str(s).unwrap());
+        assert_eq!(parse("[1.5]"), Err(JsonError::Unsupported("floats")));
+        assert_eq!(
+            parse("{\"a\":true}"),
+            Err(JsonError::Unsupported("booleans"))
+        );
+        assert_eq!(parse("null"), Err(JsonError::Unsupported("null")));
+        assert_eq!(
+            parse("18446744073709551615"),
+            Err(JsonError::IntegerOutOfRange)
+        );
+        assert_eq!(
+            parse("\"hex:zz\""),
+            Err(JsonError::BadHex("hex:zz".to_string()))
+        );
+    }
+}
@@ -0,0 +1,201 @@
+// A view over bencoded bytes that decodes as little as it can. Construction only checks the
+// structure, stepping over byte strings by their declared length, so a torrent's multi-megabyte
+// `pieces` string is never copied unless something asks for it. Dictionaries index their keys the
+// first time one is looked up and every value is decoded at most once, on first access.
+use super::{bdecode, Bencodable, BencodeParseError, BencodeParseErrorType, DecodeLimits};
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+
+#[derive(Debug)]
+pub struct LazyBencodable<'a> {
+    // exactly one value, already checked to be well formed
+    bytes: &'a [u8],
+    entries: OnceCell<BTreeMap<&'a [u8], LazyBencodable<'a>>>,
+    decoded: OnceCell<Bencodable>,
+}
+
+impl<'a> LazyBencodable<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, BencodeParseError> {
+        let end = skip_value(0, bytes, 0)?;
+        if end != bytes.len() {
+            return Err(BencodeParseError::from((
+                BencodeParseErrorType::End,
+                end,
+                bytes,
+            )));
+        }
+        Ok(LazyBencodable::unchecked(bytes))
+    }
+
+    fn unchecked(bytes: &'a [u8]) -> Self {
+        LazyBencodable {
+            bytes,
+            entries: OnceCell::new(),
+            decoded: OnceCell::new(),
+        }
+    }
+
+    // The value's bytes exactly as they appeared in the input
+    pub fn raw(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub fn is_dictionary(&self) -> bool {
+        self.bytes.first() == Some(&b'd')
+    }
+
+    // The value under `key` when this is a dictionary that has it, still undecoded
+    pub fn get(&self, key: &str) -> Option<&LazyBencodable<'a>> {
+        if !self.is_dictionary() {
+            return None;
+        }
+        self.entries
+            .get_or_init(|| index_entries(self.bytes))
+            .get(key.as_bytes())
+    }
+
+    // Follows dictionary keys separated by dots, such as `info.piece length`
+    pub fn get_path(&self, path: &str) -> Option<&LazyBencodable<'a>> {
+        path.split('.')
+            .try_fold(self, |current, key| current.get(key))
+    }
+
+    pub fn decode(&self) -> Result<&Bencodable, BencodeParseError> {
+        if let Some(decoded) = self.decoded.get() {
+            return Ok(decoded);
+        }
+        let decoded = bdecode(self.bytes)?;
+        Ok(self.decoded.get_or_init(|| decoded))
+    }
+}
+
+// Byte ranges of each key and value of the dictionary in `bytes`, which `skip_value` has already
+// accepted. A repeated key keeps its last value, as `bdecode` does.
+fn index_entries(bytes: &[u8]) -> BTreeMap<&[u8], LazyBencodable<'_>> {
+    let mut entries = BTreeMap::new();
+    let mut i = 1;
+    while bytes[i] != b'e' {
+        let (key_start, key_end) = byte_string_bounds(i, bytes).expect("checked on construction");
+        let value_end = skip_value(key_end, bytes, 0).expect("checked on construction");
+        entries.insert(
+            &bytes[key_start..key_end],
+            LazyBencodable::unchecked(&bytes[key_end..value_end]),
+        );
+        i = value_end;
+    }
+    entries
+}
+
+// Where the contents of the byte string at `index` start and end
+pub(super) fn byte_string_bounds(
+    index: usize,
+    bytes: &[u8],
+) -> Result<(usize, usize), BencodeParseError> {
+    let error = |error_type, i| BencodeParseError::from((error_type, i, bytes));
+    let colon = bytes[index..]
+        .iter()
+        .position(|b| *b == b':')
+        .map(|offset| index + offset)
+        .ok_or_else(|| error(BencodeParseErrorType::ByteStringLength, bytes.len()))?;
+    let length = std::str::from_utf8(&bytes[index..colon])
+        .ok()
+        .and_then(|length| length.parse::<usize>().ok())
+        .ok_or_else(|| error(BencodeParseErrorType::ByteStringLength, colon))?;
+    if length > DecodeLimits::default().max_string_length {
+        return Err(error(BencodeParseErrorType::StringTooLong, index));
+    }
+    let end = colon + 1 + length;
+    if end > bytes.len() {
+        return Err(error(BencodeParseErrorType::ByteString, colon));
+    }
+    Ok((colon + 1, end))
+}
+
+// The integer at `index`, its leading `i` included, and where it ends
+pub(super) fn integer_at(index: usize, bytes: &[u8]) -> Result<(i64, usize), BencodeParseError> {
+    let error = |i| BencodeParseError::from((BencodeParseErrorType::Integer, i, bytes));
+    let end = bytes[index..]
+        .iter()
+        .position(|b| *b == b'e')
+        .map(|offset| index + offset)
+        .ok_or_else(|| error(bytes.len()))?;
+    let integer = std::str::from_utf8(&bytes[index + 1..end])
+        .ok()
+        .and_then(|integer| integer.parse::<i64>().ok())
+        .ok_or_else(|| error(end))?;
+    Ok((integer, end + 1))
+}
+
+// Where the value starting at `index` ends, checking its structure without building anything
+fn skip_value(index: usize, bytes: &[u8], depth: usize) -> Result<usize, BencodeParseError> {
+    let error = |error_type, i| BencodeParseError::from((error_type, i, bytes));
+    if depth == DecodeLimits::default().max_depth {
+        return Err(error(BencodeParseErrorType::TooDeep, index));
+    }
+    match bytes.get(index) {
+        None => Err(error(BencodeParseErrorType::Value, index)),
+        Some(b) if b.is_ascii_digit() => byte_string_bounds(index, bytes).map(|(_, end)| end),
+        Some(b'i') => integer_at(index, bytes).map(|(_, end)| end),
+        Some(b @ (b'l' | b'd')) => {
+            let (error_type, is_dictionary) = match b {
+                b'd' => (BencodeParseErrorType::Dictionary, true),
+                _ => (BencodeParseErrorType::List, false),
+            };
+            let mut i = index + 1;
+            loop {
+                match bytes.get(i) {
+                    None => return Err(error(error_type, i)),
+                    Some(b'e') => return Ok(i + 1),
+                    Some(b) if is_dictionary && !b.is_ascii_digit() => {
+                        return Err(error(error_type, i))
+                    }
+                    Some(_) if is_dictionary => {
+                        i = byte_string_bounds(i, bytes)?.1;
+                        i = skip_value(i, bytes, depth + 1)?;
+                    }
+                    Some(_) => i = skip_value(i, bytes, depth + 1)?,
+                }
+            }
+        }
+        Some(_) => Err(error(BencodeParseErrorType::Initiate, index)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_decodes_only_the_values_asked_for() {
+        let bytes = b"d8:announce3:url4:infod6:lengthi5e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let torrent = LazyBencodable::new(bytes).unwrap();
+        assert_eq!(
+            torrent.get("announce").unwrap().decode(),
+            Ok(&Bencodable::from("url"))
+        );
+        let piece_length = torrent.get_path("info.piece length").unwrap();
+        assert_eq!(piece_length.decode(), Ok(&Bencodable::Integer(16384)));
+        assert_eq!(piece_length.raw(), b"i16384e");
+
+        let info = torrent.get("info").unwrap();
+        assert!(info.decoded.get().is_none());
+        assert!(info.get("pieces").unwrap().decoded.get().is_none());
+        assert!(torrent.get("missing").is_none());
+        assert!(torrent.get_path("announce.url").is_none());
+        assert_eq!(torrent.decode(), Ok(&bdecode(bytes).unwrap()));
+    }
+
+    #[test]
+    fn it_rejects_malformed_input_up_front() {
+        let error_type = |bytes: &[u8]| LazyBencodable::new(bytes).unwrap_err().error_type;
+        assert_eq!(error_type(b"d3:keyi1e"), BencodeParseErrorType::Dictionary);
+        assert_eq!(
+            error_type(b"d3:key10:shorte"),
+            BencodeParseErrorType::ByteString
+        );
+        assert_eq!(error_type(b"li1eixee"), BencodeParseErrorType::Integer);
+        assert_eq!(error_type(b"di1ei2ee"), BencodeParseErrorType::Dictionary);
+        assert_eq!(error_type(b"i1ei2e"), BencodeParseErrorType::End);
+        assert_eq!(error_type(b"x"), BencodeParseErrorType::Initiate);
+    }
+}
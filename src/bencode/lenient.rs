@@ -0,0 +1,247 @@
+// Decoding that salvages what it can from damaged input, for tools showing a user what's in a
+// slightly corrupt .torrent rather than just that it's corrupt. A malformed integer has a clear end
+// and is simply left out (along with its key, in a dictionary); anything after which the input
+// can't be followed any further ends the decode, keeping every value completed before it and
+// closing the lists and dictionaries still open. A byte string cut short by the end of the input
+// keeps the bytes that are there.
+use super::lazy::{byte_string_bounds, integer_at};
+use super::{Bencodable, BencodeParseError, BencodeParseErrorType, DecodeLimits};
+use std::collections::BTreeMap;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Recovered {
+    // None only when not even the start of a value could be read
+    pub value: Option<Bencodable>,
+    // in the order they were met; empty when the input was well formed
+    pub errors: Vec<BencodeParseError>,
+}
+
+pub fn bdecode_lenient(bencoded_bytes: &[u8]) -> Recovered {
+    let mut recovery = Recovery {
+        bytes: bencoded_bytes,
+        errors: vec![],
+        depth: 0,
+    };
+    let value = match recovery.value(0) {
+        Outcome::Value(value, end) => {
+            if end < bencoded_bytes.len() {
+                recovery.error(BencodeParseErrorType::End, end);
+            }
+            Some(value)
+        }
+        Outcome::Skipped(_) => None,
+        Outcome::Stopped(value) => value,
+    };
+    Recovered {
+        value,
+        errors: recovery.errors,
+    }
+}
+
+enum Outcome {
+    // the value and where it ended
+    Value(Bencodable, usize),
+    // malformed but with a clear end, so the caller carries on from there
+    Skipped(usize),
+    // nothing after this point can be read; whatever of the value was read before it
+    Stopped(Option<Bencodable>),
+}
+
+struct Recovery<'a> {
+    bytes: &'a [u8],
+    errors: Vec<BencodeParseError>,
+    depth: usize,
+}
+
+impl Recovery<'_> {
+    fn error(&mut self, error_type: BencodeParseErrorType, index: usize) {
+        self.errors
+            .push(BencodeParseError::from((error_type, index, self.bytes)));
+    }
+
+    fn value(&mut self, index: usize) -> Outcome {
+        let b = match self.bytes.get(index) {
+            Some(b) => *b,
+            None => {
+                self.error(BencodeParseErrorType::Value, index);
+                return Outcome::Stopped(None);
+            }
+        };
+        match b {
+            b'0'..=b'9' => self.byte_string(index),
+            b'i' => match integer_at(index, self.bytes) {
+                Ok((integer, end)) => Outcome::Value(Bencodable::Integer(integer), end),
+                Err(e) => {
+                    let terminated = e.index < self.bytes.len();
+                    self.errors.push(e);
+                    match terminated {
+                        true => Outcome::Skipped(self.errors.last().unwrap().index + 1),
+                        false => Outcome::Stopped(None),
+                    }
+                }
+            },
+            b'l' | b'd' => {
+                if self.depth == DecodeLimits::default().max_depth {
+                    self.error(BencodeParseErrorType::TooDeep, index);
+                    return Outcome::Stopped(None);
+                }
+                self.depth += 1;
+                let outcome = match b {
+                    b'l' => self.list(index + 1),
+                    _ => self.dictionary(index + 1),
+                };
+                self.depth -= 1;
+                outcome
+            }
+            _ => {
+                self.error(BencodeParseErrorType::Initiate, index);
+                Outcome::Stopped(None)
+            }
+        }
+    }
+
+    fn byte_string(&mut self, index: usize) -> Outcome {
+        match byte_string_bounds(index, self.bytes) {
+            Ok((start, end)) => Outcome::Value(Bencodable::from(&self.bytes[start..end]), end),
+            Err(e) => {
+                // only a string running past the end of the input has bytes worth keeping
+                let truncated = (e.error_type == BencodeParseErrorType::ByteString)
+                    .then(|| Bencodable::from(&self.bytes[e.index + 1..]));
+                self.errors.push(e);
+                Outcome::Stopped(truncated)
+            }
+        }
+    }
+
+    fn list(&mut self, mut i: usize) -> Outcome {
+        let mut list = vec![];
+        loop {
+            match self.bytes.get(i) {
+                Some(b'e') => return Outcome::Value(Bencodable::List(list), i + 1),
+                None => {
+                    self.error(BencodeParseErrorType::List, i);
+                    return Outcome::Stopped(Some(Bencodable::List(list)));
+                }
+                Some(_) => {}
+            }
+            match self.value(i) {
+                Outcome::Value(value, end) => {
+                    list.push(value);
+                    i = end;
+                }
+                Outcome::Skipped(end) => i = end,
+                Outcome::Stopped(value) => {
+                    list.extend(value);
+                    return Outcome::Stopped(Some(Bencodable::List(list)));
+                }
+            }
+        }
+    }
+
+    fn dictionary(&mut self, mut i: usize) -> Outcome {
+        let mut dictionary = BTreeMap::new();
+        loop {
+            let key = match self.bytes.get(i) {
+                Some(b'e') => return Outcome::Value(Bencodable::Dictionary(dictionary), i + 1),
+                Some(b) if b.is_ascii_digit() => byte_string_bounds(i, self.bytes),
+                Some(_) => Err(BencodeParseError::from((
+                    BencodeParseErrorType::Dictionary,
+                    i,
+                    self.bytes,
+                ))),
+                None => Err(BencodeParseError::from((
+                    BencodeParseErrorType::Dictionary,
+                    i,
+                    self.bytes,
+                ))),
+            };
+            let (key_start, key_end) = match key {
+                Ok(bounds) => bounds,
+                Err(e) => {
+                    self.errors.push(e);
+                    return Outcome::Stopped(Some(Bencodable::Dictionary(dictionary)));
+                }
+            };
+            let key = self.bytes[key_start..key_end].into();
+            match self.value(key_end) {
+                Outcome::Value(value, end) => {
+                    dictionary.insert(key, value);
+                    i = end;
+                }
+                Outcome::Skipped(end) => i = end,
+                Outcome::Stopped(value) => {
+                    if let Some(value) = value {
+                        dictionary.insert(key, value);
+                    }
+                    return Outcome::Stopped(Some(Bencodable::Dictionary(dictionary)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::{bdecode, DictBuilder};
+
+    fn error_types(recovered: &Recovered) -> Vec<BencodeParseErrorType> {
+        recovered.errors.iter().map(|e| e.error_type).collect()
+    }
+
+    #[test]
+    fn it_recovers_what_it_can_from_damaged_input() {
+        let bytes: &[u8] = b"d8:announce3:url4:infod6:lengthi5e4:name5:a.txtee";
+        assert_eq!(
+            bdecode_lenient(bytes),
+            Recovered {
+                value: Some(bdecode(bytes).unwrap()),
+                errors: vec![]
+            }
+        );
+
+        // a bad integer is dropped along with its key and the rest still decodes
+        let recovered = bdecode_lenient(b"d1:ai1x2e1:bli1ei2eee");
+        assert_eq!(
+            error_types(&recovered),
+            vec![BencodeParseErrorType::Integer]
+        );
+        assert_eq!(
+            recovered.value,
+            Some(
+                DictBuilder::new()
+                    .insert("b", vec![Bencodable::from(1_i64), Bencodable::from(2_i64)])
+                    .build()
+            )
+        );
+
+        // cut off mid-way: every finished value survives and the string keeps what arrived
+        let recovered = bdecode_lenient(b"d8:announce3:url4:infod6:lengthi5e6:pieces20:abc");
+        assert_eq!(
+            error_types(&recovered),
+            vec![BencodeParseErrorType::ByteString]
+        );
+        let value = recovered.value.unwrap();
+        assert_eq!(value["announce"], Bencodable::from("url"));
+        assert_eq!(value["info"]["length"], Bencodable::Integer(5));
+        assert_eq!(value["info"]["pieces"], Bencodable::from("abc"));
+
+        let recovered = bdecode_lenient(b"li1ei2e:x");
+        assert_eq!(
+            error_types(&recovered),
+            vec![BencodeParseErrorType::Initiate]
+        );
+        assert_eq!(
+            recovered.value,
+            Some(Bencodable::from(vec![
+                Bencodable::from(1_i64),
+                Bencodable::from(2_i64)
+            ]))
+        );
+
+        let recovered = bdecode_lenient(b"i1ei2e");
+        assert_eq!(error_types(&recovered), vec![BencodeParseErrorType::End]);
+        assert_eq!(recovered.value, Some(Bencodable::Integer(1)));
+        assert_eq!(bdecode_lenient(b"").value, None);
+    }
+}
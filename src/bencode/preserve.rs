@@ -0,0 +1,135 @@
+// Re-encoding that keeps the input's own spelling. `bencode` always writes the canonical encoding,
+// so a value decoded from input with unsorted keys, leading zeros or padded lengths comes back out
+// as different bytes, and anything hashed (an info dictionary above all) gets a different hash.
+// `Preserved` remembers the bytes a value was decoded from and writes every part of it that hasn't
+// changed exactly as it was read, key order included; only what was changed is encoded afresh.
+use super::{bdecode_with_span, bencode, Bencodable, BencodeParseError, EncodeError};
+use super::{Span, SpanChildren};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preserved {
+    original: Vec<u8>,
+    decoded: Bencodable,
+    span: Span,
+}
+
+impl Preserved {
+    pub fn decode(bytes: &[u8]) -> Result<Self, BencodeParseError> {
+        let (decoded, span) = bdecode_with_span(bytes)?;
+        Ok(Preserved::new(bytes.to_vec(), decoded, span))
+    }
+
+    // For a caller that already has what `bdecode_with_span` made of `original`
+    pub fn new(original: Vec<u8>, decoded: Bencodable, span: Span) -> Self {
+        Preserved {
+            original,
+            decoded,
+            span,
+        }
+    }
+
+    pub fn value(&self) -> &Bencodable {
+        &self.decoded
+    }
+
+    pub fn original(&self) -> &[u8] {
+        &self.original
+    }
+
+    // `value`, typically an edited copy of `self.value()`, written with the original bytes wherever
+    // it still matches what was decoded. Keys that weren't in the original go in before the first
+    // original key that sorts after them, so canonical input stays canonical. A key that appeared
+    // more than once in the original is written once, with the value it decoded to.
+    pub fn encode(&self, value: &Bencodable) -> Result<Vec<u8>, EncodeError> {
+        let mut out = vec![];
+        encode_preserving(value, &self.decoded, &self.span, &self.original, &mut out)?;
+        Ok(out)
+    }
+}
+
+fn encode_preserving(
+    value: &Bencodable,
+    decoded: &Bencodable,
+    span: &Span,
+    original: &[u8],
+    out: &mut Vec<u8>,
+) -> Result<(), EncodeError> {
+    if value == decoded {
+        out.extend_from_slice(span.slice(original));
+        return Ok(());
+    }
+    match (value, decoded, &span.children) {
+        (Bencodable::List(list), Bencodable::List(decoded), SpanChildren::List(spans))
+            if list.len() == decoded.len() =>
+        {
+            out.push(b'l');
+            for ((value, decoded), span) in list.iter().zip(decoded).zip(spans) {
+                encode_preserving(value, decoded, span, original, out)?;
+            }
+            out.push(b'e');
+        }
+        (
+            Bencodable::Dictionary(dictionary),
+            Bencodable::Dictionary(decoded),
+            SpanChildren::Dictionary(spans),
+        ) => {
+            let mut entries: Vec<_> = spans.iter().collect();
+            entries.sort_by_key(|(_, span)| span.start);
+            let mut added = dictionary
+                .iter()
+                .filter(|(key, _)| !spans.contains_key(*key))
+                .peekable();
+            // a key's bytes run from where the previous value ended to where its own value starts
+            let mut key_start = span.start + 1;
+            out.push(b'd');
+            for (key, span) in entries {
+                while let Some((added_key, added_value)) = added.next_if(|(k, _)| *k < key) {
+                    out.extend(bencode(&Bencodable::ByteString(added_key.clone()))?);
+                    out.extend(bencode(added_value)?);
+                }
+                if let Some(value) = dictionary.get(key) {
+                    out.extend_from_slice(&original[key_start..span.start]);
+                    encode_preserving(value, &decoded[key], span, original, out)?;
+                }
+                key_start = span.end;
+            }
+            for (added_key, added_value) in added {
+                out.extend(bencode(&Bencodable::ByteString(added_key.clone()))?);
+                out.extend(bencode(added_value)?);
+            }
+            out.push(b'e');
+        }
+        _ => out.extend(bencode(value)?),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::BencodableByteString;
+
+    #[test]
+    fn it_round_trips_non_canonical_input_exactly() {
+        let bytes = b"d4:infod6:lengthi05e4:name5:a.txte03:abcli1ei2ee8:announce3:urle";
+        let preserved = Preserved::decode(bytes).unwrap();
+        assert_eq!(preserved.encode(preserved.value()).unwrap(), bytes);
+        assert_ne!(bencode(preserved.value()).unwrap(), bytes);
+    }
+
+    #[test]
+    fn it_only_re_encodes_what_changed() {
+        let bytes = b"d8:announce3:url4:infod6:lengthi05e4:name5:a.txtee";
+        let preserved = Preserved::decode(bytes).unwrap();
+        let mut edited = preserved.value().clone();
+        if let Bencodable::Dictionary(dictionary) = &mut edited {
+            dictionary.insert(BencodableByteString::from("announce"), "other".into());
+            dictionary.insert(BencodableByteString::from("comment"), "hi".into());
+            dictionary.insert(BencodableByteString::from("z"), 1i64.into());
+        }
+        assert_eq!(
+            preserved.encode(&edited).unwrap(),
+            b"d8:announce5:other7:comment2:hi4:infod6:lengthi05e4:name5:a.txte1:zi1ee"
+        );
+    }
+}
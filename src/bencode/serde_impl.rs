@@ -0,0 +1,682 @@
+// Maps serde's data model onto `Bencodable`. Bencode only has byte strings, integers, lists and
+// dictionaries, so: bools are 0/1 integers, `None` struct fields are left out, unit variants are
+// byte strings and every other enum variant is a one entry dictionary keyed by the variant name.
+// Floats and non string map keys are rejected.
+use super::{bdecode, bencode, Bencodable, BencodableByteString, BencodeParseError, EncodeError};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug)]
+pub enum SerdeError {
+    Message(String),
+    Unsupported(&'static str),
+    IntegerOutOfRange,
+    NonStringKey,
+    Decode(BencodeParseError),
+    Encode(EncodeError),
+}
+
+impl std::fmt::Display for SerdeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerdeError::Message(m) => write!(f, "{}", m),
+            SerdeError::Unsupported(what) => write!(f, "bencode has no way to represent {}", what),
+            SerdeError::IntegerOutOfRange => write!(f, "integer does not fit in a bencode integer"),
+            SerdeError::NonStringKey => write!(f, "dictionary keys must be strings or bytes"),
+            SerdeError::Decode(e) => write!(f, "invalid bencode: {}", e),
+            SerdeError::Encode(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SerdeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SerdeError::Decode(e) => Some(e),
+            SerdeError::Encode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl ser::Error for SerdeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        SerdeError::Message(msg.to_string())
+    }
+}
+
+impl de::Error for SerdeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        SerdeError::Message(msg.to_string())
+    }
+}
+
+pub fn to_bencodable<T: Serialize + ?Sized>(value: &T) -> Result<Bencodable, SerdeError> {
+    value
+        .serialize(Serializer)?
+        .ok_or(SerdeError::Unsupported("a missing top level value"))
+}
+
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, SerdeError> {
+    bencode(&to_bencodable(value)?).map_err(SerdeError::Encode)
+}
+
+pub fn from_bencodable<T: DeserializeOwned>(bencodable: Bencodable) -> Result<T, SerdeError> {
+    T::deserialize(Deserializer(bencodable))
+}
+
+pub fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, SerdeError> {
+    from_bencodable(bdecode(bytes).map_err(SerdeError::Decode)?)
+}
+
+fn integer<T: TryInto<i64>>(v: T) -> Result<Option<Bencodable>, SerdeError> {
+    v.try_into()
+        .map(|i| Some(Bencodable::Integer(i)))
+        .map_err(|_| SerdeError::IntegerOutOfRange)
+}
+
+fn byte_string(bytes: &[u8]) -> Option<Bencodable> {
+    Some(Bencodable::from(bytes))
+}
+
+fn variant(name: &str, value: Bencodable) -> Option<Bencodable> {
+    let mut btm = BTreeMap::new();
+    btm.insert(BencodableByteString::from(name), value);
+    Some(Bencodable::Dictionary(btm))
+}
+
+// `Ok(None)` means "nothing to write", which is how `None` fields get skipped
+struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = Option<Bencodable>;
+    type Error = SerdeError;
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = ListSerializer;
+    type SerializeMap = DictionarySerializer;
+    type SerializeStruct = DictionarySerializer;
+    type SerializeStructVariant = DictionarySerializer;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, SerdeError> {
+        integer(v as i64)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, SerdeError> {
+        integer(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, SerdeError> {
+        integer(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, SerdeError> {
+        integer(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, SerdeError> {
+        integer(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, SerdeError> {
+        integer(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, SerdeError> {
+        integer(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, SerdeError> {
+        integer(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, SerdeError> {
+        integer(v)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok, SerdeError> {
+        Err(SerdeError::Unsupported("floats"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok, SerdeError> {
+        Err(SerdeError::Unsupported("floats"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, SerdeError> {
+        Ok(byte_string(v.to_string().as_bytes()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, SerdeError> {
+        Ok(byte_string(v.as_bytes()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, SerdeError> {
+        Ok(byte_string(v))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, SerdeError> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, SerdeError> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, SerdeError> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, SerdeError> {
+        Ok(byte_string(variant.as_bytes()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, SerdeError> {
+        let value = value
+            .serialize(Serializer)?
+            .ok_or(SerdeError::Unsupported("an empty enum variant payload"))?;
+        Ok(variant(name, value))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<ListSerializer, SerdeError> {
+        Ok(ListSerializer {
+            variant: None,
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, SerdeError> {
+        Ok(ListSerializer {
+            variant: Some(variant),
+            items: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<DictionarySerializer, SerdeError> {
+        Ok(DictionarySerializer {
+            variant: None,
+            entries: BTreeMap::new(),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<DictionarySerializer, SerdeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<DictionarySerializer, SerdeError> {
+        Ok(DictionarySerializer {
+            variant: Some(variant),
+            entries: BTreeMap::new(),
+            next_key: None,
+        })
+    }
+}
+
+struct ListSerializer {
+    variant: Option<&'static str>,
+    items: Vec<Bencodable>,
+}
+
+impl ListSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let item = value
+            .serialize(Serializer)?
+            .ok_or(SerdeError::Unsupported("missing values inside a list"))?;
+        self.items.push(item);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<Bencodable>, SerdeError> {
+        let list = Bencodable::List(self.items);
+        Ok(match self.variant {
+            Some(name) => variant(name, list),
+            None => Some(list),
+        })
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer {
+    type Ok = Option<Bencodable>;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Option<Bencodable>;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Option<Bencodable>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for ListSerializer {
+    type Ok = Option<Bencodable>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+struct DictionarySerializer {
+    variant: Option<&'static str>,
+    entries: BTreeMap<BencodableByteString, Bencodable>,
+    next_key: Option<BencodableByteString>,
+}
+
+impl DictionarySerializer {
+    fn insert<T: Serialize + ?Sized>(
+        &mut self,
+        key: BencodableByteString,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        if let Some(value) = value.serialize(Serializer)? {
+            self.entries.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<Bencodable>, SerdeError> {
+        let dictionary = Bencodable::Dictionary(self.entries);
+        Ok(match self.variant {
+            Some(name) => variant(name, dictionary),
+            None => Some(dictionary),
+        })
+    }
+}
+
+impl ser::SerializeMap for DictionarySerializer {
+    type Ok = Option<Bencodable>;
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        match key.serialize(Serializer)? {
+            Some(Bencodable::ByteString(bs)) => {
+                self.next_key = Some(bs);
+                Ok(())
+            }
+            _ => Err(SerdeError::NonStringKey),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| SerdeError::Message("value serialized before its key".to_string()))?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for DictionarySerializer {
+    type Ok = Option<Bencodable>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.insert(BencodableByteString::from(key), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for DictionarySerializer {
+    type Ok = Option<Bencodable>;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.insert(BencodableByteString::from(key), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerdeError> {
+        self.finish()
+    }
+}
+
+struct Deserializer(Bencodable);
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Bencodable::ByteString(bs) => match String::from_utf8(bs.0) {
+                Ok(s) => visitor.visit_string(s),
+                Err(e) => visitor.visit_byte_buf(e.into_bytes()),
+            },
+            Bencodable::Integer(i) if i < 0 => visitor.visit_i64(i),
+            Bencodable::Integer(i) => visitor.visit_u64(i as u64),
+            Bencodable::List(items) => visitor.visit_seq(de::value::SeqDeserializer::new(
+                items.into_iter().map(Deserializer),
+            )),
+            Bencodable::Dictionary(btm) => visitor
+                .visit_map(de::value::MapDeserializer::new(btm.into_iter().map(
+                    |(k, v)| (Deserializer(Bencodable::ByteString(k)), Deserializer(v)),
+                ))),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Bencodable::Integer(i) => visitor.visit_bool(i != 0),
+            other => Deserializer(other).deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Bencodable::ByteString(bs) => visitor.visit_byte_buf(bs.0),
+            other => Deserializer(other).deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    // Lets a plain `Vec<u8>` field read a byte string as well as a list of integers
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Bencodable::ByteString(bs) => {
+                visitor.visit_seq(de::value::SeqDeserializer::new(bs.0.into_iter()))
+            }
+            other => Deserializer(other).deserialize_any(visitor),
+        }
+    }
+
+    // A value that is present is always `Some`; absent struct fields become `None` in serde itself
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        match self.0 {
+            Bencodable::ByteString(bs) => {
+                let name = String::from_utf8(bs.0)
+                    .map_err(|_| SerdeError::Message("enum variant is not utf-8".to_string()))?;
+                visitor.visit_enum(name.into_deserializer())
+            }
+            Bencodable::Dictionary(btm) if btm.len() == 1 => {
+                let (name, value) = btm.into_iter().next().unwrap();
+                visitor.visit_enum(EnumDeserializer { name, value })
+            }
+            _ => Err(SerdeError::Message(
+                "expected a byte string or a single entry dictionary for an enum".to_string(),
+            )),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        unit unit_struct tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, SerdeError> for Deserializer {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+struct EnumDeserializer {
+    name: BencodableByteString,
+    value: Bencodable,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumDeserializer {
+    type Error = SerdeError;
+    type Variant = Deserializer;
+
+    fn variant_seed<S: de::DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<(S::Value, Deserializer), SerdeError> {
+        let name = seed.deserialize(Deserializer(Bencodable::ByteString(self.name)))?;
+        Ok((name, Deserializer(self.value)))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Deserializer {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<S: de::DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<S::Value, SerdeError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Info {
+        name: String,
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        #[serde(with = "bytes")]
+        pieces: Vec<u8>,
+        length: Option<u32>,
+        private: Option<bool>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Torrent {
+        announce: String,
+        #[serde(rename = "announce-list", default)]
+        announce_list: Vec<Vec<String>>,
+        info: Info,
+    }
+
+    // `Vec<u8>` serializes as a list of integers by default; torrents want a byte string
+    mod bytes {
+        pub fn serialize<S: serde::Serializer>(v: &[u8], s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_bytes(v)
+        }
+
+        pub fn deserialize<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+            serde::Deserialize::deserialize(d)
+        }
+    }
+
+    fn example() -> Torrent {
+        Torrent {
+            announce: "http://tracker.example/announce".to_string(),
+            announce_list: vec![],
+            info: Info {
+                name: "a.txt".to_string(),
+                piece_length: 16384,
+                pieces: vec![0xff; 20],
+                length: Some(5),
+                private: None,
+            },
+        }
+    }
+
+    #[test]
+    fn it_serializes_structs_to_bencode() {
+        let bytes = to_bytes(&example()).unwrap();
+        let mut expected = b"d8:announce31:http://tracker.example/announce13:announce-listle4:infod6:lengthi5e4:name5:a.txt12:piece lengthi16384e6:pieces20:".to_vec();
+        expected.extend_from_slice(&[0xff; 20]);
+        expected.extend_from_slice(b"ee");
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn it_round_trips_structs() {
+        let bytes = to_bytes(&example()).unwrap();
+        assert_eq!(from_bytes::<Torrent>(&bytes).unwrap(), example());
+    }
+
+    #[test]
+    fn it_deserializes_missing_and_extra_keys() {
+        let torrent: Torrent = from_bytes(
+            b"d8:announce3:url7:comment2:hi4:infod4:name1:a12:piece lengthi1e6:pieces0:7:privatei1eee",
+        )
+        .unwrap();
+        assert_eq!(torrent.announce_list, Vec::<Vec<String>>::new());
+        assert_eq!(torrent.info.length, None);
+        assert_eq!(torrent.info.private, Some(true));
+    }
+
+    #[test]
+    fn it_serializes_enums() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Event {
+            Started,
+            Stopped { reason: String },
+        }
+
+        assert_eq!(to_bytes(&Event::Started).unwrap(), b"7:Started");
+        let stopped = Event::Stopped {
+            reason: "done".to_string(),
+        };
+        let bytes = to_bytes(&stopped).unwrap();
+        assert_eq!(bytes, b"d7:Stoppedd6:reason4:doneee");
+        assert_eq!(from_bytes::<Event>(&bytes).unwrap(), stopped);
+    }
+
+    #[test]
+    fn it_rejects_floats_and_out_of_range_integers() {
+        assert!(matches!(to_bytes(&1.5f64), Err(SerdeError::Unsupported(_))));
+        assert!(matches!(
+            to_bytes(&u64::MAX),
+            Err(SerdeError::IntegerOutOfRange)
+        ));
+        assert_eq!(to_bytes(&-1i32).unwrap(), b"i-1e");
+        assert_eq!(from_bytes::<i32>(b"i-1e").unwrap(), -1);
+    }
+}
@@ -37,6 +37,12 @@ impl From<Vec<u8>> for BitField {
     }
 }
 
+impl From<BitField> for Vec<u8> {
+    fn from(bf: BitField) -> Vec<u8> {
+        bf.bf
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
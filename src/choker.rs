@@ -0,0 +1,85 @@
+use crate::connection_manager::PeerUsefulness;
+use crate::torrent::Torrent;
+
+pub const DEFAULT_ENDGAME_SLOTS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadPhase {
+    Downloading,
+    // every block still missing has been requested from someone; all that's left is waiting
+    Endgame,
+    Seeding,
+}
+
+impl DownloadPhase {
+    pub fn of(torrent: &Torrent) -> Self {
+        if torrent.are_we_done_yet() {
+            DownloadPhase::Seeding
+        } else if torrent.in_endgame() {
+            DownloadPhase::Endgame
+        } else {
+            DownloadPhase::Downloading
+        }
+    }
+}
+
+// Decides who we upload to. Every connection asks on each turn of its loop, so a policy that
+// answers differently as the phase changes takes effect straight away: peers it stops wanting to
+// upload to are choked again.
+pub trait ChokePolicy: Send + Sync {
+    // `unchoked` is how many of the torrent's other connections we're uploading to right now
+    fn unchoke(&self, phase: DownloadPhase, peer: &PeerUsefulness, unchoked: usize) -> bool;
+}
+
+// Uploads nothing while there's plenty left to download and to anyone who asks once seeding. In
+// the endgame, up to `endgame_slots` peers that asked and still have pieces we're missing are
+// unchoked, so they've a reason to keep answering the last requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndgameReciprocation {
+    pub endgame_slots: usize,
+}
+
+impl Default for EndgameReciprocation {
+    fn default() -> Self {
+        EndgameReciprocation {
+            endgame_slots: DEFAULT_ENDGAME_SLOTS,
+        }
+    }
+}
+
+impl ChokePolicy for EndgameReciprocation {
+    fn unchoke(&self, phase: DownloadPhase, peer: &PeerUsefulness, unchoked: usize) -> bool {
+        match phase {
+            DownloadPhase::Downloading => false,
+            DownloadPhase::Endgame => {
+                peer.remote_interested && peer.local_interested && unchoked < self.endgame_slots
+            }
+            DownloadPhase::Seeding => peer.remote_interested,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_unchokes_peers_with_missing_pieces_during_endgame() {
+        let policy = EndgameReciprocation { endgame_slots: 2 };
+        let reciprocating = PeerUsefulness {
+            local_interested: true,
+            remote_interested: true,
+            ..PeerUsefulness::default()
+        };
+        let leecher = PeerUsefulness {
+            remote_interested: true,
+            ..PeerUsefulness::default()
+        };
+
+        assert!(!policy.unchoke(DownloadPhase::Downloading, &reciprocating, 0));
+        assert!(policy.unchoke(DownloadPhase::Endgame, &reciprocating, 1));
+        assert!(!policy.unchoke(DownloadPhase::Endgame, &reciprocating, 2));
+        assert!(!policy.unchoke(DownloadPhase::Endgame, &leecher, 0));
+        assert!(policy.unchoke(DownloadPhase::Seeding, &leecher, 10));
+    }
+}
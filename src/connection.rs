@@ -1,12 +1,16 @@
+use crate::bitfield::BitField;
 use crate::messages::*;
+use crate::replay::ReplayPeer;
+use crate::scheduler::{AssignmentAudit, RequestBudget};
+use crate::sim::SimulatedPeer;
+use crate::ut_metadata::MetadataServer;
 use crate::util;
-use crate::util::ExecutionErr;
-use crate::BitField;
+use std::collections::HashMap;
 use std::io::prelude::*;
 use std::io::Error as IOError;
 use std::net::SocketAddr;
 use std::net::TcpStream;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub enum SendError {
@@ -16,27 +20,61 @@ pub enum SendError {
     ReturnHandshakeReadTimeOut,
     Connect(IOError),
     UnexpectedInfoHashOrPeerId,
+    ProtocolViolation(ProtocolViolation),
+    // the session's `HandshakeGate` is full
+    TooManyPendingHandshakes,
 }
 
 #[derive(Debug)]
 pub enum Stream {
     Tcp(TcpStream),
+    Simulated(Box<SimulatedPeer>),
+    Replay(Box<ReplayPeer>),
 }
 
-type OnReadCallBack = Box<dyn Fn((crate::Message, SocketAddr, SocketAddr), &[u8]) + 'static + Send>;
+type OnReadCallBack = Box<dyn Fn((Message, SocketAddr, SocketAddr), &[u8]) + 'static + Send>;
 
 pub struct PeerConnection {
     stream: Stream,
     pub is_local_interested: bool,
     pub is_choked: bool,
+    pub is_remote_interested: bool,
+    // whether we are choking the peer; the torrent's `ChokePolicy` decides
+    pub is_remote_choked: bool,
     pub bitfield: Option<BitField>,
     pub peer_addr: std::net::SocketAddr,
     pub local_addr: std::net::SocketAddr,
     pub in_progress_requests: usize,
+    // block data received from and sent to this peer
+    pub downloaded_bytes: u64,
+    pub uploaded_bytes: u64,
+    // how far into `Torrent::available_pieces_since` we've already sent Haves for
+    pub announced_pieces: usize,
+    pub remote_peer_id: Vec<u8>,
+    // both sides advertised the fast extension (BEP 6), so its messages may be used
+    pub fast_extension: bool,
+    // the id the peer wants ut_metadata messages sent with, once its extended handshake arrives
+    pub remote_ut_metadata: Option<u8>,
+    pub metadata_server: Option<MetadataServer>,
+    // shared by every connection in the session; each request holds a full block of it
+    pub request_budget: Option<RequestBudget>,
+    // told about every block requested from this peer, when someone is listening
+    pub assignment_audit: Option<AssignmentAudit>,
+    // pieces the peer suggested, most recent last; see `suggest`
+    suggested_pieces: Vec<u32>,
     on_read: OnReadCallBack,
+    strict: bool,
+    handshake_violation: Option<ProtocolViolation>,
+    messages_received: u64,
+    received_bitfield: bool,
+    // (index, offset) -> length of every request the peer still owes us a block for
+    outstanding_requests: HashMap<(u32, u32), u32>,
 }
 
 const HANDSHAKE_READ_TIMEOUT: Duration = Duration::from_millis(1500);
+// Suggestions only reorder what we ask this peer for, and only the most recent few count, so a
+// peer flooding us with them can't steer the download any further than that
+const MAX_SUGGESTED_PIECES: usize = 8;
 
 impl PeerConnection {
     pub fn new(
@@ -49,6 +87,7 @@ impl PeerConnection {
         let handshake = Handshake {
             info_hash: info_hash.to_vec(),
             peer_id: my_peer_id.to_vec(),
+            fast_extension: true,
         };
         println!(
             "outgoing handshake has peer ID: {:?}",
@@ -60,18 +99,9 @@ impl PeerConnection {
             .write_all(&bytes)
             .map_err(SendError::Write)
             .and_then(|_| {
-                let work = move || {
-                    let mut buf: Vec<u8> = vec![0; 68];
-                    stream
-                        .read_exact(&mut buf)
-                        .map(|_| (buf, stream))
-                        .map_err(SendError::ReturnHandshakeRead)
-                };
-
-                util::with_timeout(work, HANDSHAKE_READ_TIMEOUT).map_err(|e| match e {
-                    ExecutionErr::TimedOut => SendError::ReturnHandshakeReadTimeOut,
-                    ExecutionErr::Err(e) => e,
-                })
+                let mut buf: Vec<u8> = vec![0; 68];
+                let deadline = Instant::now() + HANDSHAKE_READ_TIMEOUT;
+                read_exact_before(&mut stream, &mut buf, deadline).map(|_| (buf, stream))
             })
             .and_then(|(buf, stream)| {
                 Handshake::new(&buf)
@@ -81,40 +111,124 @@ impl PeerConnection {
                             "incoming handshake has peer ID: {:?}",
                             std::str::from_utf8(&return_handshake.peer_id).unwrap()
                         );
-                        if handshake.info_hash == return_handshake.info_hash
-                            && return_handshake.peer_id == peer_id
-                        {
-                            stream
+                        let info_hash_matches = handshake.info_hash == return_handshake.info_hash;
+                        let peer_id_matches = return_handshake.peer_id == peer_id;
+                        let fast_extension = return_handshake.fast_extension;
+                        if info_hash_matches && peer_id_matches {
+                            (stream, return_handshake.peer_id, fast_extension, None)
                         } else {
                             println!(
                                 "the client's peer ID did not match... {:?}",
                                 SendError::UnexpectedInfoHashOrPeerId
                             );
-                            stream
+                            (
+                                stream,
+                                return_handshake.peer_id,
+                                fast_extension,
+                                Some(ProtocolViolation::HandshakeMismatch {
+                                    info_hash_matches,
+                                    peer_id_matches,
+                                }),
+                            )
                         }
                     })
             })
-            .map(|s| {
+            .map(|(s, remote_peer_id, fast_extension, handshake_violation)| {
                 let peer_addr = match &s {
                     Stream::Tcp(tcps) => tcps.peer_addr().unwrap(),
+                    Stream::Simulated(sp) => sp.peer_addr,
+                    Stream::Replay(rp) => rp.peer_addr,
                 };
                 let local_addr = match &s {
                     Stream::Tcp(tcps) => tcps.local_addr().unwrap(),
+                    Stream::Simulated(sp) => sp.local_addr,
+                    Stream::Replay(rp) => rp.local_addr,
                 };
                 PeerConnection {
                     stream: s,
                     is_local_interested: false,
                     is_choked: true,
+                    is_remote_interested: false,
+                    is_remote_choked: true,
                     bitfield: None,
                     peer_addr,
                     local_addr,
                     in_progress_requests: 0,
+                    downloaded_bytes: 0,
+                    uploaded_bytes: 0,
+                    announced_pieces: 0,
+                    remote_peer_id,
+                    fast_extension,
+                    remote_ut_metadata: None,
+                    metadata_server: None,
+                    request_budget: None,
+                    assignment_audit: None,
+                    suggested_pieces: vec![],
                     on_read: Box::new(on_read),
+                    strict: false,
+                    handshake_violation,
+                    messages_received: 0,
+                    received_bitfield: false,
+                    outstanding_requests: HashMap::new(),
                 }
             })
     }
 
+    // Turns soft protocol violations into errors from `read_message` so the caller hangs up on the
+    // peer. Fails straight away if the return handshake already broke the rules.
+    pub fn strict(mut self) -> Result<Self, ProtocolViolation> {
+        match self.handshake_violation.take() {
+            Some(violation) => Err(violation),
+            None => {
+                self.strict = true;
+                Ok(self)
+            }
+        }
+    }
+
+    // (index, offset) of every block we asked the peer for that hasn't arrived yet
+    pub fn outstanding_requests(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.outstanding_requests.keys().copied()
+    }
+
+    // Forgets a request the peer rejected; false when we never asked for that block
+    pub fn reject(&mut self, index: u32, begin: u32) -> bool {
+        self.outstanding_requests.remove(&(index, begin)).is_some()
+    }
+
+    // Likewise for a request the peer sent the block for
+    pub fn answered(&mut self, index: u32, begin: u32) -> bool {
+        self.outstanding_requests.remove(&(index, begin)).is_some()
+    }
+
+    // Remembers a Suggest Piece as a hint for the piece picker, dropping the oldest suggestion
+    // once there are too many. Suggesting the same piece again moves it to the back.
+    pub fn suggest(&mut self, index: u32) {
+        self.suggested_pieces
+            .retain(|suggested| *suggested != index);
+        if self.suggested_pieces.len() == MAX_SUGGESTED_PIECES {
+            self.suggested_pieces.remove(0);
+        }
+        self.suggested_pieces.push(index);
+    }
+
+    // Most recent first, since those are the likeliest to still be in the peer's cache
+    pub fn suggested_pieces(&self) -> Vec<u32> {
+        self.suggested_pieces.iter().rev().copied().collect()
+    }
+
     pub fn write_message(&mut self, m: Message) -> Result<(), SendError> {
+        if let Message::Request {
+            index,
+            begin,
+            length,
+        } = m
+        {
+            self.outstanding_requests.insert((index, begin), length);
+        }
+        if let Message::Piece { data, .. } = &m {
+            self.uploaded_bytes += data.len() as u64;
+        }
         let to_write = &m.serialize();
         (self.on_read)((m, self.peer_addr, self.local_addr), to_write);
         self.stream.write_all(to_write).map_err(SendError::Write)
@@ -152,19 +266,139 @@ impl PeerConnection {
             .and_then(|(message_buf, prefix_len)| {
                 Message::new(Box::new(message_buf.into_iter()), prefix_len)
             })
+            .and_then(|message| {
+                let conformance = self.check_conformance(&message);
+                if !matches!(message, Message::KeepAlive) {
+                    self.messages_received += 1;
+                }
+                if let Message::Piece { data, .. } = &message {
+                    self.downloaded_bytes += data.len() as u64;
+                }
+                match conformance {
+                    Err(violation) if self.strict => {
+                        Err(MessageParseError::ProtocolViolation(violation))
+                    }
+                    _ => Ok(message),
+                }
+            })
     }
+
+    // Tracks what the peer has sent so far whether or not the connection is strict
+    fn check_conformance(&mut self, message: &Message) -> Result<(), ProtocolViolation> {
+        match message {
+            Message::KeepAlive => Ok(()),
+            Message::HaveAll
+            | Message::HaveNone
+            | Message::RejectRequest { .. }
+            | Message::AllowedFast { .. }
+                if !self.fast_extension =>
+            {
+                Err(ProtocolViolation::FastExtensionNotNegotiated {
+                    kind: message.kind(),
+                })
+            }
+            Message::BitField(_) | Message::HaveAll | Message::HaveNone
+                if self.received_bitfield =>
+            {
+                Err(ProtocolViolation::DuplicateBitField)
+            }
+            Message::BitField(_) | Message::HaveAll | Message::HaveNone => {
+                self.received_bitfield = true;
+                match self.messages_received {
+                    0 => Ok(()),
+                    messages_before => Err(ProtocolViolation::BitFieldNotFirst { messages_before }),
+                }
+            }
+            Message::Piece {
+                index,
+                offset,
+                data,
+            } => {
+                // left outstanding until the block is handled, see `answered`
+                let (index, offset) = (*index, *offset);
+                let requested = self.outstanding_requests.get(&(index, offset)).copied();
+                if self.is_choked {
+                    Err(ProtocolViolation::PieceWhileChoked { index, offset })
+                } else {
+                    match requested {
+                        None => Err(ProtocolViolation::UnrequestedPiece { index, offset }),
+                        Some(requested) if requested as usize != data.len() => {
+                            Err(ProtocolViolation::UnexpectedPieceLength {
+                                index,
+                                offset,
+                                requested,
+                                received: data.len() as u32,
+                            })
+                        }
+                        Some(_) => Ok(()),
+                    }
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Reads the whole of `buf` unless `deadline` passes first. The deadline covers all of it, so a peer
+// sending a byte at a time can't stretch the read out by staying just inside a per-read timeout.
+fn read_exact_before(
+    stream: &mut Stream,
+    buf: &mut [u8],
+    deadline: Instant,
+) -> Result<(), SendError> {
+    let Stream::Tcp(tcp) = stream else {
+        return stream
+            .read_exact(buf)
+            .map_err(SendError::ReturnHandshakeRead);
+    };
+    let previous_timeout = tcp.read_timeout().ok().flatten();
+    let mut read = 0;
+    let result = loop {
+        if read == buf.len() {
+            break Ok(());
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break Err(SendError::ReturnHandshakeReadTimeOut);
+        }
+        let _ = tcp.set_read_timeout(Some(remaining));
+        match tcp.read(&mut buf[read..]) {
+            Ok(0) => {
+                break Err(SendError::ReturnHandshakeRead(
+                    std::io::ErrorKind::UnexpectedEof.into(),
+                ))
+            }
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                break Err(SendError::ReturnHandshakeReadTimeOut)
+            }
+            Err(e) => break Err(SendError::ReturnHandshakeRead(e)),
+        }
+    };
+    let _ = tcp.set_read_timeout(previous_timeout);
+    result
 }
 
 impl std::io::Write for Stream {
     fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
         match self {
             Stream::Tcp(ts) => ts.write(buf),
+            Stream::Simulated(sp) => sp.write(buf),
+            Stream::Replay(rp) => rp.write(buf),
         }
     }
 
     fn flush(&mut self) -> Result<(), IOError> {
         match self {
             Stream::Tcp(ts) => ts.flush(),
+            Stream::Simulated(sp) => sp.flush(),
+            Stream::Replay(rp) => rp.flush(),
         }
     }
 }
@@ -173,6 +407,179 @@ impl std::io::Read for Stream {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
         match self {
             Stream::Tcp(ts) => ts.read(buf),
+            Stream::Simulated(sp) => sp.read(buf),
+            Stream::Replay(rp) => rp.read(buf),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::spawn;
+
+    const INFO_HASH: [u8; 20] = [3u8; 20];
+    const PEER_ID: &[u8; 20] = b"-XX0001-remotepeer00";
+
+    // Connects to a fake peer that answers the handshake and then sends `messages` verbatim
+    fn connect_to(messages: Vec<Message>) -> PeerConnection {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 68];
+            stream.read_exact(&mut buf).unwrap();
+            let handshake = Handshake {
+                info_hash: INFO_HASH.to_vec(),
+                peer_id: PEER_ID.to_vec(),
+                fast_extension: false,
+            };
+            stream.write_all(&handshake.serialize()).unwrap();
+            for message in messages {
+                stream.write_all(&message.serialize()).unwrap();
+            }
+            // keep the socket open until the other side is done reading
+            let _ = stream.read(&mut buf);
+        });
+        PeerConnection::new(
+            Stream::Tcp(TcpStream::connect(addr).unwrap()),
+            &INFO_HASH,
+            b"-BT0001-localpeer000",
+            PEER_ID,
+            Box::new(|_, _| {}),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_drops_handshakes_that_trickle_in_past_the_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // each byte well inside any per-read timeout, the whole far outside the deadline
+            for _ in 0..68 {
+                if stream.write_all(&[0]).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let mut stream = Stream::Tcp(TcpStream::connect(addr).unwrap());
+        let mut buf = [0u8; 68];
+        let started = Instant::now();
+        assert!(matches!(
+            read_exact_before(&mut stream, &mut buf, started + Duration::from_millis(200)),
+            Err(SendError::ReturnHandshakeReadTimeOut)
+        ));
+        assert!(started.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn it_tolerates_unrequested_pieces_by_default() {
+        let mut connection = connect_to(vec![
+            Message::UnChoke,
+            Message::Piece {
+                index: 0,
+                offset: 0,
+                data: vec![1, 2, 3],
+            },
+        ]);
+        connection.read_message().unwrap();
+        connection.is_choked = false;
+        assert!(matches!(
+            connection.read_message(),
+            Ok(Message::Piece { .. })
+        ));
+    }
+
+    #[test]
+    fn it_rejects_unrequested_pieces_in_strict_mode() {
+        let mut connection = connect_to(vec![
+            Message::UnChoke,
+            Message::Piece {
+                index: 0,
+                offset: 0,
+                data: vec![1, 2, 3],
+            },
+        ])
+        .strict()
+        .unwrap();
+        connection.read_message().unwrap();
+        connection.is_choked = false;
+        assert!(matches!(
+            connection.read_message(),
+            Err(MessageParseError::ProtocolViolation(
+                ProtocolViolation::UnrequestedPiece {
+                    index: 0,
+                    offset: 0
+                }
+            ))
+        ));
+    }
+
+    #[test]
+    fn it_rejects_a_late_bitfield_in_strict_mode() {
+        let mut connection = connect_to(vec![
+            Message::KeepAlive,
+            Message::Have { index: 1 },
+            Message::BitField(vec![0xff]),
+        ])
+        .strict()
+        .unwrap();
+        connection.read_message().unwrap();
+        connection.read_message().unwrap();
+        assert!(matches!(
+            connection.read_message(),
+            Err(MessageParseError::ProtocolViolation(
+                ProtocolViolation::BitFieldNotFirst { messages_before: 1 }
+            ))
+        ));
+    }
+
+    #[test]
+    fn it_refuses_strict_mode_after_a_mismatched_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 68];
+            stream.read_exact(&mut buf).unwrap();
+            let handshake = Handshake {
+                info_hash: INFO_HASH.to_vec(),
+                peer_id: b"-XX0001-someoneelse0".to_vec(),
+                fast_extension: false,
+            };
+            stream.write_all(&handshake.serialize()).unwrap();
+        });
+        let connection = PeerConnection::new(
+            Stream::Tcp(TcpStream::connect(addr).unwrap()),
+            &INFO_HASH,
+            b"-BT0001-localpeer000",
+            PEER_ID,
+            Box::new(|_, _| {}),
+        )
+        .unwrap();
+        assert_eq!(
+            connection.strict().err(),
+            Some(ProtocolViolation::HandshakeMismatch {
+                info_hash_matches: true,
+                peer_id_matches: false
+            })
+        );
+    }
+
+    #[test]
+    fn it_keeps_only_the_most_recent_suggestions() {
+        let mut connection = connect_to(vec![]);
+        for index in 0..20 {
+            connection.suggest(index);
+        }
+        connection.suggest(14);
+        assert_eq!(
+            connection.suggested_pieces(),
+            vec![14, 19, 18, 17, 16, 15, 13, 12]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use crate::connection::PeerConnection;
+use std::collections::BTreeMap;
+
+// What a connection is doing for us, refreshed by the connection itself as messages go by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerUsefulness {
+    // the peer has every piece
+    pub is_seed: bool,
+    // we want pieces from the peer
+    pub local_interested: bool,
+    // the peer wants pieces from us
+    pub remote_interested: bool,
+    // we're uploading to the peer
+    pub remote_unchoked: bool,
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+impl PeerUsefulness {
+    pub fn of(connection: &PeerConnection, total_pieces: u32) -> Self {
+        let is_seed = connection
+            .bitfield
+            .as_ref()
+            .map(|bf| (0..total_pieces as usize).all(|index| bf.is_set(index).unwrap_or(false)))
+            .unwrap_or(false);
+        PeerUsefulness {
+            is_seed,
+            local_interested: connection.is_local_interested,
+            remote_interested: connection.is_remote_interested,
+            remote_unchoked: !connection.is_remote_choked,
+            downloaded: connection.downloaded_bytes,
+            uploaded: connection.uploaded_bytes,
+        }
+    }
+
+    // Higher is more useful. While downloading, a peer is worth what it trades with us; once we're
+    // seeding only leechers are, and the ones asking for data most of all.
+    fn score(&self, seeding: bool) -> (u8, u64) {
+        if seeding {
+            match (self.is_seed, self.remote_interested) {
+                (true, _) => (0, 0),
+                (false, false) => (1, self.uploaded),
+                (false, true) => (2, self.uploaded),
+            }
+        } else {
+            (
+                self.local_interested as u8 + self.remote_interested as u8,
+                self.downloaded + self.uploaded,
+            )
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    // we and the peer both have everything, so neither has anything to give
+    BothSeeding,
+    // over the connection cap and this was the least useful connection
+    LeastUseful { cap: usize },
+}
+
+// The open peer connections of one torrent. Each connection asks on every turn of its loop whether
+// it should go, so when the cap drops it's the least useful connections that close rather than
+// whichever ones happen to notice first.
+#[derive(Debug, Default)]
+pub struct ConnectionManager {
+    next_id: u64,
+    // ordered so ties between equally useful connections go the same way every time
+    connections: BTreeMap<u64, PeerUsefulness>,
+}
+
+impl ConnectionManager {
+    pub fn new() -> Self {
+        ConnectionManager::default()
+    }
+
+    pub fn open(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.connections.insert(id, PeerUsefulness::default());
+        id
+    }
+
+    // Closing a connection that was already told to disconnect is fine
+    pub fn close(&mut self, id: u64) {
+        self.connections.remove(&id);
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    // How many connections other than `id` we're uploading to
+    pub fn unchoked_except(&self, id: u64) -> usize {
+        self.connections
+            .iter()
+            .filter(|(other, usefulness)| **other != id && usefulness.remote_unchoked)
+            .count()
+    }
+
+    pub fn update(&mut self, id: u64, usefulness: PeerUsefulness) {
+        if let Some(current) = self.connections.get_mut(&id) {
+            *current = usefulness;
+        }
+    }
+
+    // Whether connection `id` should hang up now. A connection told to disconnect no longer counts
+    // against the cap, so the next least useful one only goes if that still isn't enough.
+    pub fn should_disconnect(
+        &mut self,
+        id: u64,
+        cap: Option<usize>,
+        seeding: bool,
+    ) -> Option<DisconnectReason> {
+        let usefulness = *self.connections.get(&id)?;
+        let reason = if seeding && usefulness.is_seed {
+            DisconnectReason::BothSeeding
+        } else {
+            let cap = cap.filter(|cap| self.connections.len() > *cap)?;
+            let least_useful = self
+                .connections
+                .iter()
+                .min_by_key(|(_, usefulness)| usefulness.score(seeding))
+                .map(|(id, _)| *id);
+            if least_useful != Some(id) {
+                return None;
+            }
+            DisconnectReason::LeastUseful { cap }
+        };
+        self.connections.remove(&id);
+        Some(reason)
+    }
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::BothSeeding => write!(f, "both sides are seeding"),
+            DisconnectReason::LeastUseful { cap } => write!(
+                f,
+                "it was the least useful connection over the cap of {}",
+                cap
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_disconnects_the_least_useful_connections_over_the_cap() {
+        let mut manager = ConnectionManager::new();
+        let (busy, idle, leecher) = (manager.open(), manager.open(), manager.open());
+        manager.update(
+            busy,
+            PeerUsefulness {
+                local_interested: true,
+                downloaded: 1 << 20,
+                ..PeerUsefulness::default()
+            },
+        );
+        manager.update(
+            leecher,
+            PeerUsefulness {
+                local_interested: true,
+                remote_interested: true,
+                ..PeerUsefulness::default()
+            },
+        );
+
+        assert_eq!(manager.should_disconnect(busy, None, false), None);
+        assert_eq!(manager.should_disconnect(busy, Some(1), false), None);
+        assert_eq!(
+            manager.should_disconnect(idle, Some(1), false),
+            Some(DisconnectReason::LeastUseful { cap: 1 })
+        );
+        assert_eq!(manager.open_connections(), 2);
+        // between the two left, trading both ways beats bytes already received
+        assert_eq!(
+            manager.should_disconnect(busy, Some(1), false),
+            Some(DisconnectReason::LeastUseful { cap: 1 })
+        );
+        assert_eq!(manager.should_disconnect(leecher, Some(1), false), None);
+    }
+
+    #[test]
+    fn it_disconnects_seeds_once_we_are_seeding() {
+        let mut manager = ConnectionManager::new();
+        let (seed, leecher) = (manager.open(), manager.open());
+        manager.update(
+            seed,
+            PeerUsefulness {
+                is_seed: true,
+                downloaded: 1 << 30,
+                ..PeerUsefulness::default()
+            },
+        );
+
+        assert_eq!(manager.should_disconnect(seed, None, false), None);
+        assert_eq!(
+            manager.should_disconnect(seed, None, true),
+            Some(DisconnectReason::BothSeeding)
+        );
+        assert_eq!(manager.should_disconnect(leecher, Some(1), true), None);
+        manager.close(seed);
+        manager.close(leecher);
+        assert_eq!(manager.open_connections(), 0);
+    }
+}
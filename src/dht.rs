@@ -0,0 +1,728 @@
+use crate::bencode::{bdecode, bencode, Bencodable, DictBuilder};
+use parking_lot::Mutex;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Weak};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+// nodes per bucket, and how many of the closest nodes a lookup ends on
+pub const K: usize = 8;
+// queries a lookup has in flight at once
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+// a node not heard from in this long may be replaced by a new one
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+// tokens are good for two rotations, the ten minutes BEP 5 suggests
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+// peers kept for any one info hash, and handed out in one `get_peers` answer
+const MAX_PEERS_PER_INFO_HASH: usize = 100;
+// info hashes peers are kept for; past this the one announced to least recently makes way
+const MAX_INFO_HASHES: usize = 1000;
+const MAX_VALUES: usize = 50;
+// how often the receiving thread looks up to see whether its node is gone
+const RECEIVE_POLL: Duration = Duration::from_millis(500);
+// BEP 5 error codes
+const ERROR_PROTOCOL: i64 = 203;
+const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+// Well known nodes to join the DHT through when nothing else is known
+pub const BOOTSTRAP_NODES: &[(&str, u16)] = &[
+    ("router.bittorrent.com", 6881),
+    ("dht.transmissionbt.com", 6881),
+    ("router.utorrent.com", 6881),
+];
+
+pub type NodeId = [u8; 20];
+
+#[derive(Debug)]
+pub enum DhtError {
+    Io(std::io::Error),
+    // no answer within `QUERY_TIMEOUT`
+    TimedOut,
+    // a KRPC error from the queried node: (code, message)
+    Error(i64, String),
+    // an answer missing what it should have, or not bencoded at all
+    BadResponse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0u8; 20];
+    for (d, (a, b)) in distance.iter_mut().zip(a.iter().zip(b)) {
+        *d = a ^ b;
+    }
+    distance
+}
+
+// The nodes we know, in a bucket for each length of prefix they share with our own id, with at
+// most `K` in each. That keeps many nodes close to us and a few far away, which is all a lookup
+// needs to get anywhere in a few hops.
+#[derive(Debug)]
+pub struct RoutingTable {
+    own: NodeId,
+    // each bucket oldest first, with when the node was last heard from
+    buckets: Vec<Vec<(Node, Instant)>>,
+}
+
+impl RoutingTable {
+    pub fn new(own: NodeId) -> Self {
+        RoutingTable {
+            own,
+            buckets: vec![vec![]; 160],
+        }
+    }
+
+    // Adds the node, or notes it was heard from. A full bucket only takes it in place of a node
+    // that has gone quiet; nodes that stay up are worth more than new ones.
+    pub fn insert(&mut self, node: Node, now: Instant) -> bool {
+        let Some(index) = self.bucket(&node.id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(at) = bucket.iter().position(|(known, _)| known.id == node.id) {
+            bucket.remove(at);
+        } else if bucket.len() >= K {
+            match bucket
+                .iter()
+                .position(|(_, seen)| now.duration_since(*seen) >= QUESTIONABLE_AFTER)
+            {
+                Some(at) => {
+                    bucket.remove(at);
+                }
+                None => return false,
+            }
+        }
+        bucket.push((node, now));
+        true
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.bucket(id) {
+            self.buckets[index].retain(|(node, _)| node.id != *id);
+        }
+    }
+
+    // The `count` nodes closest to `target`, closest first
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flatten().map(|(n, _)| *n).collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // None for our own id, which has no bucket
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.own, id);
+        let zeros = distance
+            .iter()
+            .position(|b| *b != 0)
+            .map(|at| at * 8 + distance[at].leading_zeros() as usize)?;
+        Some(zeros)
+    }
+}
+
+// A BEP 5 Mainline DHT node: it answers other nodes' queries, keeps the peers announced to it
+// for a while, and finds and announces to the nodes closest to an info hash on our behalf. IPv4
+// only, as BEP 5 is. The node stops once every clone of it is dropped.
+//
+//     let dht = Dht::bind("0.0.0.0:6881")?;
+//     dht.bootstrap(&[("router.bittorrent.com".to_string(), 6881)]);
+//     let peers = dht.announce(&info_hash, listen_port);
+#[derive(Clone)]
+pub struct Dht {
+    inner: Arc<DhtInner>,
+}
+
+// the node a transaction's answer has to come from, and who's waiting for it
+type PendingQuery = (SocketAddr, Sender<Bencodable>);
+
+struct DhtInner {
+    id: NodeId,
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<HashMap<[u8; 2], PendingQuery>>,
+    // peers announced to us, by info hash, with when they announced
+    peers: Mutex<HashMap<NodeId, HashMap<SocketAddrV4, Instant>>>,
+    // the current token secret, the one before it, and when the current one was made
+    secrets: Mutex<([u8; 20], [u8; 20], Instant)>,
+}
+
+// What a `get_peers` lookup found: the peers, and the closest nodes that answered with the
+// token needed to announce to them
+struct Lookup {
+    peers: Vec<SocketAddr>,
+    closest: Vec<(Node, Vec<u8>)>,
+}
+
+impl std::fmt::Debug for Dht {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dht")
+            .field("id", &hex::encode(self.inner.id))
+            .field("nodes", &self.nodes())
+            .finish()
+    }
+}
+
+impl Dht {
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<Self, DhtError> {
+        let socket = UdpSocket::bind(addr).map_err(DhtError::Io)?;
+        socket
+            .set_read_timeout(Some(RECEIVE_POLL))
+            .map_err(DhtError::Io)?;
+        let receiving = socket.try_clone().map_err(DhtError::Io)?;
+        let id = rand::random();
+        let inner = Arc::new(DhtInner {
+            id,
+            socket,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            peers: Mutex::new(HashMap::new()),
+            secrets: Mutex::new((rand::random(), rand::random(), Instant::now())),
+        });
+        let node = Arc::downgrade(&inner);
+        spawn(move || receive(receiving, node));
+        Ok(Dht { inner })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, DhtError> {
+        self.inner.socket.local_addr().map_err(DhtError::Io)
+    }
+
+    // how many nodes the routing table holds
+    pub fn nodes(&self) -> usize {
+        self.inner.table.lock().len()
+    }
+
+    // Joins the DHT through the given nodes (a torrent's `nodes`, or `BOOTSTRAP_NODES`), then
+    // looks up our own id to fill the routing table with the nodes around us. Nodes that can't be
+    // resolved or don't answer are skipped.
+    pub fn bootstrap(&self, nodes: &[(String, u16)]) {
+        for (host, port) in nodes {
+            let addrs = match (host.as_str(), *port).to_socket_addrs() {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    println!("could not resolve DHT node {}:{} {:?}", host, port, e);
+                    continue;
+                }
+            };
+            for addr in addrs.filter(SocketAddr::is_ipv4) {
+                if let Err(e) = self.find_node(addr, &self.inner.id) {
+                    println!("DHT node {} did not answer {:?}", addr, e);
+                }
+            }
+        }
+        self.lookup(&self.inner.id, false);
+    }
+
+    pub fn ping(&self, addr: SocketAddr) -> Result<NodeId, DhtError> {
+        let response = self.query(addr, "ping", DictBuilder::new())?;
+        node_id(&response).ok_or(DhtError::BadResponse)
+    }
+
+    // Peers for the info hash, from the nodes closest to it
+    pub fn get_peers(&self, info_hash: &NodeId) -> Vec<SocketAddr> {
+        self.lookup(info_hash, true).peers
+    }
+
+    // Peers for the info hash, like `get_peers`, telling the closest nodes on the way out that we
+    // are one too, listening on `port`
+    pub fn announce(&self, info_hash: &NodeId, port: u16) -> Vec<SocketAddr> {
+        let lookup = self.lookup(info_hash, true);
+        for (node, token) in lookup.closest {
+            let arguments = DictBuilder::new()
+                .insert("info_hash", &info_hash[..])
+                .insert("port", port as u32)
+                .insert("token", token);
+            if let Err(e) = self.query(SocketAddr::V4(node.addr), "announce_peer", arguments) {
+                println!("DHT node {} refused our announce {:?}", node.addr, e);
+            }
+        }
+        lookup.peers
+    }
+
+    fn find_node(&self, addr: SocketAddr, target: &NodeId) -> Result<Vec<Node>, DhtError> {
+        let response = self.query(
+            addr,
+            "find_node",
+            DictBuilder::new().insert("target", &target[..]),
+        )?;
+        let nodes = compact_nodes(&response);
+        let mut table = self.inner.table.lock();
+        for node in &nodes {
+            table.insert(*node, Instant::now());
+        }
+        Ok(nodes)
+    }
+
+    // Asks the closest nodes we know about `target`, then the closer ones they name, until the `K`
+    // closest have all been asked. Nodes that don't answer are dropped from the routing table.
+    fn lookup(&self, target: &NodeId, get_peers: bool) -> Lookup {
+        let method = if get_peers { "get_peers" } else { "find_node" };
+        let key = if get_peers { "info_hash" } else { "target" };
+        let mut candidates = self.inner.table.lock().closest(target, K);
+        let mut asked: HashSet<NodeId> = HashSet::new();
+        let mut peers: Vec<SocketAddr> = vec![];
+        let mut answered: Vec<(Node, Vec<u8>)> = vec![];
+        loop {
+            candidates.sort_by_key(|node| distance(&node.id, target));
+            let batch: Vec<Node> = candidates
+                .iter()
+                .take(K)
+                .filter(|node| !asked.contains(&node.id))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            let responses: Vec<(Node, Result<Bencodable, DhtError>)> = std::thread::scope(|s| {
+                let handles: Vec<_> = batch
+                    .iter()
+                    .map(|node| {
+                        s.spawn(move || {
+                            let arguments = DictBuilder::new().insert(key, &target[..]);
+                            (
+                                *node,
+                                self.query(SocketAddr::V4(node.addr), method, arguments),
+                            )
+                        })
+                    })
+                    .collect();
+                handles.into_iter().filter_map(|h| h.join().ok()).collect()
+            });
+            for (node, response) in responses {
+                asked.insert(node.id);
+                let response = match response {
+                    Ok(response) => response,
+                    Err(_) => {
+                        self.inner.table.lock().remove(&node.id);
+                        candidates.retain(|candidate| candidate.id != node.id);
+                        continue;
+                    }
+                };
+                for found in compact_nodes(&response) {
+                    if found.id != self.inner.id && !candidates.iter().any(|c| c.id == found.id) {
+                        candidates.push(found);
+                    }
+                }
+                if let Ok(values) = response.get("values").and_then(Bencodable::as_list) {
+                    for value in values {
+                        if let Some(peer) = value.as_bytes().ok().and_then(compact_peer) {
+                            if !peers.contains(&SocketAddr::V4(peer)) {
+                                peers.push(SocketAddr::V4(peer));
+                            }
+                        }
+                    }
+                }
+                if let Ok(token) = response.get("token").and_then(Bencodable::as_bytes) {
+                    answered.push((node, token.to_vec()));
+                }
+            }
+        }
+        answered.sort_by_key(|(node, _)| distance(&node.id, target));
+        answered.truncate(K);
+        Lookup {
+            peers,
+            closest: answered,
+        }
+    }
+
+    // Sends a query and waits for its answer's `r` dictionary. Whoever answers goes into the
+    // routing table.
+    fn query(
+        &self,
+        addr: SocketAddr,
+        method: &str,
+        arguments: DictBuilder,
+    ) -> Result<Bencodable, DhtError> {
+        let (sender, receiver) = channel();
+        let transaction_id = {
+            let mut pending = self.inner.pending.lock();
+            let id = loop {
+                let id: [u8; 2] = rand::random();
+                if !pending.contains_key(&id) {
+                    break id;
+                }
+            };
+            pending.insert(id, (addr, sender));
+            id
+        };
+        let message = DictBuilder::new()
+            .insert("a", arguments.insert("id", &self.inner.id[..]).build())
+            .insert("q", method)
+            .insert("t", &transaction_id[..])
+            .insert("y", "q")
+            .build();
+        let sent = self
+            .inner
+            .socket
+            .send_to(&bencode(&message).unwrap(), addr)
+            .map_err(DhtError::Io);
+        let answer = sent.and_then(|_| {
+            receiver
+                .recv_timeout(QUERY_TIMEOUT)
+                .map_err(|_| DhtError::TimedOut)
+        });
+        self.inner.pending.lock().remove(&transaction_id);
+        let answer = answer?;
+        if let Ok(error) = answer.get("e").and_then(Bencodable::as_list) {
+            let code = error.first().and_then(|c| c.as_int().ok()).unwrap_or(0);
+            let message = error.get(1).and_then(|m| m.as_str().ok()).unwrap_or("");
+            return Err(DhtError::Error(code, message.to_string()));
+        }
+        let response = answer.get("r").map_err(|_| DhtError::BadResponse)?.clone();
+        if let (Some(id), SocketAddr::V4(addr)) = (node_id(&response), addr) {
+            self.inner
+                .table
+                .lock()
+                .insert(Node { id, addr }, Instant::now());
+        }
+        Ok(response)
+    }
+}
+
+// Hands answers to whoever is waiting for them and answers queries, until the node is dropped
+fn receive(socket: UdpSocket, node: Weak<DhtInner>) {
+    let mut buf = [0u8; 2048];
+    loop {
+        let received = socket.recv_from(&mut buf);
+        let Some(node) = node.upgrade() else {
+            break;
+        };
+        let Ok((n, from)) = received else {
+            continue;
+        };
+        let Ok(message) = bdecode(&buf[..n]) else {
+            continue;
+        };
+        let Ok(transaction_id) = message.get("t").and_then(Bencodable::as_bytes) else {
+            continue;
+        };
+        match message.get("y").and_then(Bencodable::as_str) {
+            Ok("r") | Ok("e") => {
+                let Ok(transaction_id) = <[u8; 2]>::try_from(transaction_id) else {
+                    continue;
+                };
+                let mut pending = node.pending.lock();
+                if matches!(pending.get(&transaction_id), Some((asked, _)) if *asked == from) {
+                    if let Some((_, waiting)) = pending.remove(&transaction_id) {
+                        let _ = waiting.send(message);
+                    }
+                }
+            }
+            Ok("q") => {
+                let answer = match node.answer(&message, from) {
+                    Ok(response) => DictBuilder::new().insert("r", response).insert("y", "r"),
+                    Err((code, reason)) => DictBuilder::new()
+                        .insert(
+                            "e",
+                            vec![Bencodable::Integer(code), Bencodable::from(reason)],
+                        )
+                        .insert("y", "e"),
+                };
+                let answer = answer.insert("t", transaction_id).build();
+                let _ = socket.send_to(&bencode(&answer).unwrap(), from);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl DhtInner {
+    // The `r` dictionary answering another node's query, or a KRPC error
+    fn answer(&self, query: &Bencodable, from: SocketAddr) -> Result<Bencodable, (i64, String)> {
+        let protocol = |reason: &str| (ERROR_PROTOCOL, reason.to_string());
+        let arguments = query.get("a").map_err(|_| protocol("no arguments"))?;
+        let id = node_id(arguments).ok_or_else(|| protocol("no id"))?;
+        if let SocketAddr::V4(addr) = from {
+            self.table.lock().insert(Node { id, addr }, Instant::now());
+        }
+        let target = |key: &str| -> Result<NodeId, (i64, String)> {
+            arguments
+                .get(key)
+                .and_then(Bencodable::as_bytes)
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| protocol(&format!("no {}", key)))
+        };
+        let response = DictBuilder::new().insert("id", &self.id[..]);
+        let closest = |target: &NodeId| -> Vec<u8> {
+            self.table
+                .lock()
+                .closest(target, K)
+                .iter()
+                .flat_map(|node| {
+                    let mut compact = node.id.to_vec();
+                    compact.extend_from_slice(&node.addr.ip().octets());
+                    compact.extend_from_slice(&node.addr.port().to_be_bytes());
+                    compact
+                })
+                .collect()
+        };
+        match query.get("q").and_then(Bencodable::as_str) {
+            Ok("ping") => Ok(response.build()),
+            Ok("find_node") => Ok(response
+                .insert("nodes", closest(&target("target")?))
+                .build()),
+            Ok("get_peers") => {
+                let info_hash = target("info_hash")?;
+                let response = response.insert("token", self.token(from.ip(), 0).to_vec());
+                let now = Instant::now();
+                let values: Vec<Bencodable> = self
+                    .peers
+                    .lock()
+                    .get(&info_hash)
+                    .into_iter()
+                    .flatten()
+                    .filter(|(_, announced)| now.duration_since(**announced) < PEER_LIFETIME)
+                    .take(MAX_VALUES)
+                    .map(|(peer, _)| {
+                        let mut compact = peer.ip().octets().to_vec();
+                        compact.extend_from_slice(&peer.port().to_be_bytes());
+                        Bencodable::from(compact)
+                    })
+                    .collect();
+                if values.is_empty() {
+                    Ok(response.insert("nodes", closest(&info_hash)).build())
+                } else {
+                    Ok(response.insert("values", values).build())
+                }
+            }
+            Ok("announce_peer") => {
+                let info_hash = target("info_hash")?;
+                let token = arguments.get("token").and_then(Bencodable::as_bytes);
+                let valid = token.is_ok_and(|token| {
+                    (0..2).any(|age| token == self.token(from.ip(), age).as_slice())
+                });
+                if !valid {
+                    return Err(protocol("bad token"));
+                }
+                let SocketAddr::V4(from) = from else {
+                    return Err(protocol("IPv4 only"));
+                };
+                // BEP 5 `implied_port`: the port the query came from, for peers behind a NAT
+                let implied = arguments.get("implied_port").and_then(Bencodable::as_int);
+                let port = match (implied, arguments.get("port").and_then(Bencodable::as_int)) {
+                    (Ok(1), _) => from.port(),
+                    (_, Ok(port)) if (1..=u16::MAX as i64).contains(&port) => port as u16,
+                    _ => return Err(protocol("no port")),
+                };
+                let peer = SocketAddrV4::new(*from.ip(), port);
+                store_peer(&mut self.peers.lock(), info_hash, peer, Instant::now());
+                Ok(response.build())
+            }
+            _ => Err((ERROR_METHOD_UNKNOWN, "method unknown".to_string())),
+        }
+    }
+
+    // The token a node at `ip` has to give back to announce to us, made with the current secret
+    // (`age` 0) or the one before it (1)
+    fn token(&self, ip: IpAddr, age: usize) -> [u8; 8] {
+        let secret = {
+            let mut secrets = self.secrets.lock();
+            if secrets.2.elapsed() >= TOKEN_ROTATION {
+                *secrets = (rand::random(), secrets.0, Instant::now());
+            }
+            if age == 0 {
+                secrets.0
+            } else {
+                secrets.1
+            }
+        };
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.finalize()[..8].try_into().unwrap()
+    }
+}
+
+fn node_id(dictionary: &Bencodable) -> Option<NodeId> {
+    dictionary.get("id").ok()?.as_bytes().ok()?.try_into().ok()
+}
+
+// The compact node info in a response's `nodes`: 20 bytes of id, then 6 of address
+fn compact_nodes(response: &Bencodable) -> Vec<Node> {
+    let Ok(nodes) = response.get("nodes").and_then(Bencodable::as_bytes) else {
+        return vec![];
+    };
+    nodes
+        .chunks_exact(26)
+        .filter_map(|node| {
+            Some(Node {
+                id: node[..20].try_into().ok()?,
+                addr: compact_peer(&node[20..])?,
+            })
+        })
+        .filter(|node| node.addr.port() != 0)
+        .collect()
+}
+
+fn compact_peer(bytes: &[u8]) -> Option<SocketAddrV4> {
+    let bytes: [u8; 6] = bytes.try_into().ok()?;
+    Some(SocketAddrV4::new(
+        [bytes[0], bytes[1], bytes[2], bytes[3]].into(),
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    ))
+}
+
+// Forgets every peer that hasn't announced within `PEER_LIFETIME` before keeping `peer`, so
+// neither the info hashes nor their peers grow without bound
+fn store_peer(
+    peers: &mut HashMap<NodeId, HashMap<SocketAddrV4, Instant>>,
+    info_hash: NodeId,
+    peer: SocketAddrV4,
+    now: Instant,
+) {
+    peers.retain(|_, announced| {
+        announced.retain(|_, at| now.saturating_duration_since(*at) < PEER_LIFETIME);
+        !announced.is_empty()
+    });
+    if !peers.contains_key(&info_hash) && peers.len() >= MAX_INFO_HASHES {
+        let stalest = peers
+            .iter()
+            .min_by_key(|(_, announced)| announced.values().max().copied())
+            .map(|(info_hash, _)| *info_hash);
+        if let Some(stalest) = stalest {
+            peers.remove(&stalest);
+        }
+    }
+    let announced = peers.entry(info_hash).or_default();
+    if announced.len() < MAX_PEERS_PER_INFO_HASH || announced.contains_key(&peer) {
+        announced.insert(peer, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn it_keeps_k_nodes_a_bucket_preferring_those_still_up() {
+        let own = [0u8; 20];
+        let mut table = RoutingTable::new(own);
+        let now = Instant::now();
+        let node = |first: u8, last: u8| Node {
+            id: {
+                let mut id = [0u8; 20];
+                id[0] = first;
+                id[19] = last;
+                id
+            },
+            addr: SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, last), 6881),
+        };
+        // all sharing no prefix with our id, so all in the same bucket
+        for last in 0..K as u8 {
+            assert!(table.insert(node(0x80, last), now));
+        }
+        assert!(!table.insert(node(0x80, 100), now));
+        assert!(table.insert(node(0x01, 1), now));
+        assert!(!table.insert(node(0, 0), now));
+        assert_eq!(table.len(), K + 1);
+
+        let later = now + QUESTIONABLE_AFTER;
+        assert!(table.insert(node(0x80, 3), later));
+        assert!(table.insert(node(0x80, 100), later));
+        assert_eq!(table.len(), K + 1);
+
+        let target = node(0x80, 100).id;
+        let closest = table.closest(&target, 3);
+        assert_eq!(closest[0], node(0x80, 100));
+        assert!(closest
+            .windows(2)
+            .all(|pair| distance(&pair[0].id, &target) < distance(&pair[1].id, &target)));
+        table.remove(&node(0x80, 100).id);
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn it_finds_peers_announced_by_other_nodes() {
+        let router = Dht::bind("127.0.0.1:0").unwrap();
+        let router_addr = router.local_addr().unwrap();
+        let bootstrap = [(router_addr.ip().to_string(), router_addr.port())];
+        let seeder = Dht::bind("127.0.0.1:0").unwrap();
+        let leecher = Dht::bind("127.0.0.1:0").unwrap();
+        seeder.bootstrap(&bootstrap);
+        leecher.bootstrap(&bootstrap);
+        assert!(leecher.nodes() >= 2);
+        assert_eq!(leecher.ping(router_addr).unwrap(), router.id());
+
+        let info_hash = [7u8; 20];
+        assert!(seeder.announce(&info_hash, 6881).is_empty());
+        assert_eq!(
+            leecher.get_peers(&info_hash),
+            vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+
+        let forged = DictBuilder::new()
+            .insert("info_hash", &info_hash[..])
+            .insert("port", 7000_u32)
+            .insert("token", &b"forged"[..]);
+        assert!(matches!(
+            leecher.query(router_addr, "announce_peer", forged),
+            Err(DhtError::Error(ERROR_PROTOCOL, _))
+        ));
+        assert!(matches!(
+            leecher.query(router_addr, "vote", DictBuilder::new()),
+            Err(DhtError::Error(ERROR_METHOD_UNKNOWN, _))
+        ));
+    }
+
+    #[test]
+    fn it_expires_stored_peers_and_caps_the_info_hashes_kept() {
+        let mut peers = HashMap::new();
+        let start = Instant::now();
+        let peer = |i: usize| SocketAddrV4::new([10, 0, 0, 1].into(), 1000 + i as u16);
+        let info_hash = |i: usize| {
+            let mut info_hash = [0u8; 20];
+            info_hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            info_hash
+        };
+
+        for i in 0..MAX_INFO_HASHES {
+            let at = start + Duration::from_millis(i as u64);
+            store_peer(&mut peers, info_hash(i), peer(i), at);
+        }
+        // the first info hash is announced to again, so the second is now the stalest
+        let later = start + Duration::from_secs(60);
+        store_peer(&mut peers, info_hash(0), peer(1), later);
+        store_peer(&mut peers, info_hash(MAX_INFO_HASHES), peer(0), later);
+        assert_eq!(peers.len(), MAX_INFO_HASHES);
+        assert_eq!(peers[&info_hash(0)].len(), 2);
+        assert!(!peers.contains_key(&info_hash(1)));
+        assert!(peers.contains_key(&info_hash(MAX_INFO_HASHES)));
+
+        // everyone but those announced `later` has gone quiet for too long
+        let quiet = start + PEER_LIFETIME + Duration::from_secs(1);
+        store_peer(&mut peers, info_hash(2), peer(2), quiet);
+        assert_eq!(peers.len(), 3);
+        assert_eq!(
+            peers[&info_hash(0)].keys().collect::<Vec<_>>(),
+            vec![&peer(1)]
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// how long an answer that came without a TTL, as the system resolver's do, is reused
+pub const DEFAULT_TTL: Duration = Duration::from_secs(300);
+// however long an answer says it's good for
+const MAX_TTL: Duration = Duration::from_secs(3600);
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const RCODE_NXDOMAIN: u8 = 3;
+
+#[derive(Debug)]
+pub enum DnsError {
+    Io(std::io::Error),
+    HttpError(reqwest::Error),
+    HttpStatus(u16),
+    // the DNS-over-HTTPS server's answer couldn't be read
+    Malformed,
+    // the name has no addresses the `IpPreference` allows
+    NoAddresses(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpPreference {
+    // in whatever order the resolver gave them
+    #[default]
+    Any,
+    Ipv4First,
+    Ipv6First,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl IpPreference {
+    fn apply(self, addrs: &[IpAddr]) -> Vec<IpAddr> {
+        let (v4, v6): (Vec<IpAddr>, Vec<IpAddr>) = addrs.iter().partition(|ip| ip.is_ipv4());
+        match self {
+            IpPreference::Any => addrs.to_vec(),
+            IpPreference::Ipv4First => [v4, v6].concat(),
+            IpPreference::Ipv6First => [v6, v4].concat(),
+            IpPreference::Ipv4Only => v4,
+            IpPreference::Ipv6Only => v6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    pub addrs: Vec<IpAddr>,
+    // how long the answer may be reused; `None` when the resolver doesn't say
+    pub ttl: Option<Duration>,
+}
+
+// Where host names get turned into addresses. Implement it to resolve some other way than the
+// system or DNS-over-HTTPS, and hand it to `Session::set_resolver`.
+pub trait Resolve: Send + Sync {
+    fn resolve(&self, host: &str) -> Result<Resolved, DnsError>;
+}
+
+// Whatever the operating system is set up to use
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve(&self, host: &str) -> Result<Resolved, DnsError> {
+        let addrs = (host, 0).to_socket_addrs().map_err(DnsError::Io)?;
+        Ok(Resolved {
+            addrs: addrs.map(|addr| addr.ip()).collect(),
+            ttl: None,
+        })
+    }
+}
+
+// RFC 8484 DNS-over-HTTPS, asking for A and AAAA records with GET requests. Give the server's URL
+// with an IP address for a host (e.g. https://1.1.1.1/dns-query) to keep the system resolver out of
+// it entirely.
+pub struct DohResolver {
+    url: String,
+    client: reqwest::blocking::Client,
+}
+
+impl DohResolver {
+    pub fn new(url: &str) -> Self {
+        DohResolver {
+            url: url.to_string(),
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+
+    fn ask(&self, host: &str, record_type: u16) -> Result<Resolved, DnsError> {
+        let response = self
+            .client
+            .get(&self.url)
+            .query(&[("dns", base64_url(&query(host, record_type)))])
+            .header(reqwest::header::ACCEPT, "application/dns-message")
+            .send()
+            .map_err(DnsError::HttpError)?;
+        if !response.status().is_success() {
+            return Err(DnsError::HttpStatus(response.status().as_u16()));
+        }
+        let bytes = response.bytes().map_err(DnsError::HttpError)?;
+        parse_answer(&bytes).ok_or(DnsError::Malformed)
+    }
+}
+
+impl Resolve for DohResolver {
+    fn resolve(&self, host: &str) -> Result<Resolved, DnsError> {
+        let v4 = self.ask(host, TYPE_A)?;
+        let v6 = self.ask(host, TYPE_AAAA)?;
+        Ok(Resolved {
+            addrs: [v4.addrs, v6.addrs].concat(),
+            ttl: [v4.ttl, v6.ttl].into_iter().flatten().min(),
+        })
+    }
+}
+
+// A wire format query for one record of `host`. The id is 0, as RFC 8484 asks so that HTTP
+// caches can share answers.
+fn query(host: &str, record_type: u16) -> Vec<u8> {
+    // recursion desired, one question
+    let mut query = vec![0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in host.trim_end_matches('.').split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    query
+}
+
+// The A and AAAA records in a wire format answer, skipping any others (such as the CNAMEs that led
+// to them). A name that doesn't exist is an answer with no addresses.
+fn parse_answer(bytes: &[u8]) -> Option<Resolved> {
+    let u16_at = |at: usize| Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?));
+    if bytes.get(3)? & 0x0f == RCODE_NXDOMAIN {
+        return Some(Resolved {
+            addrs: vec![],
+            ttl: None,
+        });
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut i = 12;
+    for _ in 0..questions {
+        i = skip_name(bytes, i)? + 4;
+    }
+    let mut resolved = Resolved {
+        addrs: vec![],
+        ttl: None,
+    };
+    for _ in 0..answers {
+        i = skip_name(bytes, i)?;
+        let record_type = u16_at(i)?;
+        let ttl = u32::from_be_bytes(bytes.get(i + 4..i + 8)?.try_into().ok()?);
+        let length = u16_at(i + 8)? as usize;
+        let data = bytes.get(i + 10..i + 10 + length)?;
+        i += 10 + length;
+        let ip = match (record_type, data.len()) {
+            (TYPE_A, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(data).ok()?)),
+            (TYPE_AAAA, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?)),
+            _ => continue,
+        };
+        resolved.addrs.push(ip);
+        let ttl = Duration::from_secs(ttl as u64);
+        resolved.ttl = Some(resolved.ttl.map_or(ttl, |shortest| shortest.min(ttl)));
+    }
+    Some(resolved)
+}
+
+// Where the name starting at `i` ends, whether it ends in a label of 0 or a compression pointer
+fn skip_name(bytes: &[u8], mut i: usize) -> Option<usize> {
+    loop {
+        let length = *bytes.get(i)?;
+        if length == 0 {
+            return Some(i + 1);
+        }
+        if length & 0xc0 == 0xc0 {
+            return Some(i + 2);
+        }
+        i += 1 + length as usize;
+    }
+}
+
+// base64url without padding, as the `dns` parameter wants
+fn base64_url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+#[derive(Debug)]
+struct CachedAnswer {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+// Name resolution for the whole session: trackers (and whatever else connects by host name) look
+// names up through this rather than the system directly, so they share one resolver and one cache.
+// Clones share both.
+#[derive(Clone)]
+pub struct DnsResolver {
+    resolver: Arc<RwLock<Arc<dyn Resolve>>>,
+    cache: Arc<Mutex<HashMap<String, CachedAnswer>>>,
+}
+
+impl Default for DnsResolver {
+    fn default() -> Self {
+        DnsResolver::new(Arc::new(SystemResolver))
+    }
+}
+
+impl DnsResolver {
+    pub fn new(resolver: Arc<dyn Resolve>) -> Self {
+        DnsResolver {
+            resolver: Arc::new(RwLock::new(resolver)),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Resolves through `resolver` from now on, for every clone, forgetting every cached answer
+    pub fn set_resolver(&self, resolver: Arc<dyn Resolve>) {
+        *self.resolver.write() = resolver;
+        self.cache.lock().clear();
+    }
+
+    // The addresses of `host`, in the order `preference` puts them. An IP address is its own
+    // answer; anything else is answered from the cache while its TTL lasts.
+    pub fn lookup(&self, host: &str, preference: IpPreference) -> Result<Vec<IpAddr>, DnsError> {
+        let host = host.to_ascii_lowercase();
+        let addrs = match host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+        {
+            Ok(ip) => vec![ip],
+            Err(_) => self.cached(&host).map_or_else(|| self.resolve(&host), Ok)?,
+        };
+        match preference.apply(&addrs) {
+            addrs if addrs.is_empty() => Err(DnsError::NoAddresses(host)),
+            addrs => Ok(addrs),
+        }
+    }
+
+    fn cached(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let mut cache = self.cache.lock();
+        match cache.get(host) {
+            Some(answer) if answer.expires > Instant::now() => Some(answer.addrs.clone()),
+            Some(_) => {
+                cache.remove(host);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // Not holding the cache lock, so a slow lookup doesn't hold up lookups of other names
+    fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, DnsError> {
+        let resolver = Arc::clone(&self.resolver.read());
+        let resolved = resolver.resolve(host)?;
+        let ttl = resolved.ttl.unwrap_or(DEFAULT_TTL).min(MAX_TTL);
+        self.cache.lock().insert(
+            host.to_string(),
+            CachedAnswer {
+                addrs: resolved.addrs.clone(),
+                expires: Instant::now() + ttl,
+            },
+        );
+        Ok(resolved.addrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingResolver {
+        lookups: AtomicUsize,
+        ttl: Option<Duration>,
+    }
+
+    impl Resolve for CountingResolver {
+        fn resolve(&self, _host: &str) -> Result<Resolved, DnsError> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Ok(Resolved {
+                addrs: vec!["::1".parse().unwrap(), "127.0.0.1".parse().unwrap()],
+                ttl: self.ttl,
+            })
+        }
+    }
+
+    #[test]
+    fn it_caches_answers_for_their_ttl_and_orders_them_by_preference() {
+        let resolver = Arc::new(CountingResolver {
+            lookups: AtomicUsize::new(0),
+            ttl: None,
+        });
+        let dns = DnsResolver::new(resolver.clone());
+        let v4: IpAddr = "127.0.0.1".parse().unwrap();
+        let v6: IpAddr = "::1".parse().unwrap();
+
+        assert_eq!(
+            dns.lookup("Tracker.example", IpPreference::Ipv4First)
+                .unwrap(),
+            vec![v4, v6]
+        );
+        assert_eq!(
+            dns.clone()
+                .lookup("tracker.example", IpPreference::Ipv6Only)
+                .unwrap(),
+            vec![v6]
+        );
+        assert_eq!(resolver.lookups.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            dns.lookup("10.0.0.1", IpPreference::Any).unwrap(),
+            vec!["10.0.0.1".parse::<IpAddr>().unwrap()]
+        );
+        assert!(matches!(
+            dns.lookup("[::2]", IpPreference::Ipv4Only),
+            Err(DnsError::NoAddresses(_))
+        ));
+        assert_eq!(resolver.lookups.load(Ordering::SeqCst), 1);
+
+        let expiring = Arc::new(CountingResolver {
+            lookups: AtomicUsize::new(0),
+            ttl: Some(Duration::ZERO),
+        });
+        dns.set_resolver(expiring.clone());
+        dns.lookup("tracker.example", IpPreference::Any).unwrap();
+        dns.lookup("tracker.example", IpPreference::Any).unwrap();
+        assert_eq!(expiring.lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn it_reads_wire_format_answers() {
+        let question = query("a.example", TYPE_A);
+        assert_eq!(&question[12..], b"\x01a\x07example\x00\x00\x01\x00\x01");
+        assert_eq!(base64_url(&[0xfb, 0xff, 0x61, 0x62]), "-_9hYg");
+
+        // the question echoed back, then a CNAME and an A record both pointing at the question's name
+        let mut answer = question.clone();
+        answer[2] = 0x81;
+        answer[3] = 0x80;
+        answer[7] = 2;
+        answer.extend_from_slice(b"\xc0\x0c\x00\x05\x00\x01\x00\x00\x00\x3c\x00\x04\x01b\xc0\x0e");
+        answer
+            .extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x1e\x00\x04\x0a\x00\x00\x07");
+        assert_eq!(
+            parse_answer(&answer),
+            Some(Resolved {
+                addrs: vec!["10.0.0.7".parse().unwrap()],
+                ttl: Some(Duration::from_secs(30)),
+            })
+        );
+
+        answer[3] = 0x80 | RCODE_NXDOMAIN;
+        assert!(parse_answer(&answer).unwrap().addrs.is_empty());
+        assert_eq!(parse_answer(&question[..6]), None);
+    }
+}
@@ -0,0 +1,432 @@
+use crate::meta_info_file::{MetaInfoError, MetaInfoFile};
+use crate::session::Session;
+use regex::Regex;
+use std::collections::HashSet;
+use std::thread::sleep;
+use std::time::Duration;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct FeedItem {
+    pub title: String,
+    pub link: String,
+    pub size: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum FeedError {
+    HttpError(reqwest::Error),
+    // the link didn't lead to a usable .torrent
+    MetaInfo(MetaInfoError),
+}
+
+pub struct FeedRule {
+    pattern: Regex,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+}
+
+impl FeedRule {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(FeedRule {
+            pattern: Regex::new(pattern)?,
+            min_size: None,
+            max_size: None,
+        })
+    }
+
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = Some(bytes);
+        self
+    }
+
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    // Items that don't advertise a size are only filtered by title
+    pub fn matches(&self, item: &FeedItem) -> bool {
+        let size_ok = match item.size {
+            Some(size) => {
+                !matches!(self.min_size, Some(min) if size < min)
+                    && !matches!(self.max_size, Some(max) if size > max)
+            }
+            None => true,
+        };
+        size_ok && self.pattern.is_match(&item.title)
+    }
+}
+
+pub struct FeedWatcher {
+    url: String,
+    rules: Vec<FeedRule>,
+    seen: HashSet<String>,
+    client: reqwest::blocking::Client,
+}
+
+impl FeedWatcher {
+    pub fn new(url: &str, rules: Vec<FeedRule>) -> Self {
+        FeedWatcher {
+            url: url.to_string(),
+            rules,
+            seen: HashSet::new(),
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+
+    // Fetches the feed once and adds every new matching torrent to the session, returning their info
+    // hashes. A torrent the session already has only gets the item's trackers merged into it.
+    pub fn poll(&mut self, session: &mut Session) -> Result<Vec<[u8; 20]>, FeedError> {
+        let body = self
+            .client
+            .get(&self.url)
+            .send()
+            .and_then(|r| r.text())
+            .map_err(FeedError::HttpError)?;
+
+        let mut added = vec![];
+        for item in parse_feed(&body) {
+            if self.seen.contains(&item.link) || !self.rules.iter().any(|r| r.matches(&item)) {
+                continue;
+            }
+            self.seen.insert(item.link.clone());
+
+            let bytes = self
+                .client
+                .get(&item.link)
+                .send()
+                .and_then(|r| r.bytes())
+                .map_err(FeedError::HttpError)?;
+            let meta_info = MetaInfoFile::from_bytes(&bytes).map_err(FeedError::MetaInfo)?;
+            println!("feed item {:?} matched, adding to session", item.title);
+            let known = session.contains(&meta_info.info_hash);
+            let info_hash = session.add(meta_info);
+            if !known {
+                added.push(info_hash);
+            }
+        }
+        Ok(added)
+    }
+
+    pub fn run(mut self, session: &mut Session, interval: Duration) {
+        loop {
+            if let Err(e) = self.poll(session) {
+                println!("failed to poll feed {}: {:?}", self.url, e);
+            }
+            for event in session.events().try_iter() {
+                println!("session event {:?}", event);
+            }
+            sleep(interval);
+        }
+    }
+}
+
+// Pulls items out of both RSS (<item>) and Atom (<entry>) documents. This is deliberately a
+// small scanner instead of a full XML parser; torrent feeds are flat enough for it.
+pub fn parse_feed(xml: &str) -> Vec<FeedItem> {
+    let mut items = vec![];
+    for tag in &["item", "entry"] {
+        let mut rest = xml;
+        while let Some((element, remaining)) = next_element(rest, tag) {
+            rest = remaining;
+            let title = element_text(element, "title").unwrap_or_default();
+            let enclosure = start_tag(element, "enclosure").or_else(|| {
+                let mut rest = element;
+                while let Some((link, remaining)) = next_start_tag(rest, "link") {
+                    if attribute(link, "rel").as_deref() == Some("enclosure") {
+                        return Some(link);
+                    }
+                    rest = remaining;
+                }
+                None
+            });
+            let link = enclosure
+                .and_then(|e| attribute(e, "url").or_else(|| attribute(e, "href")))
+                .or_else(|| element_text(element, "link").filter(|l| !l.is_empty()))
+                .or_else(|| start_tag(element, "link").and_then(|l| attribute(l, "href")));
+            let size = enclosure
+                .and_then(|e| attribute(e, "length"))
+                .or_else(|| element_text(element, "contentLength"))
+                .or_else(|| element_text(element, "size"))
+                .and_then(|s| s.trim().parse::<u64>().ok());
+
+            if let Some(link) = link {
+                items.push(FeedItem { title, link, size });
+            }
+        }
+    }
+    items
+}
+
+// Finds the next `<tag ...>...</tag>` (namespace prefixes like `torrent:` are ignored) and
+// returns its inner content along with the rest of the document
+fn next_element<'a>(xml: &'a str, tag: &str) -> Option<(&'a str, &'a str)> {
+    let (open, after_open) = next_start_tag(xml, tag)?;
+    if open.ends_with("/>") {
+        return Some(("", after_open));
+    }
+    let mut search = after_open;
+    let mut consumed = 0;
+    while let Some(i) = search.find("</") {
+        let close = &search[i + 2..];
+        let end = close.find('>')?;
+        if local_name(&close[..end]) == tag {
+            let inner = &after_open[..consumed + i];
+            return Some((inner, &close[end + 1..]));
+        }
+        consumed += i + 2;
+        search = close;
+    }
+    None
+}
+
+fn next_start_tag<'a>(xml: &'a str, tag: &str) -> Option<(&'a str, &'a str)> {
+    let mut rest = xml;
+    while let Some(i) = rest.find('<') {
+        let candidate = &rest[i + 1..];
+        let end = candidate.find('>')?;
+        let name_end = candidate[..end]
+            .find(|c: char| c.is_whitespace() || c == '/')
+            .unwrap_or(end);
+        if local_name(&candidate[..name_end]) == tag {
+            return Some((&candidate[..end + 1], &candidate[end + 1..]));
+        }
+        rest = candidate;
+    }
+    None
+}
+
+fn start_tag<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    next_start_tag(xml, tag).map(|(t, _)| t)
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+fn element_text(xml: &str, tag: &str) -> Option<String> {
+    next_element(xml, tag).map(|(inner, _)| {
+        let inner = inner.trim();
+        match inner
+            .strip_prefix("<![CDATA[")
+            .and_then(|s| s.strip_suffix("]]>"))
+        {
+            Some(cdata) => cdata.to_string(),
+            None => unescape(inner),
+        }
+    })
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let mut rest = tag;
+    while let Some(i) = rest.find(name) {
+        let before = rest[..i].chars().last();
+        let after = rest[i + name.len()..].trim_start();
+        rest = &rest[i + name.len()..];
+        if !matches!(before, Some(c) if c.is_whitespace()) {
+            continue;
+        }
+        if let Some(value) = after.strip_prefix('=') {
+            let value = value.trim_start();
+            let quote = value.chars().next()?;
+            let value = &value[1..];
+            return value.find(quote).map(|end| unescape(&value[..end]));
+        }
+    }
+    None
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_rss_items_with_enclosures() {
+        let rss = r#"<?xml version="1.0"?>
+            <rss version="2.0"><channel><title>Feed</title>
+            <item>
+                <title>Charlie Chaplin 1914</title>
+                <link>http://example.com/page</link>
+                <enclosure url="http://example.com/a.torrent?x=1&amp;y=2" length="57772860" type="application/x-bittorrent" />
+            </item>
+            <item>
+                <title><![CDATA[Some <Other> Film]]></title>
+                <link>http://example.com/b.torrent</link>
+                <torrent:contentLength>1024</torrent:contentLength>
+            </item>
+            </channel></rss>"#;
+
+        assert_eq!(
+            parse_feed(rss),
+            vec![
+                FeedItem {
+                    title: "Charlie Chaplin 1914".to_string(),
+                    link: "http://example.com/a.torrent?x=1&y=2".to_string(),
+                    size: Some(57772860),
+                },
+                FeedItem {
+                    title: "Some <Other> Film".to_string(),
+                    link: "http://example.com/b.torrent".to_string(),
+                    size: Some(1024),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn it_parses_atom_entries() {
+        let atom = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+            <entry>
+                <title>Mabel's Strange Predicament</title>
+                <link rel="alternate" href="http://example.com/page"/>
+                <link rel="enclosure" href="http://example.com/c.torrent" length="300"/>
+            </entry>
+            </feed>"#;
+
+        assert_eq!(
+            parse_feed(atom),
+            vec![FeedItem {
+                title: "Mabel's Strange Predicament".to_string(),
+                link: "http://example.com/c.torrent".to_string(),
+                size: Some(300),
+            }]
+        );
+    }
+
+    #[test]
+    fn it_filters_items_by_title_and_size() {
+        let rule = FeedRule::new("(?i)chaplin")
+            .unwrap()
+            .min_size(100)
+            .max_size(1000);
+        let item = |title: &str, size| FeedItem {
+            title: title.to_string(),
+            link: "http://example.com/a.torrent".to_string(),
+            size,
+        };
+
+        assert!(rule.matches(&item("Charlie Chaplin", Some(500))));
+        assert!(rule.matches(&item("Charlie Chaplin", None)));
+        assert!(!rule.matches(&item("Charlie Chaplin", Some(50))));
+        assert!(!rule.matches(&item("Charlie Chaplin", Some(5000))));
+        assert!(!rule.matches(&item("Buster Keaton", Some(500))));
+    }
+
+    #[test]
+    fn it_merges_trackers_of_torrents_the_session_already_has() {
+        use crate::bencode::{bencode, DictBuilder};
+        use crate::logger::LogFormat;
+        use crate::session::SessionEvent;
+        use crate::test_tracker::MockTracker;
+        use crate::util::random_string;
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::sync::Arc;
+
+        let torrent = |announce: &str, name: &str| {
+            let info = DictBuilder::new()
+                .insert("length", 10_i64)
+                .insert("name", name)
+                .insert("piece length", 16384_i64)
+                .insert("pieces", &[0u8; 20][..])
+                .build();
+            bencode(
+                &DictBuilder::new()
+                    .insert("announce", announce)
+                    .insert("info", info)
+                    .build(),
+            )
+            .unwrap()
+        };
+        let files = [
+            ("/a.torrent", torrent("http://a.example/announce", "a.txt")),
+            // the same torrent again, from another tracker
+            ("/b.torrent", torrent("http://b.example/announce", "a.txt")),
+            // and once more with nothing new to it
+            ("/c.torrent", torrent("http://a.example/announce", "a.txt")),
+            ("/d.torrent", torrent("http://a.example/announce", "d.txt")),
+        ];
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let items: String = files
+            .iter()
+            .map(|(path, _)| {
+                format!(
+                    "<item><title>Chaplin {}</title><enclosure url=\"http://{}{}\"/></item>",
+                    path, addr, path
+                )
+            })
+            .collect();
+        let feed = format!("<rss><channel>{}</channel></rss>", items).into_bytes();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0u8; 1024];
+                let n = stream.read(&mut request).unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..n]);
+                let path = request.split(' ').nth(1).unwrap_or_default();
+                let body = files
+                    .iter()
+                    .find(|(file, _)| *file == path)
+                    .map_or(&feed, |(_, body)| body);
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .as_bytes(),
+                );
+                let _ = stream.write_all(body);
+            }
+        });
+
+        let dir = std::env::temp_dir().join(format!("bit_torrent_feed_{}", random_string()));
+        let log = dir.join("session.log");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut session = Session::new(log.to_str().unwrap(), LogFormat::Human);
+        session.settings().update(|s| s.download_dir = dir.clone());
+        session.set_announcer(
+            "http://a.example/announce",
+            Arc::new(MockTracker::default()),
+        );
+        session.set_announcer(
+            "http://b.example/announce",
+            Arc::new(MockTracker::default()),
+        );
+
+        let mut watcher = FeedWatcher::new(
+            &format!("http://{}/feed.xml", addr),
+            vec![FeedRule::new("Chaplin").unwrap()],
+        );
+        let added = watcher.poll(&mut session).unwrap();
+        let a = MetaInfoFile::from_bytes(&torrent("http://a.example/announce", "a.txt"))
+            .unwrap()
+            .info_hash;
+        let d = MetaInfoFile::from_bytes(&torrent("http://a.example/announce", "d.txt"))
+            .unwrap()
+            .info_hash;
+        assert_eq!(added, vec![a, d]);
+
+        let merged: Vec<SessionEvent> = session
+            .events()
+            .try_iter()
+            .filter(|event| matches!(event, SessionEvent::TrackersMerged { .. }))
+            .collect();
+        assert_eq!(
+            merged,
+            vec![SessionEvent::TrackersMerged {
+                info_hash: a,
+                added_trackers: vec!["http://b.example/announce".to_string()],
+            }]
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,376 @@
+use crate::bencode::Bencodable;
+use crate::meta_info_file::File;
+use sha1::{Digest, Sha1};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// files are read back this much at a time, however large they are
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    Md5,
+    Sha1,
+    Crc32,
+}
+
+// The whole-file checksums some torrent makers list next to each file (`md5sum`, or `md5`, `sha1`
+// and `crc32`). They say nothing the piece hashes don't, but checking them after the files are
+// written catches what happened on the way to disk, and torrents whose pieces don't line up with
+// what the maker meant.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileChecksums {
+    pub md5: Option<[u8; 16]>,
+    pub sha1: Option<[u8; 20]>,
+    pub crc32: Option<u32>,
+}
+
+// A written file that doesn't match one of the checksums the torrent gives for it. Unlike a piece
+// failing verification nothing is downloaded again; it's only reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    // in the order the torrent lists its files
+    pub index: usize,
+    pub path: PathBuf,
+    pub checksum: Checksum,
+    // both in hex
+    pub expected: String,
+    pub actual: String,
+}
+
+impl FileChecksums {
+    // From a file's dictionary in `files`, or the info dictionary of a single file torrent. Values
+    // may be hex, as they usually are, or the raw digest; anything else is ignored.
+    pub fn parse(dictionary: &Bencodable) -> Self {
+        let bytes = |key: &str| dictionary.get(key).and_then(Bencodable::as_bytes).ok();
+        FileChecksums {
+            md5: bytes("md5sum").or_else(|| bytes("md5")).and_then(digest),
+            sha1: bytes("sha1").and_then(digest),
+            crc32: bytes("crc32").and_then(digest::<4>).map(u32::from_be_bytes),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.md5.is_none() && self.sha1.is_none() && self.crc32.is_none()
+    }
+
+    // Each listed checksum `data` doesn't match, as (checksum, expected, actual) in hex
+    pub fn mismatches(&self, data: &[u8]) -> Vec<(Checksum, String, String)> {
+        let mut hashers = Hashers::new(self);
+        hashers.update(data);
+        hashers.mismatches(self)
+    }
+
+    // `mismatches` for everything `reader` has, read a buffer at a time
+    pub fn mismatches_in(
+        &self,
+        mut reader: impl Read,
+    ) -> std::io::Result<Vec<(Checksum, String, String)>> {
+        let mut hashers = Hashers::new(self);
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => return Ok(hashers.mismatches(self)),
+                Ok(n) => hashers.update(&buffer[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// Running state for each checksum a file lists, so it can be fed a buffer at a time
+struct Hashers {
+    md5: Option<Md5>,
+    sha1: Option<Sha1>,
+    crc32: Option<Crc32>,
+}
+
+impl Hashers {
+    fn new(checksums: &FileChecksums) -> Self {
+        Hashers {
+            md5: checksums.md5.map(|_| Md5::new()),
+            sha1: checksums.sha1.map(|_| Sha1::new()),
+            crc32: checksums.crc32.map(|_| Crc32::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        if let Some(md5) = &mut self.md5 {
+            md5.update(data);
+        }
+        if let Some(sha1) = &mut self.sha1 {
+            sha1.update(data);
+        }
+        if let Some(crc32) = &mut self.crc32 {
+            crc32.update(data);
+        }
+    }
+
+    fn mismatches(self, checksums: &FileChecksums) -> Vec<(Checksum, String, String)> {
+        let mut mismatches = vec![];
+        if let (Some(expected), Some(md5)) = (checksums.md5, self.md5) {
+            let actual = md5.finish();
+            if actual != expected {
+                mismatches.push((Checksum::Md5, hex::encode(expected), hex::encode(actual)));
+            }
+        }
+        if let (Some(expected), Some(sha1)) = (checksums.sha1, self.sha1) {
+            let actual = <[u8; 20]>::from(sha1.finalize());
+            if actual != expected {
+                mismatches.push((Checksum::Sha1, hex::encode(expected), hex::encode(actual)));
+            }
+        }
+        if let (Some(expected), Some(crc32)) = (checksums.crc32, self.crc32) {
+            let actual = crc32.finish();
+            if actual != expected {
+                mismatches.push((
+                    Checksum::Crc32,
+                    format!("{:08x}", expected),
+                    format!("{:08x}", actual),
+                ));
+            }
+        }
+        mismatches
+    }
+}
+
+// Reads every file that has checksums back from under `root`, a buffer at a time, and checks it.
+// Files that can't be read are skipped, writing them having already failed loudly.
+pub fn verify_files(root: &Path, files: &[&File]) -> Vec<ChecksumMismatch> {
+    let mut mismatches = vec![];
+    for (index, file) in files.iter().enumerate() {
+        if file.checksums.is_empty() {
+            continue;
+        }
+        let path = root.join(&file.path);
+        let checked = std::fs::File::open(&path).and_then(|f| file.checksums.mismatches_in(f));
+        let found = match checked {
+            Ok(found) => found,
+            Err(e) => {
+                println!("could not read {:?} back to check it {:?}", path, e);
+                continue;
+            }
+        };
+        for (checksum, expected, actual) in found {
+            mismatches.push(ChecksumMismatch {
+                index,
+                path: path.clone(),
+                checksum,
+                expected,
+                actual,
+            });
+        }
+    }
+    mismatches
+}
+
+fn digest<const N: usize>(value: &[u8]) -> Option<[u8; N]> {
+    if value.len() == 2 * N {
+        if let Some(decoded) = std::str::from_utf8(value)
+            .ok()
+            .and_then(|text| hex::decode(text).ok())
+        {
+            return decoded.try_into().ok();
+        }
+    }
+    value.try_into().ok()
+}
+
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+const MD5_CONSTANTS: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+// RFC 1321. Only here to check files against the md5sums torrents carry, so it's the plain
+// textbook version rather than another dependency.
+struct Md5 {
+    state: [u32; 4],
+    // bytes of a 64 byte block still waiting for the rest of it
+    pending: Vec<u8>,
+    length: u64,
+}
+
+impl Md5 {
+    fn new() -> Self {
+        Md5 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            pending: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.length = self.length.wrapping_add(data.len() as u64);
+        if !self.pending.is_empty() {
+            let take = (64 - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < 64 {
+                return;
+            }
+            let block = std::mem::take(&mut self.pending);
+            self.compress(&block);
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block);
+        }
+        self.pending.extend_from_slice(blocks.remainder());
+    }
+
+    fn finish(mut self) -> [u8; 16] {
+        let bits = self.length.wrapping_mul(8);
+        let mut padding = vec![0x80];
+        while (self.pending.len() + padding.len()) % 64 != 56 {
+            padding.push(0);
+        }
+        padding.extend_from_slice(&bits.to_le_bytes());
+        self.update(&padding);
+
+        let mut digest = [0u8; 16];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, chunk: &[u8]) {
+        let words: Vec<u32> = chunk
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(MD5_CONSTANTS[i])
+                .wrapping_add(words[g])
+                .rotate_left(MD5_SHIFTS[i / 16 * 4 + i % 4]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        for (word, add) in self.state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+}
+
+// The IEEE CRC-32 that zip and most sfv files use
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Self {
+        Crc32(!0)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0 = data.iter().fold(self.0, |crc, byte| {
+            (0..8).fold(crc ^ *byte as u32, |crc, _| {
+                (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg())
+            })
+        });
+    }
+
+    fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::DictBuilder;
+
+    fn md5(data: &[u8]) -> [u8; 16] {
+        let mut md5 = Md5::new();
+        md5.update(data);
+        md5.finish()
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc32 = Crc32::new();
+        crc32.update(data);
+        crc32.finish()
+    }
+
+    #[test]
+    fn it_computes_md5_and_crc32() {
+        assert_eq!(hex::encode(md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex::encode(md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        // long enough for the length to need a second block
+        assert_eq!(
+            hex::encode(md5(&[b'a'; 60])),
+            "cc7ed669cf88f201c3297c6a91e1d18d"
+        );
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn it_hashes_the_same_however_the_data_is_split() {
+        let data: Vec<u8> = (0..200_000).map(|i| (i * 7 % 251) as u8).collect();
+        let mut md5_in_pieces = Md5::new();
+        let mut crc32_in_pieces = Crc32::new();
+        let mut rest = &data[..];
+        for size in [1, 63, 64, 65, 127, 100_000].iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (piece, after) = rest.split_at((*size).min(rest.len()));
+            md5_in_pieces.update(piece);
+            crc32_in_pieces.update(piece);
+            rest = after;
+        }
+        assert_eq!(md5_in_pieces.finish(), md5(&data));
+        assert_eq!(crc32_in_pieces.finish(), crc32(&data));
+
+        let checksums = FileChecksums {
+            md5: Some(md5(&data)),
+            sha1: Some(<[u8; 20]>::from(Sha1::digest(&data))),
+            crc32: Some(crc32(&data)),
+        };
+        assert!(checksums.mismatches_in(&data[..]).unwrap().is_empty());
+        assert_eq!(checksums.mismatches_in(&data[1..]).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn it_reports_each_checksum_a_file_fails() {
+        let file = DictBuilder::new()
+            .insert("crc32", "cbf43926")
+            .insert("length", 9_i64)
+            .insert("md5", "25f9e794323b453885f5181f1b624d0b")
+            .insert("sha1", &<[u8; 20]>::from(Sha1::digest(b"123456789"))[..])
+            .build();
+        let checksums = FileChecksums::parse(&file);
+        assert_eq!(checksums.crc32, Some(0xcbf43926));
+        assert!(checksums.md5.is_some() && checksums.sha1.is_some());
+        assert!(checksums.mismatches(b"123456789").is_empty());
+
+        let mismatches = checksums.mismatches(b"123456780");
+        assert_eq!(
+            mismatches.iter().map(|m| m.0).collect::<Vec<_>>(),
+            vec![Checksum::Md5, Checksum::Sha1, Checksum::Crc32]
+        );
+        assert_eq!(mismatches[2].1, "cbf43926");
+
+        assert!(
+            FileChecksums::parse(&DictBuilder::new().insert("md5sum", "xyz").build()).is_empty()
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use crate::meta_info_file::File;
+use crate::torrent::Torrent;
+
+// Which files of a torrent have every piece under them verified, so each one can be copied out as
+// soon as it's finished instead of once the whole torrent is
+#[derive(Debug)]
+pub struct FileCompletion {
+    // where each file starts in the torrent's content, and how long it is
+    ranges: Vec<(u64, u64)>,
+    piece_length: u64,
+    last_piece: u64,
+    completed: Vec<bool>,
+}
+
+impl FileCompletion {
+    pub fn new(files: &[&File], piece_length: u32, total_pieces: u32) -> Self {
+        let mut start = 0;
+        let ranges = files
+            .iter()
+            .map(|file| {
+                let range = (start, file.length);
+                start += file.length;
+                range
+            })
+            .collect();
+        FileCompletion {
+            ranges,
+            piece_length: piece_length as u64,
+            last_piece: total_pieces.saturating_sub(1) as u64,
+            // padding files are never written, so there's nothing to announce about them
+            completed: files.iter().map(|file| file.padding).collect(),
+        }
+    }
+
+    // The pieces file `index` is stored in. An empty file has no data of its own and counts as
+    // being in the piece where it would start.
+    fn pieces(&self, index: usize) -> (u64, u64) {
+        let (start, length) = self.ranges[index];
+        let first = (start / self.piece_length).min(self.last_piece);
+        let last = match length {
+            0 => first,
+            length => (start + length - 1) / self.piece_length,
+        };
+        (first, last)
+    }
+
+    // Files that became complete now that the `changed` pieces have, each reported only once
+    pub fn update(&mut self, torrent: &Torrent, changed: &[u32]) -> Vec<usize> {
+        let mut newly_completed = vec![];
+        for index in 0..self.ranges.len() {
+            if self.completed[index] {
+                continue;
+            }
+            let (first, last) = self.pieces(index);
+            let touched = changed
+                .iter()
+                .any(|piece| (first..=last).contains(&(*piece as u64)));
+            if touched && (first..=last).all(|piece| torrent.is_piece_verified(piece as u32)) {
+                self.completed[index] = true;
+                newly_completed.push(index);
+            }
+        }
+        newly_completed
+    }
+
+    // Where file `index` starts in the torrent's content and how long it is
+    pub fn range(&self, index: usize) -> Option<(u64, u64)> {
+        self.ranges.get(index).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitfield::BitField;
+    use crate::file_checksums::FileChecksums;
+    use crate::torrent::{PieceIndexOffsetLength, PiecedContent};
+    use std::path::PathBuf;
+
+    struct Content;
+
+    impl PiecedContent for Content {
+        fn number_of_pieces(&self) -> u32 {
+            3
+        }
+
+        fn piece_length(&self) -> u32 {
+            16384
+        }
+
+        fn total_length(&self) -> u64 {
+            16384 * 2 + 10000
+        }
+    }
+
+    fn fill_piece(torrent: &mut Torrent, index: u32) {
+        let PieceIndexOffsetLength(index, offset, length) = torrent
+            .get_next_block_preferring(&BitField::from(vec![255]), &[index])
+            .unwrap();
+        torrent.fill_block((index, offset, &vec![index as u8; length as usize]));
+    }
+
+    #[test]
+    fn it_reports_each_file_once_its_pieces_are_done() {
+        let files = [
+            File {
+                path: PathBuf::from("a"),
+                length: 10000,
+                checksums: FileChecksums::default(),
+                padding: false,
+            },
+            File {
+                path: PathBuf::from("b"),
+                length: 10000,
+                checksums: FileChecksums::default(),
+                padding: false,
+            },
+            File {
+                path: PathBuf::from("empty"),
+                length: 0,
+                checksums: FileChecksums::default(),
+                padding: false,
+            },
+            File {
+                path: PathBuf::from("c"),
+                length: 16384 * 2 + 10000 - 20000,
+                checksums: FileChecksums::default(),
+                padding: false,
+            },
+        ];
+        let mut torrent = Torrent::new(&Content);
+        let mut completion = FileCompletion::new(&files.iter().collect::<Vec<&File>>(), 16384, 3);
+
+        fill_piece(&mut torrent, 0);
+        assert_eq!(completion.update(&torrent, &[0]), vec![0]);
+        assert_eq!(torrent.read_range(0, 10000), Some(&[0u8; 10000][..]));
+        assert_eq!(torrent.read_range(10000, 10000), None);
+
+        fill_piece(&mut torrent, 2);
+        assert_eq!(completion.update(&torrent, &[2]), Vec::<usize>::new());
+        fill_piece(&mut torrent, 1);
+        assert_eq!(completion.update(&torrent, &[1]), vec![1, 2, 3]);
+        assert_eq!(completion.update(&torrent, &[0, 1, 2]), Vec::<usize>::new());
+        assert_eq!(completion.range(3), Some((20000, 22768)));
+    }
+}
@@ -0,0 +1,144 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_PENDING_HANDSHAKES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeOutcome {
+    Completed,
+    // the peer didn't finish its handshake before the deadline, however it spaced out the bytes
+    TimedOut,
+    Failed,
+}
+
+// How handshakes have gone across the session so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandshakeMetrics {
+    pub pending: usize,
+    pub completed: u64,
+    pub timed_out: u64,
+    pub failed: u64,
+    // turned away without starting because `max_pending` handshakes were already under way
+    pub refused: u64,
+    // of the completed handshakes only
+    pub total_duration: Duration,
+    pub slowest: Duration,
+}
+
+impl HandshakeMetrics {
+    pub fn average(&self) -> Option<Duration> {
+        u32::try_from(self.completed)
+            .ok()
+            .filter(|completed| *completed > 0)
+            .map(|completed| self.total_duration / completed)
+    }
+}
+
+// Caps how many connections can sit in the handshake at once, so peers that connect and then stall
+// (or trickle their handshake in a byte at a time) can't tie up every thread we're willing to spend.
+// Clones share the same cap and metrics.
+#[derive(Debug, Clone)]
+pub struct HandshakeGate {
+    max_pending: usize,
+    metrics: Arc<Mutex<HandshakeMetrics>>,
+}
+
+impl HandshakeGate {
+    pub fn new(max_pending: usize) -> Self {
+        HandshakeGate {
+            max_pending,
+            metrics: Arc::new(Mutex::new(HandshakeMetrics::default())),
+        }
+    }
+
+    // A place in the handshake, or None when every place is taken
+    pub fn enter(&self) -> Option<HandshakeTicket> {
+        let mut metrics = self.metrics.lock();
+        if metrics.pending >= self.max_pending {
+            metrics.refused += 1;
+            return None;
+        }
+        metrics.pending += 1;
+        Some(HandshakeTicket {
+            metrics: Arc::clone(&self.metrics),
+            started: Instant::now(),
+            finished: false,
+        })
+    }
+
+    pub fn metrics(&self) -> HandshakeMetrics {
+        *self.metrics.lock()
+    }
+}
+
+// Holds one place in the gate until the handshake is finished. Dropping it unfinished counts as a
+// failure.
+#[derive(Debug)]
+pub struct HandshakeTicket {
+    metrics: Arc<Mutex<HandshakeMetrics>>,
+    started: Instant,
+    finished: bool,
+}
+
+impl HandshakeTicket {
+    pub fn finish(mut self, outcome: HandshakeOutcome) {
+        self.record(outcome);
+    }
+
+    fn record(&mut self, outcome: HandshakeOutcome) {
+        self.finished = true;
+        let mut metrics = self.metrics.lock();
+        metrics.pending -= 1;
+        match outcome {
+            HandshakeOutcome::Completed => {
+                let duration = self.started.elapsed();
+                metrics.completed += 1;
+                metrics.total_duration += duration;
+                metrics.slowest = metrics.slowest.max(duration);
+            }
+            HandshakeOutcome::TimedOut => metrics.timed_out += 1,
+            HandshakeOutcome::Failed => metrics.failed += 1,
+        }
+    }
+}
+
+impl Drop for HandshakeTicket {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.record(HandshakeOutcome::Failed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_caps_pending_handshakes_and_counts_outcomes() {
+        let gate = HandshakeGate::new(2);
+        let first = gate.enter().unwrap();
+        let second = gate.clone().enter().unwrap();
+        assert!(gate.enter().is_none());
+        assert_eq!(gate.metrics().pending, 2);
+
+        first.finish(HandshakeOutcome::Completed);
+        drop(second);
+        gate.enter().unwrap().finish(HandshakeOutcome::TimedOut);
+
+        let metrics = gate.metrics();
+        assert_eq!(
+            (
+                metrics.pending,
+                metrics.completed,
+                metrics.timed_out,
+                metrics.failed,
+                metrics.refused
+            ),
+            (0, 1, 1, 1, 1)
+        );
+        assert_eq!(metrics.average(), Some(metrics.total_duration));
+        assert_eq!(HandshakeMetrics::default().average(), None);
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+
+// What one peer told us about itself during a short probe connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerSample {
+    pub client: String,
+    // None when the peer never sent a bitfield or any haves before we hung up
+    pub pieces: Option<Vec<bool>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwarmHealth {
+    pub peers_announced: usize,
+    pub peers_sampled: usize,
+    pub peers_responded: usize,
+    pub seeds: usize,
+    pub leechers: usize,
+    // how many responding peers have each piece
+    pub availability: Vec<u32>,
+    pub clients: BTreeMap<String, usize>,
+}
+
+impl SwarmHealth {
+    pub fn from_samples(
+        total_pieces: u32,
+        peers_announced: usize,
+        peers_sampled: usize,
+        samples: &[PeerSample],
+    ) -> Self {
+        let mut availability = vec![0u32; total_pieces as usize];
+        let mut seeds = 0;
+        let mut clients = BTreeMap::new();
+        for sample in samples {
+            *clients.entry(sample.client.clone()).or_insert(0) += 1;
+            let pieces = match &sample.pieces {
+                Some(pieces) => pieces,
+                None => continue,
+            };
+            for (count, has) in availability.iter_mut().zip(pieces) {
+                if *has {
+                    *count += 1;
+                }
+            }
+            if pieces.len() >= total_pieces as usize && pieces.iter().all(|has| *has) {
+                seeds += 1;
+            }
+        }
+        SwarmHealth {
+            peers_announced,
+            peers_sampled,
+            peers_responded: samples.len(),
+            seeds,
+            leechers: samples.len() - seeds,
+            availability,
+            clients,
+        }
+    }
+
+    // Every piece is held by at least one of the peers we reached
+    pub fn completable(&self) -> bool {
+        self.availability.iter().all(|count| *count > 0)
+    }
+
+    // Number of full copies in the sample, plus the fraction of pieces above the rarest count
+    pub fn distributed_copies(&self) -> f64 {
+        let rarest = match self.availability.iter().min() {
+            Some(rarest) => *rarest,
+            None => return 0.0,
+        };
+        let above = self.availability.iter().filter(|c| **c > rarest).count();
+        rarest as f64 + above as f64 / self.availability.len() as f64
+    }
+}
+
+// Azureus style peer ids ("-TR2940-...") name the client and version; anything else is unknown
+pub fn client_name(peer_id: &[u8]) -> String {
+    let known = [
+        ("AZ", "Vuze"),
+        ("BT", "BitTorrent"),
+        ("DE", "Deluge"),
+        ("LT", "libtorrent"),
+        ("lt", "libTorrent"),
+        ("qB", "qBittorrent"),
+        ("TR", "Transmission"),
+        ("UT", "uTorrent"),
+    ];
+    if peer_id.len() < 8 || peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return "unknown".to_string();
+    }
+    let code = String::from_utf8_lossy(&peer_id[1..3]).to_string();
+    let version = String::from_utf8_lossy(&peer_id[3..7]).to_string();
+    match known.iter().find(|(c, _)| *c == code) {
+        Some((_, name)) => format!("{} {}", name, version),
+        None => format!("{} {}", code, version),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(client: &str, pieces: Option<Vec<bool>>) -> PeerSample {
+        PeerSample {
+            client: client.to_string(),
+            pieces,
+        }
+    }
+
+    #[test]
+    fn it_summarizes_peer_samples() {
+        let health = SwarmHealth::from_samples(
+            3,
+            10,
+            4,
+            &[
+                sample("Transmission 2940", Some(vec![true, true, true])),
+                sample("Transmission 2940", Some(vec![true, false, false])),
+                sample("unknown", None),
+            ],
+        );
+        assert_eq!(health.peers_responded, 3);
+        assert_eq!(health.seeds, 1);
+        assert_eq!(health.leechers, 2);
+        assert_eq!(health.availability, vec![2, 1, 1]);
+        assert_eq!(health.clients.get("Transmission 2940"), Some(&2));
+        assert!(health.completable());
+        assert!((health.distributed_copies() - (1.0 + 1.0 / 3.0)).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn it_is_not_completable_when_a_piece_is_missing_everywhere() {
+        let health =
+            SwarmHealth::from_samples(2, 1, 1, &[sample("unknown", Some(vec![true, false]))]);
+        assert_eq!(health.seeds, 0);
+        assert!(!health.completable());
+    }
+
+    #[test]
+    fn it_names_azureus_style_clients() {
+        assert_eq!(client_name(b"-TR2940-k8hj0wgej6ch"), "Transmission 2940");
+        assert_eq!(client_name(b"-ZZ0100-k8hj0wgej6ch"), "ZZ 0100");
+        assert_eq!(client_name(b"M7-4-3--k8hj0wgej6ch"), "unknown");
+    }
+}
@@ -0,0 +1,68 @@
+// How often peers have asked us for each piece, to show which parts of a torrent are in demand
+// while seeding. Every request we answer counts, including those served while still downloading.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PieceHeatmap {
+    requests: Vec<u64>,
+    bytes: Vec<u64>,
+}
+
+impl PieceHeatmap {
+    pub fn new(total_pieces: u32) -> Self {
+        PieceHeatmap {
+            requests: vec![0; total_pieces as usize],
+            bytes: vec![0; total_pieces as usize],
+        }
+    }
+
+    pub fn record(&mut self, index: u32, bytes: u64) {
+        if let Some(requests) = self.requests.get_mut(index as usize) {
+            *requests += 1;
+            self.bytes[index as usize] += bytes;
+        }
+    }
+
+    // Requests answered per piece, by piece index
+    pub fn requests(&self) -> &[u64] {
+        &self.requests
+    }
+
+    // Bytes uploaded per piece, by piece index
+    pub fn bytes(&self) -> &[u64] {
+        &self.bytes
+    }
+
+    // Up to `count` (piece index, requests) pairs, most requested first and lowest index first
+    // among equals; pieces nobody asked for are left out
+    pub fn hottest(&self, count: usize) -> Vec<(u32, u64)> {
+        let mut pieces: Vec<(u32, u64)> = self
+            .requests
+            .iter()
+            .enumerate()
+            .filter(|(_, requests)| **requests > 0)
+            .map(|(index, requests)| (index as u32, *requests))
+            .collect();
+        pieces.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        pieces.truncate(count);
+        pieces
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_ranks_pieces_by_how_often_they_were_requested() {
+        let mut heatmap = PieceHeatmap::new(4);
+        for index in [2, 0, 2, 3, 2, 0] {
+            heatmap.record(index, 16384);
+        }
+        // out of range requests are never answered, so never counted
+        heatmap.record(9, 16384);
+
+        assert_eq!(heatmap.requests(), &[2, 0, 3, 1]);
+        assert_eq!(heatmap.bytes()[2], 3 * 16384);
+        assert_eq!(heatmap.hottest(2), vec![(2, 3), (0, 2)]);
+        assert_eq!(heatmap.hottest(10), vec![(2, 3), (0, 2), (3, 1)]);
+    }
+}
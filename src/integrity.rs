@@ -0,0 +1,128 @@
+use std::collections::{BTreeMap, HashMap};
+
+// Where in the pipeline a block no longer matched what arrived from the network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    // right after being copied into the torrent's buffer, or when a later write clobbered it
+    Stored,
+    // on its way to the verification queue
+    Queued,
+    // being read back out for a peer's request
+    Upload,
+    // being written out to the files
+    Export,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Corruption {
+    pub index: u32,
+    pub offset: u32,
+    pub length: u32,
+    pub stage: Stage,
+}
+
+// A checksum of every block as it came off the wire, checked again each time those bytes move on
+// (see `Stage`). This is a development aid for catching the engine's own offset or buffer mistakes
+// close to where they happen; the piece hashes only notice after the whole piece is in, and not at
+// all for content without them. Only turned on with the `integrity` feature, as it hashes every
+// block several times.
+#[derive(Debug, Default)]
+pub struct BlockChecksums {
+    // piece index -> offset -> (length, checksum)
+    pieces: HashMap<u32, BTreeMap<u32, (u32, u64)>>,
+}
+
+impl BlockChecksums {
+    pub fn record(&mut self, index: u32, offset: u32, data: &[u8]) {
+        self.pieces
+            .entry(index)
+            .or_default()
+            .insert(offset, (data.len() as u32, checksum(data)));
+    }
+
+    // The piece is being downloaded again, so its blocks will be recorded anew
+    pub fn forget_piece(&mut self, index: u32) {
+        self.pieces.remove(&index);
+    }
+
+    // Checks every recorded block of piece `index` lying entirely within the `data.len()` bytes
+    // from `begin`, `data` being those bytes as they are now
+    pub fn check(
+        &self,
+        index: u32,
+        begin: u32,
+        data: &[u8],
+        stage: Stage,
+    ) -> Result<(), Corruption> {
+        let blocks = match self.pieces.get(&index) {
+            Some(blocks) => blocks,
+            None => return Ok(()),
+        };
+        let end = begin as u64 + data.len() as u64;
+        for (offset, (length, expected)) in blocks.range(begin..) {
+            if *offset as u64 + *length as u64 > end {
+                continue;
+            }
+            let start = (offset - begin) as usize;
+            if checksum(&data[start..start + *length as usize]) != *expected {
+                return Err(Corruption {
+                    index,
+                    offset: *offset,
+                    length: *length,
+                    stage,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// FNV-1a; only has to notice accidents, not withstand anyone
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_notices_blocks_that_changed_after_they_were_recorded() {
+        let mut checksums = BlockChecksums::default();
+        let mut piece = vec![0u8; 12];
+        piece[..4].copy_from_slice(&[1, 2, 3, 4]);
+        piece[4..8].copy_from_slice(&[5, 6, 7, 8]);
+        checksums.record(0, 0, &[1, 2, 3, 4]);
+        checksums.record(0, 4, &[5, 6, 7, 8]);
+        assert_eq!(checksums.check(0, 0, &piece, Stage::Stored), Ok(()));
+        // blocks only partly in the range aren't checked
+        assert_eq!(checksums.check(0, 2, &piece[2..6], Stage::Upload), Ok(()));
+
+        // a write landing a byte too early
+        piece[3..7].copy_from_slice(&[5, 6, 7, 8]);
+        assert_eq!(
+            checksums.check(0, 0, &piece, Stage::Stored),
+            Err(Corruption {
+                index: 0,
+                offset: 0,
+                length: 4,
+                stage: Stage::Stored
+            })
+        );
+        assert_eq!(
+            checksums.check(0, 4, &piece[4..8], Stage::Upload),
+            Err(Corruption {
+                index: 0,
+                offset: 4,
+                length: 4,
+                stage: Stage::Upload
+            })
+        );
+        assert_eq!(checksums.check(1, 0, &piece, Stage::Export), Ok(()));
+
+        checksums.forget_piece(0);
+        assert_eq!(checksums.check(0, 0, &piece, Stage::Queued), Ok(()));
+    }
+}
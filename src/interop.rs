@@ -0,0 +1,336 @@
+// Interop harness against a real transmission-daemon. These tests are ignored by default and
+// skip themselves when transmission isn't installed; run them with
+// `cargo test interop -- --ignored` before turning on experimental protocol features.
+use crate::bencode::{bencode, Bencodable, DictBuilder};
+use crate::connection::{PeerConnection, Stream};
+use crate::file_checksums::FileChecksums;
+use crate::messages::MessageParseError;
+use crate::meta_info_file::{File, MetaInfoFile};
+use crate::processor::process_message;
+use crate::test_seeder::{SeederProfile, TestSeeder};
+use crate::torrent::Torrent;
+use crate::util::random_string;
+use parking_lot::RwLock;
+use sha1::{Digest, Sha1};
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+const PIECE_LENGTH: u32 = 32768;
+const DATA_LENGTH: usize = 20 * 32768 + 1234;
+const TIMEOUT: Duration = Duration::from_secs(90);
+const NAME: &str = "interop.bin";
+
+fn installed(binary: &str) -> bool {
+    Command::new(binary)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn scratch_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bit_torrent_interop_{}", random_string()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn generate_data() -> Vec<u8> {
+    (0..DATA_LENGTH).map(|_| rand::random::<u8>()).collect()
+}
+
+fn generate_torrent(data: &[u8], announce: &str) -> Bencodable {
+    let pieces: Vec<u8> = data
+        .chunks(PIECE_LENGTH as usize)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+    let info = DictBuilder::new()
+        .insert("length", data.len() as i64)
+        .insert("name", NAME)
+        .insert("piece length", PIECE_LENGTH)
+        .insert("pieces", pieces)
+        .build();
+    DictBuilder::new()
+        .insert("announce", announce)
+        .insert("info", info)
+        .build()
+}
+
+// Just enough of an HTTP tracker to point transmission at a fixed list of peers
+struct MiniTracker {
+    port: u16,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl MiniTracker {
+    fn start(peers: Vec<SocketAddr>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&shutdown);
+        spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                let mut stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(_) => {
+                        sleep(Duration::from_millis(20));
+                        continue;
+                    }
+                };
+                let _ = stream.set_nonblocking(false);
+                let mut request = vec![];
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let compact: Vec<u8> = peers
+                    .iter()
+                    .flat_map(|p| match p {
+                        SocketAddr::V4(v4) => {
+                            let mut bytes = v4.ip().octets().to_vec();
+                            bytes.extend_from_slice(&v4.port().to_be_bytes());
+                            bytes
+                        }
+                        SocketAddr::V6(_) => vec![],
+                    })
+                    .collect();
+                let response = DictBuilder::new()
+                    .insert("interval", 60_i64)
+                    .insert("peers", compact.as_slice())
+                    .build();
+                let body = bencode(&response).unwrap();
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .as_bytes(),
+                );
+                let _ = stream.write_all(&body);
+            }
+        });
+        MiniTracker { port, shutdown }
+    }
+
+    fn announce_url(&self) -> String {
+        format!("http://127.0.0.1:{}/announce", self.port)
+    }
+}
+
+impl Drop for MiniTracker {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+}
+
+struct Transmission {
+    child: Child,
+    rpc_port: u16,
+    peer_port: u16,
+    download_dir: PathBuf,
+}
+
+impl Transmission {
+    fn start(dir: &Path) -> Self {
+        let rpc_port = free_port();
+        let peer_port = free_port();
+        let download_dir = dir.join("transmission-downloads");
+        std::fs::create_dir_all(&download_dir).unwrap();
+        let child = Command::new("transmission-daemon")
+            .arg("--foreground")
+            .arg("--config-dir")
+            .arg(dir.join("transmission-config"))
+            .arg("--download-dir")
+            .arg(&download_dir)
+            .arg("--port")
+            .arg(rpc_port.to_string())
+            .arg("--peerport")
+            .arg(peer_port.to_string())
+            .args(["--no-auth", "--no-dht", "--no-lpd", "--no-portmap"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to launch transmission-daemon");
+        let transmission = Transmission {
+            child,
+            rpc_port,
+            peer_port,
+            download_dir,
+        };
+        let started = Instant::now();
+        while !transmission.remote(&["--list"]) {
+            assert!(
+                started.elapsed() < TIMEOUT,
+                "transmission rpc never came up"
+            );
+            sleep(Duration::from_millis(200));
+        }
+        transmission
+    }
+
+    fn remote(&self, args: &[&str]) -> bool {
+        Command::new("transmission-remote")
+            .arg(self.rpc_port.to_string())
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false)
+    }
+
+    fn add(&self, torrent_path: &Path) {
+        assert!(self.remote(&["--add", torrent_path.to_str().unwrap()]));
+        assert!(self.remote(&["--torrent", "all", "--verify"]));
+        assert!(self.remote(&["--torrent", "all", "--start"]));
+    }
+}
+
+impl Drop for Transmission {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn write_torrent(dir: &Path, torrent: &Bencodable) -> PathBuf {
+    let path = dir.join("interop.torrent");
+    std::fs::write(&path, bencode(torrent).unwrap()).unwrap();
+    path
+}
+
+fn transmission_available() -> bool {
+    let available = installed("transmission-daemon") && installed("transmission-remote");
+    if !available {
+        println!("transmission-daemon/transmission-remote not found, skipping interop test");
+    }
+    available
+}
+
+#[test]
+#[ignore]
+fn interop_downloads_from_transmission() {
+    if !transmission_available() {
+        return;
+    }
+    let dir = scratch_dir();
+    let data = generate_data();
+    let torrent = generate_torrent(&data, "http://127.0.0.1:1/announce");
+    let transmission = Transmission::start(&dir);
+    std::fs::write(transmission.download_dir.join(NAME), &data).unwrap();
+    transmission.add(&write_torrent(&dir, &torrent));
+
+    let meta_info = MetaInfoFile::from(&torrent);
+    let torrent = Arc::new(RwLock::new(Torrent::new(&meta_info)));
+    let started = Instant::now();
+    // transmission only starts serving once it has verified its copy, so keep retrying the handshake
+    let mut connection = loop {
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "never connected to transmission"
+        );
+        let stream = match TcpStream::connect(("127.0.0.1", transmission.peer_port)) {
+            Ok(stream) => stream,
+            Err(_) => {
+                sleep(Duration::from_millis(200));
+                continue;
+            }
+        };
+        let _ = stream.set_read_timeout(Some(Duration::from_millis(200)));
+        let peer_id = random_string();
+        match PeerConnection::new(
+            Stream::Tcp(stream),
+            &meta_info.info_hash,
+            random_string().as_bytes(),
+            peer_id.as_bytes(),
+            Box::new(|_, _| {}),
+        ) {
+            Ok(connection) => break connection,
+            Err(_) => sleep(Duration::from_millis(200)),
+        }
+    };
+
+    while !torrent.read().are_we_done_yet() {
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "download from transmission timed out"
+        );
+        match connection.read_message() {
+            Ok(message) => {
+                process_message(Arc::clone(&torrent), message, &mut connection);
+            }
+            Err(MessageParseError::WouldBlock) | Err(MessageParseError::TimedOut) => {}
+            Err(e) => panic!("transmission connection failed {:?}", e),
+        }
+        for (index, ok) in torrent.write().apply_verifications() {
+            assert!(ok, "piece {} from transmission failed verification", index);
+        }
+    }
+
+    let output = dir.join("downloaded.bin");
+    let file = File {
+        length: data.len() as u64,
+        path: PathBuf::from("downloaded.bin"),
+        checksums: FileChecksums::default(),
+        padding: false,
+    };
+    torrent.read().to_file(&dir, vec![&file]).unwrap();
+    assert!(std::fs::read(&output).unwrap() == data);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[ignore]
+fn interop_seeds_to_transmission() {
+    if !transmission_available() {
+        return;
+    }
+    let dir = scratch_dir();
+    let data = generate_data();
+    let info_hash = {
+        let placeholder = generate_torrent(&data, "http://127.0.0.1:1/announce");
+        MetaInfoFile::from(&placeholder).info_hash
+    };
+    let seeder = TestSeeder::start(
+        data.clone(),
+        PIECE_LENGTH,
+        info_hash,
+        SeederProfile::default(),
+    )
+    .unwrap();
+    let tracker = MiniTracker::start(vec![seeder.addr()]);
+    let torrent = generate_torrent(&data, &tracker.announce_url());
+    let transmission = Transmission::start(&dir);
+    transmission.add(&write_torrent(&dir, &torrent));
+
+    let target = transmission.download_dir.join(NAME);
+    let started = Instant::now();
+    while std::fs::read(&target).map(|d| d != data).unwrap_or(true) {
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "transmission never finished downloading from us ({} blocks served)",
+            seeder.blocks_served()
+        );
+        sleep(Duration::from_millis(500));
+    }
+    assert!(seeder.blocks_served() > 0);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,89 @@
+// The bencode encoder/decoder is always here; everything that talks to peers and trackers needs the
+// `engine` feature. The `bit_torrent` binary is a thin command line over `session::Session`.
+pub mod bencode;
+
+#[cfg(feature = "engine")]
+pub mod bitfield;
+#[cfg(feature = "engine")]
+pub mod choker;
+#[cfg(feature = "engine")]
+pub mod connection;
+#[cfg(feature = "engine")]
+pub mod connection_manager;
+#[cfg(feature = "engine")]
+pub mod dht;
+#[cfg(feature = "engine")]
+pub mod dns;
+#[cfg(feature = "engine")]
+pub mod feed;
+#[cfg(feature = "engine")]
+pub mod file_checksums;
+#[cfg(feature = "engine")]
+pub mod file_completion;
+#[cfg(feature = "engine")]
+pub mod handshake;
+#[cfg(feature = "engine")]
+pub mod health;
+#[cfg(feature = "engine")]
+pub mod heatmap;
+#[cfg(feature = "engine")]
+pub mod integrity;
+#[cfg(feature = "engine")]
+pub mod logger;
+#[cfg(feature = "engine")]
+pub mod lsd;
+#[cfg(feature = "engine")]
+pub mod magnet;
+#[cfg(feature = "engine")]
+pub mod messages;
+#[cfg(feature = "engine")]
+pub mod meta_info_file;
+#[cfg(feature = "engine")]
+pub mod metadata_cache;
+#[cfg(feature = "engine")]
+pub mod peer_pool;
+#[cfg(feature = "engine")]
+pub mod peer_source;
+#[cfg(feature = "engine")]
+mod processor;
+#[cfg(feature = "engine")]
+pub mod replay;
+#[cfg(feature = "engine")]
+pub mod scheduler;
+#[cfg(feature = "engine")]
+pub mod self_test;
+#[cfg(feature = "engine")]
+pub mod session;
+#[cfg(feature = "engine")]
+pub mod settings;
+#[cfg(feature = "engine")]
+pub mod sim;
+#[cfg(feature = "engine")]
+pub mod storage;
+#[cfg(feature = "engine")]
+pub mod suspend;
+#[cfg(feature = "engine")]
+pub mod test_seeder;
+#[cfg(feature = "engine")]
+pub mod test_tracker;
+#[cfg(feature = "engine")]
+pub mod timeline;
+#[cfg(feature = "engine")]
+pub mod torrent;
+#[cfg(feature = "engine")]
+pub mod torrent_builder;
+#[cfg(feature = "engine")]
+pub mod torrent_editor;
+#[cfg(feature = "engine")]
+pub mod tracker;
+#[cfg(feature = "engine")]
+pub mod ut_metadata;
+#[cfg(feature = "engine")]
+mod util;
+#[cfg(feature = "engine")]
+pub mod verify;
+#[cfg(feature = "engine")]
+pub mod web_seed;
+
+#[cfg(all(test, feature = "engine"))]
+mod interop;
@@ -1,23 +1,144 @@
+use crate::messages::Message;
+use sha1::{Digest, Sha1};
 use std::fs::File;
 use std::io::Write;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Human,
+    // One JSON object per line for every peer message; see `Logger::log_message` for the fields
+    JsonLines,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogLevel {
+    Off,
+    // every peer message in both directions
+    Messages,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
 
 pub struct Logger {
     file: File,
+    format: LogFormat,
+    level: LogLevel,
 }
 
 impl Logger {
-    pub fn new(filename: &str) -> Self {
+    pub fn new(filename: &str, format: LogFormat) -> Self {
         let file = File::create(filename);
         match file {
-            Ok(file) => Logger { file },
+            Ok(file) => Logger {
+                file,
+                format,
+                level: LogLevel::Messages,
+            },
             Err(e) => {
                 panic!("could not open file for logging... {}", e);
             }
         }
     }
 
+    // Both take effect from the next line written, so they can be changed while torrents are running
+    pub fn set_format(&mut self, format: LogFormat) {
+        self.format = format;
+    }
+
+    pub fn set_level(&mut self, level: LogLevel) {
+        self.level = level;
+    }
+
     pub fn log(&mut self, s: &str) -> Result<(), std::io::Error> {
         let _ = self.file.write_all(s.as_bytes());
         self.file.write_all(b"\n")
     }
+
+    pub fn log_message(
+        &mut self,
+        direction: Direction,
+        peer_addr: SocketAddr,
+        local_addr: SocketAddr,
+        message: &Message,
+        bytes: &[u8],
+    ) -> Result<(), std::io::Error> {
+        if self.level == LogLevel::Off {
+            return Ok(());
+        }
+        let line = match self.format {
+            LogFormat::Human => match direction {
+                Direction::Incoming => format!(
+                    "From: {}, To (me): {}, Message: {}",
+                    peer_addr, local_addr, message
+                ),
+                Direction::Outgoing => format!(
+                    "From (me): {}, To: {}, Message: {}  ----  {:?}",
+                    local_addr, peer_addr, message, bytes
+                ),
+            },
+            LogFormat::JsonLines => json_line(direction, peer_addr, local_addr, message, bytes),
+        };
+        self.log(&line)
+    }
+}
+
+// `length` is the size of the message on the wire (length prefix included), `checksum` is the
+// hex SHA-1 of those same bytes so records can be matched against packet captures, and `bytes`
+// is the message itself in hex so the log can be replayed (see `replay::ReplayPeer`)
+fn json_line(
+    direction: Direction,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    message: &Message,
+    bytes: &[u8],
+) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let direction = match direction {
+        Direction::Incoming => "in",
+        Direction::Outgoing => "out",
+    };
+    format!(
+        "{{\"timestamp_ms\":{},\"direction\":\"{}\",\"peer\":\"{}\",\"local\":\"{}\",\"type\":\"{}\",\"length\":{},\"checksum\":\"{}\",\"bytes\":\"{}\"}}",
+        timestamp,
+        direction,
+        peer_addr,
+        local_addr,
+        message.kind(),
+        bytes.len(),
+        hex::encode(Sha1::digest(bytes)),
+        hex::encode(bytes)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_formats_peer_messages_as_json_lines() {
+        let message = Message::Have { index: 3 };
+        let bytes = message.serialize();
+        let line = json_line(
+            Direction::Incoming,
+            "73.140.205.84:8999".parse().unwrap(),
+            "127.0.0.1:6881".parse().unwrap(),
+            &message,
+            &bytes,
+        );
+
+        assert!(line.starts_with("{\"timestamp_ms\":"));
+        assert!(line.ends_with(&format!(
+            ",\"direction\":\"in\",\"peer\":\"73.140.205.84:8999\",\"local\":\"127.0.0.1:6881\",\"type\":\"Have\",\"length\":9,\"checksum\":\"{}\",\"bytes\":\"000000050400000003\"}}",
+            hex::encode(Sha1::digest(&bytes))
+        )));
+    }
 }
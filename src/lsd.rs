@@ -0,0 +1,282 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::{Arc, Weak};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+// BEP 14's IPv4 multicast group; IPv6 LSD isn't supported
+pub const LSD_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+// BEP 14 asks for no more than one announce a minute for a torrent; every 5 minutes is what
+// other clients do
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// how often the thread looks up from the socket to announce, or see whether its `Lsd` is gone
+const RECEIVE_POLL: Duration = Duration::from_millis(500);
+// peers heard about for a torrent and not yet taken; more than a LAN will ever have
+const MAX_WAITING_PEERS: usize = 200;
+
+// BEP 14 Local Service Discovery: torrents are announced to a multicast group on the local
+// network, and peers announcing the same torrents there are picked up straight away, with no
+// tracker involved. Only torrents registered with `register` are announced or listened for. It
+// stops once every clone of it is dropped.
+//
+//     let lsd = Lsd::start(LSD_GROUP)?;
+//     lsd.register(info_hash, listen_port);
+//     let peers = lsd.take_peers(&info_hash);
+#[derive(Clone)]
+pub struct Lsd {
+    inner: Arc<LsdInner>,
+}
+
+struct LsdInner {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    // BEP 14 `cookie`, which tells our own announces apart when the group loops them back to us
+    cookie: String,
+    // the torrents announced, each with the port its peers listen on
+    torrents: Mutex<HashMap<[u8; 20], u16>>,
+    // peers heard about, by torrent, until taken
+    peers: Mutex<HashMap<[u8; 20], Vec<SocketAddr>>>,
+    // None until a torrent is registered, which announces straight away
+    last_announce: Mutex<Option<Instant>>,
+}
+
+impl std::fmt::Debug for Lsd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Lsd")
+            .field("group", &self.inner.group)
+            .field("local_addr", &self.inner.socket.local_addr())
+            .finish()
+    }
+}
+
+impl Lsd {
+    // Listens on the group's port and joins it when it's a multicast address. Only one program on
+    // a machine can have that port, so a second client there falls back to a port of its own:
+    // it's still heard by the rest of the network, it just hears none of them.
+    pub fn start(group: SocketAddrV4) -> Result<Self, std::io::Error> {
+        let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, group.port())) {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("could not listen for local peers on {} {:?}", group, e);
+                UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?
+            }
+        };
+        if group.ip().is_multicast() {
+            socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+            // other clients on this machine are on the local network too
+            socket.set_multicast_loop_v4(true)?;
+        }
+        Lsd::with_socket(socket, group)
+    }
+
+    // Announces to `group` from `socket` as it is; tests use a unicast one standing in for the group
+    pub(crate) fn with_socket(
+        socket: UdpSocket,
+        group: SocketAddrV4,
+    ) -> Result<Self, std::io::Error> {
+        socket.set_read_timeout(Some(RECEIVE_POLL))?;
+        let receiving = socket.try_clone()?;
+        let inner = Arc::new(LsdInner {
+            socket,
+            group,
+            cookie: crate::util::random_string(),
+            torrents: Mutex::new(HashMap::new()),
+            peers: Mutex::new(HashMap::new()),
+            last_announce: Mutex::new(None),
+        });
+        let lsd = Arc::downgrade(&inner);
+        spawn(move || run(receiving, lsd));
+        Ok(Lsd { inner })
+    }
+
+    // Announces the torrent, now and every `ANNOUNCE_INTERVAL`, and starts listening for its peers
+    pub fn register(&self, info_hash: [u8; 20], port: u16) {
+        self.inner.torrents.lock().insert(info_hash, port);
+        *self.inner.last_announce.lock() = None;
+    }
+
+    pub fn unregister(&self, info_hash: &[u8; 20]) {
+        self.inner.torrents.lock().remove(info_hash);
+        self.inner.peers.lock().remove(info_hash);
+    }
+
+    // The peers heard announcing the torrent since last asked
+    pub fn take_peers(&self, info_hash: &[u8; 20]) -> Vec<SocketAddr> {
+        self.inner
+            .peers
+            .lock()
+            .get_mut(info_hash)
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+}
+
+// Announces whenever it's due and notes every peer announcing a registered torrent, until the
+// `Lsd` is dropped
+fn run(socket: UdpSocket, lsd: Weak<LsdInner>) {
+    let mut buf = [0u8; 1500];
+    loop {
+        let received = socket.recv_from(&mut buf);
+        let Some(lsd) = lsd.upgrade() else {
+            break;
+        };
+        lsd.announce_if_due();
+        let Ok((n, from)) = received else {
+            continue;
+        };
+        let Some(announce) = parse(&buf[..n]) else {
+            continue;
+        };
+        if announce.cookie.as_deref() == Some(lsd.cookie.as_str()) {
+            continue;
+        }
+        let torrents = lsd.torrents.lock();
+        let mut peers = lsd.peers.lock();
+        for info_hash in announce.info_hashes {
+            if !torrents.contains_key(&info_hash) {
+                continue;
+            }
+            let peer = SocketAddr::new(from.ip(), announce.port);
+            let waiting = peers.entry(info_hash).or_default();
+            if !waiting.contains(&peer) && waiting.len() < MAX_WAITING_PEERS {
+                println!("local peer {} for {}", peer, hex::encode(info_hash));
+                waiting.push(peer);
+            }
+        }
+    }
+}
+
+impl LsdInner {
+    // One message for each port in use, listing every torrent on it
+    fn announce_if_due(&self) {
+        {
+            let mut last = self.last_announce.lock();
+            if last.is_some_and(|last| last.elapsed() < ANNOUNCE_INTERVAL) {
+                return;
+            }
+            *last = Some(Instant::now());
+        }
+        let mut by_port: HashMap<u16, Vec<[u8; 20]>> = HashMap::new();
+        for (info_hash, port) in self.torrents.lock().iter() {
+            by_port.entry(*port).or_default().push(*info_hash);
+        }
+        for (port, info_hashes) in by_port {
+            let message = announcement(self.group, port, &info_hashes, &self.cookie);
+            if let Err(e) = self.socket.send_to(message.as_bytes(), self.group) {
+                println!("could not announce to local peers {:?}", e);
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Announcement {
+    port: u16,
+    info_hashes: Vec<[u8; 20]>,
+    cookie: Option<String>,
+}
+
+fn announcement(group: SocketAddrV4, port: u16, info_hashes: &[[u8; 20]], cookie: &str) -> String {
+    let mut message = format!(
+        "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+        group, port
+    );
+    for info_hash in info_hashes {
+        message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+    }
+    message.push_str(&format!("cookie: {}\r\n\r\n\r\n", cookie));
+    message
+}
+
+// A BT-SEARCH message; header names are matched case-insensitively, as in HTTP, and info hashes
+// that aren't 40 hex digits are skipped
+fn parse(message: &[u8]) -> Option<Announcement> {
+    let message = std::str::from_utf8(message).ok()?;
+    let mut lines = message.split("\r\n");
+    if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+        return None;
+    }
+    let mut port = None;
+    let mut info_hashes = vec![];
+    let mut cookie = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => port = value.parse().ok().filter(|port| *port != 0),
+            "infohash" => {
+                if let Some(info_hash) = hex::decode(value)
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                {
+                    info_hashes.push(info_hash);
+                }
+            }
+            "cookie" => cookie = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    Some(Announcement {
+        port: port?,
+        info_hashes,
+        cookie,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_announces_torrents_and_picks_up_local_peers() {
+        // a client on the network, here the whole of the "group"
+        let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+        other
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let group = match other.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let lsd = Lsd::with_socket(UdpSocket::bind("127.0.0.1:0").unwrap(), group).unwrap();
+        let lsd_addr = lsd.inner.socket.local_addr().unwrap();
+        let info_hash = [0xab; 20];
+        lsd.register(info_hash, 6881);
+
+        let mut buf = [0u8; 1500];
+        let (n, from) = other.recv_from(&mut buf).unwrap();
+        assert_eq!(from, lsd_addr);
+        let heard = parse(&buf[..n]).unwrap();
+        assert_eq!(heard.port, 6881);
+        assert_eq!(heard.info_hashes, vec![info_hash]);
+        assert_eq!(heard.cookie.as_deref(), Some(lsd.inner.cookie.as_str()));
+
+        let unknown = [0xcd; 20];
+        let theirs = announcement(group, 7000, &[info_hash, unknown], "theirs");
+        let ours = announcement(group, 7001, &[info_hash], &lsd.inner.cookie);
+        other.send_to(ours.as_bytes(), lsd_addr).unwrap();
+        other.send_to(theirs.as_bytes(), lsd_addr).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut peers = vec![];
+        while peers.is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+            peers = lsd.take_peers(&info_hash);
+        }
+        assert_eq!(peers, vec!["127.0.0.1:7000".parse::<SocketAddr>().unwrap()]);
+        assert!(lsd.take_peers(&unknown).is_empty());
+        assert!(lsd.take_peers(&info_hash).is_empty());
+
+        assert_eq!(
+            parse(b"BT-SEARCH * HTTP/1.1\r\nhost: x\r\nPORT: 1\r\ninfohash: zz\r\n\r\n\r\n"),
+            Some(Announcement {
+                port: 1,
+                info_hashes: vec![],
+                cookie: None
+            })
+        );
+        assert_eq!(parse(b"NOTIFY * HTTP/1.1\r\nPort: 1\r\n\r\n"), None);
+    }
+}
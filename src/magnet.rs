@@ -0,0 +1,121 @@
+use percent_encoding::percent_decode_str;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MagnetError {
+    NotAMagnet,
+    // no `xt=urn:btih:` parameter
+    MissingInfoHash,
+    // the btih was neither 40 hex digits nor 32 base32 ones
+    BadInfoHash(String),
+}
+
+// A magnet link: the info hash and wherever to start looking for peers, but no metainfo. That has
+// to come from the peers themselves, see `Session::add_magnet`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Self, MagnetError> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or(MagnetError::NotAMagnet)?;
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = vec![];
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            let value = percent_decode_str(&value.replace('+', " "))
+                .decode_utf8_lossy()
+                .to_string();
+            match key {
+                "xt" => {
+                    if let Some(btih) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(
+                            decode_info_hash(btih)
+                                .ok_or_else(|| MagnetError::BadInfoHash(btih.to_string()))?,
+                        );
+                    }
+                }
+                "dn" => display_name = Some(value),
+                "tr" => trackers.push(value),
+                _ => {}
+            }
+        }
+        Ok(Magnet {
+            info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+            display_name,
+            trackers,
+        })
+    }
+}
+
+fn decode_info_hash(btih: &str) -> Option<[u8; 20]> {
+    match btih.len() {
+        40 => hex::decode(btih).ok()?.try_into().ok(),
+        32 => base32(btih)?.try_into().ok(),
+        _ => None,
+    }
+}
+
+// RFC 4648 base32 without padding, which older magnet links write info hashes in
+fn base32(encoded: &str) -> Option<Vec<u8>> {
+    let mut bits = 0u64;
+    let mut bit_count = 0;
+    let mut decoded = vec![];
+    for c in encoded.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        bits = bits << 5 | value as u64;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_magnet_links() {
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:0123456789ABCDEF0123456789abcdef01234567&dn=Big+Buck%20Bunny&tr=http%3A%2F%2Ftracker.example%2Fannounce&tr=udp%3A%2F%2Fother.example%3A80").unwrap();
+        assert_eq!(
+            magnet,
+            Magnet {
+                info_hash: [
+                    0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89,
+                    0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67
+                ],
+                display_name: Some("Big Buck Bunny".to_string()),
+                trackers: vec![
+                    "http://tracker.example/announce".to_string(),
+                    "udp://other.example:80".to_string()
+                ],
+            }
+        );
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:AERUKZ4JVPG66AJDIVTYTK6N54ASGRLH").unwrap();
+        assert_eq!(base32.info_hash, magnet.info_hash);
+
+        assert_eq!(
+            Magnet::parse("http://example.com"),
+            Err(MagnetError::NotAMagnet)
+        );
+        assert_eq!(
+            Magnet::parse("magnet:?dn=nothing"),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert_eq!(
+            Magnet::parse("magnet:?xt=urn:btih:abc"),
+            Err(MagnetError::BadInfoHash("abc".to_string()))
+        );
+    }
+}
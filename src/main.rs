@@ -1,370 +1,380 @@
-use std::fs::File;
-use std::net::{SocketAddr, TcpStream};
-use std::sync::{Arc, RwLock};
-use std::thread::{sleep, spawn, JoinHandle};
+#[cfg(feature = "serde_json")]
+use bit_torrent::bencode::{bdecode_lenient, LazyBencodable};
+use bit_torrent::dht::BOOTSTRAP_NODES;
+use bit_torrent::dns::DohResolver;
+use bit_torrent::feed::{FeedRule, FeedWatcher};
+use bit_torrent::logger::LogFormat;
+use bit_torrent::lsd::LSD_GROUP;
+use bit_torrent::meta_info_file::MetaInfoFile;
+use bit_torrent::replay::{replay, ReplayPeer};
+use bit_torrent::self_test::SelfTest;
+use bit_torrent::session::{CompletionAction, Session};
+use bit_torrent::sim::{PeerBehavior, ScriptedPeer, SimulatedContent, Simulation};
+use bit_torrent::test_seeder::{SeederProfile, TestSeeder};
+use bit_torrent::test_tracker::{TestHttpTracker, TestUdpTracker};
+use bit_torrent::timeline::TimelineFormat;
+use bit_torrent::torrent::{PiecedContent, Torrent};
+use bit_torrent::torrent_builder::TorrentBuilder;
+use parking_lot::RwLock;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::sleep;
 use std::time::Duration;
 
-use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
-
-mod bencode;
-use bencode::*;
-
-mod meta_info_file;
-use meta_info_file::*;
-
-mod tracker;
-use tracker::{Event, Peer, Tracker, TrackerPeer, TrackerRequestParameters};
-
-mod messages;
-use messages::*;
-
-mod util;
-use util::random_string;
-
-mod connection;
-use connection::*;
-
-mod torrent;
-use torrent::*;
-
-mod bitfield;
-use bitfield::BitField;
-
-mod logger;
-use logger::Logger;
-
 const TORRENT_FILE: &str = "charlie-chaplin-.-mabels-strange-predicament-1914-restored-short-silent-film-noir-comedy_archive.local.torrent";
-const CONNECTION_TIMEOUT: Duration = Duration::from_millis(250);
-const READ_TIMEOUT: Duration = Duration::from_millis(1000);
 const PROGRESS_WAIT_TIME: Duration = Duration::from_secs(3);
-const THREADS_PER_PEER: u8 = 1;
-const MAX_IN_PROGRESS_REQUESTS_PER_CONNECTION: usize = 1;
-
-type PeerThreads = Vec<JoinHandle<()>>;
-
-#[derive(PartialEq, Debug)]
-enum MessageResult {
-    Ok,
-    BadPeerHave,
-    BadPeerPiece,
-    BadPeerRequest,
-}
-
-struct TorrentProcessor {
-    logger: Arc<RwLock<Logger>>,
-    meta_info: MetaInfoFile,
-    local_peer_id: String,
-    torrent: Arc<RwLock<Torrent>>,
-}
+const FEED_POLL_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const SIMULATION_TICK: Duration = Duration::from_millis(10);
+const HEALTH_SAMPLE_SIZE: usize = 20;
+const HEALTH_PROBE_WINDOW: Duration = Duration::from_secs(5);
+const SETTINGS_POLL_INTERVAL: Duration = Duration::from_secs(2);
 
-impl TorrentProcessor {
-    fn new(torrent_file_path: &str, log_file_path: &str) -> Self {
-        let meta_info = MetaInfoFile::from(File::open(torrent_file_path).unwrap());
-        println!("meta info {:?}", meta_info);
-        let local_peer_id = random_string();
-        let logger = Arc::new(RwLock::new(Logger::new(log_file_path)));
-        let torrent = Torrent::new(&meta_info);
-        println!(
-            "torrent num pieces {:?} num blocks {:?} len of pieces vec {:?}",
-            torrent.total_pieces,
-            torrent.total_blocks,
-            torrent.pieces.len()
-        );
-        let torrent = Arc::new(RwLock::new(torrent));
-
-        TorrentProcessor {
-            logger,
-            meta_info,
-            local_peer_id,
-            torrent,
-        }
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    // LOG_FORMAT=jsonl switches the peer message log to machine readable JSON lines
+    let log_format = match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("jsonl") => LogFormat::JsonLines,
+        _ => LogFormat::Human,
+    };
+    let mut session = Session::new("log.txt", log_format);
+    // STRICT_PROTOCOL=1 disconnects from any peer that bends the protocol instead of tolerating it
+    session.set_strict_protocol(std::env::var("STRICT_PROTOCOL").as_deref() == Ok("1"));
+    // SEED=1 keeps serving peers after the download completes instead of exiting
+    session.set_seed_after_completion(std::env::var("SEED").as_deref() == Ok("1"));
+    // SETTINGS_FILE=<path> applies a settings file (see `Settings::apply`) on top of the above and
+    // re-applies it whenever the file changes. There is no SIGHUP handler, so the file is polled;
+    // embedders can call `Session::reload_settings` directly instead.
+    if let Ok(path) = std::env::var("SETTINGS_FILE") {
+        let path = std::path::PathBuf::from(path);
+        session
+            .reload_settings(&path)
+            .expect("could not load settings file");
+        session.settings().watch(path, SETTINGS_POLL_INTERVAL);
     }
-
-    fn start(&self) {
-        let info_encoded = percent_encode(&self.meta_info.info_hash, NON_ALPHANUMERIC).to_string();
-        let possible_peers = Tracker::new()
-            .track(
-                &format!(
-                    "{}?info_hash={}&peer_id={}",
-                    &self.meta_info.announce, info_encoded, self.local_peer_id
-                ),
-                TrackerRequestParameters {
-                    port: 8999,
-                    uploaded: 0,
-                    downloaded: 0,
-                    left: 0,
-                    event: Event::Started,
-                },
-            )
-            .map(|resp: Vec<TrackerPeer>| {
-                resp.into_iter()
-                    .map(Peer::from)
-                    // Don't connect to the client we are "pretending to be" at 127.0.0.1:8999
-                    .filter(|x| match x.socket_addr {
-                        std::net::SocketAddr::V4(sa) => {
-                            !(*sa.ip() == std::net::Ipv4Addr::new(127, 0, 0, 1)
-                                && sa.port() == 8999u16)
-                        }
-                        std::net::SocketAddr::V6(_) => true,
-                    })
-                    .map(|p| {
-                        println!("peer {:?}, peer_id {:?}", p, std::str::from_utf8(&p.id));
-                        p
-                    })
-                    .collect()
-            });
-
-        println!(
-            "possible peers count {:?}",
-            possible_peers
-                .as_ref()
-                .map(|pp: &Vec<Peer>| pp.len())
-                .unwrap_or(0)
-        );
-
-        match possible_peers.map(|peers: Vec<Peer>| {
-            let join_handles: Vec<PeerThreads> = peers
-                .into_iter()
-                .map(|p| self.generate_peer_threads(Arc::new(p)))
-                .collect();
-            join_handles
-        }) {
-            Ok(jhs) => {
-                println!(
-                    "total connections/threads working {:?}",
-                    jhs.iter().flatten().count()
-                );
-                let t = Arc::clone(&self.torrent);
-                spawn(move || loop {
-                    sleep(PROGRESS_WAIT_TIME);
-                    let t = t.read().unwrap();
-                    println!("percent complete: {}", t.percent_complete);
-                    println!("repeated completed blocks: {:?}", t.repeated_blocks);
-                    println!("in progress blocks: {:?}", t.in_progress_blocks.len());
-                });
-
-                for jh in jhs {
-                    for cjh in jh {
-                        cjh.join().unwrap();
-                    }
-                }
-
-                let files = match &self.meta_info.info {
-                    Info::SingleFile {
-                        piece_length: _,
-                        pieces: _,
-                        name: _,
-                        file,
-                    } => vec![file],
-                    Info::MultiFile {
-                        piece_length: _,
-                        pieces: _,
-                        directory_name: _,
-                        files,
-                    } => files.iter().collect(),
-                };
-                let write_res = self.torrent.read().unwrap().to_file(files);
-                if write_res.iter().any(|r| r.is_err()) {
-                    println!("write err when writing blocks to file {:?}", write_res)
-                }
-            }
-            Err(e) => panic!("{:?}", e),
-        }
+    // DNS_OVER_HTTPS=<url> resolves tracker hosts with that DNS-over-HTTPS server
+    if let Ok(url) = std::env::var("DNS_OVER_HTTPS") {
+        session.set_resolver(Arc::new(DohResolver::new(&url)));
     }
-
-    fn generate_peer_threads(&self, peer: Arc<Peer>) -> PeerThreads {
-        (0..THREADS_PER_PEER)
-            .filter_map(|_| {
-                let torrent = Arc::clone(&self.torrent);
-                let peer = Arc::clone(&peer);
-                let peer_addr = peer.socket_addr.to_string();
-                let connection = self.connect(peer);
-                let logger = Arc::clone(&self.logger);
-                let work = move |mut connection: PeerConnection| {
-                    let mut done = false;
-                        while !done {
-                            let message = connection.read_message();
-                            match message {
-                                Ok(message) => {
-                                    let _ = logger.write().unwrap().log(&format!("From: {}, To (me): {}, Message: {}", connection.peer_addr, connection.local_addr, message));
-                                    let result = process_message(Arc::clone(&torrent), message, &mut connection);
-                                    if result != MessageResult::Ok {
-                                        println!("got a err for message result which means some odd scenario occurred {:?}", result);
-                                    }
-                                }
-                                Err(e) => {
-                                    match e {
-                                        MessageParseError::ConnectionRefused => {
-                                            println!("Exiting {:?}", e);
-                                            done = true;
-                                            continue;
-                                        },
-                                        MessageParseError::ConnectionReset => {
-                                            println!("Exiting {:?}", e);
-                                            done = true;
-                                            continue;
-                                        },
-                                        MessageParseError::ConnectionAborted => {
-                                            println!("Exiting {:?}", e);
-                                            done = true;
-                                            continue;
-                                        },
-                                        MessageParseError::WouldBlock => {
-                                            // println!("would block");
-                                        },
-                                        MessageParseError::TimedOut => {
-                                        },
-                                        me => {
-                                            println!("Exiting {:?}", me);
-                                            done = true;
-                                            continue;
-                                        },
-                                    }
-                                }
-                            }
-                            done = torrent.read().unwrap().are_we_done_yet();
-                            if done {
-                                println!("done because torrent said so");
-                            }
-                        }
-                        println!("a connection has finally exited on its own... still being awaited by main potentially....");
-                };
-                match connection {
-                    Ok(connection) => {
-                        Some(spawn(move || work(connection)))
-                    }
-                    Err(e) => {
-                        println!("connection err with client {:?}: {:?}", peer_addr, e);
-                        None
-                    }
-                }
-            })
-            .collect::<Vec<JoinHandle<()>>>()
+    // DHT_PORT=<port> runs a DHT node on that port, joined through the well known bootstrap nodes
+    if let Ok(port) = std::env::var("DHT_PORT") {
+        let port: u16 = port.parse().expect("DHT_PORT is not a port");
+        let bootstrap: Vec<(String, u16)> = BOOTSTRAP_NODES
+            .iter()
+            .map(|(host, port)| (host.to_string(), *port))
+            .collect();
+        session
+            .start_dht(SocketAddr::from(([0, 0, 0, 0], port)), &bootstrap)
+            .expect("could not start the DHT node");
     }
-
-    fn connect(&self, peer: Arc<Peer>) -> Result<PeerConnection, SendError> {
-        let logger = self.logger.clone();
-        let stream =
-            TcpStream::connect_timeout(&peer.socket_addr, CONNECTION_TIMEOUT).map(|stream| {
-                let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
-                stream
-            });
-        stream.map_err(SendError::Connect).and_then(|s| {
-            PeerConnection::new(
-                Stream::Tcp(s),
-                &self.meta_info.info_hash,
-                self.local_peer_id.as_bytes(),
-                &peer.id,
-                Box::new(
-                    move |message: (crate::Message, SocketAddr, SocketAddr),
-                          original_bytes: &[u8]| {
-                        let _ = logger.write().unwrap().log(&format!(
-                            "From (me): {}, To: {}, Message: {}  ----  {:?}",
-                            message.2, message.1, message.0, original_bytes
-                        ));
-                    },
-                ),
-            )
-        })
+    // LSD=1 announces torrents to the local network and connects to the peers found there, which is
+    // how clients on one machine or LAN find each other without a tracker
+    if std::env::var("LSD").as_deref() == Ok("1") {
+        session
+            .start_lsd(LSD_GROUP)
+            .expect("could not start local service discovery");
+    }
+    // METADATA_CACHE_DIR=<dir> keeps a copy of every torrent's metainfo, keyed by info hash
+    if let Ok(dir) = std::env::var("METADATA_CACHE_DIR") {
+        session
+            .set_metadata_cache_dir(std::path::Path::new(&dir))
+            .expect("could not create metadata cache dir");
     }
-}
 
-fn request_blocks(torrent: Arc<RwLock<Torrent>>, connection: &mut PeerConnection) {
-    if !connection.is_choked {
-        let in_progress = connection.in_progress_requests;
-        let to_request = MAX_IN_PROGRESS_REQUESTS_PER_CONNECTION - in_progress;
-        connection.in_progress_requests += to_request;
-        let mut t = torrent.write().unwrap();
-        let blocks: Vec<PieceIndexOffsetLength> = (0..to_request)
-            .filter_map(|_| {
-                let bf = connection.bitfield.as_ref().unwrap();
-                t.get_next_block(bf)
+    match args.get(1).map(String::as_str) {
+        // bit_torrent feed <url> [pattern] [min size] [max size] keeps polling the feed and downloads every matching torrent
+        Some("feed") => {
+            let url = args
+                .get(2)
+                .expect("usage: bit_torrent feed <url> [pattern] [min size] [max size]");
+            let pattern = args.get(3).map(String::as_str).unwrap_or(".*");
+            let mut rule = FeedRule::new(pattern).expect("feed pattern is not a valid regex");
+            if let Some(min) = args.get(4).and_then(|s| s.parse().ok()) {
+                rule = rule.min_size(min);
+            }
+            if let Some(max) = args.get(5).and_then(|s| s.parse().ok()) {
+                rule = rule.max_size(max);
+            }
+            FeedWatcher::new(url, vec![rule]).run(&mut session, FEED_POLL_INTERVAL);
+        }
+        // bit_torrent simulate [virtual seconds] runs the engine against scripted in-memory peers
+        Some("simulate") => {
+            let limit = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(600);
+            let content = SimulatedContent {
+                number_of_pieces: 64,
+                piece_length: 262144,
+                total_length: 64 * 262144 - 12345,
+            };
+            let peers: Vec<ScriptedPeer> = [
+                PeerBehavior::Seed,
+                PeerBehavior::FastLeech,
+                PeerBehavior::Choker {
+                    period: Duration::from_secs(30),
+                },
+                PeerBehavior::Dropper {
+                    after: Duration::from_secs(120),
+                },
+            ]
+            .iter()
+            .map(|behavior| ScriptedPeer {
+                behavior: *behavior,
+                upload_rate: 256 * 1024,
+                latency: Duration::from_millis(80),
             })
             .collect();
-        for b in blocks {
-            let message = Message::Request {
-                index: b.0,
-                begin: b.1,
-                length: b.2,
+            let report =
+                Simulation::new(content, &peers, SIMULATION_TICK).run(Duration::from_secs(limit));
+            println!(
+                "simulation completed: {}, virtual time {:?}, wall time {:?}, downloaded {} bytes",
+                report.completed,
+                report.virtual_elapsed,
+                report.wall_elapsed,
+                report.downloaded_bytes
+            );
+            for (behavior, uploaded) in report.uploaded_by_peer {
+                println!("  {:?} sent us {} bytes", behavior, uploaded);
+            }
+        }
+        // bit_torrent test-seed <torrent file> <data file> [slow|choke|corrupt|drop] serves the data to local test swarms
+        Some("test-seed") => {
+            let usage =
+                "usage: bit_torrent test-seed <torrent file> <data file> [slow|choke|corrupt|drop]";
+            let meta_info = open_torrent(args.get(2).expect(usage));
+            let data = std::fs::read(args.get(3).expect(usage)).unwrap();
+            let profile = match args.get(4).map(String::as_str) {
+                Some("slow") => SeederProfile::slow(Duration::from_millis(200)),
+                Some("choke") => SeederProfile::choker(Duration::from_secs(10)),
+                Some("corrupt") => SeederProfile::corrupter(10),
+                Some("drop") => SeederProfile::dropper(0.01),
+                _ => SeederProfile::default(),
             };
-            connection.write_message(message).unwrap();
+            let seeder =
+                TestSeeder::start(data, meta_info.piece_length(), meta_info.info_hash, profile)
+                    .unwrap();
+            println!(
+                "seeding {} on {} as peer {:?}",
+                args[3],
+                seeder.addr(),
+                std::str::from_utf8(seeder.peer_id())
+            );
+            loop {
+                sleep(PROGRESS_WAIT_TIME);
+                println!("blocks served: {}", seeder.blocks_served());
+            }
         }
-    }
-}
-
-fn process_message(
-    torrent: Arc<RwLock<crate::Torrent>>,
-    message: Message,
-    connection: &mut PeerConnection,
-) -> MessageResult {
-    match message {
-        Message::KeepAlive => {
-            connection.write_message(Message::KeepAlive).unwrap();
-            MessageResult::Ok
+        // bit_torrent test-tracker runs a UDP and an HTTP tracker on localhost for test swarms until killed
+        Some("test-tracker") => {
+            let tracker = TestUdpTracker::start().unwrap();
+            let http_tracker = TestHttpTracker::start().unwrap();
+            println!(
+                "tracking on {} and {}",
+                tracker.announce_url(),
+                http_tracker.announce_url()
+            );
+            loop {
+                sleep(PROGRESS_WAIT_TIME);
+            }
         }
-        Message::Choke => {
-            connection.is_choked = true;
-            MessageResult::Ok
+        // bit_torrent replay <capture.jsonl> <torrent file> [peer addr] feeds one peer's side of a LOG_FORMAT=jsonl log back through the engine
+        Some("replay") => {
+            let usage = "usage: bit_torrent replay <capture.jsonl> <torrent file> [peer addr]";
+            let capture = std::fs::read_to_string(args.get(2).expect(usage)).unwrap();
+            let meta_info = open_torrent(args.get(3).expect(usage));
+            let peer_addr = args.get(4).map(|addr| {
+                addr.parse()
+                    .expect("peer addr should look like 1.2.3.4:6881")
+            });
+            let peer = ReplayPeer::from_capture(&capture, peer_addr, b"-XX0000-replayedpeer")
+                .expect("could not read capture");
+            println!("replaying messages from {}", peer.peer_addr);
+            let torrent = Arc::new(RwLock::new(Torrent::new(&meta_info)));
+            let report = replay(peer, &meta_info.info_hash, Arc::clone(&torrent))
+                .expect("replay handshake failed");
+            println!(
+                "replayed {} messages, stopped with {:?}, percent complete {}",
+                report.messages_replayed,
+                report.ended_with,
+                torrent.read().percent_complete
+            );
+            for rejected in report.rejected_messages {
+                println!("  rejected {}", rejected);
+            }
+        }
+        // bit_torrent resume <info hash> restarts a torrent from METADATA_CACHE_DIR without its .torrent file
+        Some("resume") => {
+            let usage = "usage: bit_torrent resume <hex info hash>";
+            let info_hash: [u8; 20] = hex::decode(args.get(2).expect(usage))
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .expect(usage);
+            match session.add_cached(&info_hash) {
+                Ok(_) => session.wait(),
+                Err(e) => println!("could not resume {} {:?}", hex::encode(info_hash), e),
+            }
+        }
+        // bit_torrent create <file or directory> <out.torrent> [announce url] makes a torrent to share
+        Some("create") => {
+            let usage =
+                "usage: bit_torrent create <file or directory> <out.torrent> [announce url]";
+            let mut builder = TorrentBuilder::new(args.get(2).expect(usage));
+            if let Some(url) = args.get(4) {
+                builder = builder.announce(url);
+            }
+            match builder.build() {
+                Ok(bytes) => std::fs::write(args.get(3).expect(usage), bytes).unwrap(),
+                Err(e) => println!("could not create torrent {:?}", e),
+            }
         }
-        Message::UnChoke => {
-            connection.is_choked = false;
-            request_blocks(torrent, connection);
-            MessageResult::Ok
+        // bit_torrent magnet <uri> fetches the metainfo from the swarm before downloading as usual
+        Some("magnet") => {
+            let usage = "usage: bit_torrent magnet <magnet uri>";
+            match session.add_magnet(args.get(2).expect(usage)) {
+                Ok(_) => session.wait(),
+                Err(e) => println!("could not start magnet link {:?}", e),
+            }
         }
-        Message::Interested => MessageResult::Ok,
-        Message::NotInterested => MessageResult::Ok,
-        Message::Have { index } => {
-            if index >= torrent.read().unwrap().total_pieces {
-                MessageResult::BadPeerHave
-            } else {
-                if let Some(bf) = connection.bitfield.as_mut() {
-                    bf.set(index as usize)
+        // bit_torrent self-test [magnet] seeds generated data in process and downloads it back, checking every byte
+        Some("self-test") => {
+            let via_magnet = args.get(2).map(String::as_str) == Some("magnet");
+            match SelfTest::new().via_magnet(via_magnet).run(&mut session) {
+                Ok(report) => println!(
+                    "self-test passed: {} bytes of {} in {:?}, {} blocks served",
+                    report.bytes,
+                    hex::encode(report.info_hash),
+                    report.elapsed,
+                    report.blocks_served
+                ),
+                Err(e) => {
+                    println!("self-test failed {:?}", e);
+                    std::process::exit(1);
                 }
-                connection.is_local_interested = true;
-                connection.write_message(Message::Interested).unwrap();
-                MessageResult::Ok
             }
         }
-        Message::BitField(bf) => {
-            connection.is_local_interested = true;
-            connection.bitfield = Some(bf.into());
-            connection.write_message(Message::Interested).unwrap();
-            MessageResult::Ok
+        // bit_torrent health <torrent file> [sample size] reports on the swarm without downloading anything
+        Some("health") => {
+            let usage = "usage: bit_torrent health <torrent file> [sample size]";
+            let meta_info = open_torrent(args.get(2).expect(usage));
+            let sample_size = args
+                .get(3)
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(HEALTH_SAMPLE_SIZE);
+            match session.health(meta_info, sample_size, HEALTH_PROBE_WINDOW) {
+                Ok(health) => {
+                    println!(
+                        "peers announced {}, sampled {}, responded {}",
+                        health.peers_announced, health.peers_sampled, health.peers_responded
+                    );
+                    println!("seeds {}, leechers {}", health.seeds, health.leechers);
+                    println!(
+                        "distributed copies {:.3}, completable {}",
+                        health.distributed_copies(),
+                        health.completable()
+                    );
+                    println!("piece availability {:?}", health.availability);
+                    for (client, count) in &health.clients {
+                        println!("  {}: {}", client, count);
+                    }
+                }
+                Err(e) => println!("could not announce {:?}", e),
+            }
         }
-        Message::Request {
-            index,
-            begin: _begin,
-            length: _length,
-        } => {
-            if index >= torrent.read().unwrap().total_pieces {
-                MessageResult::BadPeerRequest
-            } else {
-                MessageResult::Ok
+        // bit_torrent ls <torrent file> lists the files in a torrent
+        Some("ls") => {
+            let usage = "usage: bit_torrent ls <torrent file>";
+            let meta_info = open_torrent(args.get(2).expect(usage));
+            for entry in meta_info.contents() {
+                println!(
+                    "{:>5} {:>14} {}",
+                    entry.index,
+                    entry.length,
+                    entry.path.display()
+                );
             }
+            let summary = meta_info.summary();
+            println!(
+                "{} files, {} bytes in {} pieces of {} bytes",
+                summary.files, summary.length, summary.pieces, summary.piece_length
+            );
         }
-        Message::Piece {
-            index,
-            offset,
-            data,
-        } => {
-            if !data.is_empty() {
-                torrent.write().unwrap().fill_block((index, offset, &data));
-                connection.in_progress_requests -= 1;
-                request_blocks(torrent, connection);
-                MessageResult::Ok
-            } else {
-                MessageResult::BadPeerPiece
+        // bit_torrent inspect <bencoded file> prints a .torrent or saved tracker response as JSON
+        #[cfg(feature = "serde_json")]
+        Some("inspect") => {
+            let usage = "usage: bit_torrent inspect <bencoded file> [path, e.g. info.files[0]]";
+            let bytes = std::fs::read(args.get(2).expect(usage)).unwrap();
+            let path = args.get(3).map(String::as_str).unwrap_or("");
+            // only the dictionaries the path leads through are decoded, up to its first index
+            let (keys, indexed) = path.split_at(path.find('[').unwrap_or(path.len()));
+            let value = LazyBencodable::new(&bytes).and_then(|root| {
+                let lazy = match keys {
+                    "" => Some(&root),
+                    keys => root.get_path(keys),
+                };
+                lazy.map(|lazy| lazy.decode().map(|b| b.query(indexed).cloned()))
+                    .transpose()
+                    .map(Option::flatten)
+            });
+            match value {
+                Ok(Some(value)) => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&value.to_json()).unwrap()
+                ),
+                Ok(None) => println!("nothing at {}", path),
+                Err(e) => {
+                    println!("not valid bencode: {}", e);
+                    // still show whatever could be made out, and everything wrong with it
+                    let recovered = bdecode_lenient(&bytes);
+                    if let Some(value) = recovered.value.as_ref().and_then(|v| v.query(path)) {
+                        println!(
+                            "recovered {}",
+                            serde_json::to_string_pretty(&value.to_json()).unwrap()
+                        );
+                    }
+                    for error in recovered.errors {
+                        println!("  {}", error);
+                    }
+                }
             }
         }
-    }
-}
+        _ => {
+            // this program is just trying to connect to as many seeders as possible and go nuts downloading
+            let meta_info = open_torrent(TORRENT_FILE);
+            let info_hash = session.add(meta_info);
+            // FINISHED_DIR=<dir> moves the files there once the download is complete
+            if let Ok(dir) = std::env::var("FINISHED_DIR") {
+                let actions = vec![CompletionAction::MoveTo(dir.into())];
+                session.set_completion_actions(&info_hash, actions).unwrap();
+            }
+            session.wait();
 
-fn main() {
-    // this program is just trying to connect to as many seeders as possible and go nuts downloading
-    let tp = TorrentProcessor::new(TORRENT_FILE, "log.txt");
-    tp.start();
+            // TIMELINE_FILE=<path>.csv|.json exports the per second transfer samples once the download is done
+            if let Ok(path) = std::env::var("TIMELINE_FILE") {
+                let path = std::path::Path::new(&path);
+                if let Err(e) =
+                    session.export_timeline(&info_hash, path, TimelineFormat::from_path(path))
+                {
+                    println!("failed to export timeline {:?}", e);
+                }
+            }
+
+            // TORRENT_EXPORT_FILE=<path> writes the torrent back out with every tracker the session learned about
+            if let Ok(path) = std::env::var("TORRENT_EXPORT_FILE") {
+                if let Err(e) = session.export_torrent(&info_hash, std::path::Path::new(&path)) {
+                    println!("failed to export torrent {:?}", e);
+                }
+            }
+        }
+    }
 
     // Now, we also need to stick around and stay connected to the tracker long term so we can connect multiple clients for our own little localhost swarm for no reason except to learn
 
     // For now, though, can I write a client more easily in JS so I can just test that my client can successfully download?
 }
+
+fn open_torrent(path: &str) -> MetaInfoFile {
+    match MetaInfoFile::from_path(std::path::Path::new(path)) {
+        Ok(meta_info) => meta_info,
+        Err(e) => {
+            println!("could not load torrent {} {:?}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -4,12 +4,17 @@ use crate::util::{attach_bytes, read_be_u32};
 
 const P_STR_LEN: u8 = 19;
 const P_STR: &str = "BitTorrent protocol";
-const RESERVED_BYTES: [u8; 8] = [0; 8];
+// bit 20 from the right advertises the extension protocol (BEP 10)
+const RESERVED_BYTES: [u8; 8] = [0, 0, 0, 0, 0, 0x10, 0, 0];
+// bit 2 from the right advertises the fast extension (BEP 6)
+const FAST_EXTENSION_BYTE: usize = 7;
+const FAST_EXTENSION_BIT: u8 = 0x04;
 
 #[derive(Debug)]
 pub struct Handshake {
     pub info_hash: Vec<u8>,
     pub peer_id: Vec<u8>,
+    pub fast_extension: bool,
 }
 
 #[derive(Debug)]
@@ -41,6 +46,28 @@ pub enum Message {
         offset: u32,
         data: Vec<u8>,
     },
+    // BEP 6; the peer hints it has this piece cached and would rather we asked for it
+    SuggestPiece {
+        index: u32,
+    },
+    // BEP 6; stand in for a bitfield with every bit set or none
+    HaveAll,
+    HaveNone,
+    // BEP 6; the peer won't be sending the block we asked for
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    // BEP 6; the peer would serve this piece even while choking us. Only taken as a hint.
+    AllowedFast {
+        index: u32,
+    },
+    // BEP 10; `id` 0 is the extended handshake, anything else is whatever the receiver assigned
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
 impl std::fmt::Display for Message {
@@ -85,6 +112,32 @@ impl std::fmt::Display for Message {
             } => {
                 write!(f, "Piece {{ index: {}, offset: {} }}", index, offset)
             }
+            Message::SuggestPiece { index } => {
+                write!(f, "SuggestPiece {{ {} }}", index)
+            }
+            Message::HaveAll => {
+                write!(f, "HaveAll")
+            }
+            Message::HaveNone => {
+                write!(f, "HaveNone")
+            }
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                write!(
+                    f,
+                    "RejectRequest {{ index: {}, begin: {}, length: {} }}",
+                    index, begin, length
+                )
+            }
+            Message::AllowedFast { index } => {
+                write!(f, "AllowedFast {{ {} }}", index)
+            }
+            Message::Extended { id, payload } => {
+                write!(f, "Extended {{ id: {}, length: {} }}", id, payload.len())
+            }
         }
     }
 }
@@ -97,8 +150,13 @@ pub enum MessageParseError {
     Id(u8),
     IdMissing,
     Have,
+    Request,
     Unimplemented(&'static str),
     Piece,
+    SuggestPiece,
+    RejectRequest,
+    AllowedFast,
+    Extended,
     ConnectionRefused,
     ConnectionReset,
     ConnectionAborted,
@@ -107,9 +165,105 @@ pub enum MessageParseError {
     WriteZero,
     Interrupted,
     UnexpectedEof,
+    ProtocolViolation(ProtocolViolation),
+}
+
+// Things a lenient peer connection shrugs off but a strict one disconnects over
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolViolation {
+    HandshakeMismatch {
+        info_hash_matches: bool,
+        peer_id_matches: bool,
+    },
+    BitFieldNotFirst {
+        messages_before: u64,
+    },
+    DuplicateBitField,
+    // a fast extension message from a peer that didn't advertise the extension in its handshake
+    FastExtensionNotNegotiated {
+        kind: &'static str,
+    },
+    PieceWhileChoked {
+        index: u32,
+        offset: u32,
+    },
+    UnrequestedPiece {
+        index: u32,
+        offset: u32,
+    },
+    UnexpectedPieceLength {
+        index: u32,
+        offset: u32,
+        requested: u32,
+        received: u32,
+    },
+}
+
+impl std::fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolViolation::HandshakeMismatch {
+                info_hash_matches,
+                peer_id_matches,
+            } => write!(
+                f,
+                "return handshake did not match (info hash matches: {}, peer id matches: {})",
+                info_hash_matches, peer_id_matches
+            ),
+            ProtocolViolation::BitFieldNotFirst { messages_before } => write!(
+                f,
+                "bitfield arrived after {} other messages instead of straight after the handshake",
+                messages_before
+            ),
+            ProtocolViolation::DuplicateBitField => write!(f, "bitfield was sent more than once"),
+            ProtocolViolation::FastExtensionNotNegotiated { kind } => write!(
+                f,
+                "{} is a fast extension message but the handshake didn't advertise the extension",
+                kind
+            ),
+            ProtocolViolation::PieceWhileChoked { index, offset } => write!(
+                f,
+                "piece {} offset {} arrived while the peer was choking us",
+                index, offset
+            ),
+            ProtocolViolation::UnrequestedPiece { index, offset } => {
+                write!(f, "piece {} offset {} was never requested", index, offset)
+            }
+            ProtocolViolation::UnexpectedPieceLength {
+                index,
+                offset,
+                requested,
+                received,
+            } => write!(
+                f,
+                "piece {} offset {} carried {} bytes but {} were requested",
+                index, offset, received, requested
+            ),
+        }
+    }
 }
 
 impl Message {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::KeepAlive => "KeepAlive",
+            Message::Choke => "Choke",
+            Message::UnChoke => "UnChoke",
+            Message::Interested => "Interested",
+            Message::NotInterested => "NotInterested",
+            Message::Have { .. } => "Have",
+            Message::BitField(_) => "BitField",
+            Message::Request { .. } => "Request",
+            Message::Piece { .. } => "Piece",
+            Message::SuggestPiece { .. } => "SuggestPiece",
+            Message::HaveAll => "HaveAll",
+            Message::HaveNone => "HaveNone",
+            Message::RejectRequest { .. } => "RejectRequest",
+            Message::AllowedFast { .. } => "AllowedFast",
+            Message::Extended { .. } => "Extended",
+        }
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         match self {
             Message::KeepAlive => attach_bytes(&[0u32.to_be_bytes().iter()]),
@@ -153,12 +307,45 @@ impl Message {
                 offset,
                 data,
             } => attach_bytes(&[
-                (data.len() + 3).to_be_bytes().iter(),
+                ((data.len() + 9) as u32).to_be_bytes().iter(),
                 7u8.to_be_bytes().iter(),
                 index.to_be_bytes().iter(),
                 offset.to_be_bytes().iter(),
                 data.iter(),
             ]),
+            Message::SuggestPiece { index } => attach_bytes(&[
+                5u32.to_be_bytes().iter(),
+                13u8.to_be_bytes().iter(),
+                index.to_be_bytes().iter(),
+            ]),
+            Message::HaveAll => {
+                attach_bytes(&[1u32.to_be_bytes().iter(), 14u8.to_be_bytes().iter()])
+            }
+            Message::HaveNone => {
+                attach_bytes(&[1u32.to_be_bytes().iter(), 15u8.to_be_bytes().iter()])
+            }
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => attach_bytes(&[
+                13u32.to_be_bytes().iter(),
+                16u8.to_be_bytes().iter(),
+                index.to_be_bytes().iter(),
+                begin.to_be_bytes().iter(),
+                length.to_be_bytes().iter(),
+            ]),
+            Message::AllowedFast { index } => attach_bytes(&[
+                5u32.to_be_bytes().iter(),
+                17u8.to_be_bytes().iter(),
+                index.to_be_bytes().iter(),
+            ]),
+            Message::Extended { id, payload } => attach_bytes(&[
+                ((payload.len() + 2) as u32).to_be_bytes().iter(),
+                20u8.to_be_bytes().iter(),
+                id.to_be_bytes().iter(),
+                payload.iter(),
+            ]),
         }
     }
 
@@ -193,7 +380,21 @@ impl Message {
                     Ok(Message::BitField(bytes))
                 }
                 // request
-                6 => Err(MessageParseError::Unimplemented("6 - request")),
+                6 => {
+                    let b: Vec<u8> = bytes.by_ref().take(12).collect();
+                    let mut b = b.as_slice();
+                    let mut next = || {
+                        if b.len() < 4 {
+                            return Err(MessageParseError::Request);
+                        }
+                        read_be_u32(&mut b).map_err(|_| MessageParseError::Request)
+                    };
+                    Ok(Message::Request {
+                        index: next()?,
+                        begin: next()?,
+                        length: next()?,
+                    })
+                }
                 // piece
                 7 => {
                     let b: Vec<u8> = bytes.by_ref().take(4).collect();
@@ -213,6 +414,53 @@ impl Message {
                 }
                 // cancel
                 8 => Err(MessageParseError::Unimplemented("8 - cancel")),
+                // suggest piece
+                13 => {
+                    let b: Vec<u8> = bytes.by_ref().take(4).collect();
+                    let index = read_be_u32(&mut b.as_slice())
+                        .map_err(|_| MessageParseError::SuggestPiece)?;
+
+                    Ok(Message::SuggestPiece { index })
+                }
+                // have all
+                14 => Ok(Message::HaveAll),
+                // have none
+                15 => Ok(Message::HaveNone),
+                // reject request
+                16 => {
+                    let b: Vec<u8> = bytes.by_ref().take(12).collect();
+                    let mut b = b.as_slice();
+                    let mut next = || {
+                        if b.len() < 4 {
+                            return Err(MessageParseError::RejectRequest);
+                        }
+                        read_be_u32(&mut b).map_err(|_| MessageParseError::RejectRequest)
+                    };
+                    Ok(Message::RejectRequest {
+                        index: next()?,
+                        begin: next()?,
+                        length: next()?,
+                    })
+                }
+                // allowed fast
+                17 => {
+                    let b: Vec<u8> = bytes.by_ref().take(4).collect();
+                    let index = read_be_u32(&mut b.as_slice())
+                        .map_err(|_| MessageParseError::AllowedFast)?;
+
+                    Ok(Message::AllowedFast { index })
+                }
+                // extended
+                20 => {
+                    let id = bytes.next().ok_or(MessageParseError::Extended)?;
+                    let payload_len = prefix_len
+                        .checked_sub(2)
+                        .ok_or(MessageParseError::Extended)?;
+                    Ok(Message::Extended {
+                        id,
+                        payload: bytes.take(payload_len as usize).collect(),
+                    })
+                }
                 _ => Err(MessageParseError::Id(id)),
             }
         }
@@ -221,10 +469,14 @@ impl Message {
 
 impl Handshake {
     pub fn serialize(&self) -> Vec<u8> {
+        let mut reserved = RESERVED_BYTES;
+        if self.fast_extension {
+            reserved[FAST_EXTENSION_BYTE] |= FAST_EXTENSION_BIT;
+        }
         [
             u8::to_be_bytes(P_STR_LEN).to_vec(),
             P_STR.as_bytes().to_vec(),
-            RESERVED_BYTES.to_vec(),
+            reserved.to_vec(),
             self.info_hash.to_vec(),
             self.peer_id.to_vec(),
         ]
@@ -246,7 +498,7 @@ impl Handshake {
             .ok_or(HandshakeParseError::PStr)
             .and_then(|s| std::str::from_utf8(s).map_err(|_| HandshakeParseError::PStr))?;
 
-        let _reserved_bytes = bytes
+        let reserved_bytes = bytes
             .get(len..len + 8)
             .ok_or(HandshakeParseError::ReservedBytes)?;
 
@@ -261,6 +513,116 @@ impl Handshake {
         Ok(Handshake {
             info_hash: info_hash.to_vec(),
             peer_id: peer_id.to_vec(),
+            fast_extension: reserved_bytes[FAST_EXTENSION_BYTE] & FAST_EXTENSION_BIT != 0,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: Message) -> Message {
+        let bytes = message.serialize();
+        let prefix_len = read_be_u32(&mut &bytes[..4]).unwrap();
+        assert_eq!(prefix_len as usize, bytes.len() - 4);
+        Message::new(Box::new(bytes.into_iter().skip(4)), prefix_len).unwrap()
+    }
+
+    #[test]
+    fn it_round_trips_requests() {
+        match round_trip(Message::Request {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        }) {
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => assert_eq!((index, begin, length), (1, 16384, 16384)),
+            m => panic!("unexpected message {}", m),
+        }
+    }
+
+    #[test]
+    fn it_round_trips_pieces() {
+        match round_trip(Message::Piece {
+            index: 2,
+            offset: 16384,
+            data: vec![1, 2, 3],
+        }) {
+            Message::Piece {
+                index,
+                offset,
+                data,
+            } => assert_eq!((index, offset, data), (2, 16384, vec![1, 2, 3])),
+            m => panic!("unexpected message {}", m),
+        }
+    }
+
+    #[test]
+    fn it_rejects_truncated_requests() {
+        assert!(matches!(
+            Message::new(Box::new(vec![6, 0, 0, 0, 1].into_iter()), 5),
+            Err(MessageParseError::Request)
+        ));
+    }
+
+    #[test]
+    fn it_round_trips_extended_messages() {
+        match round_trip(Message::Extended {
+            id: 3,
+            payload: b"d8:msg_typei0e5:piecei0ee".to_vec(),
+        }) {
+            Message::Extended { id, payload } => {
+                assert_eq!(
+                    (id, payload.as_slice()),
+                    (3, &b"d8:msg_typei0e5:piecei0ee"[..])
+                )
+            }
+            m => panic!("unexpected message {}", m),
+        }
+    }
+
+    #[test]
+    fn it_round_trips_fast_extension_messages() {
+        assert!(matches!(round_trip(Message::HaveAll), Message::HaveAll));
+        assert!(matches!(round_trip(Message::HaveNone), Message::HaveNone));
+        match round_trip(Message::RejectRequest {
+            index: 3,
+            begin: 16384,
+            length: 100,
+        }) {
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => assert_eq!((index, begin, length), (3, 16384, 100)),
+            m => panic!("unexpected message {}", m),
+        }
+
+        let handshake = |fast_extension| {
+            Handshake::new(
+                &Handshake {
+                    info_hash: vec![1; 20],
+                    peer_id: vec![2; 20],
+                    fast_extension,
+                }
+                .serialize(),
+            )
+            .unwrap()
+            .fast_extension
+        };
+        assert!(handshake(true));
+        assert!(!handshake(false));
+    }
+
+    #[test]
+    fn it_round_trips_suggest_piece() {
+        match round_trip(Message::SuggestPiece { index: 77 }) {
+            Message::SuggestPiece { index } => assert_eq!(index, 77),
+            m => panic!("unexpected message {}", m),
+        }
+    }
+}
@@ -1,17 +1,60 @@
 use crate::bencode::*;
-use crate::PiecedContent;
+use crate::file_checksums::FileChecksums;
+use crate::torrent::PiecedContent;
+use crate::util::sha256;
+use reqwest::Url;
 use sha1::{Digest, Sha1};
-use std::collections::BTreeMap;
-use std::fs::File as FsFile;
-use std::io::prelude::*;
+use std::io::Error as IOError;
+use std::path::{Component, Path, PathBuf};
 
 #[derive(Debug)]
 pub struct File {
-    pub length: u32,
-    pub path: String,
+    pub length: u64,
+    // where the file goes under the download directory: just the name for a single file torrent,
+    // inside the torrent's directory otherwise. Always relative and never climbing out, see
+    // `safe_path`.
+    pub path: PathBuf,
+    // whatever whole-file checksums the torrent lists for it, checked once it's written
+    pub checksums: FileChecksums,
+    // a BEP 47 padding file: zeros that only pad the next file out to a piece boundary. Part of
+    // the content like any other file, but never written to disk.
+    pub padding: bool,
 }
 
-pub struct Pieces(Vec<String>);
+// One file as `MetaInfoFile::contents` lists it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentEntry<'a> {
+    // into `MetaInfoFile::files`, which still counts padding files
+    pub index: usize,
+    pub name: &'a str,
+    pub path: &'a Path,
+    pub length: u64,
+}
+
+// What a torrent holds, all told
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentSummary {
+    // not counting padding files
+    pub files: usize,
+    // the bytes of every file but the padding, i.e. what ends up on disk
+    pub length: u64,
+    pub padding_length: u64,
+    pub piece_length: u32,
+    pub pieces: u32,
+}
+
+// The part of one file that a piece covers, see `MetaInfoFile::map_piece`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    // into `MetaInfoFile::files`
+    pub file_index: usize,
+    // where the slice starts in the file
+    pub file_offset: u64,
+    pub len: u64,
+}
+
+// The SHA-1 of each piece, in order
+pub struct Pieces(Vec<[u8; 20]>);
 
 impl std::fmt::Debug for Pieces {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -38,8 +81,198 @@ pub enum Info {
 #[derive(Debug)]
 pub struct MetaInfoFile {
     pub info: Info,
-    pub announce: String,
+    // None for trackerless torrents, which find peers through DHT from `nodes`
+    pub announce: Option<String>,
+    // BEP 12 tiers, tried in order with the trackers within a tier interchangeable; empty when the
+    // torrent only has `announce`
+    pub announce_list: Vec<Vec<String>>,
+    // BEP 19 web seeds: HTTP servers holding the files themselves, see `WebSeed`
+    pub url_list: Vec<Url>,
+    // BEP 5 `nodes`: (host, port) of DHT nodes to bootstrap from, usually only in trackerless
+    // torrents
+    pub nodes: Vec<(String, u16)>,
     pub info_hash: [u8; 20],
+    // the `info` dictionary exactly as it was decoded, so it can be written back out with the
+    // same info hash
+    pub info_dictionary: Bencodable,
+    // the bytes the info hash is computed from: the dictionary as it appeared in the .torrent when
+    // loaded from one, its canonical encoding otherwise
+    pub info_bytes: Vec<u8>,
+    // the .torrent it was loaded from, so writing it back out changes only what was changed
+    pub source: Option<Preserved>,
+}
+
+impl MetaInfoFile {
+    // Rebuilds a .torrent around the original info dictionary; the first tracker becomes
+    // `announce` and, when there is more than one, each gets its own tier in `announce-list`
+    pub fn to_bencodable(&self, trackers: &[String]) -> Bencodable {
+        let announce = trackers.first().or(self.announce.as_ref());
+        let announce_list = (trackers.len() > 1).then(|| {
+            trackers
+                .iter()
+                .map(|t| Bencodable::from(vec![Bencodable::from(t.clone())]))
+                .collect::<Vec<Bencodable>>()
+        });
+        let url_list = (!self.url_list.is_empty()).then(|| {
+            self.url_list
+                .iter()
+                .map(|url| Bencodable::from(url.as_str()))
+                .collect::<Vec<Bencodable>>()
+        });
+        let nodes = (!self.nodes.is_empty()).then(|| {
+            self.nodes
+                .iter()
+                .map(|(host, port)| {
+                    Bencodable::from(vec![
+                        Bencodable::from(host.as_str()),
+                        Bencodable::from(*port as i64),
+                    ])
+                })
+                .collect::<Vec<Bencodable>>()
+        });
+        DictBuilder::new()
+            .insert_some("announce", announce.map(String::as_str))
+            .insert_some("announce-list", announce_list)
+            .insert("info", self.info_dictionary.clone())
+            .insert_some("nodes", nodes)
+            .insert_some("url-list", url_list)
+            .build()
+    }
+
+    // `to_bencodable`, encoded keeping the original bytes of everything that didn't change, so a
+    // torrent that wasn't canonically encoded still has its info hash once written back out
+    pub fn encode(&self, trackers: &[String]) -> Result<Vec<u8>, EncodeError> {
+        let exported = self.to_bencodable(trackers);
+        match &self.source {
+            Some(source) => source.encode(&exported),
+            None => bencode(&exported),
+        }
+    }
+
+    // A torrent made from an info dictionary on its own, e.g. one fetched from peers for a magnet
+    // link; None if it isn't a usable info dictionary
+    pub fn from_info_bytes(info_bytes: &[u8], announce: &str) -> Option<MetaInfoFile> {
+        let info_dictionary = bdecode(info_bytes).ok()?;
+        let info = get_info_from(&info_dictionary).ok()?;
+        Some(MetaInfoFile {
+            info,
+            announce: Some(announce.to_string()),
+            announce_list: vec![],
+            url_list: vec![],
+            nodes: vec![],
+            info_hash: sha1(info_bytes),
+            info_dictionary,
+            info_bytes: info_bytes.to_vec(),
+            source: None,
+        })
+    }
+
+    // Every tracker, tier by tier; just `announce` for torrents without an `announce-list`, and
+    // nothing for trackerless ones
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        match (&self.announce, self.announce_list.is_empty()) {
+            (_, false) => self.announce_list.clone(),
+            (Some(announce), true) => vec![vec![announce.clone()]],
+            (None, true) => vec![],
+        }
+    }
+
+    // BEP 52: `meta version` 2 in the info dictionary. Only hybrid torrents, which still have v1
+    // `pieces` alongside the v2 file tree, get this far; pure v2 ones don't parse.
+    pub fn is_v2(&self) -> bool {
+        matches!(
+            self.info_dictionary.get("meta version"),
+            Ok(Bencodable::Integer(2))
+        )
+    }
+
+    // The v2 info hash, SHA-256 over the same bytes as `info_hash`; None for v1 only torrents
+    pub fn info_hash_v2(&self) -> Option<[u8; 32]> {
+        self.is_v2().then(|| sha256(&self.info_bytes))
+    }
+
+    // The v2 info hash cut to 20 bytes, which is how v2 swarms appear to trackers and in
+    // handshakes, wherever a v1 hash would go
+    pub fn truncated_info_hash_v2(&self) -> Option<[u8; 20]> {
+        self.info_hash_v2()
+            .map(|hash| <[u8; 20]>::try_from(&hash[..20]).unwrap())
+    }
+
+    // BEP 27: `private=1` in the info dictionary means peers may only come from the torrent's own
+    // trackers, never from DHT, PEX or local discovery
+    pub fn is_private(&self) -> bool {
+        matches!(
+            self.info_dictionary.get("private"),
+            Ok(Bencodable::Integer(1))
+        )
+    }
+
+    // Every file in the torrent in the order their data is laid out; one for single file torrents
+    pub fn files(&self) -> Vec<&File> {
+        match &self.info {
+            Info::SingleFile { file, .. } => vec![file],
+            Info::MultiFile { files, .. } => files.iter().collect(),
+        }
+    }
+
+    // Every file a user would see in the torrent, in order, for listing them or choosing which to
+    // download; padding files are left out
+    pub fn contents(&self) -> impl Iterator<Item = ContentEntry<'_>> {
+        self.files()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, file)| !file.padding)
+            .map(|(index, file)| ContentEntry {
+                index,
+                name: file
+                    .path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or(""),
+                path: &file.path,
+                length: file.length,
+            })
+    }
+
+    pub fn summary(&self) -> ContentSummary {
+        let files = self.files();
+        let padding_length = files.iter().filter(|f| f.padding).map(|f| f.length).sum();
+        ContentSummary {
+            files: files.iter().filter(|f| !f.padding).count(),
+            length: self.total_length() - padding_length,
+            padding_length,
+            piece_length: self.piece_length(),
+            pieces: self.number_of_pieces(),
+        }
+    }
+
+    // The files piece `index` is stored in, in order, and where in each. A piece runs on from the
+    // end of one file into the next, so it can span several; empty files never appear, and a piece
+    // past the end maps to nothing.
+    pub fn map_piece(&self, index: u32) -> Vec<FileSlice> {
+        let piece_length = self.piece_length() as u64;
+        let start = index as u64 * piece_length;
+        let end = (start + piece_length).min(self.total_length());
+        let mut slices = vec![];
+        let mut file_start = 0;
+        for (file_index, file) in self.files().iter().enumerate() {
+            let file_end = file_start + file.length;
+            let from = start.max(file_start);
+            let to = end.min(file_end);
+            if from < to {
+                slices.push(FileSlice {
+                    file_index,
+                    file_offset: from - file_start,
+                    len: to - from,
+                });
+            }
+            if file_end >= end {
+                break;
+            }
+            file_start = file_end;
+        }
+        slices
+    }
 }
 
 impl PiecedContent for MetaInfoFile {
@@ -77,7 +310,7 @@ impl PiecedContent for MetaInfoFile {
         }
     }
 
-    fn total_length(&self) -> u32 {
+    fn total_length(&self) -> u64 {
         match &self.info {
             Info::SingleFile {
                 piece_length: _,
@@ -93,54 +326,134 @@ impl PiecedContent for MetaInfoFile {
             } => files.iter().map(|f| f.length).sum(),
         }
     }
+
+    fn piece_hashes(&self) -> Option<Vec<[u8; 20]>> {
+        match &self.info {
+            Info::SingleFile { pieces, .. } | Info::MultiFile { pieces, .. } => {
+                Some(pieces.0.clone())
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 enum MetaInfoFileParseError<'a> {
     GenericError(&'a str),
+    Access(AccessError),
 }
 
-fn get_info_from_btm(
-    btm: &BTreeMap<BencodableByteString, Bencodable>,
-) -> Result<Info, MetaInfoFileParseError> {
-    let piece_length_key = &BencodableByteString::from("piece length");
-    let piece_length = match btm[piece_length_key] {
-        Bencodable::Integer(i) => i,
-        _ => {
-            return Err(MetaInfoFileParseError::GenericError(
-                "did not find `piece length`",
-            ))
-        }
+impl From<AccessError> for MetaInfoFileParseError<'_> {
+    fn from(e: AccessError) -> Self {
+        MetaInfoFileParseError::Access(e)
+    }
+}
+
+// Entries that aren't strings, and tiers left with nothing in them, are dropped rather than
+// failing the whole torrent over
+fn get_announce_list_from(torrent: &Bencodable) -> Vec<Vec<String>> {
+    let tiers = match torrent.get("announce-list").and_then(Bencodable::as_list) {
+        Ok(tiers) => tiers,
+        Err(_) => return vec![],
     };
+    tiers
+        .iter()
+        .filter_map(|tier| tier.as_list().ok())
+        .map(|tier| {
+            tier.iter()
+                .filter_map(|url| url.as_str().ok())
+                .map(str::to_string)
+                .collect::<Vec<String>>()
+        })
+        .filter(|tier| !tier.is_empty())
+        .collect()
+}
 
-    let pieces_key = &BencodableByteString::from("pieces");
-    let pieces: Vec<String> = match &btm[pieces_key] {
-        Bencodable::ByteString(bs) => bs
-            .as_bytes()
-            .chunks(20)
-            .map(|c| {
-                let chars = <[u8; 20]>::from(Sha1::digest(c));
-                hex::encode(chars)
-            })
-            .collect(),
-        _ => {
-            return Err(MetaInfoFileParseError::GenericError(
-                "did not find `pieces`",
-            ))
-        }
+// `url-list` is either a single URL or a list of them. Only http(s) URLs that parse are kept,
+// those being the only web seeds there is a client for.
+fn get_url_list_from(torrent: &Bencodable) -> Vec<Url> {
+    let urls = match torrent.get("url-list") {
+        Ok(Bencodable::List(urls)) => urls.iter().collect(),
+        Ok(url) => vec![url],
+        Err(_) => return vec![],
     };
+    urls.into_iter()
+        .filter_map(|url| url.as_str().ok())
+        .filter_map(|url| Url::parse(url).ok())
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .collect()
+}
 
-    let name_key = &BencodableByteString::from("name");
-    let name = match &btm[name_key] {
-        Bencodable::ByteString(bs) => bs.as_string().unwrap(),
-        _ => return Err(MetaInfoFileParseError::GenericError("did not find `name`")),
+// Nodes that aren't a [host, port] pair are dropped
+fn get_nodes_from(torrent: &Bencodable) -> Vec<(String, u16)> {
+    let nodes = match torrent.get("nodes").and_then(Bencodable::as_list) {
+        Ok(nodes) => nodes,
+        Err(_) => return vec![],
     };
+    nodes
+        .iter()
+        .filter_map(|node| match node.as_list().ok()? {
+            [host, port] => Some((
+                host.as_str().ok()?.to_string(),
+                u16::try_from(port.as_int().ok()?).ok()?,
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+// Torrents come from strangers, so no file in one may land outside the download directory.
+// Separators inside a component become `_`, empty and `.` components are dropped, and `..` or
+// anything else the platform reads as a root, drive or prefix is replaced by `_`.
+fn safe_path<'a>(components: impl IntoIterator<Item = &'a str>) -> PathBuf {
+    let mut path = PathBuf::new();
+    for component in components {
+        let component = component.replace(['/', '\\', '\0'], "_");
+        let normal = Path::new(&component)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+        match component.as_str() {
+            "" | "." => {}
+            _ if normal => path.push(&component),
+            _ => path.push("_"),
+        }
+    }
+    if path.as_os_str().is_empty() {
+        path.push("_");
+    }
+    path
+}
+
+fn get_info_from(info: &Bencodable) -> Result<Info, MetaInfoFileParseError<'_>> {
+    // in current example, we see 131072 => log base 2 of 131072 = 17
+    // (since spec says the piece length is almost always a power of 2)
+    let piece_length = u32::try_from(info.get("piece length")?.as_int()?)
+        .map_err(|_| MetaInfoFileParseError::GenericError("`piece length` is out of range"))?;
+    if piece_length == 0 {
+        return Err(MetaInfoFileParseError::GenericError(
+            "`piece length` is zero",
+        ));
+    }
+
+    let pieces = info.get("pieces")?.as_bytes()?;
+    if pieces.len() % 20 != 0 {
+        return Err(MetaInfoFileParseError::GenericError(
+            "`pieces` is not made of 20 byte hashes",
+        ));
+    }
+    let pieces: Vec<[u8; 20]> = pieces
+        .chunks_exact(20)
+        .map(|c| <[u8; 20]>::try_from(c).unwrap())
+        .collect();
+
+    let name = info.get("name")?.as_str()?;
 
-    let length_key = &BencodableByteString::from("length");
     // TODO(): Need to implement multiple files to download larger charlie chaplin torrent as a test...
-    let length = match &btm.get(length_key) {
-        Some(Bencodable::Integer(i)) => Some(i),
-        _ => None,
+    let length = match info.get("length") {
+        Ok(length) => Some(
+            u64::try_from(length.as_int()?)
+                .map_err(|_| MetaInfoFileParseError::GenericError("`length` is negative"))?,
+        ),
+        Err(_) => None,
     };
 
     if let Some(l) = length {
@@ -149,65 +462,54 @@ fn get_info_from_btm(
             pieces: Pieces(pieces),
             name: name.to_string(),
             file: File {
-                length: *l,
-                path: name.to_string(),
+                length: l,
+                path: safe_path([name]),
+                checksums: FileChecksums::parse(info),
+                padding: false,
             },
         })
     } else {
-        let files_key = &BencodableByteString::from("files");
-        let files: Vec<File> = match &btm[files_key] {
-            Bencodable::List(bs) => bs,
-            _ => {
-                panic!("did not find `files` when `length` was unavailable")
-            }
-        }
-        .iter()
-        .map(|b| -> Result<File, MetaInfoFileParseError> {
-            println!("processing file bencodable {:?}\n", b);
-            // crc32: ByteString(3481f090)
-            // length: Integer(57772860)
-            // md5: ByteString(bd8a51ac77e546826af44ff8396a69aa)
-            // mtime: ByteString(1627109655)
-            // path: List([ByteString(Charlie Chaplin . Mabel's Strange Predicament (1914 Restored Short Silent Film Noir Comedy).mp4)])
-            // sha1: ByteString(720b65c5f3910b8d48b15a08b55417cb4f2ebf4a)
-            match &b {
-                Bencodable::Dictionary(btm) => {
-                    let length_key = &BencodableByteString::from("length");
-                    let length = match btm[length_key] {
-                        Bencodable::Integer(i) => i,
-                        _ => {
-                            return Err(MetaInfoFileParseError::GenericError(
-                                "did not find `length` for file in multifile torrent",
-                            ))
-                        }
-                    };
-
-                    let path_key = &BencodableByteString::from("path");
-                    let path = match &btm[path_key] {
-                        Bencodable::List(bs) => bs
-                            .iter()
-                            .map(|b| match &b {
-                                Bencodable::ByteString(s) => s.as_string().unwrap().to_string(),
-                                _ => {
-                                    panic!("could not construct path for file in multifile torrent")
-                                }
-                            })
-                            .collect::<Vec<String>>()
-                            .join("\\"),
-                        _ => {
-                            return Err(MetaInfoFileParseError::GenericError(
-                                "did not find `path` for file in multifile torrent",
-                            ))
-                        }
-                    };
-
-                    Ok(File { path, length })
-                }
-                _ => panic!("did not find `info`"),
-            }
-        })
-        .map(|rf| rf.unwrap())
-        .collect();
+        let files: Vec<File> = info
+            .get("files")?
+            .as_list()?
+            .iter()
+            .map(|b| -> Result<File, MetaInfoFileParseError> {
+                println!("processing file bencodable {:?}\n", b);
+                // crc32: ByteString(3481f090)
+                // length: Integer(57772860)
+                // md5: ByteString(bd8a51ac77e546826af44ff8396a69aa)
+                // mtime: ByteString(1627109655)
+                // path: List([ByteString(Charlie Chaplin . Mabel's Strange Predicament (1914 Restored Short Silent Film Noir Comedy).mp4)])
+                // sha1: ByteString(720b65c5f3910b8d48b15a08b55417cb4f2ebf4a)
+                let length = u64::try_from(b.get("length")?.as_int()?).map_err(|_| {
+                    MetaInfoFileParseError::GenericError(
+                        "`length` is negative for file in multifile torrent",
+                    )
+                })?;
+                let components = b
+                    .get("path")?
+                    .as_list()?
+                    .iter()
+                    .map(|component| component.as_str())
+                    .collect::<Result<Vec<&str>, AccessError>>()?;
+                // older BitComet torrents only mark padding files by their name
+                let padding = b
+                    .get("attr")
+                    .and_then(Bencodable::as_bytes)
+                    .map(|attr| attr.contains(&b'p'))
+                    .unwrap_or(false)
+                    || components
+                        .last()
+                        .is_some_and(|c| c.starts_with("_____padding_file_"));
+                let path = safe_path(std::iter::once(name).chain(components));
+                Ok(File {
+                    path,
+                    length,
+                    checksums: FileChecksums::parse(b),
+                    padding,
+                })
+            })
+            .collect::<Result<Vec<File>, MetaInfoFileParseError>>()?;
         Ok(Info::MultiFile {
             piece_length,
             pieces: Pieces(pieces),
@@ -217,70 +519,536 @@ fn get_info_from_btm(
     }
 }
 
-fn get_info(b: &Bencodable) -> Result<Info, MetaInfoFileParseError> {
-    match &b {
-        Bencodable::Dictionary(btm) => {
-            let info_key = &BencodableByteString::from("info");
-            match &btm[info_key] {
-                Bencodable::Dictionary(btm) => {
-                    // in current example, we see 131072 => log base 2 of 131072 = 17
-                    // (since spec says the piece length is almost always a power of 2)
-                    get_info_from_btm(btm)
-                }
-                _ => panic!("did not find `info`"),
+// Why a .torrent couldn't be loaded
+#[derive(Debug)]
+pub enum MetaInfoError {
+    Io(IOError),
+    Decode(BencodeParseError),
+    // valid bencode, but not a torrent we can use; says what was wrong with it
+    Invalid(String),
+}
+
+impl From<MetaInfoFileParseError<'_>> for MetaInfoError {
+    fn from(e: MetaInfoFileParseError) -> Self {
+        match e {
+            MetaInfoFileParseError::GenericError(reason) => {
+                MetaInfoError::Invalid(reason.to_string())
             }
+            MetaInfoFileParseError::Access(e) => MetaInfoError::Invalid(format!("{:?}", e)),
         }
-        _ => panic!("did not find dictionary for Metainfo file structure"),
     }
 }
 
+fn from_bencodable(b: &Bencodable) -> Result<MetaInfoFile, MetaInfoError> {
+    let info_dictionary = b.get("info").map_err(MetaInfoFileParseError::from)?.clone();
+    let info = get_info_from(&info_dictionary)?;
+    let announce = b
+        .get("announce")
+        .and_then(Bencodable::as_str)
+        .ok()
+        .map(str::to_string);
+    let info_bytes =
+        bencode(&info_dictionary).map_err(|e| MetaInfoError::Invalid(e.to_string()))?;
+
+    Ok(MetaInfoFile {
+        info,
+        announce,
+        announce_list: get_announce_list_from(b),
+        url_list: get_url_list_from(b),
+        nodes: get_nodes_from(b),
+        info_hash: sha1(&info_bytes),
+        info_dictionary,
+        info_bytes,
+        source: None,
+    })
+}
+
+impl MetaInfoFile {
+    // Hashes the info dictionary's raw bytes instead of re-encoding it, so torrents that weren't
+    // canonically encoded keep the info hash the rest of the swarm computes
+    pub fn from_bytes(bytes: &[u8]) -> Result<MetaInfoFile, MetaInfoError> {
+        MetaInfoFile::from_owned_bytes(bytes.to_vec())
+    }
+
+    // A .torrent file on disk
+    pub fn from_path(path: &Path) -> Result<MetaInfoFile, MetaInfoError> {
+        let bytes = std::fs::read(path).map_err(MetaInfoError::Io)?;
+        MetaInfoFile::from_owned_bytes(bytes)
+    }
+
+    // The one decode is shared with `source`, which keeps `bytes` rather than a copy of them
+    fn from_owned_bytes(bytes: Vec<u8>) -> Result<MetaInfoFile, MetaInfoError> {
+        let (bencodable, span) = bdecode_with_span(&bytes).map_err(MetaInfoError::Decode)?;
+        let mut meta_info = from_bencodable(&bencodable)?;
+        if let Some(info_span) = span.get("info") {
+            meta_info.info_bytes = info_span.slice(&bytes).to_vec();
+            meta_info.info_hash = sha1(&meta_info.info_bytes);
+        }
+        meta_info.source = Some(Preserved::new(bytes, bencodable, span));
+        Ok(meta_info)
+    }
+}
+
+// A borrowed look at a .torrent that decodes nothing up front and copies nothing it hands out; the
+// `pieces` string of a big torrent runs to megabytes. Enough to identify a torrent, or show what it
+// is, before paying for the owned `MetaInfoFile` that `to_meta_info` makes of it.
+#[derive(Debug)]
+pub struct MetaInfoView<'a> {
+    bytes: &'a [u8],
+    root: LazyBencodable<'a>,
+}
+
+impl<'a> MetaInfoView<'a> {
+    // Only checks the bytes are well formed bencode with an `info` dictionary
+    pub fn new(bytes: &'a [u8]) -> Result<Self, MetaInfoError> {
+        let root = LazyBencodable::new(bytes).map_err(MetaInfoError::Decode)?;
+        if !root.get("info").is_some_and(LazyBencodable::is_dictionary) {
+            return Err(MetaInfoError::Invalid("no `info` dictionary".to_string()));
+        }
+        Ok(MetaInfoView { bytes, root })
+    }
+
+    // The info dictionary exactly as it appears in the .torrent
+    pub fn info_bytes(&self) -> &'a [u8] {
+        self.info().raw()
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        sha1(self.info_bytes())
+    }
+
+    pub fn announce(&self) -> Option<&'a str> {
+        self.root.get("announce").and_then(text)
+    }
+
+    pub fn name(&self) -> Option<&'a str> {
+        self.info().get("name").and_then(text)
+    }
+
+    pub fn piece_length(&self) -> Option<u32> {
+        let value = self.info().get("piece length")?.decode().ok()?;
+        u32::try_from(value.as_int().ok()?).ok()
+    }
+
+    // The concatenated SHA-1s of every piece, straight out of the .torrent
+    pub fn pieces(&self) -> Option<&'a [u8]> {
+        self.info().get("pieces").and_then(byte_string)
+    }
+
+    pub fn to_meta_info(&self) -> Result<MetaInfoFile, MetaInfoError> {
+        MetaInfoFile::from_bytes(self.bytes)
+    }
+
+    fn info(&self) -> &LazyBencodable<'a> {
+        self.root.get("info").expect("checked on construction")
+    }
+}
+
+// The contents of a byte string, borrowed from the bytes it was encoded in
+fn byte_string<'a>(value: &LazyBencodable<'a>) -> Option<&'a [u8]> {
+    let raw = value.raw();
+    if !raw.first()?.is_ascii_digit() {
+        return None;
+    }
+    let colon = raw.iter().position(|b| *b == b':')?;
+    Some(&raw[colon + 1..])
+}
+
+fn text<'a>(value: &LazyBencodable<'a>) -> Option<&'a str> {
+    std::str::from_utf8(byte_string(value)?).ok()
+}
+
+// Panics on anything that isn't a usable torrent; see `MetaInfoFile::from_bytes` for the fallible
+// version. With only the decoded value to go on, the info hash is of the dictionary re-encoded,
+// which is only right for torrents that were canonically encoded to begin with; anything read
+// from a file or the network should go through `from_bytes` instead.
 impl<'a> From<&'a Bencodable> for MetaInfoFile {
     fn from(b: &'a Bencodable) -> Self {
-        let info = get_info(b).unwrap();
-
-        let announce = match &b {
-            Bencodable::Dictionary(btm) => {
-                let info_key = &BencodableByteString::from("announce");
-                match &btm[info_key] {
-                    Bencodable::ByteString(bs) => bs.as_string(),
-                    _ => panic!("did not find announce"),
-                }
-            }
-            _ => panic!("did not find dictionary for Metainfo file structure"),
+        from_bencodable(b).unwrap()
+    }
+}
+
+fn sha1(bytes: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(bytes);
+    <[u8; 20]>::from(hasher.finalize())
+}
+
+impl From<&[u8]> for MetaInfoFile {
+    fn from(bytes: &[u8]) -> Self {
+        MetaInfoFile::from_bytes(bytes).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> Bencodable {
+        let info = DictBuilder::new()
+            .insert("length", 5_i64)
+            .insert("name", "a.txt")
+            .insert("piece length", 16384_u32)
+            .insert("pieces", &[0u8; 20][..])
+            .build();
+        DictBuilder::new()
+            .insert("announce", "http://one.example/announce")
+            .insert("info", info)
+            .build()
+    }
+
+    #[test]
+    fn it_exports_with_the_same_info_hash_and_extra_trackers() {
+        let original = MetaInfoFile::from(&example());
+        let trackers = vec![
+            "http://one.example/announce".to_string(),
+            "http://two.example/announce".to_string(),
+        ];
+        let exported = original.to_bencodable(&trackers);
+        let reparsed = MetaInfoFile::from(&bdecode(&bencode(&exported).unwrap()).unwrap());
+
+        assert_eq!(reparsed.info_hash, original.info_hash);
+        assert_eq!(
+            reparsed.announce.as_deref(),
+            Some("http://one.example/announce")
+        );
+        assert_eq!(
+            reparsed.announce_list,
+            vec![
+                vec!["http://one.example/announce".to_string()],
+                vec!["http://two.example/announce".to_string()]
+            ]
+        );
+        assert_eq!(
+            original.tiers(),
+            vec![vec!["http://one.example/announce".to_string()]]
+        );
+        match exported {
+            Bencodable::Dictionary(btm) => assert_eq!(
+                btm[b"announce-list".as_slice()],
+                Bencodable::List(vec![
+                    Bencodable::List(vec![Bencodable::from("http://one.example/announce")]),
+                    Bencodable::List(vec![Bencodable::from("http://two.example/announce")]),
+                ])
+            ),
+            _ => panic!("export should be a dictionary"),
+        }
+    }
+
+    #[test]
+    fn it_parses_announce_list_tiers() {
+        let bytes: &[u8] = b"d8:announce3:one13:announce-listll3:one3:twoeli5eeli1e5:threeelee4:infod6:lengthi5e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let meta_info = MetaInfoFile::from(bytes);
+        assert_eq!(
+            meta_info.tiers(),
+            vec![
+                vec!["one".to_string(), "two".to_string()],
+                vec!["three".to_string()]
+            ]
+        );
+    }
+
+    #[test]
+    fn it_parses_web_seeds() {
+        let bytes: &[u8] = b"d8:announce3:one4:infod6:lengthi5e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-listl22:http://seed.example/a/i5e13:ftp://x.org/a9:not a url24:https://mirror.example/aee";
+        let meta_info = MetaInfoFile::from(bytes);
+        assert_eq!(
+            meta_info
+                .url_list
+                .iter()
+                .map(Url::as_str)
+                .collect::<Vec<_>>(),
+            vec!["http://seed.example/a/", "https://mirror.example/a"]
+        );
+        let exported = meta_info.to_bencodable(&[]);
+        assert_eq!(MetaInfoFile::from(&exported).url_list, meta_info.url_list);
+
+        let single: &[u8] = b"d8:announce3:one4:infod6:lengthi5e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-list20:http://seed.example/e";
+        assert_eq!(MetaInfoFile::from(single).url_list.len(), 1);
+        assert!(MetaInfoFile::from(&example()).url_list.is_empty());
+    }
+
+    #[test]
+    fn it_parses_trackerless_torrents_with_dht_nodes() {
+        let bytes: &[u8] = b"d4:infod6:lengthi5e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll9:127.0.0.1i6881eel17:router.bt.examplei6881eel4:onlyeli1ei2eeee";
+        let meta_info = MetaInfoFile::from(bytes);
+        assert_eq!(meta_info.announce, None);
+        assert!(meta_info.tiers().is_empty());
+        assert_eq!(
+            meta_info.nodes,
+            vec![
+                ("127.0.0.1".to_string(), 6881),
+                ("router.bt.example".to_string(), 6881)
+            ]
+        );
+        let exported = meta_info.to_bencodable(&[]);
+        assert!(exported.get("announce").is_err());
+        assert_eq!(MetaInfoFile::from(&exported).nodes, meta_info.nodes);
+    }
+
+    #[test]
+    fn it_reads_the_private_flag() {
+        assert!(!MetaInfoFile::from(&example()).is_private());
+        let private: &[u8] = b"d8:announce3:one4:infod6:lengthi5e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1eee";
+        assert!(MetaInfoFile::from(private).is_private());
+        let not_private: &[u8] = b"d8:announce3:one4:infod6:lengthi5e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei0eee";
+        assert!(!MetaInfoFile::from(not_private).is_private());
+    }
+
+    #[test]
+    fn it_lists_what_a_torrent_holds() {
+        let file = |length: i64, path: &[&str]| {
+            DictBuilder::new()
+                .insert("length", length)
+                .insert(
+                    "path",
+                    path.iter()
+                        .map(|c| Bencodable::from(*c))
+                        .collect::<Vec<Bencodable>>(),
+                )
+                .build()
         };
+        let info = DictBuilder::new()
+            .insert(
+                "files",
+                vec![
+                    file(10000, &["a.bin"]),
+                    file(6384, &["_____padding_file_0"]),
+                    file(30000, &["sub", "b.bin"]),
+                ],
+            )
+            .insert("name", "dir")
+            .insert("piece length", 16384_u32)
+            .insert("pieces", &[0u8; 60][..])
+            .build();
+        let meta_info = MetaInfoFile::from(&DictBuilder::new().insert("info", info).build());
+        let listed: Vec<(usize, &str, PathBuf, u64)> = meta_info
+            .contents()
+            .map(|entry| {
+                (
+                    entry.index,
+                    entry.name,
+                    entry.path.to_path_buf(),
+                    entry.length,
+                )
+            })
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                (0, "a.bin", Path::new("dir").join("a.bin"), 10000),
+                (
+                    2,
+                    "b.bin",
+                    Path::new("dir").join("sub").join("b.bin"),
+                    30000
+                )
+            ]
+        );
+        assert_eq!(
+            meta_info.summary(),
+            ContentSummary {
+                files: 2,
+                length: 40000,
+                padding_length: 6384,
+                piece_length: 16384,
+                pieces: 3,
+            }
+        );
+    }
 
-        let info_hash = {
-            let info = match &b {
-                Bencodable::Dictionary(btm) => {
-                    let info_key = &BencodableByteString::from("info");
-                    match &btm[info_key] {
-                        Bencodable::Dictionary(btm) => {
-                            bencode(&Bencodable::Dictionary(btm.clone()))
-                        }
-                        _ => panic!("did not find info for info hash"),
-                    }
-                }
-                _ => panic!("did not find dictionary for Metainfo file structure for info hash"),
-            };
-            let mut hasher = Sha1::new();
-            hasher.update(&info.unwrap());
-            <[u8; 20]>::from(hasher.finalize())
+    #[test]
+    fn it_maps_pieces_onto_the_files_they_span() {
+        let file = |length: i64, name: &str| {
+            DictBuilder::new()
+                .insert("length", length)
+                .insert("path", vec![Bencodable::from(name)])
+                .build()
+        };
+        let info = DictBuilder::new()
+            .insert(
+                "files",
+                vec![
+                    file(10000, "a"),
+                    file(0, "empty"),
+                    file(30000, "b"),
+                    file(100, "c"),
+                ],
+            )
+            .insert("name", "dir")
+            .insert("piece length", 16384_u32)
+            .insert("pieces", &[0u8; 60][..])
+            .build();
+        let meta_info = MetaInfoFile::from(&DictBuilder::new().insert("info", info).build());
+        let slice = |file_index, file_offset, len| FileSlice {
+            file_index,
+            file_offset,
+            len,
         };
+        assert_eq!(
+            meta_info.map_piece(0),
+            vec![slice(0, 0, 10000), slice(2, 0, 6384)]
+        );
+        assert_eq!(meta_info.map_piece(1), vec![slice(2, 6384, 16384)]);
+        assert_eq!(
+            meta_info.map_piece(2),
+            vec![slice(2, 22768, 7232), slice(3, 0, 100)]
+        );
+        assert!(meta_info.map_piece(3).is_empty());
 
-        MetaInfoFile {
-            info,
-            announce: announce.unwrap().to_string(),
-            info_hash,
+        let single = MetaInfoFile::from(&example());
+        assert_eq!(single.map_piece(0), vec![slice(0, 0, 5)]);
+    }
+
+    #[test]
+    fn it_loads_torrents_without_panicking_on_bad_ones() {
+        let bytes = bencode(&example()).unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "bit_torrent_meta_info_{}.torrent",
+            crate::util::random_string()
+        ));
+        std::fs::write(&path, &bytes).unwrap();
+        let meta_info = MetaInfoFile::from_path(&path).unwrap();
+        assert_eq!(
+            meta_info.info_hash,
+            MetaInfoFile::from_bytes(&bytes).unwrap().info_hash
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            MetaInfoFile::from_path(&path),
+            Err(MetaInfoError::Io(_))
+        ));
+        assert!(matches!(
+            MetaInfoFile::from_bytes(b"d8:announce"),
+            Err(MetaInfoError::Decode(_))
+        ));
+        let no_info = bencode(&DictBuilder::new().insert("announce", "x").build()).unwrap();
+        assert!(matches!(
+            MetaInfoFile::from_bytes(&no_info),
+            Err(MetaInfoError::Invalid(_))
+        ));
+        let bad_pieces = DictBuilder::new()
+            .insert(
+                "info",
+                DictBuilder::new()
+                    .insert("length", 5_i64)
+                    .insert("name", "a.txt")
+                    .insert("piece length", 16384_u32)
+                    .insert("pieces", &[0u8; 19][..])
+                    .build(),
+            )
+            .build();
+        assert!(matches!(
+            MetaInfoFile::from_bytes(&bencode(&bad_pieces).unwrap()),
+            Err(MetaInfoError::Invalid(reason)) if reason.contains("20 byte")
+        ));
+    }
+
+    #[test]
+    fn it_decodes_files_larger_than_4_gib() {
+        let mut torrent = example();
+        if let Bencodable::Dictionary(btm) = &mut torrent {
+            if let Some(Bencodable::Dictionary(info)) = btm.get_mut(b"info".as_slice()) {
+                info.insert(
+                    BencodableByteString::from("length"),
+                    Bencodable::Integer(5_368_709_120),
+                );
+            }
+        }
+        let bytes = bencode(&torrent).unwrap();
+        let meta_info = MetaInfoFile::from(&bdecode(&bytes).unwrap());
+        assert_eq!(meta_info.total_length(), 5_368_709_120);
+        match meta_info.info {
+            Info::SingleFile { file, .. } => assert_eq!(file.length, 5_368_709_120),
+            info => panic!("expected a single file torrent, got {:?}", info),
         }
     }
-}
 
-impl From<FsFile> for MetaInfoFile {
-    fn from(mut f: FsFile) -> Self {
-        let mut bytes = Vec::new();
-        f.read_to_end(&mut bytes).unwrap();
-        let bytes_slice = bytes.as_slice();
-        let bencodable = bdecode(bytes_slice).unwrap();
-        MetaInfoFile::from(&bencodable)
+    #[test]
+    fn it_hashes_the_raw_info_dictionary() {
+        // keys out of order, so re-encoding the dictionary would change the hash
+        let bytes: &[u8] = b"d8:announce27:http://one.example/announce4:infod6:lengthi5e4:name5:a.txt6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi16384eee";
+        let meta_info = MetaInfoFile::from(bytes);
+        let raw_info = &bytes[47..bytes.len() - 1];
+        assert_eq!(meta_info.info_bytes, raw_info);
+        assert_eq!(meta_info.info_hash, sha1(raw_info));
+        assert_ne!(
+            meta_info.info_hash,
+            MetaInfoFile::from(&bdecode(bytes).unwrap()).info_hash
+        );
+
+        let exported = meta_info
+            .encode(&["http://two.example/announce".to_string()])
+            .unwrap();
+        assert_eq!(
+            MetaInfoFile::from(exported.as_slice()).info_hash,
+            meta_info.info_hash
+        );
+    }
+
+    #[test]
+    fn it_computes_the_v2_info_hash_of_hybrid_torrents() {
+        let v1 = MetaInfoFile::from(&example());
+        assert!(!v1.is_v2());
+        assert_eq!(v1.info_hash_v2(), None);
+
+        let info = DictBuilder::new()
+            .insert("length", 5_i64)
+            .insert("meta version", 2_i64)
+            .insert("name", "a.txt")
+            .insert("piece length", 16384_u32)
+            .insert("pieces", &[0u8; 20][..])
+            .build();
+        let bytes = bencode(&DictBuilder::new().insert("info", info).build()).unwrap();
+        let hybrid = MetaInfoFile::from_bytes(&bytes).unwrap();
+        let expected = sha256(&hybrid.info_bytes);
+        assert_eq!(hybrid.info_hash_v2(), Some(expected));
+        assert_eq!(hybrid.truncated_info_hash_v2().unwrap(), expected[..20]);
+        assert_ne!(hybrid.truncated_info_hash_v2().unwrap(), hybrid.info_hash);
+    }
+
+    #[test]
+    fn it_views_a_torrent_without_decoding_it() {
+        // keys out of order, so only the raw bytes give the right info hash
+        let bytes: &[u8] = b"d8:announce27:http://one.example/announce4:infod6:lengthi5e4:name5:a.txt6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi16384eee";
+        let view = MetaInfoView::new(bytes).unwrap();
+        let owned = view.to_meta_info().unwrap();
+        assert_eq!(view.info_hash(), owned.info_hash);
+        assert_eq!(view.info_bytes(), owned.info_bytes.as_slice());
+        assert_eq!(view.announce(), owned.announce.as_deref());
+        assert_eq!(view.name(), Some("a.txt"));
+        assert_eq!(view.piece_length(), Some(16384));
+        assert_eq!(view.pieces(), Some(&[b'a'; 20][..]));
+
+        assert!(matches!(
+            MetaInfoView::new(b"d8:announce1:xe"),
+            Err(MetaInfoError::Invalid(_))
+        ));
+        assert!(matches!(
+            MetaInfoView::new(b"d8:announce"),
+            Err(MetaInfoError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn it_keeps_file_paths_inside_the_download_directory() {
+        assert_eq!(
+            safe_path(["dir", "sub", "a.bin"]),
+            Path::new("dir").join("sub").join("a.bin")
+        );
+        assert_eq!(
+            safe_path(["dir", "..", "..", "etc", "passwd"]),
+            Path::new("dir")
+                .join("_")
+                .join("_")
+                .join("etc")
+                .join("passwd")
+        );
+        assert_eq!(safe_path(["/etc/passwd"]), Path::new("_etc_passwd"));
+        assert_eq!(safe_path(["a\\..\\b", ".", ""]), Path::new("a_.._b"));
+        assert_eq!(safe_path([".."]), Path::new("_"));
+        assert_eq!(safe_path([""]), Path::new("_"));
+        assert!(safe_path(["..", "x"]).is_relative());
     }
 }
@@ -0,0 +1,92 @@
+use crate::meta_info_file::MetaInfoFile;
+use std::io::{Error as IOError, ErrorKind};
+use std::path::{Path, PathBuf};
+
+// One `<hex info hash>.torrent` per torrent the session has seen, so a torrent whose metadata
+// came from peers can be restarted without fetching it again
+#[derive(Debug, Clone)]
+pub struct MetadataCache {
+    dir: PathBuf,
+}
+
+impl MetadataCache {
+    pub fn new(dir: &Path) -> Result<Self, IOError> {
+        std::fs::create_dir_all(dir)?;
+        Ok(MetadataCache {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self, info_hash: &[u8; 20]) -> PathBuf {
+        self.dir.join(format!("{}.torrent", hex::encode(info_hash)))
+    }
+
+    pub fn store(&self, meta_info: &MetaInfoFile, trackers: &[String]) -> Result<PathBuf, IOError> {
+        let bytes = meta_info
+            .encode(trackers)
+            .map_err(|e| IOError::new(ErrorKind::InvalidData, format!("{:?}", e)))?;
+        let path = self.path(&meta_info.info_hash);
+        // write then rename so a crash never leaves a truncated entry behind
+        let partial = path.with_extension("torrent.partial");
+        std::fs::write(&partial, bytes)?;
+        std::fs::rename(&partial, &path)?;
+        Ok(path)
+    }
+
+    // Entries that don't decode or whose info hash doesn't match their file name are ignored
+    pub fn load(&self, info_hash: &[u8; 20]) -> Option<MetaInfoFile> {
+        let meta_info = MetaInfoFile::from_path(&self.path(info_hash)).ok()?;
+        if &meta_info.info_hash == info_hash {
+            Some(meta_info)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::DictBuilder;
+
+    fn example() -> MetaInfoFile {
+        let info = DictBuilder::new()
+            .insert("length", 5_i64)
+            .insert("name", "a.txt")
+            .insert("piece length", 16384_u32)
+            .insert("pieces", &[1u8; 20][..])
+            .build();
+        MetaInfoFile::from(
+            &DictBuilder::new()
+                .insert("announce", "http://tracker.example/announce")
+                .insert("info", info)
+                .build(),
+        )
+    }
+
+    #[test]
+    fn it_stores_and_loads_metainfo_by_info_hash() {
+        let dir = std::env::temp_dir().join(format!(
+            "bit_torrent_cache_{}",
+            crate::util::random_string()
+        ));
+        let cache = MetadataCache::new(&dir).unwrap();
+        let meta_info = example();
+
+        assert!(cache.load(&meta_info.info_hash).is_none());
+        let path = cache
+            .store(&meta_info, &["http://tracker.example/announce".to_string()])
+            .unwrap();
+        assert_eq!(
+            path.file_name().unwrap().to_str().unwrap(),
+            format!("{}.torrent", hex::encode(meta_info.info_hash))
+        );
+
+        let loaded = cache.load(&meta_info.info_hash).unwrap();
+        assert_eq!(loaded.info_hash, meta_info.info_hash);
+        assert_eq!(loaded.announce, meta_info.announce);
+        assert!(loaded.announce.is_some());
+        assert!(cache.load(&[0u8; 20]).is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,120 @@
+use crate::dns::IpPreference;
+use crate::tracker::Peer;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+// At most this many new connection attempts per turn of a torrent's main loop, so a big tracker
+// response ramps up over a few seconds instead of arriving as one burst of SYNs
+pub const CONNECTIONS_PER_ROUND: usize = 4;
+
+// The peers a torrent has heard of but isn't connected to yet, best first. Trackers often list the
+// same address more than once, and a reannounce mostly returns peers we already have, so every
+// address is only ever queued once.
+#[derive(Debug, Default)]
+pub struct PeerPool {
+    waiting: Vec<Peer>,
+    known: HashSet<SocketAddr>,
+}
+
+impl PeerPool {
+    pub fn new() -> Self {
+        PeerPool::default()
+    }
+
+    // Queues the peers not seen before, behind those waiting already unless `preference` puts
+    // their address family first. Returns how many were queued.
+    pub fn add(&mut self, peers: Vec<Peer>, preference: IpPreference) -> usize {
+        let before = self.waiting.len();
+        for peer in peers {
+            if rank(&peer.socket_addr, preference).is_some() && self.known.insert(peer.socket_addr)
+            {
+                self.waiting.push(peer);
+            }
+        }
+        // stable, so tracker order decides within a family
+        self.waiting
+            .sort_by_key(|peer| rank(&peer.socket_addr, preference));
+        self.waiting.len() - before
+    }
+
+    // Up to `count` of the best peers, which are then no longer waiting
+    pub fn take(&mut self, count: usize) -> Vec<Peer> {
+        let count = count.min(self.waiting.len());
+        self.waiting.drain(..count).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+}
+
+// Lower goes first; None for addresses the preference rules out altogether
+fn rank(addr: &SocketAddr, preference: IpPreference) -> Option<u8> {
+    let v4 = addr.is_ipv4();
+    match preference {
+        IpPreference::Any => Some(0),
+        IpPreference::Ipv4First => Some(!v4 as u8),
+        IpPreference::Ipv6First => Some(v4 as u8),
+        IpPreference::Ipv4Only => v4.then_some(0),
+        IpPreference::Ipv6Only => (!v4).then_some(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(addr: &str) -> Peer {
+        Peer {
+            socket_addr: addr.parse().unwrap(),
+            id: vec![],
+        }
+    }
+
+    fn addrs(peers: &[Peer]) -> Vec<String> {
+        peers.iter().map(|p| p.socket_addr.to_string()).collect()
+    }
+
+    #[test]
+    fn it_hands_out_each_peer_once_best_first() {
+        let mut pool = PeerPool::new();
+        let added = pool.add(
+            vec![
+                peer("[::1]:6881"),
+                peer("10.0.0.1:6881"),
+                peer("10.0.0.1:6881"),
+                peer("10.0.0.2:6881"),
+            ],
+            IpPreference::Ipv4First,
+        );
+        assert_eq!(added, 3);
+        assert_eq!(addrs(&pool.take(2)), vec!["10.0.0.1:6881", "10.0.0.2:6881"]);
+
+        // peers already handed out aren't queued again by a later announce
+        assert_eq!(
+            pool.add(
+                vec![peer("10.0.0.1:6881"), peer("10.0.0.3:6881")],
+                IpPreference::Ipv4First
+            ),
+            1
+        );
+        assert_eq!(pool.len(), 2);
+        assert_eq!(
+            addrs(&pool.take(CONNECTIONS_PER_ROUND)),
+            vec!["10.0.0.3:6881", "[::1]:6881"]
+        );
+        assert!(pool.is_empty());
+
+        assert_eq!(
+            pool.add(
+                vec![peer("[::2]:6881"), peer("10.0.0.4:6881")],
+                IpPreference::Ipv4Only
+            ),
+            1
+        );
+    }
+}
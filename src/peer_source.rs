@@ -0,0 +1,311 @@
+use crate::dht::Dht;
+use crate::lsd::Lsd;
+use crate::tracker::{Peer, TrackerPeer};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// BEP 5 leaves it to clients; this is what the common ones use
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerOrigin {
+    Tracker,
+    Dht,
+    LocalDiscovery,
+    // added by hand, see `Session::add_peer`
+    Manual,
+}
+
+impl PeerOrigin {
+    // Whether a torrent whose peers may only come from its trackers takes peers from here. Peers
+    // added by hand are the user's call, as they are in every other client.
+    pub fn allowed_when_trackers_only(&self) -> bool {
+        matches!(self, PeerOrigin::Tracker | PeerOrigin::Manual)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCandidate {
+    pub addr: SocketAddr,
+    // only trackers sending full peer dictionaries know it
+    pub id: Option<Vec<u8>>,
+    pub origin: PeerOrigin,
+}
+
+impl PeerCandidate {
+    pub fn new(addr: SocketAddr, origin: PeerOrigin) -> Self {
+        PeerCandidate {
+            addr,
+            id: None,
+            origin,
+        }
+    }
+
+    pub fn from_tracker(peer: TrackerPeer) -> Self {
+        match peer {
+            TrackerPeer::Peer(peer) => PeerCandidate {
+                addr: peer.socket_addr,
+                id: Some(peer.id),
+                origin: PeerOrigin::Tracker,
+            },
+            TrackerPeer::SocketAddr(addr) => PeerCandidate::new(addr, PeerOrigin::Tracker),
+        }
+    }
+}
+
+impl From<PeerCandidate> for TrackerPeer {
+    fn from(candidate: PeerCandidate) -> Self {
+        match candidate.id {
+            Some(id) => TrackerPeer::Peer(Peer {
+                socket_addr: candidate.addr,
+                id,
+            }),
+            None => TrackerPeer::SocketAddr(candidate.addr),
+        }
+    }
+}
+
+// Somewhere a torrent hears about peers. A torrent polls each of its sources as it starts and then
+// on every turn of its main loop, so a source that has to go over the network keeps its own
+// schedule and hands back nothing in between.
+pub trait PeerSource: Send {
+    fn origin(&self) -> PeerOrigin;
+
+    // Peers heard about since the last poll
+    fn poll_peers(&mut self) -> Vec<PeerCandidate>;
+}
+
+// A torrent's peer sources, polled together
+#[derive(Default)]
+pub struct PeerSources<'a> {
+    sources: Vec<Box<dyn PeerSource + 'a>>,
+}
+
+impl<'a> PeerSources<'a> {
+    pub fn new() -> Self {
+        PeerSources::default()
+    }
+
+    pub fn add(&mut self, source: impl PeerSource + 'a) {
+        self.sources.push(Box::new(source));
+    }
+
+    // What every source has, each address once, in the order the sources were added; an address
+    // more than one source knows keeps the peer id if any of them had it. With `trackers_only`
+    // only sources allowed for such torrents are polled, so the others keep what they have.
+    pub fn poll(&mut self, trackers_only: bool) -> Vec<PeerCandidate> {
+        let mut candidates: Vec<PeerCandidate> = vec![];
+        let mut seen: HashMap<SocketAddr, usize> = HashMap::new();
+        for source in self.sources.iter_mut() {
+            if trackers_only && !source.origin().allowed_when_trackers_only() {
+                continue;
+            }
+            for candidate in source.poll_peers() {
+                match seen.get(&candidate.addr) {
+                    Some(&at) => {
+                        if candidates[at].id.is_none() {
+                            candidates[at].id = candidate.id;
+                        }
+                    }
+                    None => {
+                        seen.insert(candidate.addr, candidates.len());
+                        candidates.push(candidate);
+                    }
+                }
+            }
+        }
+        candidates
+    }
+}
+
+// Looks the torrent up in the DHT, announcing it there, every `DHT_ANNOUNCE_INTERVAL`
+pub struct DhtSource {
+    dht: Dht,
+    info_hash: [u8; 20],
+    port: u16,
+    // a trackerless torrent's `nodes`, where its swarm is most likely to be found
+    nodes: Vec<(String, u16)>,
+    last_lookup: Option<Instant>,
+}
+
+impl DhtSource {
+    pub fn new(dht: Dht, info_hash: [u8; 20], port: u16, nodes: Vec<(String, u16)>) -> Self {
+        DhtSource {
+            dht,
+            info_hash,
+            port,
+            nodes,
+            last_lookup: None,
+        }
+    }
+}
+
+impl PeerSource for DhtSource {
+    fn origin(&self) -> PeerOrigin {
+        PeerOrigin::Dht
+    }
+
+    fn poll_peers(&mut self) -> Vec<PeerCandidate> {
+        if self
+            .last_lookup
+            .is_some_and(|last| last.elapsed() < DHT_ANNOUNCE_INTERVAL)
+        {
+            return vec![];
+        }
+        self.last_lookup = Some(Instant::now());
+        if !self.nodes.is_empty() {
+            self.dht.bootstrap(&self.nodes);
+        }
+        let peers: Vec<PeerCandidate> = self
+            .dht
+            .announce(&self.info_hash, self.port)
+            .into_iter()
+            .map(|addr| PeerCandidate::new(addr, PeerOrigin::Dht))
+            .collect();
+        println!("DHT peers count {:?}", peers.len());
+        peers
+    }
+}
+
+// The torrent's peers on the local network. The torrent is announced there for as long as the
+// source is around.
+pub struct LsdSource {
+    lsd: Lsd,
+    info_hash: [u8; 20],
+}
+
+impl LsdSource {
+    pub fn new(lsd: Lsd, info_hash: [u8; 20], port: u16) -> Self {
+        lsd.register(info_hash, port);
+        LsdSource { lsd, info_hash }
+    }
+}
+
+impl Drop for LsdSource {
+    fn drop(&mut self) {
+        self.lsd.unregister(&self.info_hash);
+    }
+}
+
+impl PeerSource for LsdSource {
+    fn origin(&self) -> PeerOrigin {
+        PeerOrigin::LocalDiscovery
+    }
+
+    fn poll_peers(&mut self) -> Vec<PeerCandidate> {
+        self.lsd
+            .take_peers(&self.info_hash)
+            .into_iter()
+            .map(|addr| PeerCandidate::new(addr, PeerOrigin::LocalDiscovery))
+            .collect()
+    }
+}
+
+// Peers entered by hand. Clones share the queue, so the session adds to it while the torrent polls.
+#[derive(Debug, Clone, Default)]
+pub struct ManualPeers {
+    queue: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+impl ManualPeers {
+    pub fn new() -> Self {
+        ManualPeers::default()
+    }
+
+    pub fn add(&self, addr: SocketAddr) {
+        self.queue.lock().push(addr);
+    }
+}
+
+impl PeerSource for ManualPeers {
+    fn origin(&self) -> PeerOrigin {
+        PeerOrigin::Manual
+    }
+
+    fn poll_peers(&mut self) -> Vec<PeerCandidate> {
+        std::mem::take(&mut *self.queue.lock())
+            .into_iter()
+            .map(|addr| PeerCandidate::new(addr, PeerOrigin::Manual))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(PeerOrigin, Vec<PeerCandidate>);
+
+    impl PeerSource for Fixed {
+        fn origin(&self) -> PeerOrigin {
+            self.0
+        }
+
+        fn poll_peers(&mut self) -> Vec<PeerCandidate> {
+            std::mem::take(&mut self.1)
+        }
+    }
+
+    #[test]
+    fn it_merges_what_every_source_found() {
+        let addr = |port: u16| SocketAddr::from(([10, 0, 0, 1], port));
+        let manual = ManualPeers::new();
+        let mut sources = PeerSources::new();
+        sources.add(Fixed(
+            PeerOrigin::Dht,
+            vec![
+                PeerCandidate::new(addr(1), PeerOrigin::Dht),
+                PeerCandidate::new(addr(2), PeerOrigin::Dht),
+            ],
+        ));
+        sources.add(Fixed(
+            PeerOrigin::Tracker,
+            vec![PeerCandidate::from_tracker(TrackerPeer::Peer(Peer {
+                socket_addr: addr(2),
+                id: b"-XX0001-remotepeer00".to_vec(),
+            }))],
+        ));
+        sources.add(manual.clone());
+
+        manual.add(addr(3));
+        manual.add(addr(1));
+        // the DHT keeps its peers until the torrent may take them
+        assert_eq!(
+            sources
+                .poll(true)
+                .iter()
+                .map(|c| (c.addr, c.origin))
+                .collect::<Vec<_>>(),
+            vec![
+                (addr(2), PeerOrigin::Tracker),
+                (addr(3), PeerOrigin::Manual),
+                (addr(1), PeerOrigin::Manual)
+            ]
+        );
+
+        sources.add(Fixed(
+            PeerOrigin::Tracker,
+            vec![PeerCandidate::from_tracker(TrackerPeer::Peer(Peer {
+                socket_addr: addr(2),
+                id: b"-XX0001-remotepeer00".to_vec(),
+            }))],
+        ));
+        let candidates = sources.poll(false);
+        assert_eq!(
+            candidates.iter().map(|c| c.addr).collect::<Vec<_>>(),
+            vec![addr(1), addr(2)]
+        );
+        assert_eq!(candidates[1].origin, PeerOrigin::Dht);
+        assert_eq!(
+            TrackerPeer::from(candidates[1].clone()),
+            TrackerPeer::Peer(Peer {
+                socket_addr: addr(2),
+                id: b"-XX0001-remotepeer00".to_vec(),
+            })
+        );
+        assert!(sources.poll(false).is_empty());
+    }
+}
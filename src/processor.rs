@@ -0,0 +1,1778 @@
+use crate::bitfield::BitField;
+use crate::choker::{ChokePolicy, DownloadPhase, EndgameReciprocation};
+use crate::connection::*;
+use crate::connection_manager::{ConnectionManager, PeerUsefulness};
+use crate::dht::Dht;
+use crate::dns::{DnsResolver, IpPreference};
+use crate::file_checksums::verify_files;
+use crate::file_completion::FileCompletion;
+use crate::handshake::{HandshakeGate, HandshakeOutcome, DEFAULT_MAX_PENDING_HANDSHAKES};
+use crate::health::{client_name, PeerSample, SwarmHealth};
+use crate::logger::{Direction, Logger};
+use crate::lsd::Lsd;
+use crate::messages::*;
+use crate::meta_info_file::*;
+use crate::peer_pool::{PeerPool, CONNECTIONS_PER_ROUND};
+use crate::peer_source::{
+    DhtSource, LsdSource, ManualPeers, PeerCandidate, PeerOrigin, PeerSource, PeerSources,
+};
+use crate::scheduler::{AssignmentAudit, RequestBudget, Scheduler, Throttle};
+use crate::session::{seeds_after_completion, CompletionAction, LocalIdentity, SessionEvent};
+use crate::settings::{Settings, SettingsHandle};
+use crate::storage::StorageError;
+use crate::suspend::{SuspendDetector, DEFAULT_SUSPEND_THRESHOLD};
+use crate::timeline::Timeline;
+use crate::torrent::*;
+use crate::tracker::{
+    local_ipv6, promote, Announcers, Event, OptionalParameters, Peer, Tracker, TrackerPeer,
+    TrackerRequest, TrackerResponse, TrackerResponseError, TrackerStatus, UdpClient,
+};
+use crate::ut_metadata::{
+    ExtendedHandshake, MetadataMessage, MetadataServer, EXTENDED_HANDSHAKE_ID, UT_METADATA_ID,
+};
+use crate::verify::VerificationMode;
+use crate::web_seed::WebSeed;
+use parking_lot::{Mutex, RwLock};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub(crate) const CONNECTION_TIMEOUT: Duration = Duration::from_millis(250);
+pub(crate) const READ_TIMEOUT: Duration = Duration::from_millis(1000);
+const PROGRESS_WAIT_TIME: Duration = Duration::from_secs(3);
+const TIMELINE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+const THREADS_PER_PEER: u8 = 1;
+const MAX_IN_PROGRESS_REQUESTS_PER_CONNECTION: usize = 1;
+const HOTTEST_PIECES_SHOWN: usize = 5;
+const COMPLETION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// how often trackers are tried again while none has answered
+const TRACKER_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const MIN_TRACKER_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// web seeds are only downloaded from while fewer peer connections than this are open
+const WEB_SEED_PEER_THRESHOLD: usize = 4;
+const WEB_SEED_IDLE_WAIT: Duration = Duration::from_secs(1);
+// failures in a row before a web seed is given up on
+const WEB_SEED_MAX_FAILURES: u32 = 5;
+
+type PeerThreads = Vec<JoinHandle<()>>;
+
+#[derive(PartialEq, Debug)]
+pub(crate) enum MessageResult {
+    Ok,
+    BadPeerHave,
+    BadPeerPiece,
+    BadPeerRequest,
+    BadPeerExtended,
+    BadPeerSuggestPiece,
+}
+
+pub(crate) struct TorrentProcessor {
+    logger: Arc<RwLock<Logger>>,
+    pub(crate) meta_info: MetaInfoFile,
+    local_peer_id: String,
+    listen_port: u16,
+    pub(crate) torrent: Arc<RwLock<Torrent>>,
+    pub(crate) trackers: Arc<RwLock<Vec<TrackerStatus>>>,
+    pub(crate) timeline: Arc<RwLock<Timeline>>,
+    events: Sender<SessionEvent>,
+    // shared with the session, so a settings reload reaches running torrents
+    settings: SettingsHandle,
+    // weights for sharing the session-wide budgets, shared with every other torrent in the session
+    scheduler: Arc<RwLock<Scheduler>>,
+    // keeps downloads under this torrent's share of `Settings::max_download_rate`
+    throttle: Arc<Mutex<Throttle>>,
+    // shared by every torrent in the session, see `RequestBudget`
+    request_budget: RequestBudget,
+    // peer connections currently working, held under `connection_cap`
+    connections: Arc<Mutex<ConnectionManager>>,
+    // peers from the trackers not connected to yet, taken a few at a time as connections free up
+    peer_pool: Mutex<PeerPool>,
+    // peer tasks that panicked and were disconnected, for diagnostics
+    pub(crate) peer_panics: Arc<AtomicUsize>,
+    // the bencoded info dictionary, served to peers that ask for it over ut_metadata
+    info_dictionary: Arc<Vec<u8>>,
+    // which files are finished, so each is announced with `SessionEvent::FileCompleted` once
+    pub(crate) file_completion: Arc<Mutex<FileCompletion>>,
+    // who we upload to; replaceable while the torrent runs
+    pub(crate) choker: Arc<RwLock<Box<dyn ChokePolicy>>>,
+    // shared with the session, which swaps in its own after construction
+    pub(crate) handshakes: HandshakeGate,
+    // likewise shared with the session, tagged with this torrent's info hash
+    pub(crate) assignment_audit: AssignmentAudit,
+    // likewise shared with the session, so every torrent resolves tracker hosts the same way
+    pub(crate) dns: DnsResolver,
+    // likewise shared with the session, which may have been given announcers for some trackers
+    pub(crate) announcers: Announcers,
+    // likewise shared with the session, so every torrent announces to UDP trackers from one socket
+    pub(crate) udp: UdpClient,
+    // likewise shared with the session; None unless the session runs a DHT node
+    pub(crate) dht: Option<Dht>,
+    // likewise shared with the session; None unless the session runs local service discovery
+    pub(crate) lsd: Option<Lsd>,
+    // likewise shared with the session, which adds the peers it's given by hand
+    pub(crate) manual_peers: ManualPeers,
+    // likewise shared with the session: our address as the last tracker to tell us saw it
+    pub(crate) external_ip: Arc<RwLock<Option<IpAddr>>>,
+    // bumped on every resume, telling each peer connection to check its peer is still there
+    resumes: Arc<AtomicU64>,
+    // what to do once the download is complete, see `CompletionAction`
+    pub(crate) completion_actions: Arc<RwLock<Vec<CompletionAction>>>,
+    // ends every seeding connection as it next comes round its loop
+    pub(crate) stop_seeding: Arc<AtomicBool>,
+    // ends every connection, downloading or not, and the search for peers; see `Session::stop`
+    pub(crate) stopped: Arc<AtomicBool>,
+    // the event the next announce carries: `Started` until a tracker has heard it, then nothing
+    // but `Completed` and `Stopped` as the torrent gets to them
+    tracker_event: Mutex<Option<Event>>,
+    // set by the session, which carries out `completion_actions` with it once the files are written
+    pub(crate) on_complete: Option<OnComplete>,
+    // set once writing the files has failed for good; the torrent then never completes
+    pub(crate) error: RwLock<Option<StorageError>>,
+    // every file's `File::path` is relative to this
+    pub(crate) download_dir: PathBuf,
+}
+
+// The torrent's trackers as a peer source: announced to as the torrent starts, then again once the
+// soonest of them asked to hear from us, or every `TRACKER_RETRY_INTERVAL` while none has
+struct TrackerSource<'a> {
+    processor: &'a TorrentProcessor,
+    last_attempt: Option<Instant>,
+}
+
+impl PeerSource for TrackerSource<'_> {
+    fn origin(&self) -> PeerOrigin {
+        PeerOrigin::Tracker
+    }
+
+    fn poll_peers(&mut self) -> Vec<PeerCandidate> {
+        let now = Instant::now();
+        if let Some(last) = self.last_attempt {
+            let next = self
+                .processor
+                .trackers
+                .read()
+                .iter()
+                .filter_map(TrackerStatus::next_announce)
+                .min()
+                .unwrap_or(last + TRACKER_RETRY_INTERVAL);
+            // a tracker that answered long ago and fails now mustn't be retried on every poll
+            if now < next.max(last + MIN_TRACKER_RETRY_INTERVAL) {
+                return vec![];
+            }
+        }
+        self.last_attempt = Some(now);
+        match self.processor.announce(false) {
+            Ok(response) => response
+                .peers
+                .into_iter()
+                .map(PeerCandidate::from_tracker)
+                .collect(),
+            // a trackerless torrent: peers can only come from elsewhere
+            Err(TrackerResponseError::NoTrackers) => vec![],
+            // every tier failed; other sources, web seeds and later announces can still find peers
+            Err(e) => {
+                println!("no tracker answered {:?}", e);
+                vec![]
+            }
+        }
+    }
+}
+
+pub(crate) type OnComplete = Box<dyn Fn(&TorrentProcessor) + Send + Sync>;
+
+impl TorrentProcessor {
+    pub(crate) fn new(
+        meta_info: MetaInfoFile,
+        identity: LocalIdentity,
+        logger: Arc<RwLock<Logger>>,
+        events: Sender<SessionEvent>,
+        settings: SettingsHandle,
+        scheduler: Arc<RwLock<Scheduler>>,
+        request_budget: RequestBudget,
+    ) -> Self {
+        println!("meta info {:?}", meta_info);
+        let mut torrent = Torrent::new(&meta_info);
+        let verification = settings.current().verification;
+        let download_dir = settings.current().download_dir;
+        if verification != VerificationMode::Full {
+            println!(
+                "WARNING: piece verification is {:?}; only safe on a trusted local swarm",
+                verification
+            );
+        }
+        torrent.set_verification_mode(verification);
+        println!(
+            "torrent num pieces {:?} num blocks {:?} len of pieces vec {:?}",
+            torrent.total_pieces,
+            torrent.total_blocks,
+            torrent.pieces.len()
+        );
+        let torrent = Arc::new(RwLock::new(torrent));
+        let trackers = Arc::new(RwLock::new(TrackerStatus::tiered(&meta_info.tiers())));
+        let info_dictionary = Arc::new(meta_info.info_bytes.clone());
+        let assignment_audit = AssignmentAudit::new().for_torrent(meta_info.info_hash);
+        let file_completion = Arc::new(Mutex::new(FileCompletion::new(
+            &meta_info.files(),
+            meta_info.piece_length(),
+            meta_info.number_of_pieces(),
+        )));
+
+        TorrentProcessor {
+            logger,
+            meta_info,
+            local_peer_id: identity.peer_id,
+            listen_port: identity.listen_port,
+            torrent,
+            trackers,
+            timeline: Arc::new(RwLock::new(Timeline::new())),
+            events,
+            settings,
+            scheduler,
+            throttle: Arc::new(Mutex::new(Throttle::new())),
+            request_budget,
+            connections: Arc::new(Mutex::new(ConnectionManager::new())),
+            peer_pool: Mutex::new(PeerPool::new()),
+            peer_panics: Arc::new(AtomicUsize::new(0)),
+            info_dictionary,
+            file_completion,
+            choker: Arc::new(RwLock::new(Box::new(EndgameReciprocation::default()))),
+            handshakes: HandshakeGate::new(DEFAULT_MAX_PENDING_HANDSHAKES),
+            assignment_audit,
+            dns: DnsResolver::default(),
+            announcers: Announcers::default(),
+            udp: UdpClient::default(),
+            dht: None,
+            lsd: None,
+            manual_peers: ManualPeers::new(),
+            external_ip: Arc::new(RwLock::new(None)),
+            resumes: Arc::new(AtomicU64::new(0)),
+            completion_actions: Arc::new(RwLock::new(vec![])),
+            stop_seeding: Arc::new(AtomicBool::new(false)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            tracker_event: Mutex::new(Some(Event::Started)),
+            on_complete: None,
+            error: RwLock::new(None),
+            download_dir,
+        }
+    }
+
+    // Returns the trackers that were not already known for this torrent. Their tiers come after the
+    // torrent's own, so they're only tried once those have all failed. A private torrent keeps to
+    // the trackers it came with, so nothing is added to one.
+    pub(crate) fn add_trackers(&self, tiers: &[Vec<String>]) -> Vec<String> {
+        if self.meta_info.is_private() {
+            println!("not adding trackers {:?} to a private torrent", tiers);
+            return vec![];
+        }
+        let mut trackers = self.trackers.write();
+        let first_tier = trackers.iter().map(|t| t.tier + 1).max().unwrap_or(0);
+        let mut added = vec![];
+        for mut status in TrackerStatus::tiered(tiers) {
+            if !trackers.iter().any(|t| t.url == status.url) {
+                status.tier += first_tier;
+                added.push(status.url.clone());
+                trackers.push(status);
+            }
+        }
+        added
+    }
+
+    // Whether peers may only come from the trackers. Every peer source other than a tracker (DHT,
+    // PEX, local discovery) has to check this before handing out peers for the torrent.
+    pub(crate) fn trackers_only(&self) -> bool {
+        self.meta_info.is_private() || self.settings.current().trackers_only
+    }
+
+    // While the tracker that last answered is inside its min interval, announcing anyway would only
+    // move the announce on to the trackers behind it, so that waits as well
+    pub(crate) fn min_interval_refusal(&self, now: Instant) -> Option<TrackerResponseError> {
+        self.trackers
+            .read()
+            .iter()
+            .find(|t| t.is_working())
+            .and_then(|t| t.check_announce(now, false).err())
+    }
+
+    // Trackers are tried tier by tier until one of them answers; its intervals and swarm counts
+    // are kept in its `TrackerStatus`. Unless `override_min_interval` is set nothing is sent while
+    // the working tracker is inside its min interval, and other trackers still inside theirs are
+    // skipped.
+    pub(crate) fn announce(
+        &self,
+        override_min_interval: bool,
+    ) -> Result<TrackerResponse, TrackerResponseError> {
+        let settings = self.settings.current();
+        let tracker = Tracker::new()
+            .allowing_only(settings.tracker_hosts)
+            .resolving_with(self.dns.clone(), settings.ip_preference)
+            .using_udp(self.udp.clone());
+        let trackers = self.trackers.read().clone();
+        let (uploaded, downloaded, left, corrupt, redundant) = {
+            let t = self.torrent.read();
+            (
+                t.uploaded_bytes,
+                t.downloaded_bytes,
+                t.left(),
+                t.corrupt_bytes,
+                t.redundant_bytes,
+            )
+        };
+        let minimal_announces = settings.minimal_announces;
+        let ipv6 = match settings.ip_preference {
+            IpPreference::Ipv4Only => None,
+            _ => local_ipv6(),
+        };
+        if !override_min_interval {
+            if let Some(refusal) = self.min_interval_refusal(Instant::now()) {
+                println!("not announcing yet {:?}", refusal);
+                return Err(refusal);
+            }
+        }
+        let event = *self.tracker_event.lock();
+        let mut result = Err(TrackerResponseError::NoTrackers);
+        for status in trackers {
+            let now = Instant::now();
+            if let Err(e) = status.check_announce(now, override_min_interval) {
+                println!("not announcing to {} yet {:?}", status.url, e);
+                result = Err(e);
+                continue;
+            }
+            let request = TrackerRequest::new(
+                self.meta_info.info_hash,
+                self.local_peer_id.as_bytes(),
+                self.listen_port,
+            );
+            let request = match event {
+                Some(event) => request.event(event),
+                None => request,
+            }
+            .uploaded(uploaded)
+            .downloaded(downloaded)
+            .left(left)
+            .compact(true)
+            .no_peer_id(true)
+            .corrupt(corrupt)
+            .redundant(redundant);
+            let request = match ipv6 {
+                Some(ipv6) => request.ipv6(ipv6),
+                None => request,
+            }
+            .for_tracker(if minimal_announces {
+                OptionalParameters {
+                    corrupt: false,
+                    redundant: false,
+                    ..status.optional_parameters
+                }
+            } else {
+                status.optional_parameters
+            });
+            let response = self
+                .announcers
+                .for_url(&tracker, &status.url)
+                .and_then(|announcer| announcer.announce(&request));
+            match response {
+                Ok(response) => {
+                    if let Some(warning) = &response.warning_message {
+                        println!("tracker {} warns {}", status.url, warning);
+                    }
+                    if let Some(ip) = response.external_ip {
+                        *self.external_ip.write() = Some(ip);
+                    }
+                    let mut trackers = self.trackers.write();
+                    promote(&mut trackers, &status.url);
+                    if let Some(t) = trackers.iter_mut().find(|t| t.url == status.url) {
+                        t.record_response(now, &response);
+                        if let Some(redirected_to) = &response.redirected_to {
+                            println!("tracker {} moved to {}", t.url, redirected_to);
+                            t.url = redirected_to.clone();
+                        }
+                    }
+                    // a newer event may have come up while this one was on its way
+                    let mut pending = self.tracker_event.lock();
+                    if *pending == event {
+                        *pending = None;
+                    }
+                    result = Ok(response);
+                    break;
+                }
+                Err(e) => {
+                    println!("announce to {} failed {:?}", status.url, e);
+                    if let Some(t) = self
+                        .trackers
+                        .write()
+                        .iter_mut()
+                        .find(|t| t.url == status.url)
+                    {
+                        t.record_failure(&e);
+                    }
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    // `Completed` and `Stopped` go out straight away, whatever the min interval. A tracker that
+    // never heard we started doesn't need to hear we stopped.
+    fn announce_event(&self, event: Event) {
+        {
+            let mut pending = self.tracker_event.lock();
+            if event == Event::Stopped && *pending == Some(Event::Started) {
+                return;
+            }
+            *pending = Some(event);
+        }
+        if let Err(e) = self.announce(true) {
+            println!("could not announce {:?} {:?}", event, e);
+        }
+    }
+
+    fn possible_peers(&self) -> Result<Vec<Peer>, TrackerResponseError> {
+        self.announce(false)
+            .map(|response| self.usable_peers(response.peers))
+    }
+
+    fn usable_peers(&self, resp: Vec<TrackerPeer>) -> Vec<Peer> {
+        resp.into_iter()
+            .map(Peer::from)
+            // Don't connect to the client we are "pretending to be" at 127.0.0.1
+            .filter(|x| match x.socket_addr {
+                std::net::SocketAddr::V4(sa) => {
+                    !(*sa.ip() == std::net::Ipv4Addr::new(127, 0, 0, 1)
+                        && sa.port() == self.listen_port)
+                }
+                std::net::SocketAddr::V6(_) => true,
+            })
+            .map(|p| {
+                println!("peer {:?}, peer_id {:?}", p, std::str::from_utf8(&p.id));
+                p
+            })
+            .collect()
+    }
+
+    // Where this torrent hears about peers: its trackers, peers added by hand, and the session's
+    // DHT node and local discovery when it has them. `PeerSources::poll` leaves out whatever
+    // `trackers_only` rules out at the time.
+    fn peer_sources(&self) -> PeerSources<'_> {
+        let info_hash = self.meta_info.info_hash;
+        let mut sources = PeerSources::new();
+        sources.add(TrackerSource {
+            processor: self,
+            last_attempt: None,
+        });
+        sources.add(self.manual_peers.clone());
+        if let Some(dht) = self.dht_source() {
+            sources.add(dht);
+        }
+        if let Some(lsd) = self.lsd.as_ref().filter(|_| !self.trackers_only()) {
+            sources.add(LsdSource::new(lsd.clone(), info_hash, self.listen_port));
+        }
+        sources
+    }
+
+    fn dht_source(&self) -> Option<DhtSource> {
+        let dht = self.dht.as_ref().filter(|_| !self.trackers_only())?;
+        Some(DhtSource::new(
+            dht.clone(),
+            self.meta_info.info_hash,
+            self.listen_port,
+            self.meta_info.nodes.clone(),
+        ))
+    }
+
+    fn add_candidates(&self, candidates: Vec<PeerCandidate>) {
+        self.add_peers(candidates.into_iter().map(TrackerPeer::from).collect());
+    }
+
+    // Peers from a later announce, connected to as connections free up
+    pub(crate) fn add_peers(&self, peers: Vec<TrackerPeer>) {
+        let peers = self.usable_peers(peers);
+        let preference = self.settings.current().ip_preference;
+        self.peer_pool.lock().add(peers, preference);
+    }
+
+    // Tops the connections up to the cap from the pool, but only a few per call so a big tracker
+    // response doesn't become a burst of simultaneous connection attempts
+    fn connect_more(&self, jhs: &mut Vec<PeerThreads>) {
+        let cap = connection_cap(
+            &self.settings.current(),
+            &self.scheduler.read(),
+            &self.meta_info.info_hash,
+        );
+        let open = self.connections.lock().open_connections();
+        let wanted = cap
+            .unwrap_or(usize::MAX)
+            .saturating_sub(open)
+            .min(CONNECTIONS_PER_ROUND);
+        let peers = self.peer_pool.lock().take(wanted);
+        for peer in peers {
+            jhs.push(self.generate_peer_threads(Arc::new(peer)));
+        }
+    }
+
+    // Catches up after the machine was suspended for `suspended_for`: every connection checks its
+    // peer is still there, outstanding requests get their full time again rather than all expiring
+    // at once, and the trackers, which have likely dropped us by now, hear from us straight away
+    pub(crate) fn resume(&self, suspended_for: Duration) {
+        let info_hash = self.meta_info.info_hash;
+        println!("resumed after {:?} suspended", suspended_for);
+        self.torrent.write().restart_request_timers(Instant::now());
+        self.resumes.fetch_add(1, Ordering::SeqCst);
+        let _ = self.events.send(SessionEvent::Resumed {
+            info_hash,
+            suspended_for,
+        });
+        match self.announce(true) {
+            Ok(response) => {
+                let _ = self.events.send(SessionEvent::Reannounced {
+                    info_hash,
+                    peers: response.peers.len(),
+                });
+                self.add_peers(response.peers);
+            }
+            Err(e) => println!("reannounce after resuming failed {:?}", e),
+        }
+        if let Some(mut dht) = self.dht_source() {
+            self.add_candidates(dht.poll_peers());
+        }
+    }
+
+    // Announces and briefly connects to up to `sample_size` peers at once to see what they have,
+    // without requesting any data
+    pub(crate) fn probe_health(
+        &self,
+        sample_size: usize,
+        window: Duration,
+    ) -> Result<SwarmHealth, TrackerResponseError> {
+        let peers = self.possible_peers()?;
+        let peers_announced = peers.len();
+        let total_pieces = self.torrent.read().total_pieces;
+        let sampled: Vec<Peer> = peers.into_iter().take(sample_size).collect();
+        let peers_sampled = sampled.len();
+        let samples: Vec<PeerSample> = std::thread::scope(|scope| {
+            let handles: Vec<_> = sampled
+                .into_iter()
+                .map(|peer| {
+                    scope.spawn(move || self.sample_peer(Arc::new(peer), total_pieces, window))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .filter_map(|handle| handle.join().ok().flatten())
+                .collect()
+        });
+        Ok(SwarmHealth::from_samples(
+            total_pieces,
+            peers_announced,
+            peers_sampled,
+            &samples,
+        ))
+    }
+
+    fn sample_peer(
+        &self,
+        peer: Arc<Peer>,
+        total_pieces: u32,
+        window: Duration,
+    ) -> Option<PeerSample> {
+        let mut connection = self.connect(peer).ok()?;
+        let client = client_name(&connection.remote_peer_id);
+        let mut pieces: Option<Vec<bool>> = None;
+        let started = Instant::now();
+        while started.elapsed() < window {
+            match connection.read_message() {
+                Ok(Message::BitField(bf)) => {
+                    let bf = BitField::from(bf);
+                    pieces = Some(
+                        (0..total_pieces as usize)
+                            .map(|i| bf.is_set(i).unwrap_or(false))
+                            .collect(),
+                    );
+                }
+                Ok(Message::HaveAll) => pieces = Some(vec![true; total_pieces as usize]),
+                Ok(Message::HaveNone) => pieces = Some(vec![false; total_pieces as usize]),
+                Ok(Message::Have { index }) if index < total_pieces => {
+                    pieces.get_or_insert_with(|| vec![false; total_pieces as usize])
+                        [index as usize] = true;
+                }
+                Ok(_) => {}
+                Err(MessageParseError::WouldBlock) | Err(MessageParseError::TimedOut) => {}
+                Err(_) => break,
+            }
+        }
+        Some(PeerSample { client, pieces })
+    }
+
+    pub(crate) fn start(&self) {
+        if self.trackers_only() {
+            println!(
+                "finding peers through trackers only{}",
+                if self.meta_info.is_private() {
+                    " (private torrent)"
+                } else {
+                    ""
+                }
+            );
+        }
+        // only a download finished here is news to the trackers
+        let already_complete = self.torrent.read().are_we_done_yet();
+        let mut sources = self.peer_sources();
+        let candidates = sources.poll(self.trackers_only());
+        println!("possible peers count {:?}", candidates.len());
+        self.add_candidates(candidates);
+        let mut jhs: Vec<PeerThreads> = vec![];
+        self.connect_more(&mut jhs);
+        jhs.extend(self.web_seed_thread().map(|jh| vec![jh]));
+        println!(
+            "total connections/threads working {:?}",
+            jhs.iter().flatten().count()
+        );
+        let t = Arc::clone(&self.torrent);
+        let trackers = Arc::clone(&self.trackers);
+        spawn(move || loop {
+            sleep(PROGRESS_WAIT_TIME);
+            for status in trackers.read().iter() {
+                if let Some(next) = status.next_allowed_announce() {
+                    println!(
+                        "tracker {} next announce allowed in {:?}",
+                        status.url,
+                        next.saturating_duration_since(Instant::now())
+                    );
+                }
+            }
+            let t = t.read();
+            println!("percent complete: {}", t.percent_complete);
+            println!("repeated completed blocks: {:?}", t.repeated_blocks);
+            println!("in progress blocks: {:?}", t.in_progress_blocks.len());
+            let mut piece_counts = [0; 4];
+            for state in t.piece_map() {
+                piece_counts[*state as usize] += 1;
+            }
+            println!(
+                "pieces missing/requested/downloaded/verified: {:?}",
+                piece_counts
+            );
+            let hottest = t.heatmap.hottest(HOTTEST_PIECES_SHOWN);
+            if !hottest.is_empty() {
+                println!("most requested pieces (index, requests): {:?}", hottest);
+            }
+        });
+
+        // sampled for as long as this torrent runs, seeding included
+        let t = Arc::clone(&self.torrent);
+        let timeline = Arc::clone(&self.timeline);
+        let started = Instant::now();
+        let sampling = Arc::new(AtomicBool::new(true));
+        let sampler = {
+            let sampling = Arc::clone(&sampling);
+            let stopped = Arc::clone(&self.stopped);
+            spawn(move || {
+                while sampling.load(Ordering::SeqCst) && !stopped.load(Ordering::SeqCst) {
+                    sleep(TIMELINE_SAMPLE_INTERVAL);
+                    let (downloaded, uploaded) = {
+                        let t = t.read();
+                        (t.downloaded_bytes, t.uploaded_bytes)
+                    };
+                    timeline
+                        .write()
+                        .record(started.elapsed().as_secs(), downloaded, uploaded);
+                }
+            })
+        };
+
+        // seeding connections outlive the download, so the files are written as soon as it
+        // completes rather than once every connection has exited. Until then every source keeps
+        // being asked for peers, even while there's no one to download from: trackers come back,
+        // and peers turn up through the DHT, local discovery or `Session::add_peer` at any time.
+        let mut suspend = SuspendDetector::new(DEFAULT_SUSPEND_THRESHOLD);
+        let running = |jhs: &[PeerThreads]| jhs.iter().flatten().any(|jh| !jh.is_finished());
+        let stopped = || self.stopped.load(Ordering::SeqCst);
+        while !self.torrent.read().are_we_done_yet() && !stopped() {
+            sleep(COMPLETION_POLL_INTERVAL);
+            self.add_candidates(sources.poll(self.trackers_only()));
+            // connections that fail or close are replaced from the pool
+            self.connect_more(&mut jhs);
+            if let Some(suspended_for) = suspend.check() {
+                self.resume(suspended_for);
+            }
+        }
+        // done looking for peers, which takes the torrent out of local discovery's announcements
+        drop(sources);
+        if stopped() {
+            println!("torrent stopped before it completed");
+        } else {
+            self.finish(!already_complete);
+        }
+
+        while running(&jhs) {
+            sleep(COMPLETION_POLL_INTERVAL);
+            if let Some(suspended_for) = suspend.check() {
+                self.resume(suspended_for);
+            }
+        }
+        for jh in jhs {
+            for cjh in jh {
+                cjh.join().unwrap();
+            }
+        }
+        sampling.store(false, Ordering::SeqCst);
+        let _ = sampler.join();
+        // done seeding, stopped, or removed from the session
+        self.announce_event(Event::Stopped);
+    }
+
+    // Every piece is in and verified: writes the files out and, if that worked, reports the
+    // torrent complete (to the trackers too when `downloaded` here) and runs its completion actions
+    fn finish(&self, downloaded: bool) {
+        let info_hash = self.meta_info.info_hash;
+        if let Err(failures) = self
+            .torrent
+            .read()
+            .to_file(&self.download_dir, self.meta_info.files())
+        {
+            for error in &failures {
+                println!("could not write {} {:?}", error.path, error);
+                let _ = self.events.send(SessionEvent::StorageFailed {
+                    info_hash,
+                    error: error.clone(),
+                });
+            }
+            *self.error.write() = failures.into_iter().next();
+        } else {
+            // the pieces all passed, so a file failing its own checksum is worth knowing
+            // about but not worth downloading again
+            for mismatch in verify_files(&self.download_dir, &self.meta_info.files()) {
+                println!(
+                    "{:?} does not match its {:?} {} != {}",
+                    mismatch.path, mismatch.checksum, mismatch.actual, mismatch.expected
+                );
+                let _ = self.events.send(SessionEvent::FileChecksumMismatch {
+                    info_hash,
+                    mismatch,
+                });
+            }
+        }
+        // a torrent whose files didn't make it to disk isn't complete, however much of it
+        // is in memory
+        if self.error.read().is_none() {
+            let _ = self
+                .events
+                .send(SessionEvent::DownloadComplete { info_hash });
+            if downloaded {
+                self.announce_event(Event::Completed);
+            }
+            if let Some(on_complete) = &self.on_complete {
+                on_complete(self);
+            }
+        }
+    }
+
+    // Downloads from the torrent's web seeds, one block at a time, whenever there are too few peers
+    // to keep the download going; None when it has no web seeds
+    fn web_seed_thread(&self) -> Option<JoinHandle<()>> {
+        if self.meta_info.url_list.is_empty() {
+            return None;
+        }
+        let seeds: Vec<WebSeed> = self
+            .meta_info
+            .url_list
+            .iter()
+            .map(|url| {
+                WebSeed::new(url.clone(), &self.meta_info)
+                    .resolving_with(self.dns.clone(), self.settings.current().ip_preference)
+            })
+            .collect();
+        let torrent = Arc::clone(&self.torrent);
+        let connections = Arc::clone(&self.connections);
+        let events = self.events.clone();
+        let info_hash = self.meta_info.info_hash;
+        let file_completion = Arc::clone(&self.file_completion);
+        let files: Vec<PathBuf> = self
+            .meta_info
+            .files()
+            .iter()
+            .map(|f| self.download_dir.join(&f.path))
+            .collect();
+        let piece_length = self.meta_info.piece_length() as u64;
+        let stopped = Arc::clone(&self.stopped);
+        Some(spawn(move || {
+            // a web seed has every piece
+            let all = BitField::from(vec![
+                0xff;
+                (torrent.read().total_pieces as usize).div_ceil(8)
+            ]);
+            let mut failures = vec![0; seeds.len()];
+            let mut next = 0;
+            while !torrent.read().are_we_done_yet() && !stopped.load(Ordering::SeqCst) {
+                let usable: Vec<usize> = (0..seeds.len())
+                    .filter(|i| failures[*i] < WEB_SEED_MAX_FAILURES)
+                    .collect();
+                if usable.is_empty() {
+                    println!("giving up on every web seed");
+                    return;
+                }
+                if connections.lock().open_connections() >= WEB_SEED_PEER_THRESHOLD {
+                    sleep(WEB_SEED_IDLE_WAIT);
+                    continue;
+                }
+                let block = torrent.write().get_next_block(&all);
+                let PieceIndexOffsetLength(index, offset, length) = match block {
+                    Some(block) => block,
+                    None => {
+                        // everything left is requested from peers, or waiting to be verified
+                        sleep(COMPLETION_POLL_INTERVAL);
+                        report_piece_changes(
+                            &torrent,
+                            &events,
+                            info_hash,
+                            &file_completion,
+                            &files,
+                        );
+                        continue;
+                    }
+                };
+                let seed = usable[next % usable.len()];
+                next += 1;
+                let start = index as u64 * piece_length + offset as u64;
+                match seeds[seed].fetch(start, length as u64) {
+                    Ok(data) => {
+                        failures[seed] = 0;
+                        torrent.write().fill_block((index, offset, &data));
+                    }
+                    Err(e) => {
+                        failures[seed] += 1;
+                        println!("web seed {} failed: {:?}", seeds[seed].url, e);
+                        torrent.write().release_block(index, offset);
+                    }
+                }
+                report_piece_changes(&torrent, &events, info_hash, &file_completion, &files);
+            }
+        }))
+    }
+
+    fn generate_peer_threads(&self, peer: Arc<Peer>) -> PeerThreads {
+        (0..THREADS_PER_PEER)
+            .filter_map(|_| {
+                let torrent = Arc::clone(&self.torrent);
+                let peer = Arc::clone(&peer);
+                let peer_addr = peer.socket_addr.to_string();
+                let connection = self.connect(peer);
+                let logger = Arc::clone(&self.logger);
+                let events = self.events.clone();
+                let info_hash = self.meta_info.info_hash;
+                let settings = self.settings.clone();
+                let connections = Arc::clone(&self.connections);
+                let scheduler = Arc::clone(&self.scheduler);
+                let throttle = Arc::clone(&self.throttle);
+                let file_completion = Arc::clone(&self.file_completion);
+                let choker = Arc::clone(&self.choker);
+                let resumes = Arc::clone(&self.resumes);
+                let completion_actions = Arc::clone(&self.completion_actions);
+                let stop_seeding = Arc::clone(&self.stop_seeding);
+                let stopped = Arc::clone(&self.stopped);
+                let files: Vec<PathBuf> = self.meta_info.files().iter().map(|f| self.download_dir.join(&f.path)).collect();
+                let work = move |connection: &mut PeerConnection, id: u64| {
+                    let mut done = send_availability(&torrent, connection).is_err();
+                    let mut seeding = false;
+                    let mut resumes_seen = resumes.load(Ordering::SeqCst);
+                        while !done {
+                            // the machine slept and the peer may well be gone; a connection it reset
+                            // fails this write or the next read
+                            let resumed = resumes.load(Ordering::SeqCst);
+                            if resumed != resumes_seen {
+                                resumes_seen = resumed;
+                                if connection.write_message(Message::KeepAlive).is_err() {
+                                    done = true;
+                                    continue;
+                                }
+                            }
+                            // a reload, a new torrent or a weight change lowered the cap below what is
+                            // open, or we're both seeds; the least useful connections close first
+                            let cap = connection_cap(&settings.current(), &scheduler.read(), &info_hash);
+                            let (total_pieces, complete, phase) = {
+                                let t = torrent.read();
+                                (t.total_pieces, t.are_we_done_yet(), DownloadPhase::of(&t))
+                            };
+                            let (disconnect, unchoke) = {
+                                let mut manager = connections.lock();
+                                let mut usefulness = PeerUsefulness::of(connection, total_pieces);
+                                manager.update(id, usefulness);
+                                let disconnect = manager.should_disconnect(id, cap, complete);
+                                // decided under the same lock as the count, so two connections can't both take the last slot
+                                usefulness.remote_unchoked = choker.read().unchoke(phase, &usefulness, manager.unchoked_except(id));
+                                manager.update(id, usefulness);
+                                (disconnect, usefulness.remote_unchoked)
+                            };
+                            if let Some(reason) = disconnect {
+                                println!("Disconnecting from {} because {}", connection.peer_addr, reason);
+                                done = true;
+                                continue;
+                            }
+                            if unchoke == connection.is_remote_choked {
+                                connection.is_remote_choked = !unchoke;
+                                let message = if unchoke { Message::UnChoke } else { Message::Choke };
+                                if connection.write_message(message).is_err() {
+                                    done = true;
+                                    continue;
+                                }
+                            }
+                            let message = connection.read_message();
+                            match message {
+                                Ok(message) => {
+                                    let _ = logger.write().log_message(Direction::Incoming, connection.peer_addr, connection.local_addr, &message, &message.serialize());
+                                    let received = match &message {
+                                        Message::Piece { data, .. } => data.len() as u64,
+                                        _ => 0,
+                                    };
+                                    let result = process_message(Arc::clone(&torrent), message, connection);
+                                    if result != MessageResult::Ok {
+                                        println!("got a err for message result which means some odd scenario occurred {:?}", result);
+                                    }
+                                    if received > 0 {
+                                        let rate = scheduler.read().download_share(&info_hash, settings.current().max_download_rate);
+                                        let wait = throttle.lock().consume(Instant::now(), received, rate);
+                                        if !wait.is_zero() {
+                                            sleep(wait);
+                                        }
+                                    }
+                                }
+                                Err(e) => {
+                                    match e {
+                                        MessageParseError::ConnectionRefused => {
+                                            println!("Exiting {:?}", e);
+                                            done = true;
+                                            continue;
+                                        },
+                                        MessageParseError::ConnectionReset => {
+                                            println!("Exiting {:?}", e);
+                                            done = true;
+                                            continue;
+                                        },
+                                        MessageParseError::ConnectionAborted => {
+                                            println!("Exiting {:?}", e);
+                                            done = true;
+                                            continue;
+                                        },
+                                        MessageParseError::WouldBlock => {
+                                            // println!("would block");
+                                        },
+                                        MessageParseError::TimedOut => {
+                                        },
+                                        MessageParseError::ProtocolViolation(violation) => {
+                                            println!("Disconnecting from {} in strict mode: {}", connection.peer_addr, violation);
+                                            done = true;
+                                            continue;
+                                        },
+                                        me => {
+                                            println!("Exiting {:?}", me);
+                                            done = true;
+                                            continue;
+                                        },
+                                    }
+                                }
+                            }
+                            report_piece_changes(&torrent, &events, info_hash, &file_completion, &files);
+                            if announce_pieces(&torrent, connection).is_err() {
+                                done = true;
+                                continue;
+                            }
+                            if !seeding && torrent.read().are_we_done_yet() {
+                                println!("done because torrent said so");
+                                seeding = true;
+                                let seed = seeds_after_completion(&completion_actions.read(), &settings.current());
+                                done = !seed || enter_seed_mode(&torrent, connection).is_err();
+                            }
+                            if seeding && stop_seeding.load(Ordering::SeqCst) {
+                                println!("done seeding to {}", connection.peer_addr);
+                                done = true;
+                            }
+                            if stopped.load(Ordering::SeqCst) {
+                                println!("torrent stopped, closing {}", connection.peer_addr);
+                                done = true;
+                            }
+                        }
+                        abandon_requests(&torrent, connection);
+                        connections.lock().close(id);
+                        println!("a connection has finally exited on its own... still being awaited by main potentially....");
+                };
+                match connection {
+                    Ok(mut connection) => {
+                        let id = self.connections.lock().open();
+                        let torrent = Arc::clone(&self.torrent);
+                        let events = self.events.clone();
+                        let connections = Arc::clone(&self.connections);
+                        let peer_panics = Arc::clone(&self.peer_panics);
+                        Some(spawn(move || {
+                            if let Some(message) = run_with_panic_boundary(&mut connection, &torrent, |connection| work(connection, id)) {
+                                connections.lock().close(id);
+                                peer_panics.fetch_add(1, Ordering::SeqCst);
+                                println!("Disconnecting from {} after a panic: {}", connection.peer_addr, message);
+                                let _ = events.send(SessionEvent::PeerPanicked { info_hash, peer: connection.peer_addr, message });
+                            }
+                        }))
+                    }
+                    Err(e) => {
+                        println!("connection err with client {:?}: {:?}", peer_addr, e);
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<JoinHandle<()>>>()
+    }
+
+    fn connect(&self, peer: Arc<Peer>) -> Result<PeerConnection, SendError> {
+        let logger = self.logger.clone();
+        let stream =
+            TcpStream::connect_timeout(&peer.socket_addr, CONNECTION_TIMEOUT).map(|stream| {
+                let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+                stream
+            });
+        stream
+            .map_err(SendError::Connect)
+            .and_then(|s| {
+                let ticket = self
+                    .handshakes
+                    .enter()
+                    .ok_or(SendError::TooManyPendingHandshakes)?;
+                let connection = PeerConnection::new(
+                    Stream::Tcp(s),
+                    &self.meta_info.info_hash,
+                    self.local_peer_id.as_bytes(),
+                    &peer.id,
+                    Box::new(
+                        move |message: (Message, SocketAddr, SocketAddr), original_bytes: &[u8]| {
+                            let _ = logger.write().log_message(
+                                Direction::Outgoing,
+                                message.1,
+                                message.2,
+                                &message.0,
+                                original_bytes,
+                            );
+                        },
+                    ),
+                );
+                ticket.finish(match &connection {
+                    Ok(_) => HandshakeOutcome::Completed,
+                    Err(SendError::ReturnHandshakeReadTimeOut) => HandshakeOutcome::TimedOut,
+                    Err(_) => HandshakeOutcome::Failed,
+                });
+                connection
+            })
+            .map(|mut connection| {
+                connection.metadata_server =
+                    Some(MetadataServer::new(Arc::clone(&self.info_dictionary)));
+                connection.request_budget = Some(self.request_budget.clone());
+                connection.assignment_audit = Some(self.assignment_audit.clone());
+                connection
+            })
+            .and_then(|connection| {
+                if self.settings.current().strict_protocol {
+                    connection.strict().map_err(SendError::ProtocolViolation)
+                } else {
+                    Ok(connection)
+                }
+            })
+    }
+}
+
+// The tighter of the per-torrent cap and the torrent's share of the session-wide one
+fn connection_cap(
+    settings: &Settings,
+    scheduler: &Scheduler,
+    info_hash: &[u8; 20],
+) -> Option<usize> {
+    let share = scheduler.connection_share(info_hash, settings.max_total_connections);
+    match (settings.max_connections, share) {
+        (Some(max), Some(share)) => Some(max.min(share)),
+        (max, share) => max.or(share),
+    }
+}
+
+// Runs a peer task so that a panic in it (an unwrap on a surprising message, say) costs us that peer
+// and nothing else. The blocks it was still waiting on are handed back for other peers to fetch.
+// Returns the panic message if it panicked.
+fn run_with_panic_boundary(
+    connection: &mut PeerConnection,
+    torrent: &RwLock<Torrent>,
+    work: impl FnOnce(&mut PeerConnection),
+) -> Option<String> {
+    // shared state sits behind parking_lot locks, which are simply released when a panic unwinds
+    // through them instead of being poisoned; the expects inside `Torrent` all fire before it
+    // changes anything, so what the lock guards is still consistent
+    let panic = catch_unwind(AssertUnwindSafe(|| work(connection))).err()?;
+    abandon_requests(torrent, connection);
+    Some(
+        panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string()),
+    )
+}
+
+fn request_blocks(torrent: Arc<RwLock<Torrent>>, connection: &mut PeerConnection) {
+    if !connection.is_choked && connection.is_local_interested && connection.bitfield.is_some() {
+        let in_progress = connection.in_progress_requests;
+        let to_request = MAX_IN_PROGRESS_REQUESTS_PER_CONNECTION - in_progress;
+        let mut t = torrent.write();
+        let mut blocks: Vec<PieceIndexOffsetLength> = vec![];
+        let suggested = connection.suggested_pieces();
+        for _ in 0..to_request {
+            // once the session has enough data in flight, wait for some of it to arrive
+            if let Some(budget) = &connection.request_budget {
+                if !budget.try_reserve(FIXED_BLOCK_SIZE as u64) {
+                    break;
+                }
+            }
+            let bf = connection.bitfield.as_ref().unwrap();
+            match t.pick_next_block(bf, &suggested) {
+                Some((block, reason)) => {
+                    if let Some(audit) = &connection.assignment_audit {
+                        audit.record(connection.peer_addr, &block, reason);
+                    }
+                    blocks.push(block)
+                }
+                None => {
+                    if let Some(budget) = &connection.request_budget {
+                        budget.release(FIXED_BLOCK_SIZE as u64);
+                    }
+                    break;
+                }
+            }
+        }
+        connection.in_progress_requests += blocks.len();
+        for b in blocks {
+            let message = Message::Request {
+                index: b.0,
+                begin: b.1,
+                length: b.2,
+            };
+            connection.write_message(message).unwrap();
+        }
+    }
+}
+
+// Requests the peer will never answer now that we're hanging up on it: the blocks go back to the
+// torrent for other peers and their share of the request budget is freed
+fn abandon_requests(torrent: &RwLock<Torrent>, connection: &mut PeerConnection) {
+    let mut torrent = torrent.write();
+    for (index, offset) in connection.outstanding_requests() {
+        torrent.release_block(index, offset);
+        if let Some(budget) = &connection.request_budget {
+            budget.release(FIXED_BLOCK_SIZE as u64);
+        }
+    }
+    connection.in_progress_requests = 0;
+}
+
+// Applies finished verifications and tells the session about pieces, and files, that changed state
+fn report_piece_changes(
+    torrent: &RwLock<Torrent>,
+    events: &Sender<SessionEvent>,
+    info_hash: [u8; 20],
+    file_completion: &Mutex<FileCompletion>,
+    files: &[PathBuf],
+) {
+    let verified = torrent.write().apply_verifications();
+    for (index, ok) in verified {
+        if !ok {
+            println!(
+                "piece {} failed verification and will be downloaded again",
+                index
+            );
+        }
+    }
+    let changes = torrent.write().take_piece_state_changes();
+    for (index, state) in &changes {
+        let _ = events.send(SessionEvent::PieceStateChanged {
+            info_hash,
+            index: *index,
+            state: *state,
+        });
+    }
+    if !changes.is_empty() {
+        let changed: Vec<u32> = changes.iter().map(|(index, _)| *index).collect();
+        let completed = file_completion.lock().update(&torrent.read(), &changed);
+        for index in completed {
+            let _ = events.send(SessionEvent::FileCompleted {
+                info_hash,
+                index,
+                path: files[index].clone(),
+            });
+        }
+    }
+}
+
+// We're interested in a peer exactly when it has a piece we don't
+fn update_interest(
+    torrent: &Arc<RwLock<Torrent>>,
+    connection: &mut PeerConnection,
+) -> Result<(), SendError> {
+    let interested = match &connection.bitfield {
+        Some(bf) => {
+            let t = torrent.read();
+            (0..t.total_pieces).any(|i| !t.has_piece(i) && bf.is_set(i as usize).unwrap_or(false))
+        }
+        None => false,
+    };
+    if interested != connection.is_local_interested {
+        connection.is_local_interested = interested;
+        let message = if interested {
+            Message::Interested
+        } else {
+            Message::NotInterested
+        };
+        connection.write_message(message)?;
+    }
+    Ok(())
+}
+
+// The first message after the handshake: the pieces we have. With the fast extension none or all
+// of them go as a single Have None or Have All; otherwise it's a bitfield, left out when there's
+// nothing in it. Pieces it covers aren't announced again with Have.
+fn send_availability(
+    torrent: &Arc<RwLock<Torrent>>,
+    connection: &mut PeerConnection,
+) -> Result<(), SendError> {
+    let (total_pieces, available) = {
+        let t = torrent.read();
+        (t.total_pieces, t.available_pieces_since(0).to_vec())
+    };
+    connection.announced_pieces = available.len();
+    let message = if connection.fast_extension && available.is_empty() {
+        Message::HaveNone
+    } else if connection.fast_extension && available.len() == total_pieces as usize {
+        Message::HaveAll
+    } else if available.is_empty() {
+        return Ok(());
+    } else {
+        let mut bitfield = BitField::from(vec![0u8; (total_pieces as usize).div_ceil(8)]);
+        for index in available {
+            bitfield.set(index as usize);
+        }
+        Message::BitField(bitfield.into())
+    };
+    connection.write_message(message)
+}
+
+// Sends Have for every piece that became available since the last call, skipping the ones the
+// peer already has
+fn announce_pieces(
+    torrent: &Arc<RwLock<Torrent>>,
+    connection: &mut PeerConnection,
+) -> Result<(), SendError> {
+    let new_pieces = torrent
+        .read()
+        .available_pieces_since(connection.announced_pieces)
+        .to_vec();
+    connection.announced_pieces += new_pieces.len();
+    for index in new_pieces {
+        let peer_has = connection
+            .bitfield
+            .as_ref()
+            .map(|bf| bf.is_set(index as usize).unwrap_or(false))
+            .unwrap_or(false);
+        if !peer_has {
+            connection.write_message(Message::Have { index })?;
+        }
+    }
+    Ok(())
+}
+
+// Called once per connection when the download completes: stop asking the peer for anything,
+// tell it about every piece it is missing and start serving it if it wants data from us
+fn enter_seed_mode(
+    torrent: &Arc<RwLock<Torrent>>,
+    connection: &mut PeerConnection,
+) -> Result<(), SendError> {
+    update_interest(torrent, connection)?;
+    announce_pieces(torrent, connection)?;
+    if connection.is_remote_interested && connection.is_remote_choked {
+        connection.is_remote_choked = false;
+        connection.write_message(Message::UnChoke)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn process_message(
+    torrent: Arc<RwLock<Torrent>>,
+    message: Message,
+    connection: &mut PeerConnection,
+) -> MessageResult {
+    match message {
+        Message::KeepAlive => {
+            connection.write_message(Message::KeepAlive).unwrap();
+            MessageResult::Ok
+        }
+        Message::Choke => {
+            connection.is_choked = true;
+            MessageResult::Ok
+        }
+        Message::UnChoke => {
+            connection.is_choked = false;
+            request_blocks(torrent, connection);
+            MessageResult::Ok
+        }
+        Message::Interested => {
+            connection.is_remote_interested = true;
+            if connection.is_remote_choked && torrent.read().are_we_done_yet() {
+                connection.is_remote_choked = false;
+                connection.write_message(Message::UnChoke).unwrap();
+            }
+            MessageResult::Ok
+        }
+        Message::NotInterested => {
+            connection.is_remote_interested = false;
+            MessageResult::Ok
+        }
+        Message::Have { index } => {
+            let total_pieces = torrent.read().total_pieces;
+            if index >= total_pieces {
+                MessageResult::BadPeerHave
+            } else {
+                connection
+                    .bitfield
+                    .get_or_insert_with(|| {
+                        BitField::from(vec![0u8; (total_pieces as usize).div_ceil(8)])
+                    })
+                    .set(index as usize);
+                update_interest(&torrent, connection).unwrap();
+                MessageResult::Ok
+            }
+        }
+        Message::BitField(bf) => {
+            connection.bitfield = Some(bf.into());
+            update_interest(&torrent, connection).unwrap();
+            MessageResult::Ok
+        }
+        Message::HaveAll | Message::HaveNone => {
+            let total_pieces = torrent.read().total_pieces as usize;
+            let mut bitfield = BitField::from(vec![0u8; total_pieces.div_ceil(8)]);
+            if matches!(message, Message::HaveAll) {
+                for index in 0..total_pieces {
+                    bitfield.set(index);
+                }
+            }
+            connection.bitfield = Some(bitfield);
+            update_interest(&torrent, connection).unwrap();
+            MessageResult::Ok
+        }
+        Message::RejectRequest { index, begin, .. } => {
+            if connection.reject(index, begin) {
+                torrent.write().release_block(index, begin);
+                connection.in_progress_requests = connection.in_progress_requests.saturating_sub(1);
+                if let Some(budget) = &connection.request_budget {
+                    budget.release(FIXED_BLOCK_SIZE as u64);
+                }
+            }
+            MessageResult::Ok
+        }
+        // we never ask for anything while choked, so there's nothing to do with the hint
+        Message::AllowedFast { .. } => MessageResult::Ok,
+        Message::Request {
+            index,
+            begin,
+            length,
+        } => {
+            if index >= torrent.read().total_pieces {
+                return MessageResult::BadPeerRequest;
+            }
+            let reject = Message::RejectRequest {
+                index,
+                begin,
+                length,
+            };
+            if connection.is_remote_choked {
+                if connection.fast_extension {
+                    connection.write_message(reject).unwrap();
+                }
+                return MessageResult::Ok;
+            }
+            let data = torrent
+                .read()
+                .read_block(index, begin, length)
+                .map(|data| data.to_vec());
+            match data {
+                Some(data) => {
+                    {
+                        let mut t = torrent.write();
+                        t.uploaded_bytes += data.len() as u64;
+                        t.heatmap.record(index, data.len() as u64);
+                    }
+                    connection
+                        .write_message(Message::Piece {
+                            index,
+                            offset: begin,
+                            data,
+                        })
+                        .unwrap();
+                    MessageResult::Ok
+                }
+                None => {
+                    if connection.fast_extension {
+                        connection.write_message(reject).unwrap();
+                    }
+                    MessageResult::BadPeerRequest
+                }
+            }
+        }
+        Message::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload,
+        } => match ExtendedHandshake::from_payload(&payload) {
+            Some(theirs) => {
+                connection.remote_ut_metadata = theirs.ut_metadata;
+                // answer with ours so the peer knows it can fetch metadata from us
+                if let Some(server) = &connection.metadata_server {
+                    let ours = ExtendedHandshake {
+                        ut_metadata: Some(UT_METADATA_ID),
+                        metadata_size: Some(server.metadata_size()),
+                    };
+                    connection
+                        .write_message(Message::Extended {
+                            id: EXTENDED_HANDSHAKE_ID,
+                            payload: ours.to_payload(),
+                        })
+                        .unwrap();
+                }
+                MessageResult::Ok
+            }
+            None => MessageResult::BadPeerExtended,
+        },
+        Message::Extended {
+            id: UT_METADATA_ID,
+            payload,
+        } => {
+            let reply = match (
+                MetadataMessage::parse(&payload),
+                connection.remote_ut_metadata,
+                connection.metadata_server.as_mut(),
+            ) {
+                (Some(MetadataMessage::Request { piece }), Some(id), Some(server)) => {
+                    (id, server.respond(Instant::now(), piece))
+                }
+                _ => return MessageResult::BadPeerExtended,
+            };
+            connection
+                .write_message(Message::Extended {
+                    id: reply.0,
+                    payload: reply.1.serialize(),
+                })
+                .unwrap();
+            MessageResult::Ok
+        }
+        // extensions we never advertised
+        Message::Extended { .. } => MessageResult::Ok,
+        // only a hint; pieces the peer doesn't have or we already have are ignored when picking
+        Message::SuggestPiece { index } => {
+            if index >= torrent.read().total_pieces {
+                return MessageResult::BadPeerSuggestPiece;
+            }
+            connection.suggest(index);
+            MessageResult::Ok
+        }
+        Message::Piece {
+            index,
+            offset,
+            data,
+        } => {
+            // a block we never asked this peer for, or already gave up on, is neither stored nor
+            // holds any of the request budget
+            if data.is_empty() || !connection.answered(index, offset) {
+                return MessageResult::BadPeerPiece;
+            }
+            torrent.write().fill_block((index, offset, &data));
+            connection.in_progress_requests = connection.in_progress_requests.saturating_sub(1);
+            if let Some(budget) = &connection.request_budget {
+                budget.release(FIXED_BLOCK_SIZE as u64);
+            }
+            request_blocks(torrent, connection);
+            MessageResult::Ok
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimulatedContent;
+    use crate::util;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+
+    const INFO_HASH: [u8; 20] = [4u8; 20];
+    const PEER_ID: &[u8; 20] = b"-XX0001-remotepeer00";
+
+    // A fake peer that answers the handshake and hands back every message we sent it once we hang up
+    fn connect() -> (PeerConnection, Receiver<Vec<Message>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = channel();
+        spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 68];
+            stream.read_exact(&mut buf).unwrap();
+            let handshake = Handshake {
+                info_hash: INFO_HASH.to_vec(),
+                peer_id: PEER_ID.to_vec(),
+                fast_extension: false,
+            };
+            stream.write_all(&handshake.serialize()).unwrap();
+            let mut bytes = vec![];
+            let _ = stream.read_to_end(&mut bytes);
+            let mut messages = vec![];
+            let mut rest = bytes;
+            while rest.len() >= 4 {
+                let prefix_len = util::read_be_u32(&mut &rest[..4]).unwrap();
+                let next = rest.split_off(4 + prefix_len as usize);
+                let message = rest.split_off(4);
+                messages.push(Message::new(Box::new(message.into_iter()), prefix_len).unwrap());
+                rest = next;
+            }
+            let _ = sender.send(messages);
+        });
+        let connection = PeerConnection::new(
+            Stream::Tcp(TcpStream::connect(addr).unwrap()),
+            &INFO_HASH,
+            b"-BT0001-localpeer000",
+            PEER_ID,
+            Box::new(|_, _| {}),
+        )
+        .unwrap();
+        (connection, receiver)
+    }
+
+    fn completed_torrent() -> Arc<RwLock<Torrent>> {
+        let content = SimulatedContent {
+            number_of_pieces: 2,
+            piece_length: 16384,
+            total_length: 16384 + 100,
+        };
+        let mut torrent = Torrent::new(&content);
+        let all = BitField::from(vec![0b1100_0000]);
+        while let Some(PieceIndexOffsetLength(index, offset, length)) = torrent.get_next_block(&all)
+        {
+            torrent.fill_block((index, offset, &vec![index as u8 + 1; length as usize]));
+        }
+        assert!(torrent.are_we_done_yet());
+        Arc::new(RwLock::new(torrent))
+    }
+
+    #[test]
+    fn it_switches_to_seeding_once_the_download_completes() {
+        let torrent = completed_torrent();
+        let (mut connection, sent) = connect();
+        connection.is_local_interested = true;
+        connection.bitfield = Some(BitField::from(vec![0b1000_0000]));
+
+        enter_seed_mode(&torrent, &mut connection).unwrap();
+        assert!(!connection.is_local_interested);
+        process_message(Arc::clone(&torrent), Message::Interested, &mut connection);
+        assert!(!connection.is_remote_choked);
+        let result = process_message(
+            Arc::clone(&torrent),
+            Message::Request {
+                index: 1,
+                begin: 0,
+                length: 100,
+            },
+            &mut connection,
+        );
+        assert_eq!(result, MessageResult::Ok);
+        assert_eq!(torrent.read().uploaded_bytes, 100);
+        assert_eq!(torrent.read().heatmap.requests(), &[0, 1]);
+        drop(connection);
+
+        let sent: Vec<String> = sent.recv().unwrap().iter().map(|m| m.to_string()).collect();
+        assert_eq!(
+            sent,
+            vec![
+                "NotIntereseted",
+                "Have { 1 }",
+                "UnChoke",
+                "Piece { index: 1, offset: 0 }"
+            ]
+        );
+    }
+
+    #[test]
+    fn it_sends_have_all_instead_of_a_bitfield_with_the_fast_extension() {
+        let torrent = completed_torrent();
+        let (mut connection, sent) = connect();
+        connection.fast_extension = true;
+        send_availability(&torrent, &mut connection).unwrap();
+        announce_pieces(&torrent, &mut connection).unwrap();
+        // still choked, so the request is turned down rather than ignored
+        process_message(
+            Arc::clone(&torrent),
+            Message::Request {
+                index: 1,
+                begin: 0,
+                length: 100,
+            },
+            &mut connection,
+        );
+        drop(connection);
+        let sent: Vec<String> = sent.recv().unwrap().iter().map(|m| m.to_string()).collect();
+        assert_eq!(
+            sent,
+            vec![
+                "HaveAll",
+                "RejectRequest { index: 1, begin: 0, length: 100 }"
+            ]
+        );
+
+        let (mut connection, sent) = connect();
+        send_availability(&torrent, &mut connection).unwrap();
+        drop(connection);
+        let sent: Vec<String> = sent.recv().unwrap().iter().map(|m| m.to_string()).collect();
+        assert_eq!(sent, vec!["BitField"]);
+
+        let content = SimulatedContent {
+            number_of_pieces: 2,
+            piece_length: 16384,
+            total_length: 16384 + 100,
+        };
+        let empty = Arc::new(RwLock::new(Torrent::new(&content)));
+        let (mut connection, _sent) = connect();
+        process_message(Arc::clone(&empty), Message::HaveAll, &mut connection);
+        assert!(connection.is_local_interested);
+    }
+
+    #[test]
+    fn it_disconnects_a_panicking_peer_and_recovers_its_blocks() {
+        let content = SimulatedContent {
+            number_of_pieces: 2,
+            piece_length: 16384,
+            total_length: 16384 + 100,
+        };
+        let torrent = Arc::new(RwLock::new(Torrent::new(&content)));
+        let (mut connection, _sent) = connect();
+        connection.is_choked = false;
+        connection.is_local_interested = true;
+        connection.bitfield = Some(BitField::from(vec![0b1100_0000]));
+        request_blocks(Arc::clone(&torrent), &mut connection);
+
+        let message = run_with_panic_boundary(&mut connection, &torrent, |_| {
+            let _held = torrent.write();
+            panic!("peer sent nonsense");
+        });
+        assert_eq!(message.as_deref(), Some("peer sent nonsense"));
+        assert!(torrent.try_write().is_some());
+        // the block the peer still owed us can be fetched from someone else
+        let only_first = BitField::from(vec![0b1000_0000]);
+        assert!(matches!(
+            torrent.write().get_next_block(&only_first),
+            Some(PieceIndexOffsetLength(0, 0, _))
+        ));
+        assert_eq!(
+            run_with_panic_boundary(&mut connection, &torrent, |_| {}),
+            None
+        );
+    }
+
+    #[test]
+    fn it_audits_why_each_block_was_picked() {
+        let content = SimulatedContent {
+            number_of_pieces: 2,
+            piece_length: 16384,
+            total_length: 16384 + 100,
+        };
+        let torrent = Arc::new(RwLock::new(Torrent::new(&content)));
+        let audit = AssignmentAudit::new();
+        let assignments = audit.subscribe();
+        let (mut connection, _sent) = connect();
+        connection.is_choked = false;
+        connection.is_local_interested = true;
+        connection.bitfield = Some(BitField::from(vec![0b1100_0000]));
+        connection.assignment_audit = Some(audit.for_torrent(INFO_HASH));
+
+        request_blocks(Arc::clone(&torrent), &mut connection);
+        let first = assignments.try_recv().unwrap();
+        assert_eq!(
+            (first.info_hash, first.index, first.reason),
+            (INFO_HASH, 0, PickReason::FirstAvailable)
+        );
+        assert_eq!(first.peer, connection.peer_addr);
+
+        abandon_requests(&torrent, &mut connection);
+        connection.suggest(1);
+        request_blocks(Arc::clone(&torrent), &mut connection);
+        let second = assignments.try_recv().unwrap();
+        assert_eq!((second.index, second.reason), (1, PickReason::Suggested));
+    }
+
+    #[test]
+    fn it_holds_back_requests_once_the_request_budget_is_spent() {
+        let content = SimulatedContent {
+            number_of_pieces: 2,
+            piece_length: 16384,
+            total_length: 16384 + 100,
+        };
+        let torrent = Arc::new(RwLock::new(Torrent::new(&content)));
+        let budget = RequestBudget::new(FIXED_BLOCK_SIZE as u64);
+        let (mut connection, sent) = connect();
+        connection.is_choked = false;
+        connection.is_local_interested = true;
+        connection.bitfield = Some(BitField::from(vec![0b1100_0000]));
+        connection.request_budget = Some(budget.clone());
+
+        assert!(budget.try_reserve(1));
+        request_blocks(Arc::clone(&torrent), &mut connection);
+        assert_eq!(connection.in_progress_requests, 0);
+
+        budget.release(1);
+        request_blocks(Arc::clone(&torrent), &mut connection);
+        assert_eq!(connection.in_progress_requests, 1);
+        assert_eq!(budget.outstanding(), FIXED_BLOCK_SIZE as u64);
+
+        abandon_requests(&torrent, &mut connection);
+        assert_eq!(budget.outstanding(), 0);
+        drop(connection);
+        let sent: Vec<String> = sent.recv().unwrap().iter().map(|m| m.to_string()).collect();
+        assert_eq!(sent.len(), 1);
+    }
+
+    #[test]
+    fn it_only_gives_back_the_budget_of_blocks_it_asked_for() {
+        let content = SimulatedContent {
+            number_of_pieces: 2,
+            piece_length: 16384,
+            total_length: 16384 + 100,
+        };
+        let torrent = Arc::new(RwLock::new(Torrent::new(&content)));
+        let budget = RequestBudget::new(FIXED_BLOCK_SIZE as u64);
+        let (mut connection, _sent) = connect();
+        connection.is_choked = false;
+        connection.is_local_interested = true;
+        connection.bitfield = Some(BitField::from(vec![0b1000_0000]));
+        connection.request_budget = Some(budget.clone());
+        request_blocks(Arc::clone(&torrent), &mut connection);
+        assert_eq!(connection.in_progress_requests, 1);
+
+        let piece = |index, length| Message::Piece {
+            index,
+            offset: 0,
+            data: vec![0; length],
+        };
+        assert_eq!(
+            process_message(Arc::clone(&torrent), piece(1, 100), &mut connection),
+            MessageResult::BadPeerPiece
+        );
+        assert_eq!(connection.in_progress_requests, 1);
+        assert_eq!(budget.outstanding(), FIXED_BLOCK_SIZE as u64);
+
+        // the block asked for, then the same block again
+        for _ in 0..2 {
+            process_message(Arc::clone(&torrent), piece(0, 16384), &mut connection);
+            assert_eq!(connection.in_progress_requests, 0);
+            assert_eq!(budget.outstanding(), 0);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use crate::connection::{PeerConnection, SendError, Stream};
+use crate::messages::{Handshake, MessageParseError};
+use crate::processor::process_message;
+use crate::torrent::Torrent;
+use parking_lot::RwLock;
+use sha1::{Digest, Sha1};
+use std::collections::VecDeque;
+use std::io::{Error as IOError, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+const HANDSHAKE_LEN: usize = 68;
+
+#[derive(Debug)]
+pub enum ReplayError {
+    // 1-based line number in the capture
+    MalformedRecord { line: usize },
+    ChecksumMismatch { line: usize },
+    NoMessagesFromPeer,
+}
+
+// One peer's side of a JSON lines capture (`LOG_FORMAT=jsonl`) played back as if it were live.
+// Captures don't contain handshakes, so the peer answers ours with the same info hash and the
+// peer id it was given; after that it hands out the captured incoming messages in order and then
+// reports end of file. Whatever the engine writes is ignored once the handshake is answered.
+#[derive(Debug)]
+pub struct ReplayPeer {
+    pub peer_addr: SocketAddr,
+    pub local_addr: SocketAddr,
+    peer_id: Vec<u8>,
+    incoming: VecDeque<u8>,
+    readable: VecDeque<u8>,
+    written: Vec<u8>,
+    handshake_done: bool,
+}
+
+impl ReplayPeer {
+    // Replays the messages `peer` sent, or the first peer in the capture when `peer` is None
+    pub fn from_capture(
+        capture: &str,
+        peer: Option<SocketAddr>,
+        peer_id: &[u8],
+    ) -> Result<Self, ReplayError> {
+        let mut addrs: Option<(SocketAddr, SocketAddr)> = None;
+        let mut incoming = VecDeque::new();
+        for (i, line) in capture.lines().enumerate() {
+            let line_number = i + 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record =
+                Record::parse(line).ok_or(ReplayError::MalformedRecord { line: line_number })?;
+            if hex::encode(Sha1::digest(&record.bytes)) != record.checksum {
+                return Err(ReplayError::ChecksumMismatch { line: line_number });
+            }
+            if !record.incoming {
+                continue;
+            }
+            let wanted = peer.or(addrs.map(|(p, _)| p)).unwrap_or(record.peer);
+            if record.peer != wanted {
+                continue;
+            }
+            addrs.get_or_insert((record.peer, record.local));
+            incoming.extend(record.bytes);
+        }
+        let (peer_addr, local_addr) = addrs.ok_or(ReplayError::NoMessagesFromPeer)?;
+        Ok(ReplayPeer {
+            peer_addr,
+            local_addr,
+            peer_id: peer_id.to_vec(),
+            incoming,
+            readable: VecDeque::new(),
+            written: vec![],
+            handshake_done: false,
+        })
+    }
+}
+
+impl std::io::Write for ReplayPeer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+        self.written.extend_from_slice(buf);
+        if !self.handshake_done && self.written.len() >= HANDSHAKE_LEN {
+            let theirs = Handshake::new(&self.written[..HANDSHAKE_LEN])
+                .map_err(|e| IOError::new(ErrorKind::InvalidData, format!("{:?}", e)))?;
+            let ours = Handshake {
+                info_hash: theirs.info_hash,
+                peer_id: self.peer_id.clone(),
+                fast_extension: false,
+            };
+            self.readable.extend(ours.serialize());
+            self.readable.extend(self.incoming.drain(..));
+            self.handshake_done = true;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), IOError> {
+        Ok(())
+    }
+}
+
+impl std::io::Read for ReplayPeer {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+        if !self.handshake_done {
+            return Err(IOError::from(ErrorKind::WouldBlock));
+        }
+        let n = buf.len().min(self.readable.len());
+        for (slot, byte) in buf.iter_mut().zip(self.readable.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+struct Record {
+    incoming: bool,
+    peer: SocketAddr,
+    local: SocketAddr,
+    checksum: String,
+    bytes: Vec<u8>,
+}
+
+impl Record {
+    fn parse(line: &str) -> Option<Self> {
+        Some(Record {
+            incoming: match string_field(line, "direction")? {
+                "in" => true,
+                "out" => false,
+                _ => return None,
+            },
+            peer: string_field(line, "peer")?.parse().ok()?,
+            local: string_field(line, "local")?.parse().ok()?,
+            checksum: string_field(line, "checksum")?.to_string(),
+            bytes: hex::decode(string_field(line, "bytes")?).ok()?,
+        })
+    }
+}
+
+// Only understands the flat records `Logger` writes, where no string value contains a quote
+fn string_field<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!("\"{}\":\"", name);
+    let start = line.find(&key)? + key.len();
+    let end = start + line[start..].find('"')?;
+    Some(&line[start..end])
+}
+
+#[derive(Debug)]
+pub struct ReplayReport {
+    pub messages_replayed: usize,
+    // messages the engine considered bad (see `MessageResult`)
+    pub rejected_messages: Vec<String>,
+    // why the replay stopped; UnexpectedEof means the capture simply ran out
+    pub ended_with: MessageParseError,
+}
+
+// Runs the captured messages through the same `process_message` the live engine uses
+pub fn replay(
+    peer: ReplayPeer,
+    info_hash: &[u8],
+    torrent: Arc<RwLock<Torrent>>,
+) -> Result<ReplayReport, SendError> {
+    let peer_id = peer.peer_id.clone();
+    let mut connection = PeerConnection::new(
+        Stream::Replay(Box::new(peer)),
+        info_hash,
+        b"-BT0001-replay000000",
+        &peer_id,
+        Box::new(|_, _| {}),
+    )?;
+    let mut messages_replayed = 0;
+    let mut rejected_messages = vec![];
+    let ended_with = loop {
+        match connection.read_message() {
+            Ok(message) => {
+                messages_replayed += 1;
+                let description = message.to_string();
+                let result = process_message(Arc::clone(&torrent), message, &mut connection);
+                if result != crate::processor::MessageResult::Ok {
+                    rejected_messages.push(format!("{} ({:?})", description, result));
+                }
+            }
+            Err(e) => break e,
+        }
+    };
+    Ok(ReplayReport {
+        messages_replayed,
+        rejected_messages,
+        ended_with,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::{Direction, LogFormat, Logger};
+    use crate::messages::Message;
+    use crate::torrent::PiecedContent;
+
+    struct Content;
+
+    impl PiecedContent for Content {
+        fn number_of_pieces(&self) -> u32 {
+            2
+        }
+
+        fn piece_length(&self) -> u32 {
+            16384
+        }
+
+        fn total_length(&self) -> u64 {
+            16384 + 100
+        }
+    }
+
+    fn capture(messages: Vec<(Direction, &str, Message)>) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "bit_torrent_replay_{}.jsonl",
+            crate::util::random_string()
+        ));
+        let mut logger = Logger::new(path.to_str().unwrap(), LogFormat::JsonLines);
+        for (direction, peer, message) in messages {
+            let bytes = message.serialize();
+            logger
+                .log_message(
+                    direction,
+                    peer.parse().unwrap(),
+                    "127.0.0.1:8999".parse().unwrap(),
+                    &message,
+                    &bytes,
+                )
+                .unwrap();
+        }
+        let capture = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        capture
+    }
+
+    #[test]
+    fn it_replays_a_captured_download() {
+        let seed = "10.0.0.1:6881";
+        let capture = capture(vec![
+            (
+                Direction::Incoming,
+                seed,
+                Message::BitField(vec![0b1100_0000]),
+            ),
+            (Direction::Outgoing, seed, Message::Interested),
+            (Direction::Incoming, "10.0.0.2:6881", Message::Choke),
+            (Direction::Incoming, seed, Message::UnChoke),
+            (
+                Direction::Incoming,
+                seed,
+                Message::Piece {
+                    index: 0,
+                    offset: 0,
+                    data: vec![1; 16384],
+                },
+            ),
+            (
+                Direction::Incoming,
+                seed,
+                Message::Piece {
+                    index: 1,
+                    offset: 0,
+                    data: vec![2; 100],
+                },
+            ),
+        ]);
+        let peer = ReplayPeer::from_capture(&capture, None, b"-XX0001-capturedpeer").unwrap();
+        assert_eq!(peer.peer_addr, seed.parse().unwrap());
+
+        let torrent = Arc::new(RwLock::new(Torrent::new(&Content)));
+        let report = replay(peer, &[5u8; 20], Arc::clone(&torrent)).unwrap();
+
+        assert_eq!(report.messages_replayed, 4);
+        assert!(report.rejected_messages.is_empty());
+        assert!(matches!(
+            report.ended_with,
+            MessageParseError::UnexpectedEof
+        ));
+        assert!(torrent.read().are_we_done_yet());
+    }
+
+    #[test]
+    fn it_rejects_tampered_captures() {
+        let capture = capture(vec![(
+            Direction::Incoming,
+            "10.0.0.1:6881",
+            Message::Have { index: 1 },
+        )])
+        .replace(
+            "\"bytes\":\"000000050400000001\"",
+            "\"bytes\":\"000000050400000002\"",
+        );
+        assert!(matches!(
+            ReplayPeer::from_capture(&capture, None, b"-XX0001-capturedpeer"),
+            Err(ReplayError::ChecksumMismatch { line: 1 })
+        ));
+    }
+}
@@ -0,0 +1,274 @@
+use crate::torrent::{PickReason, PieceIndexOffsetLength};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_WEIGHT: u32 = 1;
+pub const DEFAULT_REQUEST_BUDGET: u64 = 64 * 1024 * 1024;
+
+// Splits the session-wide connection and download budgets between running torrents in proportion
+// to their weights, so the torrent that happened to start first doesn't take everything. Weights
+// can be changed at any time; torrents pick up their new share the next time they ask.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    // ordered so leftover units always go to the same torrents
+    weights: BTreeMap<[u8; 20], u32>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler::default()
+    }
+
+    pub fn register(&mut self, info_hash: [u8; 20]) {
+        self.weights.entry(info_hash).or_insert(DEFAULT_WEIGHT);
+    }
+
+    pub fn unregister(&mut self, info_hash: &[u8; 20]) {
+        self.weights.remove(info_hash);
+    }
+
+    // Weights below 1 are treated as 1; there is no pausing a torrent through its weight
+    pub fn set_weight(&mut self, info_hash: [u8; 20], weight: u32) {
+        self.weights.insert(info_hash, weight.max(1));
+    }
+
+    pub fn weight(&self, info_hash: &[u8; 20]) -> Option<u32> {
+        self.weights.get(info_hash).copied()
+    }
+
+    // How many of `budget` connections the torrent may hold; None when there is no budget. Shares
+    // are rounded down and the units left over handed out one each by largest remainder, so the
+    // shares always add up to the whole budget.
+    pub fn connection_share(&self, info_hash: &[u8; 20], budget: Option<usize>) -> Option<usize> {
+        budget.map(|budget| self.share(info_hash, budget as u64) as usize)
+    }
+
+    // Bytes per second out of a session-wide download rate
+    pub fn download_share(&self, info_hash: &[u8; 20], budget: Option<u64>) -> Option<u64> {
+        budget.map(|budget| self.share(info_hash, budget))
+    }
+
+    fn share(&self, info_hash: &[u8; 20], budget: u64) -> u64 {
+        if !self.weights.contains_key(info_hash) {
+            return budget;
+        }
+        let total: u64 = self.weights.values().map(|w| *w as u64).sum();
+        let mut shares: Vec<([u8; 20], u64, u64)> = self
+            .weights
+            .iter()
+            .map(|(hash, weight)| {
+                let exact = budget * *weight as u64;
+                (*hash, exact / total, exact % total)
+            })
+            .collect();
+        let handed_out: u64 = shares.iter().map(|(_, share, _)| share).sum();
+        // stable, so ties go to the lower info hash
+        shares.sort_by_key(|(_, _, remainder)| std::cmp::Reverse(*remainder));
+        for (_, share, _) in shares.iter_mut().take((budget - handed_out) as usize) {
+            *share += 1;
+        }
+        shares
+            .iter()
+            .find(|(hash, _, _)| hash == info_hash)
+            .map(|(_, share, _)| *share)
+            .unwrap_or(0)
+    }
+}
+
+// Keeps one torrent's downloads under its rate. Every block received reserves the next
+// `bytes / rate` seconds of transfer time and the connection that received it waits until its
+// reservation is over, so however many connections share the throttle they add up to the rate.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    next_free: Option<Instant>,
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Throttle::default()
+    }
+
+    // How long to wait after receiving `bytes` at `now`
+    pub fn consume(&mut self, now: Instant, bytes: u64, rate: Option<u64>) -> Duration {
+        let rate = match rate {
+            Some(rate) => rate.max(1),
+            None => {
+                self.next_free = None;
+                return Duration::ZERO;
+            }
+        };
+        let start = self.next_free.map_or(now, |next_free| next_free.max(now));
+        let next_free = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+        self.next_free = Some(next_free);
+        next_free.saturating_duration_since(now)
+    }
+}
+
+// Bounds the data we've asked peers for but haven't received yet, across every connection of every
+// torrent, so a burst of arriving blocks can't outrun the memory we're willing to spend on them.
+// Connections reserve before each request and release once the block arrives or the request is
+// abandoned. Clones share the same budget.
+#[derive(Debug, Clone)]
+pub struct RequestBudget {
+    limit: u64,
+    outstanding: Arc<AtomicU64>,
+}
+
+impl RequestBudget {
+    pub fn new(limit: u64) -> Self {
+        RequestBudget {
+            limit,
+            outstanding: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn try_reserve(&self, bytes: u64) -> bool {
+        self.outstanding
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |outstanding| {
+                (outstanding + bytes <= self.limit).then(|| outstanding + bytes)
+            })
+            .is_ok()
+    }
+
+    pub fn release(&self, bytes: u64) {
+        let _ = self
+            .outstanding
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |outstanding| {
+                Some(outstanding.saturating_sub(bytes))
+            });
+    }
+
+    pub fn outstanding(&self) -> u64 {
+        self.outstanding.load(Ordering::SeqCst)
+    }
+}
+
+// One block handed to one peer, and why the picker chose it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockAssignment {
+    pub info_hash: [u8; 20],
+    pub peer: SocketAddr,
+    pub index: u32,
+    pub offset: u32,
+    pub length: u32,
+    pub reason: PickReason,
+}
+
+// A debug stream of `BlockAssignment`s for tuning the picker. Off until someone subscribes, and off
+// again once the subscriber's receiver is dropped. Clones share the subscriber.
+#[derive(Debug, Clone, Default)]
+pub struct AssignmentAudit {
+    info_hash: [u8; 20],
+    subscriber: Arc<Mutex<Option<Sender<BlockAssignment>>>>,
+}
+
+impl AssignmentAudit {
+    pub fn new() -> Self {
+        AssignmentAudit::default()
+    }
+
+    // Replaces any earlier subscriber
+    pub fn subscribe(&self) -> Receiver<BlockAssignment> {
+        let (sender, receiver) = channel();
+        *self.subscriber.lock() = Some(sender);
+        receiver
+    }
+
+    // A clone that tags what it records with `info_hash`
+    pub fn for_torrent(&self, info_hash: [u8; 20]) -> Self {
+        AssignmentAudit {
+            info_hash,
+            subscriber: Arc::clone(&self.subscriber),
+        }
+    }
+
+    pub fn record(&self, peer: SocketAddr, block: &PieceIndexOffsetLength, reason: PickReason) {
+        let mut subscriber = self.subscriber.lock();
+        let Some(sender) = subscriber.as_ref() else {
+            return;
+        };
+        let PieceIndexOffsetLength(index, offset, length) = *block;
+        let assignment = BlockAssignment {
+            info_hash: self.info_hash,
+            peer,
+            index,
+            offset,
+            length,
+            reason,
+        };
+        if sender.send(assignment).is_err() {
+            *subscriber = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_splits_budgets_by_weight() {
+        let mut scheduler = Scheduler::new();
+        let (a, b, c) = ([1u8; 20], [2u8; 20], [3u8; 20]);
+        scheduler.register(a);
+        assert_eq!(scheduler.connection_share(&a, Some(10)), Some(10));
+        assert_eq!(scheduler.connection_share(&a, None), None);
+
+        scheduler.register(b);
+        scheduler.register(c);
+        let shares = |s: &Scheduler| {
+            [a, b, c]
+                .iter()
+                .map(|hash| s.connection_share(hash, Some(10)).unwrap())
+                .collect::<Vec<usize>>()
+        };
+        assert_eq!(shares(&scheduler), vec![4, 3, 3]);
+
+        scheduler.set_weight(c, 3);
+        assert_eq!(shares(&scheduler), vec![2, 2, 6]);
+        assert_eq!(scheduler.download_share(&c, Some(1000)), Some(600));
+
+        scheduler.unregister(&a);
+        assert_eq!(scheduler.connection_share(&b, Some(10)), Some(3));
+        assert_eq!(scheduler.connection_share(&c, Some(10)), Some(7));
+    }
+
+    #[test]
+    fn it_throttles_to_the_rate_across_callers() {
+        let mut throttle = Throttle::new();
+        let now = Instant::now();
+        assert_eq!(
+            throttle.consume(now, 1000, Some(1000)),
+            Duration::from_secs(1)
+        );
+        // a second connection receiving at the same moment waits behind the first
+        assert_eq!(
+            throttle.consume(now, 500, Some(1000)),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(
+            throttle.consume(now + Duration::from_secs(5), 1000, Some(1000)),
+            Duration::from_secs(1)
+        );
+        assert_eq!(throttle.consume(now, 1000, None), Duration::ZERO);
+    }
+
+    #[test]
+    fn it_bounds_outstanding_request_bytes() {
+        let budget = RequestBudget::new(40000);
+        let shared = budget.clone();
+        assert!(budget.try_reserve(16384));
+        assert!(shared.try_reserve(16384));
+        assert!(!budget.try_reserve(16384));
+        assert_eq!(budget.outstanding(), 32768);
+        shared.release(16384);
+        assert!(budget.try_reserve(16384));
+        budget.release(100000);
+        assert_eq!(shared.outstanding(), 0);
+    }
+}
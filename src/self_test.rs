@@ -0,0 +1,224 @@
+use crate::meta_info_file::MetaInfoFile;
+use crate::session::{Session, SessionError, SessionEvent};
+use crate::storage::StorageError;
+use crate::test_seeder::{SeederProfile, TestSeeder};
+use crate::test_tracker::TestHttpTracker;
+use crate::torrent_builder::{BuildError, TorrentBuilder};
+use crate::util::random_string;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use std::io::Error as IOError;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// a few MiB with a short last piece, so the odd-sized tail gets exercised too
+pub const DEFAULT_SIZE: usize = 4 * 1024 * 1024 + 1234;
+pub const DEFAULT_PIECE_LENGTH: u32 = 64 * 1024;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug)]
+pub enum SelfTestError {
+    Io(IOError),
+    Build(BuildError),
+    Session(SessionError),
+    // the session gave up writing the downloaded file
+    Storage(StorageError),
+    // no `DownloadComplete` before the timeout
+    TimedOut,
+    // the file written differs from the generated data from this byte on
+    Mismatch { offset: u64 },
+}
+
+impl From<IOError> for SelfTestError {
+    fn from(e: IOError) -> Self {
+        SelfTestError::Io(e)
+    }
+}
+
+#[derive(Debug)]
+pub struct SelfTestReport {
+    pub info_hash: [u8; 20],
+    pub bytes: u64,
+    // from adding the torrent (or magnet link) to the files being written
+    pub elapsed: Duration,
+    pub blocks_served: u64,
+}
+
+// The whole path a download takes, end to end in one process: random data is made into a torrent,
+// seeded by a `TestSeeder` listed on a `TestHttpTracker`, downloaded by the session like any other
+// torrent (or from its magnet link, metadata fetch included) and the file it writes compared with
+// the data byte for byte.
+//
+//     let report = SelfTest::new().via_magnet(true).run(&mut session)?;
+#[derive(Debug)]
+pub struct SelfTest {
+    size: usize,
+    piece_length: u32,
+    via_magnet: bool,
+    timeout: Duration,
+}
+
+impl Default for SelfTest {
+    fn default() -> Self {
+        SelfTest::new()
+    }
+}
+
+impl SelfTest {
+    pub fn new() -> Self {
+        SelfTest {
+            size: DEFAULT_SIZE,
+            piece_length: DEFAULT_PIECE_LENGTH,
+            via_magnet: false,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn piece_length(mut self, piece_length: u32) -> Self {
+        self.piece_length = piece_length;
+        self
+    }
+
+    pub fn via_magnet(mut self, via_magnet: bool) -> Self {
+        self.via_magnet = via_magnet;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // The file is downloaded into the session's download directory, as every torrent is, under a
+    // random name; it's removed again afterwards whatever the outcome
+    pub fn run(&self, session: &mut Session) -> Result<SelfTestReport, SelfTestError> {
+        let name = format!("bit_torrent_self_test_{}.bin", random_string());
+        let scratch =
+            std::env::temp_dir().join(format!("bit_torrent_self_test_{}", random_string()));
+        std::fs::create_dir_all(&scratch)?;
+        let downloaded = session.settings().current().download_dir.join(&name);
+        let result = self.run_in(session, &scratch, &name, &downloaded);
+        let _ = std::fs::remove_dir_all(&scratch);
+        let _ = std::fs::remove_file(&downloaded);
+        result
+    }
+
+    fn run_in(
+        &self,
+        session: &mut Session,
+        scratch: &Path,
+        name: &str,
+        downloaded: &Path,
+    ) -> Result<SelfTestReport, SelfTestError> {
+        let data: Vec<u8> = (0..self.size).map(|_| rand::random::<u8>()).collect();
+        let source = scratch.join(name);
+        std::fs::write(&source, &data)?;
+
+        let tracker = TestHttpTracker::start()?;
+        let bytes = TorrentBuilder::new(&source)
+            .piece_length(self.piece_length)
+            .announce(&tracker.announce_url())
+            .build()
+            .map_err(SelfTestError::Build)?;
+        let meta_info = MetaInfoFile::from(bytes.as_slice());
+        let info_hash = meta_info.info_hash;
+        let seeder = TestSeeder::start_with_metadata(
+            data.clone(),
+            self.piece_length,
+            meta_info.info_bytes.clone(),
+            SeederProfile::default(),
+        )?;
+        // the seeder only ever listens on 127.0.0.1
+        if let SocketAddr::V4(addr) = seeder.addr() {
+            tracker.add_seed(info_hash, addr, seeder.peer_id());
+        }
+
+        let started = Instant::now();
+        if self.via_magnet {
+            let magnet = magnet_link(&info_hash, name, &tracker.announce_url());
+            session
+                .add_magnet(&magnet)
+                .map_err(SelfTestError::Session)?;
+        } else {
+            session.add(meta_info);
+        }
+        let deadline = started + self.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match session.events().recv_timeout(remaining) {
+                Ok(SessionEvent::DownloadComplete { info_hash: done }) if done == info_hash => {
+                    break
+                }
+                Ok(SessionEvent::StorageFailed {
+                    info_hash: failed,
+                    error,
+                }) if failed == info_hash => return Err(SelfTestError::Storage(error)),
+                Ok(_) => {}
+                Err(_) => return Err(SelfTestError::TimedOut),
+            }
+        }
+        let elapsed = started.elapsed();
+
+        let written = std::fs::read(downloaded)?;
+        if let Some(offset) = first_difference(&written, &data) {
+            return Err(SelfTestError::Mismatch { offset });
+        }
+        Ok(SelfTestReport {
+            info_hash,
+            bytes: data.len() as u64,
+            elapsed,
+            blocks_served: seeder.blocks_served(),
+        })
+    }
+}
+
+fn magnet_link(info_hash: &[u8; 20], name: &str, tracker: &str) -> String {
+    format!(
+        "magnet:?xt=urn:btih:{}&dn={}&tr={}",
+        hex::encode(info_hash),
+        utf8_percent_encode(name, NON_ALPHANUMERIC),
+        utf8_percent_encode(tracker, NON_ALPHANUMERIC)
+    )
+}
+
+fn first_difference(a: &[u8], b: &[u8]) -> Option<u64> {
+    a.iter()
+        .zip(b)
+        .position(|(x, y)| x != y)
+        .or_else(|| (a.len() != b.len()).then(|| a.len().min(b.len())))
+        .map(|offset| offset as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::LogFormat;
+
+    #[test]
+    fn it_downloads_what_it_seeded() {
+        let log =
+            std::env::temp_dir().join(format!("bit_torrent_self_test_{}.log", random_string()));
+        let mut session = Session::new(log.to_str().unwrap(), LogFormat::Human);
+        for via_magnet in [false, true] {
+            let report = SelfTest::new()
+                .size(100_000)
+                .piece_length(16384)
+                .via_magnet(via_magnet)
+                .timeout(Duration::from_secs(60))
+                .run(&mut session)
+                .unwrap();
+            assert_eq!(report.bytes, 100_000);
+            assert!(report.blocks_served >= 7);
+        }
+        let _ = std::fs::remove_file(&log);
+
+        assert_eq!(first_difference(b"abc", b"abc"), None);
+        assert_eq!(first_difference(b"abc", b"abd"), Some(2));
+        assert_eq!(first_difference(b"ab", b"abc"), Some(2));
+    }
+}
@@ -0,0 +1,1175 @@
+use crate::bencode::EncodeError;
+use crate::choker::ChokePolicy;
+use crate::connection::{PeerConnection, SendError, Stream};
+use crate::dht::{Dht, DhtError};
+use crate::dns::{DnsResolver, Resolve};
+use crate::file_checksums::ChecksumMismatch;
+use crate::handshake::{
+    HandshakeGate, HandshakeMetrics, HandshakeOutcome, DEFAULT_MAX_PENDING_HANDSHAKES,
+};
+use crate::health::SwarmHealth;
+use crate::heatmap::PieceHeatmap;
+use crate::logger::{LogFormat, Logger};
+use crate::lsd::Lsd;
+use crate::magnet::{Magnet, MagnetError};
+use crate::meta_info_file::{File, MetaInfoFile};
+use crate::metadata_cache::MetadataCache;
+use crate::processor::{OnComplete, TorrentProcessor, CONNECTION_TIMEOUT, READ_TIMEOUT};
+use crate::scheduler::{
+    AssignmentAudit, BlockAssignment, RequestBudget, Scheduler, DEFAULT_REQUEST_BUDGET,
+};
+use crate::settings::{Settings, SettingsError, SettingsHandle};
+use crate::storage::StorageError;
+use crate::timeline::TimelineFormat;
+use crate::torrent::PieceState;
+use crate::tracker::{
+    Announce, Announcers, Event, OptionalParameters, Peer, Tracker, TrackerRequest,
+    TrackerResponseError, TrackerStatus, UdpClient,
+};
+use crate::ut_metadata::{fetch_metadata, MetadataError};
+use crate::util::{random_port, random_string};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Weak};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_LISTEN_PORT: u16 = 8999;
+// how long a single peer gets to hand over a magnet link's info dictionary
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Who a torrent says it is to trackers and peers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalIdentity {
+    pub peer_id: String,
+    pub listen_port: u16,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    UnknownTorrent([u8; 20]),
+    UnknownTracker(String),
+    UnknownFile(usize),
+    // some of the pieces the file is stored in aren't downloaded and verified yet
+    FileIncomplete(usize),
+    Io(std::io::Error),
+    Tracker(TrackerResponseError),
+    Encode(EncodeError),
+    Settings(SettingsError),
+    Magnet(MagnetError),
+    // none of the magnet link's peers handed over an info dictionary matching its info hash
+    MetadataUnavailable,
+    Dht(DhtError),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SessionEvent {
+    TorrentAdded {
+        info_hash: [u8; 20],
+    },
+    // The same info hash was added again (e.g. from a different .torrent file); its trackers were
+    // merged into the existing torrent instead of starting a second download
+    TrackersMerged {
+        info_hash: [u8; 20],
+        added_trackers: Vec<String>,
+    },
+    Reannounced {
+        info_hash: [u8; 20],
+        peers: usize,
+    },
+    PieceStateChanged {
+        info_hash: [u8; 20],
+        index: u32,
+        state: PieceState,
+    },
+    // Every piece is downloaded and verified and the files are written; the torrent's
+    // `CompletionAction`s run straight after
+    DownloadComplete {
+        info_hash: [u8; 20],
+    },
+    // Taken out of the session by `CompletionAction::Remove`; its files stay where they are
+    TorrentRemoved {
+        info_hash: [u8; 20],
+    },
+    // A peer's task panicked; the peer was disconnected and the torrent carries on without it
+    PeerPanicked {
+        info_hash: [u8; 20],
+        peer: SocketAddr,
+        message: String,
+    },
+    // The machine was suspended (noticed by the wall clock jumping ahead, or reported with
+    // `Session::resume`); the torrent rechecked its connections and reannounced
+    Resumed {
+        info_hash: [u8; 20],
+        suspended_for: Duration,
+    },
+    // Every piece file `index` (in the order the torrent lists them) is stored in has been
+    // verified, so it can be copied out with `Session::extract_file` before the torrent finishes
+    FileCompleted {
+        info_hash: [u8; 20],
+        index: usize,
+        path: PathBuf,
+    },
+    // A file couldn't be written even after retrying; the torrent is now in error (see
+    // `Session::torrent_error`) and won't complete. One event per file that failed.
+    StorageFailed {
+        info_hash: [u8; 20],
+        error: StorageError,
+    },
+    // A written file doesn't match a checksum its torrent lists for it (`md5sum`, `sha1` or
+    // `crc32`), though every piece passed. Sent before `DownloadComplete`, which still follows.
+    FileChecksumMismatch {
+        info_hash: [u8; 20],
+        mismatch: ChecksumMismatch,
+    },
+}
+
+// What a torrent does once its download is complete, set with `Session::set_completion_actions`.
+// They're carried out in order, so a hook after `MoveTo` is given the files' new paths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompletionAction {
+    StopSeeding,
+    // whatever `Settings::seed_after_completion` says
+    KeepSeeding,
+    // into this directory, keeping the torrent's own layout underneath it
+    MoveTo(PathBuf),
+    // runs the program with the info hash in hex and then the path of every file as arguments,
+    // waiting for it to exit
+    RunHook(PathBuf),
+    // stops seeding and takes the torrent out of the session, leaving its files where they are
+    Remove,
+}
+
+// Whether connections go on to seed when the download completes: the last of the torrent's actions
+// that has a say, or the settings if none do
+pub(crate) fn seeds_after_completion(actions: &[CompletionAction], settings: &Settings) -> bool {
+    actions
+        .iter()
+        .rev()
+        .find_map(|action| match action {
+            CompletionAction::StopSeeding | CompletionAction::Remove => Some(false),
+            CompletionAction::KeepSeeding => Some(true),
+            _ => None,
+        })
+        .unwrap_or(settings.seed_after_completion)
+}
+
+struct SessionTorrent {
+    processor: Arc<TorrentProcessor>,
+    handle: Option<JoinHandle<()>>,
+}
+
+type Torrents = RwLock<HashMap<[u8; 20], SessionTorrent>>;
+
+pub struct Session {
+    logger: Arc<RwLock<Logger>>,
+    local_peer_id: String,
+    // announced instead of `DEFAULT_LISTEN_PORT` with `Settings::randomize_port`
+    random_port: u16,
+    // shared with every torrent's completion actions, one of which removes the torrent
+    torrents: Arc<Torrents>,
+    event_sender: Sender<SessionEvent>,
+    events: Receiver<SessionEvent>,
+    settings: SettingsHandle,
+    scheduler: Arc<RwLock<Scheduler>>,
+    request_budget: RequestBudget,
+    // caps connections mid-handshake across every torrent
+    handshakes: HandshakeGate,
+    assignment_audit: AssignmentAudit,
+    dns: DnsResolver,
+    announcers: Announcers,
+    udp: UdpClient,
+    dht: Option<Dht>,
+    lsd: Option<Lsd>,
+    external_ip: Arc<RwLock<Option<IpAddr>>>,
+    metadata_cache: Option<MetadataCache>,
+}
+
+impl Session {
+    pub fn new(log_file_path: &str, log_format: LogFormat) -> Self {
+        let (event_sender, events) = channel();
+        let logger = Arc::new(RwLock::new(Logger::new(log_file_path, log_format)));
+        let settings = Settings {
+            log_format,
+            ..Settings::default()
+        };
+        Session {
+            settings: SettingsHandle::new(settings, Arc::clone(&logger)),
+            scheduler: Arc::new(RwLock::new(Scheduler::new())),
+            request_budget: RequestBudget::new(DEFAULT_REQUEST_BUDGET),
+            handshakes: HandshakeGate::new(DEFAULT_MAX_PENDING_HANDSHAKES),
+            assignment_audit: AssignmentAudit::new(),
+            dns: DnsResolver::default(),
+            announcers: Announcers::default(),
+            udp: UdpClient::default(),
+            dht: None,
+            lsd: None,
+            external_ip: Arc::new(RwLock::new(None)),
+            logger,
+            local_peer_id: random_string(),
+            random_port: random_port(),
+            torrents: Arc::new(RwLock::new(HashMap::new())),
+            event_sender,
+            events,
+            metadata_cache: None,
+        }
+    }
+
+    // Every torrent added from now on gets a copy of its metainfo kept in `dir`
+    pub fn set_metadata_cache_dir(&mut self, dir: &Path) -> Result<(), SessionError> {
+        self.metadata_cache = Some(MetadataCache::new(dir).map_err(SessionError::Io)?);
+        Ok(())
+    }
+
+    // Restarts a torrent from the metadata cache alone, e.g. one that was originally added from a
+    // magnet link
+    pub fn add_cached(&mut self, info_hash: &[u8; 20]) -> Result<[u8; 20], SessionError> {
+        let meta_info = self
+            .metadata_cache
+            .as_ref()
+            .and_then(|cache| cache.load(info_hash))
+            .ok_or(SessionError::UnknownTorrent(*info_hash))?;
+        Ok(self.add(meta_info))
+    }
+
+    // Starts a torrent from a magnet link: its trackers are asked for peers, the info dictionary is
+    // fetched from the first of them that has it (see `fetch_metadata`) and the torrent is then added
+    // like any other. Blocks until the metainfo is in hand or every peer has been tried.
+    pub fn add_magnet(&mut self, uri: &str) -> Result<[u8; 20], SessionError> {
+        let magnet = Magnet::parse(uri).map_err(SessionError::Magnet)?;
+        if self.contains(&magnet.info_hash) {
+            // a magnet's trackers aren't tiered, so each gets a tier of its own
+            let tiers: Vec<Vec<String>> = magnet.trackers.iter().map(|t| vec![t.clone()]).collect();
+            self.merge_trackers(&magnet.info_hash, &tiers);
+            return Ok(magnet.info_hash);
+        }
+        if let Some(meta_info) = self
+            .metadata_cache
+            .as_ref()
+            .and_then(|cache| cache.load(&magnet.info_hash))
+        {
+            return Ok(self.add(meta_info));
+        }
+
+        let identity = self.identity();
+        let settings = self.settings.current();
+        let tracker = Tracker::new()
+            .allowing_only(settings.tracker_hosts)
+            .resolving_with(self.dns.clone(), settings.ip_preference)
+            .using_udp(self.udp.clone());
+        let request = TrackerRequest::new(
+            magnet.info_hash,
+            identity.peer_id.as_bytes(),
+            identity.listen_port,
+        )
+        .event(Event::Started)
+        .compact(true)
+        .no_peer_id(true);
+        for url in &magnet.trackers {
+            let response = self
+                .announcers
+                .for_url(&tracker, url)
+                .and_then(|announcer| announcer.announce(&request));
+            let peers = match response {
+                Ok(response) => response.peers,
+                Err(e) => {
+                    println!("announce to {} failed {:?}", url, e);
+                    continue;
+                }
+            };
+            for peer in peers.into_iter().map(Peer::from) {
+                match self.fetch_info_from(&peer, &magnet, &identity.peer_id) {
+                    Ok(info_bytes) => {
+                        if let Some(meta_info) = MetaInfoFile::from_info_bytes(&info_bytes, url) {
+                            return Ok(self.add(meta_info));
+                        }
+                        println!("unusable info dictionary from {}", peer.socket_addr);
+                    }
+                    Err(e) => println!("no metadata from {} {:?}", peer.socket_addr, e),
+                }
+            }
+        }
+        Err(SessionError::MetadataUnavailable)
+    }
+
+    fn fetch_info_from(
+        &self,
+        peer: &Peer,
+        magnet: &Magnet,
+        peer_id: &str,
+    ) -> Result<Vec<u8>, MetadataError> {
+        let stream = TcpStream::connect_timeout(&peer.socket_addr, CONNECTION_TIMEOUT)
+            .map_err(|e| MetadataError::Write(SendError::Connect(e)))?;
+        let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+        let ticket = self
+            .handshakes
+            .enter()
+            .ok_or(MetadataError::Write(SendError::TooManyPendingHandshakes))?;
+        let connection = PeerConnection::new(
+            Stream::Tcp(stream),
+            &magnet.info_hash,
+            peer_id.as_bytes(),
+            &peer.id,
+            Box::new(|_, _| {}),
+        );
+        ticket.finish(match &connection {
+            Ok(_) => HandshakeOutcome::Completed,
+            Err(SendError::ReturnHandshakeReadTimeOut) => HandshakeOutcome::TimedOut,
+            Err(_) => HandshakeOutcome::Failed,
+        });
+        fetch_metadata(
+            &mut connection.map_err(MetadataError::Write)?,
+            magnet.info_hash,
+            METADATA_TIMEOUT,
+        )
+    }
+
+    // Applies to connections made from now on, including those of torrents already running
+    pub fn set_strict_protocol(&mut self, strict: bool) {
+        self.settings.update(|s| s.strict_protocol = strict);
+    }
+
+    // Applies to every torrent that hasn't completed yet; without it `wait` returns once downloads
+    // finish
+    pub fn set_seed_after_completion(&mut self, seed: bool) {
+        self.settings.update(|s| s.seed_after_completion = seed);
+    }
+
+    // Re-reads a settings file (see `Settings::apply`) and applies it to the running session
+    // without restarting any torrents
+    pub fn reload_settings(&self, path: &Path) -> Result<Settings, SessionError> {
+        self.settings.reload(path).map_err(SessionError::Settings)
+    }
+
+    // For reloading from another thread, e.g. `SettingsHandle::watch`
+    pub fn settings(&self) -> SettingsHandle {
+        self.settings.clone()
+    }
+
+    // Adds the torrent and immediately starts downloading it on its own thread. Adding an info hash
+    // the session already knows about merges the trackers into the running torrent.
+    pub fn add(&mut self, meta_info: MetaInfoFile) -> [u8; 20] {
+        let info_hash = meta_info.info_hash;
+        if self.contains(&info_hash) {
+            self.merge_trackers(&info_hash, &meta_info.tiers());
+            return info_hash;
+        }
+
+        if let Some(cache) = &self.metadata_cache {
+            if let Err(e) = cache.store(&meta_info, &meta_info.tiers().concat()) {
+                println!(
+                    "could not cache metainfo for {} {:?}",
+                    hex::encode(info_hash),
+                    e
+                );
+            }
+        }
+
+        let mut processor = TorrentProcessor::new(
+            meta_info,
+            self.identity(),
+            Arc::clone(&self.logger),
+            self.event_sender.clone(),
+            self.settings.clone(),
+            Arc::clone(&self.scheduler),
+            self.request_budget.clone(),
+        );
+        processor.handshakes = self.handshakes.clone();
+        processor.assignment_audit = self.assignment_audit.for_torrent(info_hash);
+        processor.dns = self.dns.clone();
+        processor.announcers = self.announcers.clone();
+        processor.udp = self.udp.clone();
+        processor.dht = self.dht.clone();
+        processor.lsd = self.lsd.clone();
+        processor.external_ip = Arc::clone(&self.external_ip);
+        processor.on_complete = Some(self.on_complete());
+        let processor = Arc::new(processor);
+        self.scheduler.write().register(info_hash);
+        let handle = {
+            let processor = Arc::clone(&processor);
+            let scheduler = Arc::clone(&self.scheduler);
+            // finished torrents stop counting against the session-wide budgets
+            spawn(move || {
+                processor.start();
+                scheduler.write().unregister(&info_hash);
+            })
+        };
+        self.torrents.write().insert(
+            info_hash,
+            SessionTorrent {
+                processor,
+                handle: Some(handle),
+            },
+        );
+        let _ = self
+            .event_sender
+            .send(SessionEvent::TorrentAdded { info_hash });
+        info_hash
+    }
+
+    pub fn contains(&self, info_hash: &[u8; 20]) -> bool {
+        self.torrents.read().contains_key(info_hash)
+    }
+
+    // Only trackers the torrent didn't have yet count; adding the same torrent again is silent
+    fn merge_trackers(&self, info_hash: &[u8; 20], tiers: &[Vec<String>]) {
+        let added_trackers = match self.processor(info_hash) {
+            Ok(existing) => existing.add_trackers(tiers),
+            Err(_) => return,
+        };
+        if added_trackers.is_empty() {
+            return;
+        }
+        let _ = self.event_sender.send(SessionEvent::TrackersMerged {
+            info_hash: *info_hash,
+            added_trackers,
+        });
+        // trackers we already announced to are still held back by their min interval
+        let _ = self.reannounce(info_hash, false);
+    }
+
+    // The peer id and port the next torrent added will use, as the privacy settings have them
+    pub fn identity(&self) -> LocalIdentity {
+        let settings = self.settings.current();
+        LocalIdentity {
+            peer_id: if settings.peer_id_per_torrent {
+                random_string()
+            } else {
+                self.local_peer_id.clone()
+            },
+            listen_port: if settings.randomize_port {
+                self.random_port
+            } else {
+                DEFAULT_LISTEN_PORT
+            },
+        }
+    }
+
+    // Probes the swarm for a torrent without adding it to the session or downloading anything
+    pub fn health(
+        &self,
+        meta_info: MetaInfoFile,
+        sample_size: usize,
+        window: Duration,
+    ) -> Result<SwarmHealth, TrackerResponseError> {
+        let mut processor = TorrentProcessor::new(
+            meta_info,
+            self.identity(),
+            Arc::clone(&self.logger),
+            self.event_sender.clone(),
+            self.settings.clone(),
+            Arc::clone(&self.scheduler),
+            self.request_budget.clone(),
+        );
+        processor.handshakes = self.handshakes.clone();
+        processor.dns = self.dns.clone();
+        processor.announcers = self.announcers.clone();
+        processor.udp = self.udp.clone();
+        processor.dht = self.dht.clone();
+        processor.lsd = self.lsd.clone();
+        processor.external_ip = Arc::clone(&self.external_ip);
+        processor.probe_health(sample_size, window)
+    }
+
+    // Announces on a background thread and reports the outcome as `SessionEvent::Reannounced`.
+    // Without `override_min_interval` this refuses outright when the working tracker, or every
+    // tracker, is still inside its min interval.
+    pub fn reannounce(
+        &self,
+        info_hash: &[u8; 20],
+        override_min_interval: bool,
+    ) -> Result<(), SessionError> {
+        let processor = self.processor(info_hash)?;
+        if !override_min_interval {
+            let now = Instant::now();
+            if let Some(refusal) = processor.min_interval_refusal(now) {
+                return Err(SessionError::Tracker(refusal));
+            }
+            let trackers = processor.trackers.read();
+            let refusals: Vec<TrackerResponseError> = trackers
+                .iter()
+                .filter_map(|status| status.check_announce(now, false).err())
+                .collect();
+            if refusals.len() == trackers.len() {
+                if let Some(refusal) = refusals.into_iter().next() {
+                    return Err(SessionError::Tracker(refusal));
+                }
+            }
+        }
+        let processor = Arc::clone(&processor);
+        let events = self.event_sender.clone();
+        let info_hash = *info_hash;
+        spawn(move || match processor.announce(override_min_interval) {
+            Ok(response) => {
+                let _ = events.send(SessionEvent::Reannounced {
+                    info_hash,
+                    peers: response.peers.len(),
+                });
+                processor.add_peers(response.peers);
+            }
+            Err(e) => println!("reannounce for {} failed {:?}", hex::encode(info_hash), e),
+        });
+        Ok(())
+    }
+
+    // For embedders that hear about a resume from the operating system before torrents notice the
+    // clock jump themselves. Every torrent goes through its resume on a background thread.
+    pub fn resume(&self, suspended_for: Duration) {
+        for torrent in self.torrents.read().values() {
+            let processor = Arc::clone(&torrent.processor);
+            spawn(move || processor.resume(suspended_for));
+        }
+    }
+
+    // Every tracker of the torrent in the order they're tried, with how each has been doing
+    pub fn tracker_status(&self, info_hash: &[u8; 20]) -> Result<Vec<TrackerStatus>, SessionError> {
+        let processor = self.processor(info_hash)?;
+        let trackers = processor.trackers.read().clone();
+        Ok(trackers)
+    }
+
+    // Which optional counters go out in announces to the tracker at `url`
+    pub fn set_optional_parameters(
+        &self,
+        info_hash: &[u8; 20],
+        url: &str,
+        optional_parameters: OptionalParameters,
+    ) -> Result<(), SessionError> {
+        let processor = self.processor(info_hash)?;
+        let mut trackers = processor.trackers.write();
+        let status = trackers
+            .iter_mut()
+            .find(|status| status.url == url)
+            .ok_or_else(|| SessionError::UnknownTracker(url.to_string()))?;
+        status.optional_parameters = optional_parameters;
+        Ok(())
+    }
+
+    // The torrent's share of `Settings::max_total_connections` and `Settings::max_download_rate` is
+    // proportional to its weight (1 unless changed). Takes effect on running torrents straight away.
+    pub fn set_weight(&self, info_hash: &[u8; 20], weight: u32) -> Result<(), SessionError> {
+        self.processor(info_hash)?;
+        self.scheduler.write().set_weight(*info_hash, weight);
+        Ok(())
+    }
+
+    // How many of the torrent's peer tasks have panicked and been disconnected so far
+    pub fn peer_panics(&self, info_hash: &[u8; 20]) -> Result<usize, SessionError> {
+        let processor = self.processor(info_hash)?;
+        Ok(processor.peer_panics.load(Ordering::SeqCst))
+    }
+
+    // How outgoing handshakes have gone across every torrent, including any turned away because too
+    // many were already under way
+    pub fn handshake_metrics(&self) -> HandshakeMetrics {
+        self.handshakes.metrics()
+    }
+
+    // Resolves host names with `resolver` from now on, in every torrent, instead of the system
+    // resolver. See `Settings::ip_preference` for which of the addresses are tried first.
+    pub fn set_resolver(&self, resolver: Arc<dyn Resolve>) {
+        self.dns.set_resolver(resolver);
+    }
+
+    // Our public address, as the last tracker to report it (BEP 24 `external ip`) saw it; None
+    // until one has
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        *self.external_ip.read()
+    }
+
+    // Runs a DHT node on `addr`, joined through `bootstrap` (see `dht::BOOTSTRAP_NODES`), for
+    // every torrent added from now on to find peers through alongside its trackers. Torrents whose
+    // peers may only come from their trackers never use it.
+    pub fn start_dht(
+        &mut self,
+        addr: SocketAddr,
+        bootstrap: &[(String, u16)],
+    ) -> Result<(), SessionError> {
+        let dht = Dht::bind(addr).map_err(SessionError::Dht)?;
+        dht.bootstrap(bootstrap);
+        println!("DHT node {:?} on {:?}", dht, dht.local_addr());
+        self.dht = Some(dht);
+        Ok(())
+    }
+
+    // Announces every torrent added from now on to the local network (BEP 14's multicast group,
+    // see `lsd::LSD_GROUP`) and connects to the peers there announcing the same ones. Torrents
+    // whose peers may only come from their trackers are left out.
+    pub fn start_lsd(&mut self, group: SocketAddrV4) -> Result<(), SessionError> {
+        let lsd = Lsd::start(group).map_err(SessionError::Io)?;
+        println!("local service discovery {:?}", lsd);
+        self.lsd = Some(lsd);
+        Ok(())
+    }
+
+    // Closes every connection of the torrent and stops it looking for peers; a download that
+    // hadn't finished is left unwritten. It stays in the session, and `wait` returns once it's done.
+    pub fn stop(&self, info_hash: &[u8; 20]) -> Result<(), SessionError> {
+        self.processor(info_hash)?
+            .stopped
+            .store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Hands the torrent a peer to connect to, as if a tracker had named it. Private torrents take
+    // these too: the user, not some other client, decided on the peer.
+    pub fn add_peer(&self, info_hash: &[u8; 20], addr: SocketAddr) -> Result<(), SessionError> {
+        self.processor(info_hash)?.manual_peers.add(addr);
+        Ok(())
+    }
+
+    // Announces to `url`, in every torrent, go to `announcer` from now on instead of the tracker
+    // itself; for trying the session against a `MockTracker`
+    pub fn set_announcer(&self, url: &str, announcer: Arc<dyn Announce>) {
+        self.announcers.set(url, announcer);
+    }
+
+    // Every block requested from a peer from now on, by any torrent, and why the picker chose it.
+    // For seeing what the picker actually does while tuning it; a later call takes the stream over
+    // and dropping the receiver turns it off.
+    pub fn audit_assignments(&self) -> Receiver<BlockAssignment> {
+        self.assignment_audit.subscribe()
+    }
+
+    pub fn events(&self) -> &Receiver<SessionEvent> {
+        &self.events
+    }
+
+    // Blocks until every torrent added so far has finished; the torrents stay in the session
+    pub fn wait(&mut self) {
+        // not joined under the lock, which a torrent's `CompletionAction::Remove` needs
+        let handles: Vec<_> = self
+            .torrents
+            .write()
+            .iter_mut()
+            .filter_map(|(info_hash, torrent)| {
+                let handle = torrent.handle.take()?;
+                Some((*info_hash, Arc::clone(&torrent.processor), handle))
+            })
+            .collect();
+        for (info_hash, processor, handle) in handles {
+            if handle.join().is_err() {
+                println!(
+                    "torrent {} ({}) exited with a panic",
+                    hex::encode(info_hash),
+                    processor
+                        .meta_info
+                        .announce
+                        .as_deref()
+                        .unwrap_or("trackerless")
+                );
+            }
+        }
+    }
+
+    // Snapshot to render from before applying `SessionEvent::PieceStateChanged` updates
+    pub fn piece_map(&self, info_hash: &[u8; 20]) -> Result<Vec<PieceState>, SessionError> {
+        let processor = self.processor(info_hash)?;
+        let piece_map = processor.torrent.read().piece_map().to_vec();
+        Ok(piece_map)
+    }
+
+    // Why the torrent stopped short of completing, if writing its files failed
+    pub fn torrent_error(
+        &self,
+        info_hash: &[u8; 20],
+    ) -> Result<Option<StorageError>, SessionError> {
+        let processor = self.processor(info_hash)?;
+        let error = processor.error.read().clone();
+        Ok(error)
+    }
+
+    // How often peers have requested each piece from us, see `PieceHeatmap`
+    pub fn piece_heatmap(&self, info_hash: &[u8; 20]) -> Result<PieceHeatmap, SessionError> {
+        let processor = self.processor(info_hash)?;
+        let heatmap = processor.torrent.read().heatmap.clone();
+        Ok(heatmap)
+    }
+
+    // Writes a .torrent for the torrent as the session currently knows it, trackers added or
+    // moved since it was loaded included
+    pub fn export_torrent(&self, info_hash: &[u8; 20], path: &Path) -> Result<(), SessionError> {
+        let processor = self.processor(info_hash)?;
+        let trackers: Vec<String> = processor
+            .trackers
+            .read()
+            .iter()
+            .map(|t| t.url.clone())
+            .collect();
+        let bytes = processor
+            .meta_info
+            .encode(&trackers)
+            .map_err(SessionError::Encode)?;
+        std::fs::write(path, bytes).map_err(SessionError::Io)
+    }
+
+    // Copies file `index` out to `path` as soon as every piece it's stored in is verified, whether
+    // or not the rest of the torrent is done
+    pub fn extract_file(
+        &self,
+        info_hash: &[u8; 20],
+        index: usize,
+        path: &Path,
+    ) -> Result<(), SessionError> {
+        let processor = self.processor(info_hash)?;
+        let (start, length) = processor
+            .file_completion
+            .lock()
+            .range(index)
+            .ok_or(SessionError::UnknownFile(index))?;
+        let content = processor.torrent.read();
+        let data = content
+            .read_range(start, length)
+            .ok_or(SessionError::FileIncomplete(index))?;
+        std::fs::write(path, data).map_err(SessionError::Io)
+    }
+
+    // Replaces what the torrent does once its download completes; until then it can be changed freely
+    pub fn set_completion_actions(
+        &self,
+        info_hash: &[u8; 20],
+        actions: Vec<CompletionAction>,
+    ) -> Result<(), SessionError> {
+        let processor = self.processor(info_hash)?;
+        *processor.completion_actions.write() = actions;
+        Ok(())
+    }
+
+    // Replaces the policy deciding which of the torrent's peers we upload to, see `ChokePolicy`
+    pub fn set_choke_policy(
+        &self,
+        info_hash: &[u8; 20],
+        policy: Box<dyn ChokePolicy>,
+    ) -> Result<(), SessionError> {
+        let processor = self.processor(info_hash)?;
+        *processor.choker.write() = policy;
+        Ok(())
+    }
+
+    pub fn export_timeline(
+        &self,
+        info_hash: &[u8; 20],
+        path: &Path,
+        format: TimelineFormat,
+    ) -> Result<(), SessionError> {
+        let processor = self.processor(info_hash)?;
+        let exported = processor.timeline.read().export(path, format);
+        exported.map_err(SessionError::Io)
+    }
+
+    fn processor(&self, info_hash: &[u8; 20]) -> Result<Arc<TorrentProcessor>, SessionError> {
+        self.torrents
+            .read()
+            .get(info_hash)
+            .map(|torrent| Arc::clone(&torrent.processor))
+            .ok_or(SessionError::UnknownTorrent(*info_hash))
+    }
+
+    // Carries out a torrent's completion actions. Holds the torrents weakly, since each one holds
+    // this.
+    fn on_complete(&self) -> OnComplete {
+        let torrents = Arc::downgrade(&self.torrents);
+        let events = self.event_sender.clone();
+        Box::new(move |processor| complete(processor, &torrents, &events))
+    }
+}
+
+fn complete(
+    processor: &TorrentProcessor,
+    torrents: &Weak<Torrents>,
+    events: &Sender<SessionEvent>,
+) {
+    let info_hash = processor.meta_info.info_hash;
+    let files: Vec<&File> = processor
+        .meta_info
+        .files()
+        .into_iter()
+        .filter(|file| !file.padding)
+        .collect();
+    let mut paths: Vec<PathBuf> = files
+        .iter()
+        .map(|file| processor.download_dir.join(&file.path))
+        .collect();
+    let actions = processor.completion_actions.read().clone();
+    for action in actions {
+        match action {
+            CompletionAction::StopSeeding => processor.stop_seeding.store(true, Ordering::SeqCst),
+            // each connection looked at the actions as it saw the download complete
+            CompletionAction::KeepSeeding => {}
+            CompletionAction::MoveTo(dir) => match move_files(&paths, &files, &dir) {
+                Ok(moved) => paths = moved,
+                Err(e) => println!("could not move files to {:?} {:?}", dir, e),
+            },
+            CompletionAction::RunHook(program) => {
+                let status = Command::new(&program)
+                    .arg(hex::encode(info_hash))
+                    .args(&paths)
+                    .status();
+                if !matches!(&status, Ok(status) if status.success()) {
+                    println!("completion hook {:?} failed {:?}", program, status);
+                }
+            }
+            CompletionAction::Remove => {
+                processor.stop_seeding.store(true, Ordering::SeqCst);
+                if let Some(torrents) = torrents.upgrade() {
+                    torrents.write().remove(&info_hash);
+                }
+                let _ = events.send(SessionEvent::TorrentRemoved { info_hash });
+            }
+        }
+    }
+}
+
+// Moves each file from `paths` to where it goes under `dir`, copying when that's on another file
+// system. Returns where the files are now.
+fn move_files(
+    paths: &[PathBuf],
+    files: &[&File],
+    dir: &Path,
+) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut moved = vec![];
+    for (path, file) in paths.iter().zip(files) {
+        let target = dir.join(&file.path);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if std::fs::rename(path, &target).is_err() {
+            std::fs::copy(path, &target)?;
+            std::fs::remove_file(path)?;
+        }
+        moved.push(target);
+    }
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_lets_the_last_seeding_action_decide() {
+        let seeding = Settings {
+            seed_after_completion: true,
+            ..Settings::default()
+        };
+        let moved = CompletionAction::MoveTo(PathBuf::from("finished"));
+        assert!(seeds_after_completion(&[], &seeding));
+        assert!(seeds_after_completion(
+            std::slice::from_ref(&moved),
+            &seeding
+        ));
+        assert!(!seeds_after_completion(
+            &[CompletionAction::KeepSeeding, CompletionAction::Remove],
+            &seeding
+        ));
+        assert!(seeds_after_completion(
+            &[
+                CompletionAction::StopSeeding,
+                CompletionAction::KeepSeeding,
+                moved
+            ],
+            &Settings::default()
+        ));
+    }
+
+    #[test]
+    fn it_moves_finished_files_keeping_their_layout() {
+        let dir = std::env::temp_dir().join(format!("bit_torrent_complete_{}", random_string()));
+        let downloaded = dir.join("downloads/album/track.txt");
+        std::fs::create_dir_all(downloaded.parent().unwrap()).unwrap();
+        std::fs::write(&downloaded, b"data").unwrap();
+
+        let file = File {
+            length: 4,
+            path: PathBuf::from("album/track.txt"),
+            checksums: Default::default(),
+            padding: false,
+        };
+        let finished = dir.join("finished");
+        let moved = move_files(std::slice::from_ref(&downloaded), &[&file], &finished).unwrap();
+        assert_eq!(moved, vec![finished.join("album").join("track.txt")]);
+        assert_eq!(std::fs::read(&moved[0]).unwrap(), b"data");
+        assert!(!downloaded.exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn it_announces_through_the_announcers_it_was_given() {
+        use crate::bencode::{Bencodable, DictBuilder};
+        use crate::logger::LogFormat;
+        use crate::test_tracker::MockTracker;
+
+        let tier = |url: &str| Bencodable::from(vec![Bencodable::from(url)]);
+        let meta_info = MetaInfoFile::from(
+            &DictBuilder::new()
+                .insert(
+                    "announce-list",
+                    vec![
+                        tier("http://down.example/announce"),
+                        tier("udp://up.example:80"),
+                    ],
+                )
+                .insert(
+                    "info",
+                    DictBuilder::new()
+                        .insert("length", 10_i64)
+                        .insert("name", "a.txt")
+                        .insert("piece length", 16384_i64)
+                        .insert("pieces", &[0u8; 20][..])
+                        .build(),
+                )
+                .build(),
+        );
+        let log = std::env::temp_dir().join(format!("bit_torrent_mock_{}.log", random_string()));
+        let session = Session::new(log.to_str().unwrap(), LogFormat::Human);
+        let down = Arc::new(MockTracker::failing("down for maintenance"));
+        let up = Arc::new(
+            MockTracker::new(vec!["10.0.0.1:6881".parse().unwrap()])
+                .external_ip("203.0.113.7".parse().unwrap()),
+        );
+        session.set_announcer("http://down.example/announce", down.clone());
+        session.set_announcer("udp://up.example:80", up.clone());
+
+        assert_eq!(session.external_ip(), None);
+        let health = session
+            .health(meta_info, 0, Duration::from_millis(10))
+            .unwrap();
+        assert_eq!(session.external_ip(), Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(health.peers_announced, 1);
+        assert_eq!(down.announces().len(), 1);
+        assert_eq!(up.announces().len(), 1);
+        // nothing downloaded yet, so all ten bytes are left
+        assert!(up.announces()[0]
+            .query_string()
+            .contains("&uploaded=0&downloaded=0&left=10"));
+        let _ = std::fs::remove_file(&log);
+    }
+
+    // Random data made into a torrent under `dir` and seeded from memory; `announce` is None for a
+    // trackerless one
+    fn seeded_torrent(
+        dir: &Path,
+        announce: Option<&str>,
+    ) -> (MetaInfoFile, crate::test_seeder::TestSeeder, Vec<u8>) {
+        use crate::test_seeder::{SeederProfile, TestSeeder};
+        use crate::torrent_builder::TorrentBuilder;
+
+        let data: Vec<u8> = (0..100_000).map(|_| rand::random::<u8>()).collect();
+        let source = dir.join("source").join("data.bin");
+        std::fs::create_dir_all(source.parent().unwrap()).unwrap();
+        std::fs::write(&source, &data).unwrap();
+        let builder = TorrentBuilder::new(&source).piece_length(16384);
+        let builder = match announce {
+            Some(url) => builder.announce(url),
+            None => builder,
+        };
+        let meta_info = MetaInfoFile::from(builder.build().unwrap().as_slice());
+        let seeder = TestSeeder::start(
+            data.clone(),
+            16384,
+            meta_info.info_hash,
+            SeederProfile::default(),
+        )
+        .unwrap();
+        (meta_info, seeder, data)
+    }
+
+    fn wait_for_completion(session: &Session, info_hash: [u8; 20], timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match session.events().recv_timeout(remaining) {
+                Ok(SessionEvent::DownloadComplete { info_hash: done }) if done == info_hash => {
+                    return true
+                }
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+    }
+
+    #[test]
+    fn it_keeps_looking_for_peers_until_one_turns_up() {
+        use crate::logger::LogFormat;
+
+        let dir = std::env::temp_dir().join(format!("bit_torrent_late_{}", random_string()));
+        let (meta_info, seeder, data) = seeded_torrent(&dir, None);
+        let mut session = Session::new(dir.join("session.log").to_str().unwrap(), LogFormat::Human);
+        session
+            .settings()
+            .update(|s| s.download_dir = dir.join("downloads"));
+
+        let info_hash = session.add(meta_info);
+        // nothing to download from yet, so nothing may be written
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(!dir.join("downloads").join("data.bin").exists());
+        session.add_peer(&info_hash, seeder.addr()).unwrap();
+
+        assert!(wait_for_completion(
+            &session,
+            info_hash,
+            Duration::from_secs(30)
+        ));
+        assert_eq!(
+            std::fs::read(dir.join("downloads").join("data.bin")).unwrap(),
+            data
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn it_downloads_from_a_peer_found_through_local_discovery_alone() {
+        use crate::logger::LogFormat;
+        use std::net::UdpSocket;
+
+        let dir = std::env::temp_dir().join(format!("bit_torrent_lsd_{}", random_string()));
+        let (meta_info, seeder, data) = seeded_torrent(&dir, None);
+        let info_hash = meta_info.info_hash;
+        // the seeder's side of the local network, here the whole of the "group"
+        let network = UdpSocket::bind("127.0.0.1:0").unwrap();
+        network
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let group = match network.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let mut session = Session::new(dir.join("session.log").to_str().unwrap(), LogFormat::Human);
+        session
+            .settings()
+            .update(|s| s.download_dir = dir.join("downloads"));
+        session.lsd =
+            Some(Lsd::with_socket(UdpSocket::bind("127.0.0.1:0").unwrap(), group).unwrap());
+
+        session.add(meta_info);
+        // the torrent announces itself, and the seeder answers with where it is
+        let mut buf = [0u8; 1500];
+        let (_, lsd_addr) = network.recv_from(&mut buf).unwrap();
+        let answer = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\nInfohash: {}\r\ncookie: seeder\r\n\r\n\r\n",
+            group,
+            seeder.addr().port(),
+            hex::encode(info_hash)
+        );
+        network.send_to(answer.as_bytes(), lsd_addr).unwrap();
+
+        assert!(wait_for_completion(
+            &session,
+            info_hash,
+            Duration::from_secs(30)
+        ));
+        assert_eq!(
+            std::fs::read(dir.join("downloads").join("data.bin")).unwrap(),
+            data
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn it_tells_the_tracker_it_started_completed_and_stopped() {
+        use crate::logger::LogFormat;
+        use crate::test_tracker::MockTracker;
+
+        let dir = std::env::temp_dir().join(format!("bit_torrent_events_{}", random_string()));
+        let (meta_info, seeder, _) = seeded_torrent(&dir, Some("http://events.example/announce"));
+        let mut session = Session::new(dir.join("session.log").to_str().unwrap(), LogFormat::Human);
+        session
+            .settings()
+            .update(|s| s.download_dir = dir.join("downloads"));
+        let tracker = Arc::new(MockTracker::new(vec![seeder.addr()]));
+        session.set_announcer("http://events.example/announce", tracker.clone());
+
+        let info_hash = session.add(meta_info);
+        assert!(wait_for_completion(
+            &session,
+            info_hash,
+            Duration::from_secs(30)
+        ));
+        // not seeding after completion, so the torrent stops once its connections close
+        session.wait();
+        let events: Vec<Option<String>> = tracker
+            .announces()
+            .iter()
+            .map(|request| {
+                request
+                    .query_string()
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("event="))
+                    .map(str::to_string)
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                Some("started".to_string()),
+                Some("completed".to_string()),
+                Some("stopped".to_string())
+            ]
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn it_refuses_reannounces_while_the_working_tracker_is_in_its_min_interval() {
+        use crate::bencode::{Bencodable, DictBuilder};
+        use crate::logger::LogFormat;
+        use crate::test_tracker::MockTracker;
+
+        let tier = |url: &str| Bencodable::from(vec![Bencodable::from(url)]);
+        let meta_info = MetaInfoFile::from(
+            &DictBuilder::new()
+                .insert(
+                    "announce-list",
+                    vec![
+                        tier("http://first.example/announce"),
+                        tier("http://backup.example/announce"),
+                    ],
+                )
+                .insert(
+                    "info",
+                    DictBuilder::new()
+                        .insert("length", 10_i64)
+                        .insert("name", "a.txt")
+                        .insert("piece length", 16384_i64)
+                        .insert("pieces", &[0u8; 20][..])
+                        .build(),
+                )
+                .build(),
+        );
+        let dir = std::env::temp_dir().join(format!("bit_torrent_tiers_{}", random_string()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut session = Session::new(dir.join("session.log").to_str().unwrap(), LogFormat::Human);
+        session.settings().update(|s| s.download_dir = dir.clone());
+        let first = Arc::new(MockTracker::default());
+        let backup = Arc::new(MockTracker::default());
+        session.set_announcer("http://first.example/announce", first.clone());
+        session.set_announcer("http://backup.example/announce", backup.clone());
+
+        let info_hash = session.add(meta_info);
+        let started = Instant::now();
+        while first.announces().is_empty() && started.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(first.announces().len(), 1);
+
+        assert!(matches!(
+            session.reannounce(&info_hash, false),
+            Err(SessionError::Tracker(
+                TrackerResponseError::MinIntervalNotElapsed { .. }
+            ))
+        ));
+        session.reannounce(&info_hash, true).unwrap();
+        let started = Instant::now();
+        while first.announces().len() < 2 && started.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(first.announces().len(), 2);
+        assert!(backup.announces().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,306 @@
+use crate::dns::IpPreference;
+use crate::logger::{LogFormat, LogLevel, Logger};
+use crate::verify::VerificationMode;
+use parking_lot::RwLock;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Io(std::io::Error),
+    Invalid { line: usize, reason: String },
+}
+
+// Knobs that can change while torrents are running. Running torrents read them whenever they
+// need them rather than copying them at startup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Settings {
+    // checked as each new connection is made
+    pub strict_protocol: bool,
+    // checked by each connection when the download completes
+    pub seed_after_completion: bool,
+    // open peer connections per torrent; lowering it closes the surplus, raising it only lets
+    // later connections through
+    pub max_connections: Option<usize>,
+    // open peer connections across the whole session, split between torrents by weight
+    pub max_total_connections: Option<usize>,
+    // bytes per second across the whole session, split between torrents by weight
+    pub max_download_rate: Option<u64>,
+    // announce the port picked at random when the session started instead of the usual one;
+    // checked as each torrent is added
+    pub randomize_port: bool,
+    // leave optional parameters such as `corrupt` out of announces, whatever the tracker takes
+    pub minimal_announces: bool,
+    // give every torrent its own peer id so peers and trackers can't tie our torrents together;
+    // checked as each torrent is added
+    pub peer_id_per_torrent: bool,
+    // announce only to trackers on these hosts or their subdomains, following redirects only
+    // within them; `None` allows any tracker
+    pub tracker_hosts: Option<Vec<String>>,
+    // find peers through trackers alone, never DHT, PEX or local discovery, as private torrents
+    // always do. The DHT and local discovery honour it, as every source added later has to; see
+    // `TorrentProcessor::trackers_only`.
+    pub trackers_only: bool,
+    // which of a host name's addresses are connected to, and in what order
+    pub ip_preference: IpPreference,
+    // anything but full verification is for measuring raw transfer speed on a trusted local swarm
+    // and is unsafe anywhere else; checked as each torrent is added
+    pub verification: VerificationMode,
+    // where downloaded files are written, each multi-file torrent in a directory of its own
+    // inside it; checked as each torrent is added
+    pub download_dir: PathBuf,
+    pub log_format: LogFormat,
+    pub log_level: LogLevel,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            strict_protocol: false,
+            seed_after_completion: false,
+            max_connections: None,
+            max_total_connections: None,
+            max_download_rate: None,
+            randomize_port: false,
+            minimal_announces: false,
+            peer_id_per_torrent: false,
+            tracker_hosts: None,
+            trackers_only: false,
+            ip_preference: IpPreference::Any,
+            verification: VerificationMode::Full,
+            download_dir: PathBuf::from("."),
+            log_format: LogFormat::Human,
+            log_level: LogLevel::Messages,
+        }
+    }
+}
+
+impl Settings {
+    // `key = value` lines, `#` starts a comment. Keys missing from the text keep the value they
+    // have in `self`, so a settings file only needs to mention what it changes.
+    pub fn apply(&self, text: &str) -> Result<Settings, SettingsError> {
+        let mut settings = self.clone();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason: &str| SettingsError::Invalid {
+                line: number + 1,
+                reason: reason.to_string(),
+            };
+            let (key, value) = line
+                .split_once('=')
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .ok_or_else(|| invalid("expected key = value"))?;
+            let limit = || match value {
+                "unlimited" => Ok(None),
+                n => n
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| invalid("expected a number or unlimited")),
+            };
+            let flag = || match value {
+                "true" | "1" => Ok(true),
+                "false" | "0" => Ok(false),
+                _ => Err(invalid("expected true or false")),
+            };
+            match key {
+                "strict_protocol" => settings.strict_protocol = flag()?,
+                "seed_after_completion" => settings.seed_after_completion = flag()?,
+                "max_connections" => settings.max_connections = limit()?,
+                "max_total_connections" => settings.max_total_connections = limit()?,
+                "max_download_rate" => {
+                    settings.max_download_rate = limit()?.map(|n: usize| n as u64)
+                }
+                "randomize_port" => settings.randomize_port = flag()?,
+                "minimal_announces" => settings.minimal_announces = flag()?,
+                "peer_id_per_torrent" => settings.peer_id_per_torrent = flag()?,
+                "tracker_hosts" => {
+                    settings.tracker_hosts = match value {
+                        "any" => None,
+                        hosts => Some(
+                            hosts
+                                .split(',')
+                                .map(str::trim)
+                                .filter(|host| !host.is_empty())
+                                .map(str::to_string)
+                                .collect(),
+                        ),
+                    }
+                }
+                "trackers_only" => settings.trackers_only = flag()?,
+                "ip_preference" => {
+                    settings.ip_preference = match value {
+                        "any" => IpPreference::Any,
+                        "ipv4_first" => IpPreference::Ipv4First,
+                        "ipv6_first" => IpPreference::Ipv6First,
+                        "ipv4_only" => IpPreference::Ipv4Only,
+                        "ipv6_only" => IpPreference::Ipv6Only,
+                        _ => {
+                            return Err(invalid(
+                                "expected any, ipv4_first, ipv6_first, ipv4_only or ipv6_only",
+                            ))
+                        }
+                    }
+                }
+                "verification" => {
+                    settings.verification = match value {
+                        "full" => VerificationMode::Full,
+                        "deferred" => VerificationMode::Deferred,
+                        "off" => VerificationMode::Off,
+                        _ => return Err(invalid("expected full, deferred or off")),
+                    }
+                }
+                "download_dir" => settings.download_dir = PathBuf::from(value),
+                "log_format" => {
+                    settings.log_format = match value {
+                        "human" => LogFormat::Human,
+                        "jsonl" => LogFormat::JsonLines,
+                        _ => return Err(invalid("expected human or jsonl")),
+                    }
+                }
+                "log_level" => {
+                    settings.log_level = match value {
+                        "off" => LogLevel::Off,
+                        "messages" => LogLevel::Messages,
+                        _ => return Err(invalid("expected off or messages")),
+                    }
+                }
+                _ => return Err(invalid("unknown setting")),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+// Shared between the session and every torrent it runs, cheap to clone onto a watcher thread
+#[derive(Clone)]
+pub struct SettingsHandle {
+    settings: Arc<RwLock<Settings>>,
+    logger: Arc<RwLock<Logger>>,
+}
+
+impl SettingsHandle {
+    pub fn new(settings: Settings, logger: Arc<RwLock<Logger>>) -> Self {
+        let handle = SettingsHandle {
+            settings: Arc::new(RwLock::new(settings)),
+            logger,
+        };
+        // brings the logger in line with the initial settings
+        handle.update(|_| {});
+        handle
+    }
+
+    pub fn current(&self) -> Settings {
+        self.settings.read().clone()
+    }
+
+    pub fn update(&self, change: impl FnOnce(&mut Settings)) {
+        let mut settings = self.settings.write();
+        change(&mut settings);
+        let mut logger = self.logger.write();
+        logger.set_format(settings.log_format);
+        logger.set_level(settings.log_level);
+    }
+
+    // A file that fails to read or parse leaves the running settings untouched
+    pub fn reload(&self, path: &Path) -> Result<Settings, SettingsError> {
+        let text = std::fs::read_to_string(path).map_err(SettingsError::Io)?;
+        let reloaded = self.current().apply(&text)?;
+        self.update(|s| *s = reloaded.clone());
+        Ok(reloaded)
+    }
+
+    // Reloads `path` every time its modification time changes
+    pub fn watch(self, path: PathBuf, interval: Duration) -> JoinHandle<()> {
+        let modified = |path: &Path| -> Option<SystemTime> {
+            std::fs::metadata(path).and_then(|m| m.modified()).ok()
+        };
+        spawn(move || {
+            let mut last_modified = modified(&path);
+            loop {
+                sleep(interval);
+                let current = modified(&path);
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+                match self.reload(&path) {
+                    Ok(settings) => println!("reloaded settings {:?}", settings),
+                    Err(e) => println!("keeping previous settings, reload failed {:?}", e),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::random_string;
+
+    #[test]
+    fn it_applies_only_the_settings_that_are_mentioned() {
+        let current = Settings {
+            seed_after_completion: true,
+            ..Settings::default()
+        };
+        let text = "# tightened for the night\nmax_connections = 4\nlog_level = off   # quiet\n\nstrict_protocol=1\nmax_download_rate = 65536\nminimal_announces = true\ntracker_hosts = tracker.example, lab.internal\nip_preference = ipv4_only\nverification = deferred\ndownload_dir = /srv/torrents\n";
+        assert_eq!(
+            current.apply(text).unwrap(),
+            Settings {
+                strict_protocol: true,
+                seed_after_completion: true,
+                max_connections: Some(4),
+                max_total_connections: None,
+                max_download_rate: Some(65536),
+                randomize_port: false,
+                minimal_announces: true,
+                peer_id_per_torrent: false,
+                tracker_hosts: Some(vec![
+                    "tracker.example".to_string(),
+                    "lab.internal".to_string()
+                ]),
+                trackers_only: false,
+                ip_preference: IpPreference::Ipv4Only,
+                verification: VerificationMode::Deferred,
+                download_dir: PathBuf::from("/srv/torrents"),
+                log_format: LogFormat::Human,
+                log_level: LogLevel::Off,
+            }
+        );
+        assert!(matches!(
+            current.apply("max_connections = 4\nmax_connectoins = 5"),
+            Err(SettingsError::Invalid { line: 2, .. })
+        ));
+        assert!(matches!(
+            current.apply("log_format = xml"),
+            Err(SettingsError::Invalid { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn it_keeps_running_settings_when_a_reload_fails() {
+        let dir = std::env::temp_dir().join(format!("bit_torrent_settings_{}", random_string()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let logger = Arc::new(RwLock::new(Logger::new(
+            dir.join("log.txt").to_str().unwrap(),
+            LogFormat::Human,
+        )));
+        let handle = SettingsHandle::new(Settings::default(), logger);
+        let path = dir.join("settings.conf");
+
+        std::fs::write(&path, "max_connections = 8\nlog_format = jsonl\n").unwrap();
+        handle.reload(&path).unwrap();
+        assert_eq!(handle.current().max_connections, Some(8));
+        assert_eq!(handle.current().log_format, LogFormat::JsonLines);
+
+        std::fs::write(&path, "max_connections = lots\n").unwrap();
+        assert!(handle.reload(&path).is_err());
+        assert_eq!(handle.current().max_connections, Some(8));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
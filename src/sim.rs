@@ -0,0 +1,440 @@
+use crate::connection::{PeerConnection, Stream};
+use crate::messages::{Handshake, Message, MessageParseError};
+use crate::processor::process_message;
+use crate::torrent::{PiecedContent, Torrent};
+use crate::util::read_be_u32;
+use parking_lot::RwLock;
+use std::collections::VecDeque;
+use std::io::{Error as IOError, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const HANDSHAKE_LEN: usize = 68;
+
+// Shared, manually advanced clock; nothing in a simulation ever sleeps
+#[derive(Clone, Debug, Default)]
+pub struct VirtualClock(Arc<AtomicU64>);
+
+impl VirtualClock {
+    pub fn now(&self) -> Duration {
+        Duration::from_micros(self.0.load(Ordering::SeqCst))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.0.fetch_add(by.as_micros() as u64, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerBehavior {
+    // Has everything and serves every request
+    Seed,
+    // Starts with every other piece and picks up another one each virtual second
+    FastLeech,
+    // Serves like a seed but flips between choking and unchoking us every `period`,
+    // discarding whatever requests were still queued when it chokes
+    Choker { period: Duration },
+    // Serves like a seed until `after`, then resets the connection
+    Dropper { after: Duration },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ScriptedPeer {
+    pub behavior: PeerBehavior,
+    // bytes per virtual second
+    pub upload_rate: u64,
+    pub latency: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SimulatedContent {
+    pub number_of_pieces: u32,
+    pub piece_length: u32,
+    pub total_length: u64,
+}
+
+impl PiecedContent for SimulatedContent {
+    fn number_of_pieces(&self) -> u32 {
+        self.number_of_pieces
+    }
+
+    fn piece_length(&self) -> u32 {
+        self.piece_length
+    }
+
+    fn total_length(&self) -> u64 {
+        self.total_length
+    }
+}
+
+// The remote end of an in-memory connection. Whatever the engine writes is parsed as peer wire
+// messages and answered according to the scripted behavior; answers only become readable once
+// the virtual clock reaches their delivery time.
+#[derive(Debug)]
+pub struct SimulatedPeer {
+    pub peer_addr: SocketAddr,
+    pub local_addr: SocketAddr,
+    script: ScriptedPeer,
+    clock: VirtualClock,
+    peer_id: Vec<u8>,
+    have: Vec<bool>,
+    // (delivery time, message bytes, piece payload length)
+    inbound: VecDeque<(Duration, Vec<u8>, u64)>,
+    readable: VecDeque<u8>,
+    outbound: Vec<u8>,
+    handshake_done: bool,
+    choking: bool,
+    upload_busy_until: Duration,
+    next_event: Duration,
+    disconnected: bool,
+    uploaded: Arc<AtomicU64>,
+}
+
+impl SimulatedPeer {
+    fn new(
+        id: u8,
+        script: ScriptedPeer,
+        clock: VirtualClock,
+        number_of_pieces: u32,
+        uploaded: Arc<AtomicU64>,
+    ) -> Self {
+        let have = (0..number_of_pieces)
+            .map(|i| script.behavior != PeerBehavior::FastLeech || i % 2 == 0)
+            .collect();
+        let next_event = match script.behavior {
+            PeerBehavior::Seed => Duration::MAX,
+            PeerBehavior::FastLeech => clock.now() + Duration::from_secs(1),
+            PeerBehavior::Choker { period } => clock.now() + period,
+            PeerBehavior::Dropper { after } => clock.now() + after,
+        };
+        SimulatedPeer {
+            peer_addr: SocketAddr::from((Ipv4Addr::new(10, 0, 0, id), 6881)),
+            local_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 8999)),
+            script,
+            clock,
+            peer_id: format!("-SIM{:04}-{:011}", id, id).into_bytes(),
+            have,
+            inbound: VecDeque::new(),
+            readable: VecDeque::new(),
+            outbound: vec![],
+            handshake_done: false,
+            choking: true,
+            upload_busy_until: Duration::ZERO,
+            next_event,
+            disconnected: false,
+            uploaded,
+        }
+    }
+
+    fn send_at(&mut self, at: Duration, message: Message) {
+        let payload = match &message {
+            Message::Piece { data, .. } => data.len() as u64,
+            _ => 0,
+        };
+        self.inbound.push_back((at, message.serialize(), payload));
+    }
+
+    fn send(&mut self, message: Message) {
+        let at = self.clock.now() + self.script.latency;
+        self.send_at(at, message);
+    }
+
+    fn bitfield(&self) -> Vec<u8> {
+        let mut bf = vec![0u8; self.have.len().div_ceil(8)];
+        for (i, have) in self.have.iter().enumerate() {
+            if *have {
+                bf[i / 8] |= 1 << (7 - (i % 8));
+            }
+        }
+        bf
+    }
+
+    // Applies whatever the behavior has scheduled up to the current virtual time
+    fn update(&mut self) {
+        let now = self.clock.now();
+        while !self.disconnected && self.next_event <= now {
+            match self.script.behavior {
+                PeerBehavior::Seed => self.next_event = Duration::MAX,
+                PeerBehavior::FastLeech => {
+                    if let Some(index) = self.have.iter().position(|h| !h) {
+                        self.have[index] = true;
+                        self.send(Message::Have {
+                            index: index as u32,
+                        });
+                        self.next_event += Duration::from_secs(1);
+                    } else {
+                        self.next_event = Duration::MAX;
+                    }
+                }
+                PeerBehavior::Choker { period } => {
+                    self.choking = !self.choking;
+                    if self.choking {
+                        // choking discards every request we had not answered yet
+                        self.inbound.retain(|(at, _, _)| *at <= now);
+                        self.upload_busy_until = now;
+                        self.send(Message::Choke);
+                    } else {
+                        self.send(Message::UnChoke);
+                    }
+                    self.next_event += period;
+                }
+                PeerBehavior::Dropper { .. } => self.disconnected = true,
+            }
+        }
+    }
+
+    fn receive(&mut self, message: Message) {
+        match message {
+            Message::Interested
+                if self.choking && !matches!(self.script.behavior, PeerBehavior::Choker { .. }) =>
+            {
+                self.choking = false;
+                self.send(Message::UnChoke);
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                if self.choking || !self.have.get(index as usize).copied().unwrap_or(false) {
+                    return;
+                }
+                let transfer =
+                    Duration::from_secs_f64(length as f64 / self.script.upload_rate as f64);
+                let start = self.upload_busy_until.max(self.clock.now());
+                self.upload_busy_until = start + transfer;
+                let at = self.upload_busy_until + self.script.latency;
+                self.send_at(
+                    at,
+                    Message::Piece {
+                        index,
+                        offset: begin,
+                        data: vec![index as u8; length as usize],
+                    },
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+impl std::io::Write for SimulatedPeer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+        if self.disconnected {
+            return Err(IOError::from(ErrorKind::ConnectionReset));
+        }
+        self.outbound.extend_from_slice(buf);
+
+        if !self.handshake_done && self.outbound.len() >= HANDSHAKE_LEN {
+            let theirs = Handshake::new(&self.outbound[..HANDSHAKE_LEN])
+                .map_err(|_| IOError::from(ErrorKind::InvalidData))?;
+            self.outbound.drain(..HANDSHAKE_LEN);
+            self.handshake_done = true;
+            let ours = Handshake {
+                info_hash: theirs.info_hash,
+                peer_id: self.peer_id.clone(),
+                fast_extension: false,
+            };
+            // the handshake is read on a separate thread with a real timeout, so it is delivered right away
+            self.inbound
+                .push_back((Duration::ZERO, ours.serialize(), 0));
+            let bitfield = self.bitfield();
+            self.send(Message::BitField(bitfield));
+        }
+
+        while self.handshake_done && self.outbound.len() >= 4 {
+            let prefix_len = read_be_u32(&mut &self.outbound[..4]).unwrap() as usize;
+            if self.outbound.len() < 4 + prefix_len {
+                break;
+            }
+            let bytes: Vec<u8> = self.outbound.drain(..4 + prefix_len).skip(4).collect();
+            if let Ok(message) = Message::new(Box::new(bytes.into_iter()), prefix_len as u32) {
+                self.receive(message);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), IOError> {
+        Ok(())
+    }
+}
+
+impl std::io::Read for SimulatedPeer {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+        self.update();
+        if self.disconnected {
+            return Err(IOError::from(ErrorKind::ConnectionReset));
+        }
+        let now = self.clock.now();
+        while let Some((at, _, _)) = self.inbound.front() {
+            if *at > now {
+                break;
+            }
+            let (_, bytes, payload) = self.inbound.pop_front().unwrap();
+            self.uploaded.fetch_add(payload, Ordering::SeqCst);
+            self.readable.extend(bytes);
+        }
+        if self.readable.is_empty() {
+            return Err(IOError::from(ErrorKind::WouldBlock));
+        }
+        let n = buf.len().min(self.readable.len());
+        for (slot, byte) in buf.iter_mut().zip(self.readable.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+#[derive(Debug)]
+pub struct SimulationReport {
+    pub completed: bool,
+    pub virtual_elapsed: Duration,
+    pub wall_elapsed: Duration,
+    pub downloaded_bytes: u64,
+    // (behavior, bytes that peer sent us) in the order the peers were given
+    pub uploaded_by_peer: Vec<(PeerBehavior, u64)>,
+}
+
+pub struct Simulation {
+    clock: VirtualClock,
+    tick: Duration,
+    torrent: Arc<RwLock<Torrent>>,
+    connections: Vec<Option<PeerConnection>>,
+    uploaded_by_peer: Vec<(PeerBehavior, Arc<AtomicU64>)>,
+}
+
+impl Simulation {
+    pub fn new(content: SimulatedContent, peers: &[ScriptedPeer], tick: Duration) -> Self {
+        let clock = VirtualClock::default();
+        let torrent = Arc::new(RwLock::new(Torrent::new(&content)));
+        let info_hash = [0u8; 20];
+        let mut uploaded_by_peer = vec![];
+        let connections = peers
+            .iter()
+            .enumerate()
+            .map(|(i, script)| {
+                let uploaded = Arc::new(AtomicU64::new(0));
+                uploaded_by_peer.push((script.behavior, Arc::clone(&uploaded)));
+                let peer = SimulatedPeer::new(
+                    i as u8 + 1,
+                    *script,
+                    clock.clone(),
+                    content.number_of_pieces,
+                    uploaded,
+                );
+                let peer_id = peer.peer_id.clone();
+                PeerConnection::new(
+                    Stream::Simulated(Box::new(peer)),
+                    &info_hash,
+                    b"-BT0001-simulation00",
+                    &peer_id,
+                    Box::new(|_, _| {}),
+                )
+                .ok()
+            })
+            .collect();
+
+        Simulation {
+            clock,
+            tick,
+            torrent,
+            connections,
+            uploaded_by_peer,
+        }
+    }
+
+    // Advances virtual time tick by tick, feeding every readable message through the same
+    // `process_message` the real engine uses, until the download completes or `limit` passes
+    pub fn run(&mut self, limit: Duration) -> SimulationReport {
+        let started = Instant::now();
+        while self.clock.now() < limit && !self.torrent.read().are_we_done_yet() {
+            self.clock.advance(self.tick);
+            for slot in self.connections.iter_mut() {
+                let connection = match slot {
+                    Some(connection) => connection,
+                    None => continue,
+                };
+                loop {
+                    match connection.read_message() {
+                        Ok(message) => {
+                            process_message(Arc::clone(&self.torrent), message, connection);
+                        }
+                        Err(MessageParseError::WouldBlock) => break,
+                        Err(_) => {
+                            *slot = None;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        SimulationReport {
+            completed: self.torrent.read().are_we_done_yet(),
+            virtual_elapsed: self.clock.now(),
+            wall_elapsed: started.elapsed(),
+            downloaded_bytes: self.torrent.read().downloaded_bytes,
+            uploaded_by_peer: self
+                .uploaded_by_peer
+                .iter()
+                .map(|(behavior, uploaded)| (*behavior, uploaded.load(Ordering::SeqCst)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content() -> SimulatedContent {
+        SimulatedContent {
+            number_of_pieces: 8,
+            piece_length: 32768,
+            total_length: 8 * 32768 - 1000,
+        }
+    }
+
+    fn peer(behavior: PeerBehavior) -> ScriptedPeer {
+        ScriptedPeer {
+            behavior,
+            upload_rate: 1 << 20,
+            latency: Duration::from_millis(50),
+        }
+    }
+
+    #[test]
+    fn it_downloads_everything_from_a_seed_in_virtual_time() {
+        let mut simulation = Simulation::new(
+            content(),
+            &[peer(PeerBehavior::Seed)],
+            Duration::from_millis(10),
+        );
+        let report = simulation.run(Duration::from_secs(60));
+
+        assert!(report.completed);
+        assert_eq!(report.downloaded_bytes, content().total_length);
+        assert_eq!(report.uploaded_by_peer[0].1, content().total_length);
+        // 16 blocks requested one at a time, each delayed by at least the peer's latency
+        assert!(report.virtual_elapsed >= Duration::from_millis(16 * 50));
+    }
+
+    #[test]
+    fn it_stalls_when_the_only_peer_drops_the_connection() {
+        let mut simulation = Simulation::new(
+            content(),
+            &[peer(PeerBehavior::Dropper {
+                after: Duration::from_millis(500),
+            })],
+            Duration::from_millis(10),
+        );
+        let report = simulation.run(Duration::from_secs(10));
+
+        assert!(!report.completed);
+        assert!(report.downloaded_bytes < content().total_length);
+        assert_eq!(report.virtual_elapsed, Duration::from_secs(10));
+    }
+}
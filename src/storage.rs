@@ -0,0 +1,119 @@
+use std::io::{Error as IOError, ErrorKind};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
+
+pub const MAX_WRITE_ATTEMPTS: u32 = 3;
+// doubled after each failed attempt
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// A file that still couldn't be written after retrying. The data stays in memory, so nothing is lost
+// until the torrent is removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageError {
+    pub path: String,
+    pub kind: ErrorKind,
+    // the operating system's error number, when the error came from it
+    pub errno: Option<i32>,
+    pub message: String,
+    pub attempts: u32,
+}
+
+impl StorageError {
+    fn new(path: &Path, e: &IOError, attempts: u32) -> Self {
+        StorageError {
+            path: path.display().to_string(),
+            kind: e.kind(),
+            errno: e.raw_os_error(),
+            message: e.to_string(),
+            attempts,
+        }
+    }
+}
+
+// Errors that might well not happen again; anything else (no space, no permission, no such
+// directory) fails straight away
+fn is_transient(e: &IOError) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+    )
+}
+
+// Creates (or truncates) `path` and writes `data` to it, trying again a few times on transient
+// errors
+pub fn write_file(path: &Path, data: &[u8]) -> Result<(), StorageError> {
+    with_retries(FIRST_RETRY_DELAY, || std::fs::write(path, data))
+        .map_err(|(e, attempts)| StorageError::new(path, &e, attempts))
+}
+
+// Creates whatever directories `path` is to go in that don't exist yet
+pub fn create_parent_dirs(path: &Path) -> Result<(), StorageError> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            with_retries(FIRST_RETRY_DELAY, || std::fs::create_dir_all(parent))
+                .map_err(|(e, attempts)| StorageError::new(parent, &e, attempts))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Runs `operation` until it succeeds, fails with an error that isn't transient or has been tried
+// `MAX_WRITE_ATTEMPTS` times; failures come back with the number of attempts made
+fn with_retries<T>(
+    first_delay: Duration,
+    mut operation: impl FnMut() -> Result<T, IOError>,
+) -> Result<T, (IOError, u32)> {
+    let mut delay = first_delay;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(e) if is_transient(&e) && attempts < MAX_WRITE_ATTEMPTS => {
+                println!("write failed ({}), trying again in {:?}", e, delay);
+                sleep(delay);
+                delay *= 2;
+            }
+            Err(e) => return Err((e, attempts)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_retries_transient_errors_a_bounded_number_of_times() {
+        let mut failures = vec![ErrorKind::Interrupted, ErrorKind::TimedOut];
+        let result = with_retries(Duration::ZERO, || match failures.pop() {
+            Some(kind) => Err(IOError::from(kind)),
+            None => Ok(7),
+        });
+        assert_eq!(result.unwrap(), 7);
+
+        let mut calls = 0;
+        let result: Result<(), _> = with_retries(Duration::ZERO, || {
+            calls += 1;
+            Err(IOError::from(ErrorKind::Interrupted))
+        });
+        assert_eq!(result.unwrap_err().1, MAX_WRITE_ATTEMPTS);
+        assert_eq!(calls, MAX_WRITE_ATTEMPTS);
+
+        let dir = std::env::temp_dir().join(format!(
+            "bit_torrent_storage_{}",
+            crate::util::random_string()
+        ));
+        let path = dir.join("missing").join("a.bin");
+        let error = write_file(&path, b"data").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::NotFound);
+        assert_eq!(error.errno, Some(2));
+        assert_eq!(error.attempts, 1);
+
+        create_parent_dirs(&path).unwrap();
+        write_file(&path, b"data").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
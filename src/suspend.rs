@@ -0,0 +1,67 @@
+use std::time::{Duration, Instant, SystemTime};
+
+// Less than this unaccounted for is clock adjustment or scheduling noise, not a suspend
+pub const DEFAULT_SUSPEND_THRESHOLD: Duration = Duration::from_secs(30);
+
+// Notices the machine having been suspended. The monotonic clock behind `Instant` stops while the
+// machine sleeps (on Linux and macOS at least) but the wall clock keeps going, so the wall clock
+// getting ahead of the monotonic one between two checks is time spent asleep. Someone setting the
+// clock forward looks the same, which at worst costs a needless reannounce.
+#[derive(Debug)]
+pub struct SuspendDetector {
+    threshold: Duration,
+    last: (SystemTime, Instant),
+}
+
+impl SuspendDetector {
+    pub fn new(threshold: Duration) -> Self {
+        SuspendDetector {
+            threshold,
+            last: (SystemTime::now(), Instant::now()),
+        }
+    }
+
+    // How long the machine was asleep since the last check, if it was
+    pub fn check(&mut self) -> Option<Duration> {
+        self.check_at(SystemTime::now(), Instant::now())
+    }
+
+    fn check_at(&mut self, wall: SystemTime, monotonic: Instant) -> Option<Duration> {
+        let (last_wall, last_monotonic) = std::mem::replace(&mut self.last, (wall, monotonic));
+        // a wall clock set backwards just starts over from the new time
+        let wall_elapsed = wall.duration_since(last_wall).ok()?;
+        let asleep = wall_elapsed.saturating_sub(monotonic.duration_since(last_monotonic));
+        (asleep >= self.threshold).then_some(asleep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reports_wall_clock_time_the_monotonic_clock_missed() {
+        let mut detector = SuspendDetector::new(DEFAULT_SUSPEND_THRESHOLD);
+        let (wall, monotonic) = detector.last;
+        let minute = Duration::from_secs(60);
+
+        assert_eq!(detector.check_at(wall + minute, monotonic + minute), None);
+        assert_eq!(
+            detector.check_at(wall + 62 * minute, monotonic + 2 * minute),
+            Some(60 * minute)
+        );
+        // the clock being stepped back is neither a suspend nor forgotten
+        assert_eq!(detector.check_at(wall, monotonic + 3 * minute), None);
+        assert_eq!(
+            detector.check_at(wall + 2 * minute, monotonic + 4 * minute),
+            Some(minute)
+        );
+        assert_eq!(
+            detector.check_at(
+                wall + 2 * minute + Duration::from_secs(10),
+                monotonic + 4 * minute
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,467 @@
+use crate::messages::{Handshake, Message};
+use crate::ut_metadata::{
+    ExtendedHandshake, MetadataMessage, MetadataServer, EXTENDED_HANDSHAKE_ID, UT_METADATA_ID,
+};
+use crate::util::{random_string, read_be_u32};
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+const HANDSHAKE_LEN: usize = 68;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// The default profile is a well behaved seed; every knob makes it misbehave in one specific way
+#[derive(Clone, Debug, Default)]
+pub struct SeederProfile {
+    // wait this long before answering each request
+    pub delay_per_block: Duration,
+    // flip between choking and unchoking every interval; requests received while choked are dropped
+    pub choke_interval: Option<Duration>,
+    // flip a byte in every nth block served
+    pub corrupt_every: Option<u64>,
+    // chance of hanging up after each message received
+    pub disconnect_probability: f64,
+}
+
+impl SeederProfile {
+    pub fn slow(delay_per_block: Duration) -> Self {
+        SeederProfile {
+            delay_per_block,
+            ..Default::default()
+        }
+    }
+
+    pub fn choker(choke_interval: Duration) -> Self {
+        SeederProfile {
+            choke_interval: Some(choke_interval),
+            ..Default::default()
+        }
+    }
+
+    pub fn corrupter(corrupt_every: u64) -> Self {
+        SeederProfile {
+            corrupt_every: Some(corrupt_every),
+            ..Default::default()
+        }
+    }
+
+    pub fn dropper(disconnect_probability: f64) -> Self {
+        SeederProfile {
+            disconnect_probability,
+            ..Default::default()
+        }
+    }
+}
+
+struct Content {
+    data: Vec<u8>,
+    piece_length: u32,
+    info_hash: [u8; 20],
+    // the bencoded info dictionary, for peers that ask for it over ut_metadata
+    info_dictionary: Option<Arc<Vec<u8>>>,
+    peer_id: Vec<u8>,
+    profile: SeederProfile,
+    blocks_served: AtomicU64,
+}
+
+// Serves one complete copy of some data over real TCP on localhost until dropped
+pub struct TestSeeder {
+    addr: SocketAddr,
+    content: Arc<Content>,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl TestSeeder {
+    pub fn start(
+        data: Vec<u8>,
+        piece_length: u32,
+        info_hash: [u8; 20],
+        profile: SeederProfile,
+    ) -> Result<Self, std::io::Error> {
+        TestSeeder::launch(data, piece_length, info_hash, None, profile)
+    }
+
+    // Also hands the info dictionary out over ut_metadata, so it can be added from a magnet link
+    pub fn start_with_metadata(
+        data: Vec<u8>,
+        piece_length: u32,
+        info_dictionary: Vec<u8>,
+        profile: SeederProfile,
+    ) -> Result<Self, std::io::Error> {
+        let info_hash = <[u8; 20]>::from(Sha1::digest(&info_dictionary));
+        let info_dictionary = Some(Arc::new(info_dictionary));
+        TestSeeder::launch(data, piece_length, info_hash, info_dictionary, profile)
+    }
+
+    fn launch(
+        data: Vec<u8>,
+        piece_length: u32,
+        info_hash: [u8; 20],
+        info_dictionary: Option<Arc<Vec<u8>>>,
+        profile: SeederProfile,
+    ) -> Result<Self, std::io::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let content = Arc::new(Content {
+            data,
+            piece_length,
+            info_hash,
+            info_dictionary,
+            peer_id: random_string().into_bytes(),
+            profile,
+            blocks_served: AtomicU64::new(0),
+        });
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let content = Arc::clone(&content);
+            let shutdown = Arc::clone(&shutdown);
+            spawn(move || {
+                while !shutdown.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            let content = Arc::clone(&content);
+                            let shutdown = Arc::clone(&shutdown);
+                            spawn(move || {
+                                if let Err(e) = serve(stream, &content, &shutdown) {
+                                    println!("test seeder connection ended {:?}", e);
+                                }
+                            });
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => sleep(POLL_INTERVAL),
+                        Err(e) => {
+                            println!("test seeder failed to accept {:?}", e);
+                            break;
+                        }
+                    }
+                }
+            })
+        };
+
+        Ok(TestSeeder {
+            addr,
+            content,
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn peer_id(&self) -> &[u8] {
+        &self.content.peer_id
+    }
+
+    pub fn blocks_served(&self) -> u64 {
+        self.content.blocks_served.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for TestSeeder {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn read_message(stream: &mut TcpStream) -> Result<Option<Message>, std::io::Error> {
+    let mut prefix = [0u8; 4];
+    match stream.read_exact(&mut prefix) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            return Ok(None)
+        }
+        Err(e) => return Err(e),
+    }
+    let prefix_len = read_be_u32(&mut &prefix[..]).unwrap();
+    let mut body = vec![0u8; prefix_len as usize];
+    stream.read_exact(&mut body)?;
+    Message::new(Box::new(body.into_iter()), prefix_len)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, format!("{:?}", e)))
+}
+
+fn serve(
+    mut stream: TcpStream,
+    content: &Content,
+    shutdown: &AtomicBool,
+) -> Result<(), std::io::Error> {
+    let mut buf = [0u8; HANDSHAKE_LEN];
+    stream.read_exact(&mut buf)?;
+    let theirs = Handshake::new(&buf)
+        .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, format!("{:?}", e)))?;
+    if theirs.info_hash != content.info_hash {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "handshake for an unknown info hash",
+        ));
+    }
+    let ours = Handshake {
+        info_hash: content.info_hash.to_vec(),
+        peer_id: content.peer_id.clone(),
+        fast_extension: false,
+    };
+    stream.write_all(&ours.serialize())?;
+
+    let pieces = (content.data.len() as u32).div_ceil(content.piece_length);
+    let mut bitfield = vec![0u8; (pieces as usize).div_ceil(8)];
+    for i in 0..pieces as usize {
+        bitfield[i / 8] |= 1 << (7 - (i % 8));
+    }
+    stream.write_all(&Message::BitField(bitfield).serialize())?;
+
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let profile = &content.profile;
+    let mut choking = true;
+    let mut interested = false;
+    let mut last_toggle = Instant::now();
+    let mut metadata = content
+        .info_dictionary
+        .as_ref()
+        .map(|info| MetadataServer::new(Arc::clone(info)));
+    // the id the peer wants its ut_metadata messages sent with
+    let mut their_ut_metadata = None;
+
+    while !shutdown.load(Ordering::SeqCst) {
+        if let Some(interval) = profile.choke_interval {
+            if interested && last_toggle.elapsed() >= interval {
+                choking = !choking;
+                last_toggle = Instant::now();
+                let message = if choking {
+                    Message::Choke
+                } else {
+                    Message::UnChoke
+                };
+                stream.write_all(&message.serialize())?;
+            }
+        }
+
+        let message = match read_message(&mut stream)? {
+            Some(message) => message,
+            None => continue,
+        };
+        if profile.disconnect_probability > 0.0
+            && rand::thread_rng().gen_bool(profile.disconnect_probability.min(1.0))
+        {
+            return Ok(());
+        }
+
+        match message {
+            Message::Interested => {
+                interested = true;
+                if choking {
+                    choking = false;
+                    last_toggle = Instant::now();
+                    stream.write_all(&Message::UnChoke.serialize())?;
+                }
+            }
+            Message::NotInterested => interested = false,
+            Message::Extended { id, payload } => {
+                let server = match metadata.as_mut() {
+                    Some(server) => server,
+                    None => continue,
+                };
+                if id == EXTENDED_HANDSHAKE_ID {
+                    their_ut_metadata =
+                        ExtendedHandshake::from_payload(&payload).and_then(|h| h.ut_metadata);
+                    let ours = ExtendedHandshake {
+                        ut_metadata: Some(UT_METADATA_ID),
+                        metadata_size: Some(server.metadata_size()),
+                    };
+                    stream.write_all(
+                        &Message::Extended {
+                            id: EXTENDED_HANDSHAKE_ID,
+                            payload: ours.to_payload(),
+                        }
+                        .serialize(),
+                    )?;
+                } else if let (UT_METADATA_ID, Some(theirs)) = (id, their_ut_metadata) {
+                    if let Some(MetadataMessage::Request { piece }) =
+                        MetadataMessage::parse(&payload)
+                    {
+                        let response = server.respond(Instant::now(), piece);
+                        stream.write_all(
+                            &Message::Extended {
+                                id: theirs,
+                                payload: response.serialize(),
+                            }
+                            .serialize(),
+                        )?;
+                    }
+                }
+            }
+            Message::KeepAlive => stream.write_all(&Message::KeepAlive.serialize())?,
+            Message::Request {
+                index,
+                begin,
+                length,
+            } if !choking => {
+                let start = (index as u64 * content.piece_length as u64 + begin as u64) as usize;
+                let mut data = match content.data.get(start..start + length as usize) {
+                    Some(data) => data.to_vec(),
+                    None => continue,
+                };
+                sleep(profile.delay_per_block);
+                let served = content.blocks_served.fetch_add(1, Ordering::SeqCst) + 1;
+                if let Some(n) = profile.corrupt_every {
+                    if served.is_multiple_of(n) && !data.is_empty() {
+                        data[0] = !data[0];
+                    }
+                }
+                stream.write_all(
+                    &Message::Piece {
+                        index,
+                        offset: begin,
+                        data,
+                    }
+                    .serialize(),
+                )?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{PeerConnection, Stream};
+    use crate::messages::MessageParseError;
+
+    fn connect(seeder: &TestSeeder, info_hash: &[u8; 20]) -> PeerConnection {
+        let stream = TcpStream::connect(seeder.addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        PeerConnection::new(
+            Stream::Tcp(stream),
+            info_hash,
+            random_string().as_bytes(),
+            seeder.peer_id(),
+            Box::new(|_, _| {}),
+        )
+        .unwrap()
+    }
+
+    fn next_message(connection: &mut PeerConnection) -> Message {
+        loop {
+            match connection.read_message() {
+                Ok(message) => return message,
+                Err(MessageParseError::WouldBlock) | Err(MessageParseError::TimedOut) => continue,
+                Err(e) => panic!("unexpected read error {:?}", e),
+            }
+        }
+    }
+
+    fn request(connection: &mut PeerConnection, index: u32, begin: u32, length: u32) -> Vec<u8> {
+        connection
+            .write_message(Message::Request {
+                index,
+                begin,
+                length,
+            })
+            .unwrap();
+        match next_message(connection) {
+            Message::Piece { data, .. } => data,
+            m => panic!("expected a piece but got {}", m),
+        }
+    }
+
+    fn example_data() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn it_serves_requested_blocks() {
+        let data = example_data();
+        let info_hash = [7u8; 20];
+        let seeder =
+            TestSeeder::start(data.clone(), 32768, info_hash, SeederProfile::default()).unwrap();
+        let mut connection = connect(&seeder, &info_hash);
+
+        assert!(matches!(
+            next_message(&mut connection),
+            Message::BitField(_)
+        ));
+        connection.write_message(Message::Interested).unwrap();
+        assert!(matches!(next_message(&mut connection), Message::UnChoke));
+
+        assert_eq!(
+            request(&mut connection, 0, 0, 16384),
+            data[..16384].to_vec()
+        );
+        assert_eq!(
+            request(&mut connection, 3, 0, 1696),
+            data[3 * 32768..].to_vec()
+        );
+        assert_eq!(seeder.blocks_served(), 2);
+    }
+
+    #[test]
+    fn it_corrupts_every_nth_block() {
+        let data = example_data();
+        let info_hash = [8u8; 20];
+        let seeder =
+            TestSeeder::start(data.clone(), 32768, info_hash, SeederProfile::corrupter(2)).unwrap();
+        let mut connection = connect(&seeder, &info_hash);
+
+        next_message(&mut connection);
+        connection.write_message(Message::Interested).unwrap();
+        next_message(&mut connection);
+
+        assert_eq!(
+            request(&mut connection, 0, 0, 16384),
+            data[..16384].to_vec()
+        );
+        assert_ne!(
+            request(&mut connection, 0, 16384, 16384),
+            data[16384..32768].to_vec()
+        );
+    }
+
+    #[test]
+    fn it_rejects_handshakes_for_other_torrents() {
+        let seeder =
+            TestSeeder::start(example_data(), 32768, [9u8; 20], SeederProfile::default()).unwrap();
+        let stream = TcpStream::connect(seeder.addr()).unwrap();
+        let result = PeerConnection::new(
+            Stream::Tcp(stream),
+            &[1u8; 20],
+            random_string().as_bytes(),
+            seeder.peer_id(),
+            Box::new(|_, _| {}),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn it_hands_out_the_info_dictionary() {
+        let info = b"d6:lengthi100000e4:name4:data12:piece lengthi32768e6:pieces0:e".to_vec();
+        let info_hash = <[u8; 20]>::from(Sha1::digest(&info));
+        let seeder = TestSeeder::start_with_metadata(
+            example_data(),
+            32768,
+            info.clone(),
+            SeederProfile::default(),
+        )
+        .unwrap();
+        let mut connection = connect(&seeder, &info_hash);
+        let fetched =
+            crate::ut_metadata::fetch_metadata(&mut connection, info_hash, Duration::from_secs(5))
+                .unwrap();
+        assert_eq!(fetched, info);
+    }
+}
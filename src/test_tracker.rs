@@ -0,0 +1,672 @@
+use crate::bencode::{bencode, Bencodable, DictBuilder};
+use crate::tracker::{
+    Announce, AnnounceIntervals, TrackerPeer, TrackerRequest, TrackerResponse, TrackerResponseError,
+};
+use parking_lot::Mutex;
+use percent_encoding::percent_decode_str;
+use rand::Rng;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+// BEP 15: every exchange starts with a connect carrying this magic number, and the connection id it
+// hands back is good for a couple of minutes of announces and scrapes
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+const EVENT_NONE: u32 = 0;
+const EVENT_COMPLETED: u32 = 1;
+const EVENT_STOPPED: u32 = 3;
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(120);
+const DEFAULT_NUM_WANT: usize = 50;
+const ANNOUNCE_INTERVAL: u32 = 60;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+struct Member {
+    // bytes still left to download; 0 is a seed
+    left: u64,
+    peer_id: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<SocketAddrV4, Member>,
+    completed: u32,
+}
+
+impl Swarm {
+    // Records an announce and returns the (leechers, seeders) counts with up to `num_want` of the
+    // other peers
+    fn announce(
+        &mut self,
+        peer: SocketAddrV4,
+        peer_id: &[u8],
+        left: u64,
+        event: u32,
+        num_want: usize,
+    ) -> (u32, u32, Vec<(SocketAddrV4, &[u8])>) {
+        if event == EVENT_STOPPED {
+            self.peers.remove(&peer);
+        } else {
+            if event == EVENT_COMPLETED {
+                self.completed += 1;
+            }
+            let peer_id = peer_id.to_vec();
+            self.peers.insert(peer, Member { left, peer_id });
+        }
+        let (leechers, seeders) = self.counts();
+        let others = self
+            .peers
+            .iter()
+            .filter(|(other, _)| **other != peer)
+            .take(num_want)
+            .map(|(other, member)| (*other, member.peer_id.as_slice()))
+            .collect();
+        (leechers, seeders, others)
+    }
+
+    fn counts(&self) -> (u32, u32) {
+        let seeders = self.peers.values().filter(|m| m.left == 0).count() as u32;
+        (self.peers.len() as u32 - seeders, seeders)
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    swarms: HashMap<[u8; 20], Swarm>,
+    connection_ids: HashMap<u64, Instant>,
+}
+
+// A UDP tracker on localhost that remembers whoever announces to it, so test swarms can find each
+// other with no HTTP involved. Only IPv4 peers are tracked. Stops when dropped.
+pub struct TestUdpTracker {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl TestUdpTracker {
+    pub fn start() -> Result<Self, std::io::Error> {
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let addr = socket.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let state = Arc::clone(&state);
+            let shutdown = Arc::clone(&shutdown);
+            spawn(move || {
+                let mut buf = [0u8; 2048];
+                while !shutdown.load(Ordering::SeqCst) {
+                    match socket.recv_from(&mut buf) {
+                        Ok((n, from)) => {
+                            if let Some(response) = respond(&buf[..n], from, &mut state.lock()) {
+                                let _ = socket.send_to(&response, from);
+                            }
+                        }
+                        Err(e)
+                            if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                        Err(e) => {
+                            println!("test tracker failed to receive {:?}", e);
+                            break;
+                        }
+                    }
+                }
+            })
+        };
+
+        Ok(TestUdpTracker {
+            addr,
+            state,
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn announce_url(&self) -> String {
+        format!("udp://{}/announce", self.addr)
+    }
+
+    // Lists a peer that never announces itself, such as a `TestSeeder`, as a seed
+    pub fn add_seed(&self, info_hash: [u8; 20], addr: SocketAddrV4, peer_id: &[u8]) {
+        add_seed(&self.state, info_hash, addr, peer_id);
+    }
+}
+
+// The same tracker over HTTP, for `HttpTracker` rather than `UdpTracker`. Peers come back
+// compactly only when the announce asks for it with `compact=1`, and as dictionaries with their
+// peer ids otherwise. Stops when dropped.
+pub struct TestHttpTracker {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl TestHttpTracker {
+    pub fn start() -> Result<Self, std::io::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let state = Arc::clone(&state);
+            let shutdown = Arc::clone(&shutdown);
+            spawn(move || {
+                while !shutdown.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        Ok((stream, from)) => {
+                            if let Err(e) = serve_http(stream, from, &state) {
+                                println!("test tracker request failed {:?}", e);
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => sleep(POLL_INTERVAL),
+                        Err(e) => {
+                            println!("test tracker failed to accept {:?}", e);
+                            break;
+                        }
+                    }
+                }
+            })
+        };
+
+        Ok(TestHttpTracker {
+            addr,
+            state,
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn announce_url(&self) -> String {
+        format!("http://{}/announce", self.addr)
+    }
+
+    // Lists a peer that never announces itself, such as a `TestSeeder`, as a seed
+    pub fn add_seed(&self, info_hash: [u8; 20], addr: SocketAddrV4, peer_id: &[u8]) {
+        add_seed(&self.state, info_hash, addr, peer_id);
+    }
+}
+
+impl Drop for TestHttpTracker {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// A tracker that's only a value: every announce gets the same peers back (or the same failure),
+// and each request is kept for the test to look at. Given to a session with
+// `Session::set_announcer`.
+#[derive(Debug, Default)]
+pub struct MockTracker {
+    peers: Vec<SocketAddr>,
+    failure: Option<String>,
+    external_ip: Option<IpAddr>,
+    announces: Mutex<Vec<TrackerRequest>>,
+}
+
+impl MockTracker {
+    pub fn new(peers: Vec<SocketAddr>) -> Self {
+        MockTracker {
+            peers,
+            ..MockTracker::default()
+        }
+    }
+
+    // Refuses every announce with `reason` as its failure reason
+    pub fn failing(reason: &str) -> Self {
+        MockTracker {
+            failure: Some(reason.to_string()),
+            ..MockTracker::default()
+        }
+    }
+
+    // Tells every announcer this is the address it announced from
+    pub fn external_ip(mut self, ip: IpAddr) -> Self {
+        self.external_ip = Some(ip);
+        self
+    }
+
+    pub fn announces(&self) -> Vec<TrackerRequest> {
+        self.announces.lock().clone()
+    }
+}
+
+impl Announce for MockTracker {
+    fn announce(&self, request: &TrackerRequest) -> Result<TrackerResponse, TrackerResponseError> {
+        self.announces.lock().push(request.clone());
+        if let Some(reason) = &self.failure {
+            return Err(TrackerResponseError::Failure(reason.clone()));
+        }
+        Ok(TrackerResponse {
+            peers: self
+                .peers
+                .iter()
+                .map(|addr| TrackerPeer::SocketAddr(*addr))
+                .collect(),
+            intervals: AnnounceIntervals {
+                interval: Some(Duration::from_secs(ANNOUNCE_INTERVAL as u64)),
+                min_interval: None,
+            },
+            complete: None,
+            incomplete: None,
+            warning_message: None,
+            external_ip: self.external_ip,
+            redirected_to: None,
+        })
+    }
+}
+
+fn add_seed(state: &Mutex<State>, info_hash: [u8; 20], addr: SocketAddrV4, peer_id: &[u8]) {
+    let peer_id = peer_id.to_vec();
+    state
+        .lock()
+        .swarms
+        .entry(info_hash)
+        .or_default()
+        .peers
+        .insert(addr, Member { left: 0, peer_id });
+}
+
+impl Drop for TestUdpTracker {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn header(action: u32, transaction_id: u32) -> Vec<u8> {
+    let mut response = action.to_be_bytes().to_vec();
+    response.extend_from_slice(&transaction_id.to_be_bytes());
+    response
+}
+
+fn error(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut response = header(ACTION_ERROR, transaction_id);
+    response.extend_from_slice(message.as_bytes());
+    response
+}
+
+// One request per connection, answered with a bencoded announce response or failure reason
+fn serve_http(
+    mut stream: TcpStream,
+    from: SocketAddr,
+    state: &Mutex<State>,
+) -> Result<(), std::io::Error> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(HTTP_READ_TIMEOUT))?;
+    let mut request = vec![];
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf)? {
+            0 => break,
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    let response = http_announce(&request, from, &mut state.lock())
+        .unwrap_or_else(|reason| DictBuilder::new().insert("failure reason", reason).build());
+    let body = bencode(&response)
+        .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, format!("{:?}", e)))?;
+    stream.write_all(
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .as_bytes(),
+    )?;
+    stream.write_all(&body)
+}
+
+fn http_announce(
+    request: &[u8],
+    from: SocketAddr,
+    state: &mut State,
+) -> Result<Bencodable, &'static str> {
+    let line = request.split(|b| *b == b'\r').next().unwrap_or_default();
+    let target = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_prefix("GET "))
+        .and_then(|rest| rest.split(' ').next())
+        .ok_or("bad request")?;
+    let query = target.split_once('?').map(|(_, query)| query).unwrap_or("");
+    let params: HashMap<&str, Vec<u8>> = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key, percent_decode_str(value).collect()))
+        .collect();
+    let text = |key: &str| {
+        params
+            .get(key)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    };
+
+    let info_hash: [u8; 20] = params
+        .get("info_hash")
+        .and_then(|value| value.as_slice().try_into().ok())
+        .ok_or("missing info_hash")?;
+    let peer_id = params.get("peer_id").ok_or("missing peer_id")?;
+    let port = text("port")
+        .and_then(|port| port.parse().ok())
+        .ok_or("missing port")?;
+    let left = text("left")
+        .and_then(|left| left.parse().ok())
+        .ok_or("missing left")?;
+    let event = match text("event") {
+        Some("completed") => EVENT_COMPLETED,
+        Some("stopped") => EVENT_STOPPED,
+        _ => EVENT_NONE,
+    };
+    let num_want = text("numwant")
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_NUM_WANT);
+    let ip = match from.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return Err("IPv4 only"),
+    };
+
+    let swarm = state.swarms.entry(info_hash).or_default();
+    let peer = SocketAddrV4::new(ip, port);
+    let (leechers, seeders, others) = swarm.announce(peer, peer_id, left, event, num_want);
+    let peers = if text("compact") == Some("1") {
+        let compact: Vec<u8> = others
+            .iter()
+            .flat_map(|(other, _)| [&other.ip().octets()[..], &other.port().to_be_bytes()].concat())
+            .collect();
+        Bencodable::from(compact)
+    } else {
+        let dictionaries: Vec<Bencodable> = others
+            .iter()
+            .map(|(other, peer_id)| {
+                DictBuilder::new()
+                    .insert("ip", other.ip().to_string())
+                    .insert("peer id", *peer_id)
+                    .insert("port", other.port() as i64)
+                    .build()
+            })
+            .collect();
+        Bencodable::from(dictionaries)
+    };
+    Ok(DictBuilder::new()
+        .insert("complete", seeders)
+        .insert("incomplete", leechers)
+        .insert("interval", ANNOUNCE_INTERVAL)
+        .insert("peers", peers)
+        .build())
+}
+
+// The reply to one request, or None for datagrams too mangled to answer
+fn respond(request: &[u8], from: SocketAddr, state: &mut State) -> Option<Vec<u8>> {
+    let connection_id = u64_at(request, 0)?;
+    let action = u32_at(request, 8)?;
+    let transaction_id = u32_at(request, 12)?;
+    let now = Instant::now();
+    state
+        .connection_ids
+        .retain(|_, issued| now.duration_since(*issued) < CONNECTION_ID_LIFETIME);
+
+    if action == ACTION_CONNECT {
+        if connection_id != PROTOCOL_ID {
+            return Some(error(transaction_id, "bad protocol id"));
+        }
+        let connection_id = rand::thread_rng().gen();
+        state.connection_ids.insert(connection_id, now);
+        let mut response = header(ACTION_CONNECT, transaction_id);
+        response.extend_from_slice(&u64::to_be_bytes(connection_id));
+        return Some(response);
+    }
+    if !state.connection_ids.contains_key(&connection_id) {
+        return Some(error(transaction_id, "unknown connection id"));
+    }
+    match action {
+        ACTION_ANNOUNCE => {
+            let info_hash: [u8; 20] = request.get(16..36)?.try_into().ok()?;
+            let peer_id = request.get(36..56)?;
+            let left = u64_at(request, 64)?;
+            let event = u32_at(request, 80)?;
+            let ip = match u32_at(request, 84)? {
+                0 => match from.ip() {
+                    IpAddr::V4(ip) => ip,
+                    IpAddr::V6(_) => return Some(error(transaction_id, "IPv4 only")),
+                },
+                ip => Ipv4Addr::from(ip),
+            };
+            let num_want = match u32_at(request, 92)? as i32 {
+                n if n < 0 => DEFAULT_NUM_WANT,
+                n => n as usize,
+            };
+            let port = u16::from_be_bytes(request.get(96..98)?.try_into().ok()?);
+            let peer = SocketAddrV4::new(ip, port);
+
+            let swarm = state.swarms.entry(info_hash).or_default();
+            let (leechers, seeders, others) = swarm.announce(peer, peer_id, left, event, num_want);
+            let mut response = header(ACTION_ANNOUNCE, transaction_id);
+            response.extend_from_slice(&ANNOUNCE_INTERVAL.to_be_bytes());
+            response.extend_from_slice(&leechers.to_be_bytes());
+            response.extend_from_slice(&seeders.to_be_bytes());
+            for (other, _) in others {
+                response.extend_from_slice(&other.ip().octets());
+                response.extend_from_slice(&other.port().to_be_bytes());
+            }
+            Some(response)
+        }
+        ACTION_SCRAPE => {
+            let mut response = header(ACTION_SCRAPE, transaction_id);
+            for info_hash in request.get(16..)?.chunks_exact(20) {
+                let (seeders, completed, leechers) = match state.swarms.get(info_hash) {
+                    Some(swarm) => {
+                        let (leechers, seeders) = swarm.counts();
+                        (seeders, swarm.completed, leechers)
+                    }
+                    None => (0, 0, 0),
+                };
+                for n in [seeders, completed, leechers] {
+                    response.extend_from_slice(&n.to_be_bytes());
+                }
+            }
+            Some(response)
+        }
+        _ => Some(error(transaction_id, "unknown action")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::{Event, Tracker, UdpClient};
+
+    const INFO_HASH: [u8; 20] = [5u8; 20];
+
+    fn exchange(socket: &UdpSocket, request: &[u8]) -> Vec<u8> {
+        socket.send(request).unwrap();
+        let mut buf = [0u8; 2048];
+        let n = socket.recv(&mut buf).unwrap();
+        buf[..n].to_vec()
+    }
+
+    fn connect(socket: &UdpSocket) -> u64 {
+        let mut request = PROTOCOL_ID.to_be_bytes().to_vec();
+        request.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        request.extend_from_slice(&7u32.to_be_bytes());
+        let response = exchange(socket, &request);
+        assert_eq!(
+            (u32_at(&response, 0), u32_at(&response, 4)),
+            (Some(0), Some(7))
+        );
+        u64_at(&response, 8).unwrap()
+    }
+
+    fn announce(
+        socket: &UdpSocket,
+        connection_id: u64,
+        left: u64,
+        event: Event,
+        port: u16,
+    ) -> Vec<u8> {
+        let request = TrackerRequest::new(INFO_HASH, b"-BT0001-localpeer000", port)
+            .left(left)
+            .event(event);
+        exchange(socket, &request.udp_announce(connection_id, 9))
+    }
+
+    #[test]
+    fn it_connects_announces_and_scrapes() {
+        let tracker = TestUdpTracker::start().unwrap();
+        let seed = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+        tracker.add_seed(INFO_HASH, seed, b"-XX0001-seed00000000");
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(tracker.addr()).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+
+        let stale = announce(&socket, 1234, 100, Event::Started, 7000);
+        assert_eq!(u32_at(&stale, 0), Some(ACTION_ERROR));
+
+        let connection_id = connect(&socket);
+        let response = announce(&socket, connection_id, 100, Event::Started, 7000);
+        assert_eq!(u32_at(&response, 0), Some(ACTION_ANNOUNCE));
+        // interval, leechers (us), seeders, then the seed in compact form
+        assert_eq!(u32_at(&response, 8), Some(ANNOUNCE_INTERVAL));
+        assert_eq!(
+            (u32_at(&response, 12), u32_at(&response, 16)),
+            (Some(1), Some(1))
+        );
+        assert_eq!(&response[20..], &[10, 0, 0, 1, 0x1a, 0xe1]);
+
+        announce(&socket, connection_id, 0, Event::Completed, 7000);
+        let mut scrape = connection_id.to_be_bytes().to_vec();
+        scrape.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+        scrape.extend_from_slice(&3u32.to_be_bytes());
+        scrape.extend_from_slice(&INFO_HASH);
+        scrape.extend_from_slice(&[6u8; 20]);
+        let response = exchange(&socket, &scrape);
+        let counts: Vec<u32> = (8..response.len())
+            .step_by(4)
+            .map(|at| u32_at(&response, at).unwrap())
+            .collect();
+        assert_eq!(counts, vec![2, 1, 0, 0, 0, 0]);
+
+        let response = announce(&socket, connection_id, 0, Event::Stopped, 7000);
+        assert_eq!(
+            (u32_at(&response, 12), u32_at(&response, 16)),
+            (Some(0), Some(1))
+        );
+    }
+
+    #[test]
+    fn it_answers_udp_announces_from_the_udp_announcer() {
+        let tracker = TestUdpTracker::start().unwrap();
+        let seed = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+        tracker.add_seed(INFO_HASH, seed, b"-XX0001-seed00000000");
+        let announcer = Tracker::new().announcer(&tracker.announce_url()).unwrap();
+
+        let request = TrackerRequest::new(INFO_HASH, b"-BT0001-localpeer000", 7000).left(100);
+        let response = announcer.announce(&request).unwrap();
+        assert_eq!(
+            response.peers,
+            vec![TrackerPeer::SocketAddr(SocketAddr::V4(seed))]
+        );
+        assert_eq!((response.complete, response.incomplete), (Some(1), Some(1)));
+        assert_eq!(
+            response.intervals.interval,
+            Some(Duration::from_secs(ANNOUNCE_INTERVAL as u64))
+        );
+
+        assert!(matches!(
+            Tracker::new().announcer("wss://tracker.example/announce"),
+            Err(TrackerResponseError::UnsupportedScheme(_))
+        ));
+        assert!(matches!(
+            Tracker::new()
+                .announcer("udp://tracker.example/announce")
+                .unwrap()
+                .announce(&request),
+            Err(TrackerResponseError::InvalidUrl(_))
+        ));
+    }
+
+    #[test]
+    fn it_connects_once_for_every_announce_to_a_udp_tracker() {
+        let tracker = TestUdpTracker::start().unwrap();
+        let udp = UdpClient::default();
+        let addr = tracker.addr;
+        for info_hash in [INFO_HASH, [9; 20]] {
+            let request = TrackerRequest::new(info_hash, b"-BT0001-localpeer000", 7000).left(100);
+            udp.announce(addr, &request).unwrap();
+        }
+        // a client of its own connects afresh
+        let request = TrackerRequest::new(INFO_HASH, b"-BT0001-otherpeer000", 7001).left(100);
+        UdpClient::default().announce(addr, &request).unwrap();
+        assert_eq!(tracker.state.lock().connection_ids.len(), 2);
+        assert_eq!(tracker.state.lock().swarms[&INFO_HASH].peers.len(), 2);
+    }
+
+    #[test]
+    fn it_answers_http_announces() {
+        let tracker = TestHttpTracker::start().unwrap();
+        let seed = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+        tracker.add_seed(INFO_HASH, seed, b"-XX0001-seed00000000");
+        let client = Tracker::new();
+
+        let request = TrackerRequest::new(INFO_HASH, b"-BT0001-localpeer000", 7000).left(100);
+        let outcome = client.track(&tracker.announce_url(), &request).unwrap();
+        assert_eq!(
+            outcome.peers,
+            vec![TrackerPeer::Peer(crate::tracker::Peer {
+                socket_addr: SocketAddr::V4(seed),
+                id: b"-XX0001-seed00000000".to_vec(),
+            })]
+        );
+        assert_eq!(outcome.intervals.interval, Some(Duration::from_secs(60)));
+        assert_eq!((outcome.complete, outcome.incomplete), (Some(1), Some(1)));
+
+        let compact = TrackerRequest::new(INFO_HASH, b"-BT0001-otherpeer000", 7001)
+            .left(0)
+            .numwant(0)
+            .compact(true);
+        assert!(client
+            .track(&tracker.announce_url(), &compact)
+            .unwrap()
+            .peers
+            .is_empty());
+        let (leechers, seeders) = tracker.state.lock().swarms[&INFO_HASH].counts();
+        assert_eq!((leechers, seeders), (1, 2));
+
+        let stopped = request.event(Event::Stopped);
+        client.track(&tracker.announce_url(), &stopped).unwrap();
+        assert_eq!(tracker.state.lock().swarms[&INFO_HASH].counts(), (0, 2));
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs::File as FsFile;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimelineFormat {
+    Csv,
+    Json,
+}
+
+impl TimelineFormat {
+    // Anything that isn't a .json file gets CSV
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => TimelineFormat::Json,
+            _ => TimelineFormat::Csv,
+        }
+    }
+}
+
+// Byte counters are cumulative; rates are bytes per second since the previous sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimelineSample {
+    pub elapsed_secs: u64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub download_rate: u64,
+    pub upload_rate: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Timeline {
+    samples: Vec<TimelineSample>,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Timeline { samples: vec![] }
+    }
+
+    pub fn record(&mut self, elapsed_secs: u64, downloaded: u64, uploaded: u64) {
+        let (download_rate, upload_rate) = match self.samples.last() {
+            Some(previous) if elapsed_secs > previous.elapsed_secs => {
+                let secs = elapsed_secs - previous.elapsed_secs;
+                (
+                    downloaded.saturating_sub(previous.downloaded) / secs,
+                    uploaded.saturating_sub(previous.uploaded) / secs,
+                )
+            }
+            Some(_) => (0, 0),
+            None if elapsed_secs > 0 => (downloaded / elapsed_secs, uploaded / elapsed_secs),
+            None => (0, 0),
+        };
+        self.samples.push(TimelineSample {
+            elapsed_secs,
+            downloaded,
+            uploaded,
+            download_rate,
+            upload_rate,
+        });
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("elapsed_secs,downloaded,uploaded,download_rate,upload_rate\n");
+        for s in &self.samples {
+            csv.push_str(&format!(
+                "{},{},{},{},{}\n",
+                s.elapsed_secs, s.downloaded, s.uploaded, s.download_rate, s.upload_rate
+            ));
+        }
+        csv
+    }
+
+    pub fn to_json(&self) -> String {
+        let samples: Vec<String> = self
+            .samples
+            .iter()
+            .map(|s| {
+                format!(
+                    "{{\"elapsed_secs\":{},\"downloaded\":{},\"uploaded\":{},\"download_rate\":{},\"upload_rate\":{}}}",
+                    s.elapsed_secs, s.downloaded, s.uploaded, s.download_rate, s.upload_rate
+                )
+            })
+            .collect();
+        format!("[{}]", samples.join(","))
+    }
+
+    pub fn export(&self, path: &Path, format: TimelineFormat) -> Result<(), std::io::Error> {
+        let contents = match format {
+            TimelineFormat::Csv => self.to_csv(),
+            TimelineFormat::Json => self.to_json(),
+        };
+        FsFile::create(path).and_then(|mut f| f.write_all(contents.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> Timeline {
+        let mut timeline = Timeline::new();
+        timeline.record(1, 16384, 0);
+        timeline.record(2, 49152, 0);
+        timeline.record(4, 49152, 32768);
+        timeline
+    }
+
+    #[test]
+    fn it_computes_rates_between_samples() {
+        let rates: Vec<(u64, u64)> = example()
+            .samples
+            .iter()
+            .map(|s| (s.download_rate, s.upload_rate))
+            .collect();
+        assert_eq!(rates, vec![(16384, 0), (32768, 0), (0, 16384)]);
+    }
+
+    #[test]
+    fn it_exports_csv() {
+        assert_eq!(
+            example().to_csv(),
+            "elapsed_secs,downloaded,uploaded,download_rate,upload_rate\n1,16384,0,16384,0\n2,49152,0,32768,0\n4,49152,32768,0,16384\n"
+        );
+    }
+
+    #[test]
+    fn it_exports_json() {
+        let mut timeline = Timeline::new();
+        timeline.record(1, 10, 0);
+        assert_eq!(
+            timeline.to_json(),
+            "[{\"elapsed_secs\":1,\"downloaded\":10,\"uploaded\":0,\"download_rate\":10,\"upload_rate\":0}]"
+        );
+    }
+}
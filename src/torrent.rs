@@ -1,15 +1,23 @@
 use crate::meta_info_file::File;
 use std::collections::{HashMap, VecDeque};
-use std::fs::File as FsFile;
 use std::io::Write;
+use std::path::Path;
 use std::time::Instant;
 
-use crate::BitField;
+use crate::bitfield::BitField;
+use crate::heatmap::PieceHeatmap;
+use crate::integrity::{BlockChecksums, Stage};
+use crate::storage::{create_parent_dirs, write_file, StorageError};
+use crate::verify::{VerificationMode, VerificationQueue};
 
 pub trait PiecedContent {
     fn number_of_pieces(&self) -> u32;
     fn piece_length(&self) -> u32;
-    fn total_length(&self) -> u32;
+    fn total_length(&self) -> u64;
+    // SHA-1 of every piece; content without them is trusted as downloaded
+    fn piece_hashes(&self) -> Option<Vec<[u8; 20]>> {
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -34,7 +42,17 @@ enum BlockState {
     Done,
 }
 
-const FIXED_BLOCK_SIZE: u32 = 16384;
+pub const FIXED_BLOCK_SIZE: u32 = 16384;
+
+// One entry per piece, cheap enough for UIs to render the classic piece bar from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PieceState {
+    Missing = 0,
+    Requested = 1,
+    Downloaded = 2,
+    Verified = 3,
+}
 
 #[derive(Debug)]
 pub struct Torrent {
@@ -46,25 +64,58 @@ pub struct Torrent {
     requested_blocks: u32,
     pub percent_complete: f32,
     pub repeated_blocks: HashMap<(u32, u32), u32>,
+    pub downloaded_bytes: u64,
+    pub uploaded_bytes: u64,
+    // which pieces peers ask us for most
+    pub heatmap: PieceHeatmap,
+    // downloaded but thrown away: pieces that failed their hash check, and blocks we already had
+    pub corrupt_bytes: u64,
+    pub redundant_bytes: u64,
 
     pub in_progress_blocks: Vec<Block>,
     completed_pieces: Vec<Vec<Option<Block>>>,
+    // the whole content in memory, so a torrent needs as much RAM as it is large; offsets into it
+    // are 64-bit throughout, but content over 4 GiB only fits on a 64-bit machine with the memory
     data_buffer: Vec<u8>,
+    blocks_per_piece: u32,
+    last_piece_block_count: u32,
+    piece_states: Vec<PieceState>,
+    piece_state_changes: Vec<(u32, PieceState)>,
+    piece_hashes: Option<Vec<[u8; 20]>>,
+    verification: Option<VerificationQueue>,
+    verification_mode: VerificationMode,
+    // every piece we can offer to peers, in the order it became available; connections keep
+    // their own position in it so each Have goes out exactly once and in order
+    available_pieces: Vec<u32>,
+    // only with the `integrity` feature
+    integrity: Option<BlockChecksums>,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct PieceIndexOffsetLength(pub u32, pub u32, pub u32);
 
+// Why the picker handed out the block it did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PickReason {
+    // the peer suggested the piece
+    Suggested,
+    // nothing preferred applied, so the first piece still missing blocks that the peer has
+    FirstAvailable,
+}
+
 impl Torrent {
     pub fn new(pieced_content: &dyn PiecedContent) -> Self {
         let number_of_pieces = pieced_content.number_of_pieces();
         let piece_length = pieced_content.piece_length();
         let total_length = pieced_content.total_length();
+        let piece_hashes = pieced_content.piece_hashes();
 
         let number_of_blocks =
             (piece_length / FIXED_BLOCK_SIZE) + !!(piece_length % FIXED_BLOCK_SIZE);
 
-        let mut pieces: Vec<Piece> = (0..(number_of_pieces - 1))
+        // every piece but the last is full
+        let last_piece_index = number_of_pieces.saturating_sub(1);
+        let mut pieces: Vec<Piece> = (0..last_piece_index)
             .map(|index| {
                 let blocks: VecDeque<Block> = (0..number_of_blocks)
                     .map(|block_index| Block {
@@ -79,24 +130,21 @@ impl Torrent {
             })
             .collect();
 
-        let last_piece_length = total_length % piece_length;
+        // whatever the full pieces leave, which is a whole piece when the length divides evenly
+        let last_piece_length = total_length
+            .saturating_sub(last_piece_index as u64 * piece_length as u64)
+            .min(piece_length as u64) as u32;
         println!(
             "total length {} piece_length {} last piece length {}",
             total_length, piece_length, last_piece_length
         );
-        let last_piece_block_count = {
-            // TODO(): hack for controlling subtraction with overflow when perfect pieces are divided
-            let m = (last_piece_length as f32 / FIXED_BLOCK_SIZE as f32).ceil() as u32;
-            if m == 0 {
-                1
-            } else {
-                m
-            }
+        // an empty torrent has no pieces at all
+        let last_piece_block_count = match number_of_pieces {
+            0 => 0,
+            _ => last_piece_length.div_ceil(FIXED_BLOCK_SIZE).max(1),
         };
 
-        let last_piece_index = (total_length as f32 / piece_length as f32).floor() as u32;
-
-        let mut last_blocks: VecDeque<Block> = (0..last_piece_block_count - 1)
+        let mut last_blocks: VecDeque<Block> = (0..last_piece_block_count.saturating_sub(1))
             .map(|block_index| Block {
                 state: BlockState::NotRequested,
                 offset: FIXED_BLOCK_SIZE * block_index,
@@ -108,7 +156,7 @@ impl Torrent {
 
         let last_block = Block {
             state: BlockState::NotRequested,
-            offset: FIXED_BLOCK_SIZE * (last_piece_block_count - 1),
+            offset: FIXED_BLOCK_SIZE * last_piece_block_count.saturating_sub(1),
             last_request: None,
             piece_index: (pieces.len()) as u32,
             block_length: last_piece_length - (FIXED_BLOCK_SIZE * last_blocks.len() as u32),
@@ -116,12 +164,14 @@ impl Torrent {
 
         last_blocks.push_back(last_block);
 
-        pieces.push(Piece {
-            index: last_piece_index,
-            blocks: last_blocks,
-        });
+        if number_of_pieces > 0 {
+            pieces.push(Piece {
+                index: last_piece_index,
+                blocks: last_blocks,
+            });
+        }
 
-        let total_blocks = ((number_of_pieces - 1) * number_of_blocks) + last_piece_block_count;
+        let total_blocks = (last_piece_index * number_of_blocks) + last_piece_block_count;
 
         Torrent {
             total_blocks,
@@ -132,15 +182,49 @@ impl Torrent {
             requested_blocks: 0,
             percent_complete: 0.0,
             repeated_blocks: HashMap::new(),
+            downloaded_bytes: 0,
+            uploaded_bytes: 0,
+            heatmap: PieceHeatmap::new(number_of_pieces),
+            corrupt_bytes: 0,
+            redundant_bytes: 0,
             in_progress_blocks: vec![],
             completed_pieces: (0..number_of_pieces)
                 .map(|_pi| (0..number_of_blocks).map(|_bi| None).collect())
                 .collect(),
             data_buffer: vec![0u8; total_length as usize],
+            blocks_per_piece: number_of_blocks,
+            last_piece_block_count,
+            piece_states: vec![PieceState::Missing; number_of_pieces as usize],
+            piece_state_changes: vec![],
+            verification: piece_hashes.as_ref().map(|_| VerificationQueue::new()),
+            piece_hashes,
+            verification_mode: VerificationMode::Full,
+            available_pieces: vec![],
+            integrity: cfg!(feature = "integrity").then(BlockChecksums::default),
         }
     }
 
     pub fn get_next_block(&mut self, bitfield: &BitField) -> Option<PieceIndexOffsetLength> {
+        self.get_next_block_preferring(bitfield, &[])
+    }
+
+    // Like `get_next_block`, but pieces in `preferred` that the peer has and we still need are
+    // tried first, in the order given. Anything else falls back to the usual order.
+    pub fn get_next_block_preferring(
+        &mut self,
+        bitfield: &BitField,
+        preferred: &[u32],
+    ) -> Option<PieceIndexOffsetLength> {
+        self.pick_next_block(bitfield, preferred)
+            .map(|(block, _)| block)
+    }
+
+    // `get_next_block_preferring`, also saying why that block was the one picked
+    pub fn pick_next_block(
+        &mut self,
+        bitfield: &BitField,
+        preferred: &[u32],
+    ) -> Option<(PieceIndexOffsetLength, PickReason)> {
         if self.in_progress_blocks.len() == 1 {
             // there are no more blocks for the requester to help with "right now"
             println!(
@@ -152,23 +236,40 @@ impl Torrent {
             return None;
         }
 
-        let res: Option<(u32, &mut VecDeque<Block>)> = {
-            let mut res = None;
-            // O(total number of pieces); always pulls pieces and blocks based on exact order of index of piece from 0 to total number of pieces
-            for piece in self.pieces.iter_mut() {
-                let piece_index = piece.index;
+        let peer_has = |index: u32| bitfield.is_set(index as usize).unwrap_or(false);
+        let preferred_position = preferred.iter().find_map(|index| {
+            self.pieces
+                .iter()
+                .position(|piece| piece.index == *index && peer_has(*index))
+        });
+
+        let reason = match preferred_position {
+            Some(_) => PickReason::Suggested,
+            None => PickReason::FirstAvailable,
+        };
+        let res: Option<(u32, &mut VecDeque<Block>)> = match preferred_position {
+            Some(position) => {
+                let piece = &mut self.pieces[position];
+                Some((piece.index, &mut piece.blocks))
+            }
+            None => {
+                let mut res = None;
+                // O(total number of pieces); always pulls pieces and blocks based on exact order of index of piece from 0 to total number of pieces
+                for piece in self.pieces.iter_mut() {
+                    let piece_index = piece.index;
 
-                // relatively cheap; should not panic!!!
-                match bitfield.is_set(piece_index as usize).unwrap() {
-                    true => {
-                        let blocks_to_request_queue = &mut piece.blocks;
-                        res = Some((piece_index, blocks_to_request_queue));
-                        break;
+                    // relatively cheap; should not panic!!!
+                    match bitfield.is_set(piece_index as usize).unwrap() {
+                        true => {
+                            let blocks_to_request_queue = &mut piece.blocks;
+                            res = Some((piece_index, blocks_to_request_queue));
+                            break;
+                        }
+                        false => continue,
                     }
-                    false => continue,
                 }
+                res
             }
-            res
         };
 
         // println!("selected piece {:?} based on bf {:?}", res, bitfield);
@@ -197,7 +298,14 @@ impl Torrent {
                     self.pieces.swap_remove(index);
                 }
 
-                Some(PieceIndexOffsetLength(piece_index, offset, block_length))
+                if self.piece_states[piece_index as usize] == PieceState::Missing {
+                    self.set_piece_state(piece_index, PieceState::Requested);
+                }
+
+                Some((
+                    PieceIndexOffsetLength(piece_index, offset, block_length),
+                    reason,
+                ))
             }
             None => None,
         }
@@ -206,6 +314,23 @@ impl Torrent {
     pub fn fill_block(&mut self, block: (u32, u32, &[u8])) {
         let (piece_index, offset, data) = block;
         let block_index = offset / FIXED_BLOCK_SIZE;
+        self.downloaded_bytes += data.len() as u64;
+
+        // another peer got the block to us first
+        let already_have = self
+            .completed_pieces
+            .get(piece_index as usize)
+            .and_then(|blocks| blocks.get(block_index as usize))
+            .map(|block| block.is_some())
+            .unwrap_or(false);
+        if already_have {
+            self.redundant_bytes += data.len() as u64;
+            *self
+                .repeated_blocks
+                .entry((piece_index, offset))
+                .or_insert(0) += 1;
+            return;
+        }
 
         let index = self
             .in_progress_blocks
@@ -218,21 +343,40 @@ impl Torrent {
                 })
             ));
 
+        let blocks_file_position = block_offset(self.piece_length, piece_index, offset) as usize;
         let b = &mut self.in_progress_blocks[index];
 
         if b.state != BlockState::Done {
-            let blocks_file_position: usize =
-                (piece_index * self.piece_length) as usize + offset as usize;
             b.state = BlockState::Done;
             let mut buff =
                 &mut self.data_buffer[blocks_file_position..blocks_file_position + data.len()];
             buff.write_all(data)
                 .expect("failed to write a block of data to internal buffer");
+            if let Some(integrity) = self.integrity.as_mut() {
+                integrity.record(piece_index, offset, data);
+            }
+            // the whole piece, so a write that strayed into a neighbouring block is caught too
+            self.check_integrity(piece_index, 0, self.piece_data(piece_index), Stage::Stored);
             self.completed_blocks += 1;
             self.percent_complete = self.completed_blocks as f32 / self.total_blocks as f32;
             self.completed_pieces[piece_index as usize][block_index as usize] =
                 Some(self.in_progress_blocks.swap_remove(index));
+
+            let expected_blocks = if piece_index == self.total_pieces - 1 {
+                self.last_piece_block_count
+            } else {
+                self.blocks_per_piece
+            };
+            let completed = self.completed_pieces[piece_index as usize]
+                .iter()
+                .filter(|b| b.is_some())
+                .count();
+            if completed == expected_blocks as usize {
+                self.set_piece_state(piece_index, PieceState::Downloaded);
+                self.submit_for_verification(piece_index);
+            }
         } else {
+            self.redundant_bytes += data.len() as u64;
             self.repeated_blocks
                 .entry((piece_index, offset))
                 .and_modify(|v| *v += 1)
@@ -240,13 +384,17 @@ impl Torrent {
         }
     }
 
-    pub fn to_file(&self, files: Vec<&File>) -> Vec<Result<FsFile, std::io::Error>> {
+    // Writes each file under `root`, making the directories it goes in; padding files are skipped.
+    // Every file is attempted even after one fails; the failures come back in file order.
+    pub fn to_file(&self, root: &Path, files: Vec<&File>) -> Result<(), Vec<StorageError>> {
+        for index in 0..self.total_pieces {
+            self.check_integrity(index, 0, self.piece_data(index), Stage::Export);
+        }
         // Now go through the buffer by size of files and write out the amount needed
         let mut curr_pos = 0;
-        files
+        let failures: Vec<StorageError> = files
             .iter()
-            .map(|f| {
-                let p = &f.path;
+            .filter_map(|f| {
                 let l = f.length as usize;
                 println!(
                     "trying to write internal buffer (length {}) to file from {} to {}",
@@ -255,22 +403,249 @@ impl Torrent {
                     curr_pos + l
                 );
                 let buff = &self.data_buffer[curr_pos..curr_pos + l];
+                curr_pos += l;
+                if f.padding {
+                    return None;
+                }
+                let path = root.join(&f.path);
+                create_parent_dirs(&path)
+                    .and_then(|_| write_file(&path, buff))
+                    .err()
+            })
+            .collect();
+        match failures.is_empty() {
+            true => Ok(()),
+            false => Err(failures),
+        }
+    }
 
-                let f = FsFile::create(p);
-                f.and_then(|mut f| {
-                    let r = f.write_all(buff).map(|_| f);
-                    curr_pos += l;
-                    r
-                })
+    fn piece_start(&self, index: u32) -> usize {
+        block_offset(self.piece_length, index, 0) as usize
+    }
+
+    fn piece_data(&self, index: u32) -> &[u8] {
+        let start = self.piece_start(index);
+        let end = (start + self.piece_length as usize).min(self.data_buffer.len());
+        &self.data_buffer[start..end]
+    }
+
+    // Only meant to be changed before the download starts; see `VerificationMode`
+    pub fn set_verification_mode(&mut self, mode: VerificationMode) {
+        self.verification_mode = mode;
+        if mode == VerificationMode::Off {
+            self.verification = None;
+        } else if self.verification.is_none() && self.piece_hashes.is_some() {
+            self.verification = Some(VerificationQueue::new());
+        }
+    }
+
+    pub fn verification_mode(&self) -> VerificationMode {
+        self.verification_mode
+    }
+
+    fn submit_for_verification(&mut self, index: u32) {
+        let expected = self
+            .piece_hashes
+            .as_ref()
+            .and_then(|hashes| hashes.get(index as usize).copied());
+        match expected {
+            Some(expected) if self.verification.is_some() => {
+                let data = self.piece_data(index).to_vec();
+                self.check_integrity(index, 0, &data, Stage::Queued);
+                if self.verification_mode == VerificationMode::Deferred {
+                    self.available_pieces.push(index);
+                }
+                if let Some(verification) = self.verification.as_mut() {
+                    verification.submit(index, data, expected);
+                }
+            }
+            _ => self.available_pieces.push(index),
+        }
+    }
+
+    // Applies whatever verification results are ready. Pieces that pass become Verified and
+    // available to peers (already so when verification is deferred); pieces that fail are thrown
+    // away and downloaded again.
+    pub fn apply_verifications(&mut self) -> Vec<(u32, bool)> {
+        let results = match self.verification.as_mut() {
+            Some(verification) => verification.completed(),
+            None => return vec![],
+        };
+        for (index, ok) in &results {
+            if *ok {
+                self.set_piece_state(*index, PieceState::Verified);
+                if self.verification_mode != VerificationMode::Deferred {
+                    self.available_pieces.push(*index);
+                }
+            } else {
+                self.reset_piece(*index);
+            }
+        }
+        results
+    }
+
+    // Stops at the first block that no longer matches what was downloaded; see `BlockChecksums`
+    fn check_integrity(&self, index: u32, begin: u32, data: &[u8], stage: Stage) {
+        if let Some(Err(corruption)) = self
+            .integrity
+            .as_ref()
+            .map(|integrity| integrity.check(index, begin, data, stage))
+        {
+            panic!(
+                "block no longer matches what was downloaded: {:?}",
+                corruption
+            );
+        }
+    }
+
+    fn reset_piece(&mut self, index: u32) {
+        if let Some(integrity) = self.integrity.as_mut() {
+            integrity.forget_piece(index);
+        }
+        let blocks: VecDeque<Block> = self.completed_pieces[index as usize]
+            .iter_mut()
+            .filter_map(|block| block.take())
+            .map(|mut block| {
+                block.state = BlockState::NotRequested;
+                block.last_request = None;
+                block
             })
-            .collect::<Vec<Result<FsFile, _>>>()
+            .collect();
+        self.corrupt_bytes += self.piece_data(index).len() as u64;
+        self.completed_blocks -= blocks.len() as u32;
+        self.percent_complete = self.completed_blocks as f32 / self.total_blocks as f32;
+        self.pieces.push(Piece { index, blocks });
+        self.set_piece_state(index, PieceState::Missing);
+    }
+
+    // Puts a block that was requested but will never arrive, e.g. because its connection died,
+    // back at the front of its piece's queue so another peer can fetch it
+    pub fn release_block(&mut self, index: u32, offset: u32) {
+        let position = match self.in_progress_blocks.iter().position(|block| {
+            block.piece_index == index
+                && block.offset == offset
+                && block.state == BlockState::Requested
+        }) {
+            Some(position) => position,
+            None => return,
+        };
+        let mut block = self.in_progress_blocks.swap_remove(position);
+        block.state = BlockState::NotRequested;
+        block.last_request = None;
+        match self.pieces.iter_mut().find(|piece| piece.index == index) {
+            Some(piece) => piece.blocks.push_front(block),
+            None => self.pieces.push(Piece {
+                index,
+                blocks: VecDeque::from(vec![block]),
+            }),
+        }
+    }
+
+    // Counts every outstanding request as made `now`, so time the machine spent suspended isn't
+    // held against the peers they went to
+    pub fn restart_request_timers(&mut self, now: Instant) {
+        for block in self.in_progress_blocks.iter_mut() {
+            if block.state == BlockState::Requested {
+                block.last_request = Some(now);
+            }
+        }
+    }
+
+    // Every block still missing has been requested, so there's nothing new to hand out
+    pub fn in_endgame(&self) -> bool {
+        self.pieces.is_empty() && !self.in_progress_blocks.is_empty()
+    }
+
+    pub fn has_pending_verifications(&self) -> bool {
+        self.verification
+            .as_ref()
+            .map(|verification| verification.in_flight() > 0)
+            .unwrap_or(false)
+    }
+
+    // Pieces that became available since position `from` of the announcement log
+    pub fn available_pieces_since(&self, from: usize) -> &[u32] {
+        self.available_pieces.get(from..).unwrap_or(&[])
+    }
+
+    pub fn piece_map(&self) -> &[PieceState] {
+        &self.piece_states
+    }
+
+    // Drains the piece state transitions that happened since the last call, oldest first
+    pub fn take_piece_state_changes(&mut self) -> Vec<(u32, PieceState)> {
+        std::mem::take(&mut self.piece_state_changes)
+    }
+
+    fn set_piece_state(&mut self, index: u32, state: PieceState) {
+        if let Some(current) = self.piece_states.get_mut(index as usize) {
+            if *current != state {
+                *current = state;
+                self.piece_state_changes.push((index, state));
+            }
+        }
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        matches!(
+            self.piece_states.get(index as usize),
+            Some(PieceState::Downloaded) | Some(PieceState::Verified)
+        )
+    }
+
+    // Downloaded and, when the content came with piece hashes and they aren't being skipped,
+    // checked against them
+    pub fn is_piece_verified(&self, index: u32) -> bool {
+        match self.piece_states.get(index as usize) {
+            Some(PieceState::Verified) => true,
+            Some(PieceState::Downloaded) => {
+                self.piece_hashes.is_none() || self.verification_mode == VerificationMode::Off
+            }
+            _ => false,
+        }
+    }
+
+    // `length` bytes of content from `start`, once every piece they fall in is verified
+    pub fn read_range(&self, start: u64, length: u64) -> Option<&[u8]> {
+        let end = start.checked_add(length)?;
+        let piece_length = self.piece_length as u64;
+        let all_verified = (start / piece_length..end.div_ceil(piece_length))
+            .all(|index| self.is_piece_verified(index as u32));
+        if !all_verified {
+            return None;
+        }
+        self.data_buffer.get(start as usize..end as usize)
+    }
+
+    // The requested slice of a piece we already have, for answering a peer's request
+    pub fn read_block(&self, index: u32, begin: u32, length: u32) -> Option<&[u8]> {
+        if !self.has_piece(index) || begin.checked_add(length)? > self.piece_length {
+            return None;
+        }
+        let start = block_offset(self.piece_length, index, begin) as usize;
+        let block = self.data_buffer.get(start..start + length as usize)?;
+        self.check_integrity(index, begin, block, Stage::Upload);
+        Some(block)
+    }
+
+    // What trackers are told is `left`: every byte not yet in a verified piece
+    pub fn left(&self) -> u64 {
+        (0..self.total_pieces)
+            .filter(|index| !self.is_piece_verified(*index))
+            .map(|index| self.piece_data(index).len() as u64)
+            .sum()
     }
 
     pub fn are_we_done_yet(&self) -> bool {
-        self.completed_blocks == self.total_blocks
+        self.completed_blocks == self.total_blocks && !self.has_pending_verifications()
     }
 }
 
+// Where `begin` bytes into piece `index` is in the content; 64-bit, as content can be over 4 GiB
+fn block_offset(piece_length: u32, index: u32, begin: u32) -> u64 {
+    index as u64 * piece_length as u64 + begin as u64
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -283,11 +658,43 @@ mod tests {
         fn piece_length(&self) -> u32 {
             131072
         }
-        fn total_length(&self) -> u32 {
+        fn total_length(&self) -> u64 {
             170835968
         }
     }
 
+    // 5 GiB in 256 KiB pieces, only ever asked about its shape so nothing is allocated
+    struct LargeContent;
+    impl PiecedContent for LargeContent {
+        fn number_of_pieces(&self) -> u32 {
+            20480
+        }
+        fn piece_length(&self) -> u32 {
+            262144
+        }
+        fn total_length(&self) -> u64 {
+            5 * 1024 * 1024 * 1024
+        }
+    }
+
+    #[test]
+    fn it_finds_blocks_past_4_gib() {
+        let content = LargeContent;
+        let last = content.number_of_pieces() - 1;
+        let offset = block_offset(content.piece_length(), last, 2 * FIXED_BLOCK_SIZE);
+        assert!(offset > u32::MAX as u64);
+        assert_eq!(offset, 20479 * 262144 + 32768);
+        assert_eq!(
+            block_offset(content.piece_length(), last, content.piece_length()),
+            content.total_length()
+        );
+        // the first piece entirely past 4 GiB, which 32-bit arithmetic wraps back to the start
+        assert_eq!(
+            block_offset(content.piece_length(), 16384, 0),
+            4 * 1024 * 1024 * 1024
+        );
+    }
+
     #[test]
     fn gets_the_next_block_correctly() {
         let pieced_content = &FakeMetaInfo {};
@@ -350,4 +757,338 @@ mod tests {
             t.fill_block((1302, FIXED_BLOCK_SIZE * i, &[]));
         }
     }
+
+    #[test]
+    fn it_prefers_suggested_pieces_the_peer_has() {
+        let pieced_content = &FakeMetaInfo {};
+        let mut t = Torrent::new(pieced_content);
+        let mut bf = BitField::from(vec![0u8; 163]);
+        bf.set(0);
+        bf.set(40);
+
+        // 7 isn't on offer from this peer and 5000 doesn't exist, so 40 wins
+        let next_block = t.get_next_block_preferring(&bf, &[7, 5000, 40]);
+        assert_eq!(
+            Some(PieceIndexOffsetLength(40, 0, FIXED_BLOCK_SIZE)),
+            next_block
+        );
+        t.fill_block((40, 0, &[]));
+
+        let next_block = t.get_next_block_preferring(&bf, &[7]);
+        assert_eq!(
+            Some(PieceIndexOffsetLength(0, 0, FIXED_BLOCK_SIZE)),
+            next_block
+        );
+    }
+
+    #[test]
+    fn it_restarts_the_timers_of_outstanding_requests() {
+        let pieced_content = &FakeMetaInfo {};
+        let mut t = Torrent::new(pieced_content);
+        let bf = &BitField::from(vec![255; 1304]);
+        t.get_next_block(bf);
+        t.fill_block((0, 0, &[]));
+        t.get_next_block(bf);
+
+        let resumed = Instant::now() + std::time::Duration::from_secs(3600);
+        t.restart_request_timers(resumed);
+        let timers: Vec<Option<Instant>> = t
+            .in_progress_blocks
+            .iter()
+            .filter(|block| block.state == BlockState::Requested)
+            .map(|block| block.last_request)
+            .collect();
+        assert_eq!(timers, vec![Some(resumed)]);
+    }
+
+    #[test]
+    fn it_tracks_piece_states_incrementally() {
+        let pieced_content = &FakeMetaInfo {};
+        let mut t = Torrent::new(pieced_content);
+        let bf = &BitField::from(vec![255; 1304]);
+
+        assert_eq!(1304, t.piece_map().len());
+        assert!(t.piece_map().iter().all(|s| *s == PieceState::Missing));
+
+        t.get_next_block(bf);
+        assert_eq!(t.piece_map()[0], PieceState::Requested);
+        assert_eq!(
+            t.take_piece_state_changes(),
+            vec![(0, PieceState::Requested)]
+        );
+
+        t.fill_block((0, 0, &[]));
+        for i in 1..8 {
+            t.get_next_block(bf);
+            t.fill_block((0, FIXED_BLOCK_SIZE * i, &[]));
+        }
+        assert_eq!(t.piece_map()[0], PieceState::Downloaded);
+        assert_eq!(
+            t.take_piece_state_changes(),
+            vec![(0, PieceState::Downloaded)]
+        );
+        assert!(t.take_piece_state_changes().is_empty());
+    }
+
+    #[test]
+    fn it_only_serves_blocks_of_pieces_we_have() {
+        let pieced_content = &FakeMetaInfo {};
+        let mut t = Torrent::new(pieced_content);
+        let bf = &BitField::from(vec![255; 1304]);
+        assert_eq!(t.read_block(0, 0, FIXED_BLOCK_SIZE), None);
+
+        for i in 0..8 {
+            t.get_next_block(bf);
+            t.fill_block((
+                0,
+                FIXED_BLOCK_SIZE * i,
+                &[i as u8; FIXED_BLOCK_SIZE as usize],
+            ));
+        }
+        assert!(t.has_piece(0));
+        assert_eq!(
+            t.read_block(0, FIXED_BLOCK_SIZE * 7, 4),
+            Some(&[7u8, 7, 7, 7][..])
+        );
+        assert_eq!(t.read_block(0, 131072 - 2, 4), None);
+        assert_eq!(t.read_block(1, 0, 4), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "block no longer matches what was downloaded")]
+    fn it_catches_blocks_corrupted_after_they_were_stored() {
+        let pieced_content = &FakeMetaInfo {};
+        let mut t = Torrent::new(pieced_content);
+        t.integrity = Some(BlockChecksums::default());
+        let bf = &BitField::from(vec![255; 1304]);
+        for i in 0..8 {
+            t.get_next_block(bf);
+            t.fill_block((
+                0,
+                FIXED_BLOCK_SIZE * i,
+                &[i as u8; FIXED_BLOCK_SIZE as usize],
+            ));
+        }
+        assert!(t
+            .read_block(0, FIXED_BLOCK_SIZE, FIXED_BLOCK_SIZE)
+            .is_some());
+
+        t.data_buffer[FIXED_BLOCK_SIZE as usize] ^= 1;
+        t.read_block(0, FIXED_BLOCK_SIZE, FIXED_BLOCK_SIZE);
+    }
+
+    struct HashedContent(Vec<[u8; 20]>);
+    impl PiecedContent for HashedContent {
+        fn number_of_pieces(&self) -> u32 {
+            2
+        }
+        fn piece_length(&self) -> u32 {
+            FIXED_BLOCK_SIZE
+        }
+        fn total_length(&self) -> u64 {
+            FIXED_BLOCK_SIZE as u64 + 100
+        }
+        fn piece_hashes(&self) -> Option<Vec<[u8; 20]>> {
+            Some(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn it_verifies_pieces_and_downloads_corrupt_ones_again() {
+        use sha1::{Digest, Sha1};
+        let good = vec![1u8; FIXED_BLOCK_SIZE as usize];
+        let last = vec![2u8; 100];
+        let content = HashedContent(vec![
+            <[u8; 20]>::from(Sha1::digest(&good)),
+            <[u8; 20]>::from(Sha1::digest(&last)),
+        ]);
+        let mut t = Torrent::new(&content);
+        let bf = &BitField::from(vec![0b1100_0000]);
+        assert_eq!(t.left(), FIXED_BLOCK_SIZE as u64 + 100);
+
+        t.get_next_block(bf);
+        t.fill_block((0, 0, &good));
+        t.get_next_block(bf);
+        t.fill_block((1, 0, &[9u8; 100]));
+        assert!(t.has_pending_verifications());
+        assert!(!t.are_we_done_yet());
+        // downloaded isn't verified
+        assert_eq!(t.left(), FIXED_BLOCK_SIZE as u64 + 100);
+
+        let mut results = vec![];
+        while results.len() < 2 {
+            results.extend(t.apply_verifications());
+        }
+        assert_eq!(results, vec![(0, true), (1, false)]);
+        assert_eq!(t.corrupt_bytes, 100);
+        t.fill_block((0, 0, &good));
+        assert_eq!(t.redundant_bytes, FIXED_BLOCK_SIZE as u64);
+        assert_eq!(t.piece_map(), &[PieceState::Verified, PieceState::Missing]);
+        assert_eq!(t.left(), 100);
+        assert_eq!(t.available_pieces_since(0), &[0]);
+        assert!(!t.are_we_done_yet());
+
+        assert_eq!(
+            t.get_next_block(bf),
+            Some(PieceIndexOffsetLength(1, 0, 100))
+        );
+        t.fill_block((1, 0, &last));
+        while t.has_pending_verifications() {
+            t.apply_verifications();
+        }
+        assert!(t.are_we_done_yet());
+        assert_eq!(t.left(), 0);
+        assert_eq!(t.available_pieces_since(1), &[1]);
+    }
+
+    struct EvenContent(u32);
+    impl PiecedContent for EvenContent {
+        fn number_of_pieces(&self) -> u32 {
+            self.0
+        }
+        fn piece_length(&self) -> u32 {
+            FIXED_BLOCK_SIZE * 2
+        }
+        fn total_length(&self) -> u64 {
+            self.0 as u64 * FIXED_BLOCK_SIZE as u64 * 2
+        }
+    }
+
+    #[test]
+    fn it_handles_a_full_last_piece_and_empty_files() {
+        use crate::file_checksums::FileChecksums;
+        use std::path::PathBuf;
+        let mut t = Torrent::new(&EvenContent(2));
+        assert_eq!(t.total_blocks, 4);
+        assert_eq!(t.pieces.last().unwrap().index, 1);
+        assert!(t
+            .pieces
+            .iter()
+            .flat_map(|p| &p.blocks)
+            .all(|b| b.block_length == FIXED_BLOCK_SIZE));
+        let bf = &BitField::from(vec![0b1100_0000]);
+        while let Some(PieceIndexOffsetLength(index, offset, length)) = t.get_next_block(bf) {
+            t.fill_block((index, offset, &vec![index as u8 + 1; length as usize]));
+        }
+        assert!(t.are_we_done_yet());
+
+        let file = |path: &str, length| File {
+            length,
+            path: PathBuf::from(path),
+            checksums: FileChecksums::default(),
+            padding: false,
+        };
+        let half = FIXED_BLOCK_SIZE as u64 * 2;
+        let files = [
+            file("empty-first", 0),
+            file("a", half),
+            file("empty-between", 0),
+            file("b", half),
+            file("empty-last", 0),
+        ];
+        let root = std::env::temp_dir().join(format!(
+            "bit_torrent_empty_files_{}",
+            crate::util::random_string()
+        ));
+        t.to_file(&root, files.iter().collect()).unwrap();
+        for name in ["empty-first", "empty-between", "empty-last"] {
+            assert_eq!(std::fs::read(root.join(name)).unwrap(), vec![0u8; 0]);
+        }
+        assert_eq!(
+            std::fs::read(root.join("b")).unwrap(),
+            vec![2u8; half as usize]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // nothing to download at all
+        let empty = Torrent::new(&EvenContent(0));
+        assert!(empty.pieces.is_empty());
+        assert!(empty.are_we_done_yet());
+    }
+
+    #[test]
+    fn it_writes_every_file_but_the_padding() {
+        use crate::file_checksums::FileChecksums;
+        use sha1::{Digest, Sha1};
+        use std::path::PathBuf;
+        let first = [vec![1u8; 16000], vec![0u8; 384]].concat();
+        let last = vec![2u8; 100];
+        let content = HashedContent(vec![
+            <[u8; 20]>::from(Sha1::digest(&first)),
+            <[u8; 20]>::from(Sha1::digest(&last)),
+        ]);
+        let mut t = Torrent::new(&content);
+        let bf = &BitField::from(vec![0b1100_0000]);
+        t.get_next_block(bf);
+        t.fill_block((0, 0, &first));
+        t.get_next_block(bf);
+        t.fill_block((1, 0, &last));
+        while t.has_pending_verifications() {
+            t.apply_verifications();
+        }
+
+        let file = |path: &str, length, padding| File {
+            length,
+            path: PathBuf::from(path),
+            checksums: FileChecksums::default(),
+            padding,
+        };
+        let files = [
+            file("dir/a", 16000, false),
+            file("dir/.pad/384", 384, true),
+            file("dir/b", 100, false),
+        ];
+        let root = std::env::temp_dir().join(format!(
+            "bit_torrent_padding_{}",
+            crate::util::random_string()
+        ));
+        t.to_file(&root, files.iter().collect()).unwrap();
+        assert_eq!(std::fs::read(root.join("dir/a")).unwrap(), vec![1u8; 16000]);
+        assert_eq!(std::fs::read(root.join("dir/b")).unwrap(), last);
+        assert!(!root.join("dir/.pad").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn it_offers_pieces_before_hashing_them_when_verification_is_relaxed() {
+        use sha1::{Digest, Sha1};
+        let good = vec![1u8; FIXED_BLOCK_SIZE as usize];
+        let last = vec![2u8; 100];
+        let content = HashedContent(vec![
+            <[u8; 20]>::from(Sha1::digest(&good)),
+            <[u8; 20]>::from(Sha1::digest(&last)),
+        ]);
+        let bf = &BitField::from(vec![0b1100_0000]);
+
+        let mut deferred = Torrent::new(&content);
+        deferred.set_verification_mode(VerificationMode::Deferred);
+        deferred.get_next_block(bf);
+        deferred.fill_block((0, 0, &good));
+        deferred.get_next_block(bf);
+        deferred.fill_block((1, 0, &[9u8; 100]));
+        assert_eq!(deferred.available_pieces_since(0), &[0, 1]);
+        assert!(!deferred.is_piece_verified(0));
+        let mut results = vec![];
+        while results.len() < 2 {
+            results.extend(deferred.apply_verifications());
+        }
+        assert_eq!(results, vec![(0, true), (1, false)]);
+        // a failed piece is still downloaded again, it just isn't offered twice
+        assert_eq!(
+            deferred.piece_map(),
+            &[PieceState::Verified, PieceState::Missing]
+        );
+        assert_eq!(deferred.available_pieces_since(0), &[0, 1]);
+
+        let mut off = Torrent::new(&content);
+        off.set_verification_mode(VerificationMode::Off);
+        off.get_next_block(bf);
+        off.fill_block((0, 0, &good));
+        off.get_next_block(bf);
+        off.fill_block((1, 0, &[9u8; 100]));
+        assert!(!off.has_pending_verifications());
+        assert!(off.are_we_done_yet());
+        assert!(off.is_piece_verified(1));
+        assert_eq!(off.corrupt_bytes, 0);
+    }
 }
@@ -0,0 +1,281 @@
+use crate::bencode::{bencode, Bencodable, DictBuilder, EncodeError};
+use crate::torrent::FIXED_BLOCK_SIZE;
+use sha1::{Digest, Sha1};
+use std::fs::File as FsFile;
+use std::io::{Error as IOError, Read};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_PIECE_LENGTH: u32 = 256 * 1024;
+
+#[derive(Debug)]
+pub enum BuildError {
+    Io(IOError),
+    // piece lengths have to be a power of two no smaller than a block
+    PieceLength(u32),
+    // nothing to share: no files, or only empty ones
+    Empty,
+    // file names go into the torrent as UTF-8
+    NonUtf8Path(PathBuf),
+    Encode(EncodeError),
+}
+
+impl From<IOError> for BuildError {
+    fn from(e: IOError) -> Self {
+        BuildError::Io(e)
+    }
+}
+
+// Makes a .torrent out of a file, or out of everything under a directory. Files in a directory go
+// into the torrent sorted by path, so building the same tree twice gives the same info hash.
+//
+//     let bytes = TorrentBuilder::new("some/dir")
+//         .announce("http://tracker.example/announce")
+//         .build()?;
+#[derive(Debug)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: u32,
+    announce: Option<String>,
+    announce_list: Vec<Vec<String>>,
+    comment: Option<String>,
+    private: bool,
+}
+
+impl TorrentBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        TorrentBuilder {
+            path: path.into(),
+            piece_length: DEFAULT_PIECE_LENGTH,
+            announce: None,
+            announce_list: vec![],
+            comment: None,
+            private: false,
+        }
+    }
+
+    pub fn piece_length(mut self, piece_length: u32) -> Self {
+        self.piece_length = piece_length;
+        self
+    }
+
+    pub fn announce(mut self, url: &str) -> Self {
+        self.announce = Some(url.to_string());
+        self
+    }
+
+    // Adds a tier to `announce-list`; trackers in earlier tiers are tried first
+    pub fn announce_tier(mut self, urls: &[&str]) -> Self {
+        self.announce_list
+            .push(urls.iter().map(|url| url.to_string()).collect());
+        self
+    }
+
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    // Private torrents get their peers from their trackers only
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    // The encoded .torrent, ready to be written out or handed to `MetaInfoFile::from_bytes`
+    pub fn build(&self) -> Result<Vec<u8>, BuildError> {
+        bencode(&self.to_bencodable()?).map_err(BuildError::Encode)
+    }
+
+    pub fn to_bencodable(&self) -> Result<Bencodable, BuildError> {
+        if !self.piece_length.is_power_of_two() || self.piece_length < FIXED_BLOCK_SIZE {
+            return Err(BuildError::PieceLength(self.piece_length));
+        }
+        let name = utf8(self.path.file_name().map(Path::new).unwrap_or(&self.path))?;
+        let single_file = self.path.is_file();
+        let files = if single_file {
+            vec![self.path.clone()]
+        } else {
+            let mut files = vec![];
+            walk(&self.path, &mut files)?;
+            files.sort();
+            files
+        };
+
+        let mut lengths = vec![];
+        for file in &files {
+            lengths.push(std::fs::metadata(file)?.len());
+        }
+        if lengths.iter().sum::<u64>() == 0 {
+            return Err(BuildError::Empty);
+        }
+
+        let info = DictBuilder::new()
+            .insert("name", name)
+            .insert("piece length", self.piece_length)
+            .insert("pieces", self.hash_pieces(&files)?)
+            .insert_some("private", self.private.then_some(1_i64));
+        let info = if single_file {
+            info.insert("length", lengths[0] as i64)
+        } else {
+            let mut entries = vec![];
+            for (file, length) in files.iter().zip(lengths) {
+                let path = file
+                    .strip_prefix(&self.path)
+                    .unwrap_or(file)
+                    .iter()
+                    .map(|component| utf8(Path::new(component)).map(Bencodable::from))
+                    .collect::<Result<Vec<Bencodable>, BuildError>>()?;
+                entries.push(
+                    DictBuilder::new()
+                        .insert("length", length as i64)
+                        .insert("path", path)
+                        .build(),
+                );
+            }
+            info.insert("files", entries)
+        };
+
+        let announce_list = (!self.announce_list.is_empty()).then(|| {
+            self.announce_list
+                .iter()
+                .map(|tier| {
+                    Bencodable::from(
+                        tier.iter()
+                            .map(|url| Bencodable::from(url.as_str()))
+                            .collect::<Vec<Bencodable>>(),
+                    )
+                })
+                .collect::<Vec<Bencodable>>()
+        });
+        // clients that don't know `announce-list` still find a tracker
+        let announce = self
+            .announce
+            .clone()
+            .or_else(|| self.announce_list.iter().flatten().next().cloned());
+        Ok(DictBuilder::new()
+            .insert_some("announce", announce)
+            .insert_some("announce-list", announce_list)
+            .insert_some("comment", self.comment.clone())
+            .insert("created by", "bit_torrent")
+            .insert("info", info.build())
+            .build())
+    }
+
+    // Pieces run on from one file into the next, as they do in the download
+    fn hash_pieces(&self, files: &[PathBuf]) -> Result<Vec<u8>, BuildError> {
+        let piece_length = self.piece_length as usize;
+        let mut pieces = vec![];
+        let mut piece = Vec::with_capacity(piece_length);
+        for path in files {
+            let mut file = FsFile::open(path)?;
+            loop {
+                let filled = piece.len();
+                piece.resize(piece_length, 0);
+                let read = file.read(&mut piece[filled..])?;
+                piece.truncate(filled + read);
+                if read == 0 {
+                    break;
+                }
+                if piece.len() == piece_length {
+                    pieces.extend(Sha1::digest(&piece));
+                    piece.clear();
+                }
+            }
+        }
+        if !piece.is_empty() {
+            pieces.extend(Sha1::digest(&piece));
+        }
+        Ok(pieces)
+    }
+}
+
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), IOError> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn utf8(path: &Path) -> Result<&str, BuildError> {
+    path.to_str()
+        .ok_or_else(|| BuildError::NonUtf8Path(path.to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::bdecode;
+    use crate::meta_info_file::{Info, MetaInfoFile};
+
+    #[test]
+    fn it_builds_torrents_of_a_directory() {
+        let dir = std::env::temp_dir().join(format!(
+            "bit_torrent_builder_{}",
+            crate::util::random_string()
+        ));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let name = dir.file_name().unwrap().to_str().unwrap().to_string();
+        let first = vec![1u8; 20000];
+        let second = vec![2u8; 30000];
+        std::fs::write(dir.join("sub").join("b.bin"), &second).unwrap();
+        std::fs::write(dir.join("a.bin"), &first).unwrap();
+
+        let builder = TorrentBuilder::new(&dir)
+            .piece_length(16384)
+            .announce_tier(&["http://one.example/announce"])
+            .announce_tier(&["http://two.example/announce", "udp://three.example:80"])
+            .comment("made in a test")
+            .private(true);
+        let bytes = builder.build().unwrap();
+        let decoded = bdecode(&bytes).unwrap();
+        assert_eq!(
+            decoded["announce"],
+            Bencodable::from("http://one.example/announce")
+        );
+        assert_eq!(decoded["announce-list"].as_list().unwrap().len(), 2);
+        assert_eq!(decoded["comment"], Bencodable::from("made in a test"));
+        assert_eq!(decoded["info"]["private"], Bencodable::Integer(1));
+
+        let content = [first, second].concat();
+        let expected: Vec<u8> = content.chunks(16384).flat_map(Sha1::digest).collect();
+        assert_eq!(decoded["info"]["pieces"], Bencodable::from(expected));
+
+        let meta_info = MetaInfoFile::from(bytes.as_slice());
+        match &meta_info.info {
+            Info::MultiFile { files, .. } => {
+                assert_eq!(
+                    files
+                        .iter()
+                        .map(|f| (f.path.clone(), f.length))
+                        .collect::<Vec<_>>(),
+                    vec![
+                        (Path::new(&name).join("a.bin"), 20000),
+                        (Path::new(&name).join("sub").join("b.bin"), 30000)
+                    ]
+                );
+            }
+            info => panic!("expected a multi-file torrent, got {:?}", info),
+        }
+        // the same tree always makes the same torrent
+        assert_eq!(builder.build().unwrap(), bytes);
+
+        let single = TorrentBuilder::new(dir.join("a.bin"))
+            .announce("http://one.example/announce")
+            .to_bencodable()
+            .unwrap();
+        assert_eq!(single["info"]["length"], Bencodable::Integer(20000));
+        assert_eq!(single["info"]["name"], Bencodable::from("a.bin"));
+        assert!(single["info"].get("private").is_err());
+
+        assert!(matches!(
+            TorrentBuilder::new(&dir).piece_length(1000).build(),
+            Err(BuildError::PieceLength(1000))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+use crate::bencode::{bdecode_with_span, Bencodable, BencodeParseError, EncodeError, Preserved};
+use sha1::{Digest, Sha1};
+use std::io::Error as IOError;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum EditError {
+    Io(IOError),
+    Decode(BencodeParseError),
+    // decodes, but isn't a dictionary with an `info` dictionary in it
+    NotATorrent,
+    Encode(EncodeError),
+}
+
+// Changes what a .torrent says around its content (trackers, comment, web seeds) and writes it back
+// out. Everything left alone keeps its original bytes, the `info` dictionary above all, so the
+// torrent keeps its info hash and joins the same swarm; only `private`, which lives in `info`,
+// makes a new torrent of it.
+//
+//     let bytes = TorrentEditor::from_path("old.torrent")?
+//         .announce(Some("http://new-tracker.example/announce"))
+//         .announce_list(&[])
+//         .build()?;
+#[derive(Debug)]
+pub struct TorrentEditor {
+    source: Preserved,
+    edited: Bencodable,
+}
+
+impl TorrentEditor {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EditError> {
+        let source = Preserved::decode(bytes).map_err(EditError::Decode)?;
+        if !matches!(source.value().get("info"), Ok(Bencodable::Dictionary(_))) {
+            return Err(EditError::NotATorrent);
+        }
+        Ok(TorrentEditor {
+            edited: source.value().clone(),
+            source,
+        })
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, EditError> {
+        let bytes = std::fs::read(path).map_err(EditError::Io)?;
+        TorrentEditor::from_bytes(&bytes)
+    }
+
+    // None removes it, leaving only `announce-list`, or a trackerless torrent
+    pub fn announce(mut self, url: Option<&str>) -> Self {
+        set(&mut self.edited, "announce", url.map(Bencodable::from));
+        self
+    }
+
+    // Replaces every tier; no tiers removes `announce-list` altogether
+    pub fn announce_list(mut self, tiers: &[Vec<&str>]) -> Self {
+        let tiers = (!tiers.is_empty()).then(|| {
+            Bencodable::from(
+                tiers
+                    .iter()
+                    .map(|tier| {
+                        Bencodable::from(
+                            tier.iter()
+                                .map(|url| Bencodable::from(*url))
+                                .collect::<Vec<Bencodable>>(),
+                        )
+                    })
+                    .collect::<Vec<Bencodable>>(),
+            )
+        });
+        set(&mut self.edited, "announce-list", tiers);
+        self
+    }
+
+    pub fn comment(mut self, comment: Option<&str>) -> Self {
+        set(&mut self.edited, "comment", comment.map(Bencodable::from));
+        self
+    }
+
+    // BEP 19 `url-list`; none removes it
+    pub fn web_seeds(mut self, urls: &[&str]) -> Self {
+        let urls = (!urls.is_empty()).then(|| {
+            Bencodable::from(
+                urls.iter()
+                    .map(|url| Bencodable::from(*url))
+                    .collect::<Vec<Bencodable>>(),
+            )
+        });
+        set(&mut self.edited, "url-list", urls);
+        self
+    }
+
+    // Changes the info dictionary, and with it the info hash: the result is a different torrent
+    // to every tracker and peer
+    pub fn private(mut self, private: bool) -> Self {
+        if let Bencodable::Dictionary(entries) = &mut self.edited {
+            if let Some(info) = entries.get_mut(b"info".as_slice()) {
+                set(info, "private", private.then_some(Bencodable::Integer(1)));
+            }
+        }
+        self
+    }
+
+    // The info hash of the torrent as `build` would write it
+    pub fn info_hash(&self) -> Result<[u8; 20], EditError> {
+        let bytes = self.build()?;
+        let (_, span) = bdecode_with_span(&bytes).map_err(EditError::Decode)?;
+        let info = span.get("info").ok_or(EditError::NotATorrent)?;
+        Ok(<[u8; 20]>::from(Sha1::digest(info.slice(&bytes))))
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>, EditError> {
+        self.source.encode(&self.edited).map_err(EditError::Encode)
+    }
+
+    pub fn write_to(&self, path: impl AsRef<Path>) -> Result<(), EditError> {
+        std::fs::write(path, self.build()?).map_err(EditError::Io)
+    }
+}
+
+// Sets or removes a key of a dictionary; anything else is left alone
+fn set(dictionary: &mut Bencodable, key: &str, value: Option<Bencodable>) {
+    if let Bencodable::Dictionary(entries) = dictionary {
+        match value {
+            Some(value) => {
+                entries.insert(key.into(), value);
+            }
+            None => {
+                entries.remove(key.as_bytes());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta_info_file::MetaInfoFile;
+
+    // keys out of order inside `info`, so re-encoding it would change the info hash
+    const TORRENT: &[u8] = b"d8:announce27:http://one.example/announce7:comment3:old4:infod6:lengthi5e4:name5:a.txt6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi16384eee";
+
+    #[test]
+    fn it_retracks_a_torrent_keeping_its_info_hash() {
+        let original = MetaInfoFile::from_bytes(TORRENT).unwrap();
+        let editor = TorrentEditor::from_bytes(TORRENT)
+            .unwrap()
+            .announce(Some("http://two.example/announce"))
+            .announce_list(&[
+                vec!["http://two.example/announce"],
+                vec!["http://three.example/announce", "udp://four.example:80"],
+            ])
+            .comment(None)
+            .web_seeds(&["http://seed.example/files/"]);
+        assert_eq!(editor.info_hash().unwrap(), original.info_hash);
+
+        let edited = MetaInfoFile::from_bytes(&editor.build().unwrap()).unwrap();
+        assert_eq!(edited.info_hash, original.info_hash);
+        assert_eq!(edited.info_bytes, original.info_bytes);
+        assert_eq!(
+            edited.announce.as_deref(),
+            Some("http://two.example/announce")
+        );
+        assert_eq!(edited.announce_list.len(), 2);
+        assert_eq!(edited.url_list.len(), 1);
+        assert!(editor.edited.get("comment").is_err());
+
+        // nothing changed, nothing different
+        assert_eq!(
+            TorrentEditor::from_bytes(TORRENT).unwrap().build().unwrap(),
+            TORRENT
+        );
+    }
+
+    #[test]
+    fn it_makes_a_new_torrent_when_the_private_flag_changes() {
+        let original = MetaInfoFile::from_bytes(TORRENT).unwrap();
+        let editor = TorrentEditor::from_bytes(TORRENT).unwrap().private(true);
+        let private = MetaInfoFile::from_bytes(&editor.build().unwrap()).unwrap();
+        assert!(private.is_private());
+        assert_ne!(private.info_hash, original.info_hash);
+        assert_eq!(editor.info_hash().unwrap(), private.info_hash);
+
+        assert!(matches!(
+            TorrentEditor::from_bytes(b"d8:announce1:xe"),
+            Err(EditError::NotATorrent)
+        ));
+    }
+}
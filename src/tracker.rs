@@ -1,11 +1,26 @@
 use crate::bencode;
+use crate::dns::{DnsError, DnsResolver, IpPreference};
 use crate::util::random_string;
+use parking_lot::{Mutex, RwLock};
+use percent_encoding::{percent_encode, utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::seq::SliceRandom;
 use reqwest::blocking::Response;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs, UdpSocket,
+};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread::spawn;
+use std::time::{Duration, Instant};
 
-#[derive(PartialEq, Eq)]
+// Left out of regular announces, which are neither of these
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Event {
     Started,
+    Completed,
+    Stopped,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -37,6 +52,7 @@ impl From<TrackerPeer> for Peer {
 
 #[derive(Debug)]
 pub enum TrackerResponseError {
+    NoTrackers,
     BdecodeFailure(bencode::BencodeParseError),
     NoPeerKey,
     HttpError(reqwest::Error),
@@ -45,41 +61,533 @@ pub enum TrackerResponseError {
     NoPeerByteString {
         original_string: bencode::Bencodable,
     },
+    MinIntervalNotElapsed {
+        url: String,
+        next_allowed: Instant,
+    },
+    HttpStatus(u16),
+    Failure(String),
+    TooManyRedirects,
+    BadRedirect,
+    Decompress(std::io::Error),
+    // the announce URL, or one it redirected to, is on a host outside the allow-list
+    HostNotAllowed(String),
+    Dns(DnsError),
+    // no `Announce` for this kind of announce URL
+    UnsupportedScheme(String),
+    InvalidUrl(String),
+    Io(std::io::Error),
+    // a UDP tracker's answer was too short or for another transaction
+    BadUdpResponse,
+}
+
+const MAX_REDIRECTS: usize = 5;
+
+// Everything a successful announce brought back
+#[derive(Debug)]
+pub struct TrackerResponse {
+    pub peers: Vec<TrackerPeer>,
+    pub intervals: AnnounceIntervals,
+    // seeders and leechers in the swarm, by the tracker's count
+    pub complete: Option<u32>,
+    pub incomplete: Option<u32>,
+    // something the tracker wants seen; the announce still worked
+    pub warning_message: Option<String>,
+    // BEP 24: the address the announce came from, as the tracker saw it
+    pub external_ip: Option<IpAddr>,
+    // the tracker moved; later announces should go here instead
+    pub redirected_to: Option<String>,
+}
+
+// How often the tracker asked to be announced to; `min_interval` is a hard floor that private
+// trackers enforce by banning clients that announce sooner
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AnnounceIntervals {
+    pub interval: Option<Duration>,
+    pub min_interval: Option<Duration>,
+}
+
+// Announce parameters beyond the ones every tracker understands. Trackers ignore parameters they
+// don't know, so all of them are sent unless turned off for a tracker that objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptionalParameters {
+    // bytes thrown away because their piece failed its hash check
+    pub corrupt: bool,
+    // bytes received for blocks we already had
+    pub redundant: bool,
+    // ask for compact peer lists (`compact=1&no_peer_id=1`); off for trackers that only know the
+    // dictionary form, which then get neither and answer however they like
+    pub compact: bool,
+}
+
+impl Default for OptionalParameters {
+    fn default() -> Self {
+        OptionalParameters {
+            corrupt: true,
+            redundant: true,
+            compact: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerStatus {
+    pub url: String,
+    // BEP 12: trackers are tried tier by tier, lower first
+    pub tier: usize,
+    pub last_announce: Option<Instant>,
+    pub intervals: AnnounceIntervals,
+    pub optional_parameters: OptionalParameters,
+    // from the last successful announce
+    pub complete: Option<u32>,
+    pub incomplete: Option<u32>,
+    pub warning_message: Option<String>,
+    pub peers_returned: usize,
+    // how the last announce failed, if it did, and how many in a row have
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+}
+
+impl TrackerStatus {
+    pub fn new(url: &str) -> Self {
+        TrackerStatus {
+            url: url.to_string(),
+            tier: 0,
+            last_announce: None,
+            intervals: AnnounceIntervals::default(),
+            optional_parameters: OptionalParameters::default(),
+            complete: None,
+            incomplete: None,
+            warning_message: None,
+            peers_returned: 0,
+            last_error: None,
+            consecutive_failures: 0,
+        }
+    }
+
+    // Answered the last time it was asked. Trackers not announced to yet aren't working, whatever
+    // they'd say.
+    pub fn is_working(&self) -> bool {
+        self.last_announce.is_some() && self.consecutive_failures == 0
+    }
+
+    // When the tracker asked to hear from us again; regular announces wait for this, where
+    // `next_allowed_announce` is only the floor
+    pub fn next_announce(&self) -> Option<Instant> {
+        let interval = self.intervals.interval.or(self.intervals.min_interval)?;
+        self.last_announce.map(|last| last + interval)
+    }
+
+    // Falls back to the regular interval when the tracker didn't send a `min interval`
+    pub fn next_allowed_announce(&self) -> Option<Instant> {
+        let floor = self.intervals.min_interval.or(self.intervals.interval)?;
+        self.last_announce.map(|last| last + floor)
+    }
+
+    pub fn check_announce(
+        &self,
+        now: Instant,
+        override_min_interval: bool,
+    ) -> Result<(), TrackerResponseError> {
+        match self.next_allowed_announce() {
+            Some(next_allowed) if now < next_allowed && !override_min_interval => {
+                Err(TrackerResponseError::MinIntervalNotElapsed {
+                    url: self.url.clone(),
+                    next_allowed,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Only successful announces move the window; a failed one can be retried straight away
+    pub fn record_announce(&mut self, at: Instant, intervals: AnnounceIntervals) {
+        self.last_announce = Some(at);
+        self.intervals = intervals;
+    }
+
+    // `record_announce`, keeping the swarm counts and any warning too
+    pub fn record_response(&mut self, at: Instant, response: &TrackerResponse) {
+        self.record_announce(at, response.intervals);
+        self.complete = response.complete;
+        self.incomplete = response.incomplete;
+        self.warning_message = response.warning_message.clone();
+        self.peers_returned = response.peers.len();
+        self.last_error = None;
+        self.consecutive_failures = 0;
+    }
+
+    // An announce that got no answer, or a refusal; the min interval window stays where it was
+    pub fn record_failure(&mut self, error: &TrackerResponseError) {
+        self.last_error = Some(match error {
+            TrackerResponseError::Failure(reason) => reason.clone(),
+            error => format!("{:?}", error),
+        });
+        self.consecutive_failures += 1;
+    }
+
+    // Every tracker of `tiers` (as `MetaInfoFile::tiers` gives them) in the order they're tried:
+    // tier by tier, each tier shuffled so clients don't all pile onto its first tracker
+    pub fn tiered(tiers: &[Vec<String>]) -> Vec<TrackerStatus> {
+        let mut trackers = vec![];
+        for (tier, urls) in tiers.iter().enumerate() {
+            let mut urls: Vec<&String> = urls.iter().collect();
+            urls.shuffle(&mut rand::thread_rng());
+            trackers.extend(urls.into_iter().map(|url| TrackerStatus {
+                tier,
+                ..TrackerStatus::new(url)
+            }));
+        }
+        trackers
+    }
+}
+
+// BEP 12: a tracker that answered moves to the front of its tier, so it's tried first next time.
+// `trackers` is in announce order, as `TrackerStatus::tiered` makes it.
+pub fn promote(trackers: &mut [TrackerStatus], url: &str) {
+    let Some(index) = trackers.iter().position(|t| t.url == url) else {
+        return;
+    };
+    let tier = trackers[index].tier;
+    let first = trackers
+        .iter()
+        .position(|t| t.tier == tier)
+        .unwrap_or(index);
+    trackers[first..=index].rotate_right(1);
+}
+
+// BEP 15: a connect carrying this magic number gets a connection id, which announces then carry
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+const UDP_ACTION_CONNECT: u32 = 0;
+// BEP 15 announce, the action that follows the connect handshake
+const UDP_ACTION_ANNOUNCE: u32 = 1;
+const UDP_ACTION_ERROR: u32 = 3;
+// far shorter than BEP 15's 15 seconds doubling: there are other trackers to try
+const UDP_TIMEOUT: Duration = Duration::from_secs(5);
+const UDP_ATTEMPTS: usize = 2;
+// BEP 15: a connection id can be used for a minute after the tracker hands it out
+const UDP_CONNECTION_LIFETIME: Duration = Duration::from_secs(60);
+// how often the receiving thread looks up to see whether its client is gone
+const UDP_RECEIVE_POLL: Duration = Duration::from_millis(500);
+
+// Everything one announce tells a tracker, rendered as an HTTP query string or as the fields of a
+// UDP announce packet, so both transports send the same thing:
+//
+//     let request = TrackerRequest::new(info_hash, peer_id.as_bytes(), 6881)
+//         .event(Event::Started)
+//         .left(total_length);
+//     tracker.track(&announce_url, &request)?;
+//
+// Parameters that weren't set are left out of the query string (and get the protocol's default in
+// a UDP packet).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerRequest {
+    info_hash: [u8; 20],
+    peer_id: Vec<u8>,
+    port: u16,
+    event: Option<Event>,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    numwant: Option<u32>,
+    key: Option<u32>,
+    compact: Option<bool>,
+    no_peer_id: Option<bool>,
+    ipv6: Option<Ipv6Addr>,
+    corrupt: Option<u64>,
+    redundant: Option<u64>,
+}
+
+impl TrackerRequest {
+    pub fn new(info_hash: [u8; 20], peer_id: &[u8], port: u16) -> Self {
+        TrackerRequest {
+            info_hash,
+            peer_id: peer_id.to_vec(),
+            port,
+            event: None,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            numwant: None,
+            key: None,
+            compact: None,
+            no_peer_id: None,
+            ipv6: None,
+            corrupt: None,
+            redundant: None,
+        }
+    }
+
+    pub fn event(mut self, event: Event) -> Self {
+        self.event = Some(event);
+        self
+    }
+
+    pub fn uploaded(mut self, uploaded: u64) -> Self {
+        self.uploaded = uploaded;
+        self
+    }
+
+    pub fn downloaded(mut self, downloaded: u64) -> Self {
+        self.downloaded = downloaded;
+        self
+    }
+
+    pub fn left(mut self, left: u64) -> Self {
+        self.left = left;
+        self
+    }
+
+    // How many peers we'd like back; trackers pick their own number otherwise
+    pub fn numwant(mut self, numwant: u32) -> Self {
+        self.numwant = Some(numwant);
+        self
+    }
+
+    // Lets the tracker recognise us across IP address changes
+    pub fn key(mut self, key: u32) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    // Six bytes a peer instead of a dictionary each; trackers choose for themselves otherwise
+    pub fn compact(mut self, compact: bool) -> Self {
+        self.compact = Some(compact);
+        self
+    }
+
+    // Leaves peer ids out of dictionary peer lists; compact ones never have them
+    pub fn no_peer_id(mut self, no_peer_id: bool) -> Self {
+        self.no_peer_id = Some(no_peer_id);
+        self
+    }
+
+    // BEP 7: the address IPv6 peers can reach us on, for a tracker we announce to over IPv4 (which
+    // would otherwise only know that one)
+    pub fn ipv6(mut self, ipv6: Ipv6Addr) -> Self {
+        self.ipv6 = Some(ipv6);
+        self
+    }
+
+    pub fn corrupt(mut self, corrupt: u64) -> Self {
+        self.corrupt = Some(corrupt);
+        self
+    }
+
+    pub fn redundant(mut self, redundant: u64) -> Self {
+        self.redundant = Some(redundant);
+        self
+    }
+
+    // Leaves out the optional counters the tracker hasn't opted into
+    pub fn for_tracker(mut self, optional_parameters: OptionalParameters) -> Self {
+        if !optional_parameters.corrupt {
+            self.corrupt = None;
+        }
+        if !optional_parameters.redundant {
+            self.redundant = None;
+        }
+        if !optional_parameters.compact {
+            self.compact = None;
+            self.no_peer_id = None;
+        }
+        self
+    }
+
+    pub fn query_string(&self) -> String {
+        let mut query = format!(
+            "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}",
+            percent_encode(&self.info_hash, NON_ALPHANUMERIC),
+            percent_encode(&self.peer_id, NON_ALPHANUMERIC),
+            self.port,
+            self.uploaded,
+            self.downloaded,
+            self.left
+        );
+        if let Some(event) = self.event {
+            let event = match event {
+                Event::Started => "started",
+                Event::Completed => "completed",
+                Event::Stopped => "stopped",
+            };
+            query.push_str(&format!("&event={}", event));
+        }
+        if let Some(compact) = self.compact {
+            query.push_str(&format!("&compact={}", compact as u8));
+        }
+        if let Some(no_peer_id) = self.no_peer_id {
+            query.push_str(&format!("&no_peer_id={}", no_peer_id as u8));
+        }
+        if let Some(ipv6) = self.ipv6 {
+            query.push_str(&format!(
+                "&ipv6={}",
+                utf8_percent_encode(&ipv6.to_string(), NON_ALPHANUMERIC)
+            ));
+        }
+        if let Some(numwant) = self.numwant {
+            query.push_str(&format!("&numwant={}", numwant));
+        }
+        if let Some(key) = self.key {
+            query.push_str(&format!("&key={:08x}", key));
+        }
+        if let Some(corrupt) = self.corrupt {
+            query.push_str(&format!("&corrupt={}", corrupt));
+        }
+        if let Some(redundant) = self.redundant {
+            query.push_str(&format!("&redundant={}", redundant));
+        }
+        query
+    }
+
+    // The announce URL with the query string added, after any query of the tracker's own (e.g. a
+    // private tracker's passkey). A fragment never reaches the tracker, so it's dropped.
+    pub fn url(&self, announce_url: &str) -> String {
+        let announce_url = announce_url
+            .split_once('#')
+            .map_or(announce_url, |(url, _)| url);
+        let separator = match announce_url.find('?') {
+            None => "?",
+            // `?` or `&` on the end already separates what comes next
+            Some(_) if announce_url.ends_with(['?', '&']) => "",
+            Some(_) => "&",
+        };
+        format!("{}{}{}", announce_url, separator, self.query_string())
+    }
+
+    // A BEP 15 announce packet. UDP trackers take no optional counters and always answer compactly.
+    pub fn udp_announce(&self, connection_id: u64, transaction_id: u32) -> Vec<u8> {
+        let mut peer_id = self.peer_id.clone();
+        peer_id.resize(20, 0);
+        let event: u32 = match self.event {
+            None => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        };
+        let mut packet = Vec::with_capacity(98);
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&UDP_ACTION_ANNOUNCE.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(&self.info_hash);
+        packet.extend_from_slice(&peer_id);
+        packet.extend_from_slice(&self.downloaded.to_be_bytes());
+        packet.extend_from_slice(&self.left.to_be_bytes());
+        packet.extend_from_slice(&self.uploaded.to_be_bytes());
+        packet.extend_from_slice(&event.to_be_bytes());
+        // the address we're announcing from
+        packet.extend_from_slice(&0u32.to_be_bytes());
+        packet.extend_from_slice(&self.key.unwrap_or(0).to_be_bytes());
+        let numwant = self
+            .numwant
+            .map(|n| n.min(i32::MAX as u32) as i32)
+            .unwrap_or(-1);
+        packet.extend_from_slice(&numwant.to_be_bytes());
+        packet.extend_from_slice(&self.port.to_be_bytes());
+        packet
+    }
 }
 
-pub struct TrackerRequestParameters {
-    pub port: u16,
-    pub uploaded: u32,
-    pub downloaded: u32,
-    pub left: u32,
-    pub event: Event,
+// One tracker, whatever it's spoken to over. The session gets one for each announce URL from
+// `Tracker::announcer`, or uses one it was given for the URL instead (e.g. a `MockTracker`).
+pub trait Announce: Send + Sync {
+    fn announce(&self, request: &TrackerRequest) -> Result<TrackerResponse, TrackerResponseError>;
 }
 
+// Announcers to use for particular announce URLs instead of the usual one for their scheme, shared
+// by every torrent in a session
+#[derive(Clone, Default)]
+pub struct Announcers {
+    overrides: Arc<RwLock<HashMap<String, Arc<dyn Announce>>>>,
+}
+
+impl Announcers {
+    pub fn set(&self, url: &str, announcer: Arc<dyn Announce>) {
+        self.overrides.write().insert(url.to_string(), announcer);
+    }
+
+    // The one set for `url`, or else what `tracker` would use
+    pub fn for_url(
+        &self,
+        tracker: &Tracker,
+        url: &str,
+    ) -> Result<Arc<dyn Announce>, TrackerResponseError> {
+        if let Some(announcer) = self.overrides.read().get(url) {
+            return Ok(Arc::clone(announcer));
+        }
+        tracker.announcer(url).map(Arc::from)
+    }
+}
+
+// How trackers are contacted: allowed hosts, name resolution and the HTTP client, shared by every
+// kind of tracker
+#[derive(Clone)]
 pub struct Tracker {
     client: reqwest::blocking::Client,
+    allowed_hosts: Option<Vec<String>>,
+    dns: Option<(DnsResolver, IpPreference)>,
+    udp: UdpClient,
 }
 
 impl From<&bencode::BencodableByteString> for Result<Vec<TrackerPeer>, TrackerResponseError> {
     fn from(b: &bencode::BencodableByteString) -> Result<Vec<TrackerPeer>, TrackerResponseError> {
-        let peer_bytes: &[u8] = b.as_bytes();
-        let total_bytes = peer_bytes.len();
-        if total_bytes % 6 == 0 {
-            let mut socket_addrs: Vec<TrackerPeer> = vec![];
-            let mut i = 0;
-            while i < total_bytes {
-                let ip_bytes = &peer_bytes[i..i + 6];
-                let ip = Ipv4Addr::new(ip_bytes[0], ip_bytes[1], ip_bytes[2], ip_bytes[3]);
-                let port = u16::from_be_bytes([peer_bytes[4], peer_bytes[5]]);
-                let socket_addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
-                socket_addrs.push(TrackerPeer::SocketAddr(socket_addr));
-                i += 6;
-            }
+        compact_peers(b.as_bytes())
+    }
+}
 
-            Ok(socket_addrs)
-        } else {
-            Err(TrackerResponseError::MisalignedPeers)
+// BEP 23 `peers`: 4 bytes of address and 2 of port each
+fn compact_peers(peer_bytes: &[u8]) -> Result<Vec<TrackerPeer>, TrackerResponseError> {
+    let total_bytes = peer_bytes.len();
+    if total_bytes % 6 == 0 {
+        let mut socket_addrs: Vec<TrackerPeer> = vec![];
+        let mut i = 0;
+        while i < total_bytes {
+            let ip_bytes = &peer_bytes[i..i + 6];
+            let ip = Ipv4Addr::new(ip_bytes[0], ip_bytes[1], ip_bytes[2], ip_bytes[3]);
+            let port = u16::from_be_bytes([ip_bytes[4], ip_bytes[5]]);
+            let socket_addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+            socket_addrs.push(TrackerPeer::SocketAddr(socket_addr));
+            i += 6;
         }
+
+        Ok(socket_addrs)
+    } else {
+        Err(TrackerResponseError::MisalignedPeers)
+    }
+}
+
+// BEP 7 `peers6`: 16 bytes of address and 2 of port each
+fn compact_peers6(peer_bytes: &[u8]) -> Result<Vec<TrackerPeer>, TrackerResponseError> {
+    if !peer_bytes.len().is_multiple_of(18) {
+        return Err(TrackerResponseError::MisalignedPeers);
     }
+    Ok(peer_bytes
+        .chunks_exact(18)
+        .map(|entry| {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&entry[..16]).unwrap());
+            let port = u16::from_be_bytes([entry[16], entry[17]]);
+            TrackerPeer::SocketAddr(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)))
+        })
+        .collect())
+}
+
+// A global IPv6 address of ours to announce, if the host has one. Connecting a UDP socket sends
+// nothing; it only makes the OS pick the address it would route from.
+pub fn local_ipv6() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind("[::]:0").ok()?;
+    socket.connect("[2001:4860:4860::8888]:53").ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if is_global_ipv6(&ip) => Some(ip),
+        _ => None,
+    }
+}
+
+// Not loopback, link-local or unique local: the only kind a tracker's peers could use
+fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !ip.is_loopback()
+        && !ip.is_unspecified()
+        && first & 0xffc0 != 0xfe80
+        && first & 0xfe00 != 0xfc00
 }
 
 struct BencodableList<'a> {
@@ -91,122 +599,956 @@ impl<'a> From<BencodableList<'a>> for Result<Vec<TrackerPeer>, TrackerResponseEr
         let mut rl = vec![];
 
         for b in b.list {
-            match b {
-                bencode::Bencodable::Dictionary(btm) => {
-                    let port = btm
-                        .get(&bencode::BencodableByteString::from("port"))
-                        .ok_or_else(|| TrackerResponseError::UnexpectedBencodable(b.clone()))
-                        .and_then(|port| match port {
-                            bencode::Bencodable::Integer(i) => Ok(i),
-                            _ => Err(TrackerResponseError::UnexpectedBencodable(b.clone())),
-                        })
-                        .unwrap();
-
-                    let ip: std::net::Ipv4Addr = btm
-                        .get(&bencode::BencodableByteString::from("ip"))
-                        .ok_or_else(|| TrackerResponseError::UnexpectedBencodable(b.clone()))
-                        .and_then(|ip| match ip {
-                            bencode::Bencodable::ByteString(bs) => Ok(bs),
-                            _ => Err(TrackerResponseError::UnexpectedBencodable(b.clone())),
-                        })
-                        .and_then(|s| {
-                            s.as_string()
-                                .map_err(|_| TrackerResponseError::UnexpectedBencodable(b.clone()))
-                        })
-                        .and_then(|s| {
-                            s.parse::<std::net::Ipv4Addr>()
-                                .map_err(|_| TrackerResponseError::UnexpectedBencodable(b.clone()))
-                        })
-                        .unwrap();
-
-                    let peer_id = btm
-                        .get(&bencode::BencodableByteString::from("peer id"))
-                        .ok_or_else(|| TrackerResponseError::UnexpectedBencodable(b.clone()))
-                        .and_then(|id| match id {
-                            bencode::Bencodable::ByteString(bs) => Ok(bs.as_bytes().to_vec()),
-                            _ => Err(TrackerResponseError::UnexpectedBencodable(b.clone())),
-                        })
-                        .unwrap();
-
-                    rl.push(TrackerPeer::Peer(Peer {
-                        socket_addr: SocketAddr::from((ip, *port as u16)),
-                        id: peer_id,
-                    }));
-                }
-                _ => return Err(TrackerResponseError::UnexpectedBencodable(b.clone())),
+            let unexpected = || TrackerResponseError::UnexpectedBencodable(b.clone());
+            let port = b
+                .get("port")
+                .and_then(bencode::Bencodable::as_int)
+                .map_err(|_| unexpected())?;
+            let port = u16::try_from(port).map_err(|_| unexpected())?;
+            // a hostname may be given too, but nobody does
+            let ip: IpAddr = b
+                .get("ip")
+                .and_then(bencode::Bencodable::as_str)
+                .map_err(|_| unexpected())?
+                .parse()
+                .map_err(|_| unexpected())?;
+            let socket_addr = SocketAddr::from((ip, port));
+            // left out by trackers that honour `no_peer_id` but not `compact`
+            match b.get("peer id") {
+                Ok(peer_id) => rl.push(TrackerPeer::Peer(Peer {
+                    socket_addr,
+                    id: peer_id.as_bytes().map_err(|_| unexpected())?.to_vec(),
+                })),
+                Err(_) => rl.push(TrackerPeer::SocketAddr(socket_addr)),
             }
         }
         Ok(rl)
     }
 }
 
+impl Default for Tracker {
+    fn default() -> Self {
+        Tracker::new()
+    }
+}
+
 impl Tracker {
     pub fn new() -> Self {
         Tracker {
-            client: reqwest::blocking::Client::new(),
+            client: client_builder().build().unwrap(),
+            allowed_hosts: None,
+            dns: None,
+            udp: UdpClient::default(),
         }
     }
 
+    // Announces to UDP trackers through `udp`, sharing its socket and connection ids with whatever
+    // else uses it
+    pub fn using_udp(mut self, udp: UdpClient) -> Self {
+        self.udp = udp;
+        self
+    }
+
+    // Looks tracker host names up through `dns` instead of leaving it to the HTTP client
+    pub fn resolving_with(mut self, dns: DnsResolver, preference: IpPreference) -> Self {
+        self.dns = Some((dns, preference));
+        self
+    }
+
+    // The shared client, or with a `DnsResolver` one that connects to the addresses it gave for
+    // `url`'s host
+    fn client_for(&self, url: &str) -> Result<reqwest::blocking::Client, TrackerResponseError> {
+        let Some((dns, preference)) = &self.dns else {
+            return Ok(self.client.clone());
+        };
+        let url = reqwest::Url::parse(url).map_err(|_| TrackerResponseError::BadRedirect)?;
+        let Some(host) = url.domain() else {
+            return Ok(self.client.clone());
+        };
+        // the port is taken from the URL whatever is given here
+        let addrs: Vec<SocketAddr> = dns
+            .lookup(host, *preference)
+            .map_err(TrackerResponseError::Dns)?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, 0))
+            .collect();
+        client_builder()
+            .resolve_to_addrs(host, &addrs)
+            .build()
+            .map_err(TrackerResponseError::HttpError)
+    }
+
+    // Refuses to contact any host but these and their subdomains, redirects included. `None`
+    // allows every host.
+    pub fn allowing_only(mut self, hosts: Option<Vec<String>>) -> Self {
+        self.allowed_hosts = hosts;
+        self
+    }
+
+    fn check_host(&self, url: &str) -> Result<(), TrackerResponseError> {
+        let Some(allowed_hosts) = &self.allowed_hosts else {
+            return Ok(());
+        };
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+            .unwrap_or_default();
+        let allowed = allowed_hosts.iter().any(|allowed| {
+            let allowed = allowed.to_ascii_lowercase();
+            host == allowed || host.ends_with(&format!(".{}", allowed))
+        });
+        match allowed {
+            true => Ok(()),
+            false => Err(TrackerResponseError::HostNotAllowed(host)),
+        }
+    }
+
+    // The `Announce` for an http(s):// or udp:// announce URL. WebTorrent's ws(s):// trackers are
+    // refused like any other scheme: they hand out no addresses, only WebRTC offers from browser
+    // peers, which can only be reached over WebRTC data channels and never over TCP
+    pub fn announcer(&self, url: &str) -> Result<Box<dyn Announce>, TrackerResponseError> {
+        let scheme = url
+            .split_once("://")
+            .map(|(scheme, _)| scheme.to_ascii_lowercase());
+        match scheme.as_deref() {
+            Some("http") | Some("https") => Ok(Box::new(HttpTracker {
+                url: url.to_string(),
+                tracker: self.clone(),
+            })),
+            Some("udp") => Ok(Box::new(UdpTracker {
+                url: url.to_string(),
+                tracker: self.clone(),
+            })),
+            _ => Err(TrackerResponseError::UnsupportedScheme(url.to_string())),
+        }
+    }
+
+    // The address of a udp:// tracker, which has no default port
+    fn udp_addr(&self, url: &str) -> Result<SocketAddr, TrackerResponseError> {
+        self.check_host(url)?;
+        let invalid = || TrackerResponseError::InvalidUrl(url.to_string());
+        let parsed = reqwest::Url::parse(url).map_err(|_| invalid())?;
+        let port = parsed.port().ok_or_else(invalid)?;
+        let host = parsed.host_str().ok_or_else(invalid)?;
+        // IPv6 literals keep their brackets in the host
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        let ip = match (literal.parse::<IpAddr>(), &self.dns) {
+            (Ok(ip), _) => ip,
+            (Err(_), Some((dns, preference))) => *dns
+                .lookup(host, *preference)
+                .map_err(TrackerResponseError::Dns)?
+                .first()
+                .ok_or_else(invalid)?,
+            (Err(_), None) => (host, port)
+                .to_socket_addrs()
+                .map_err(TrackerResponseError::Io)?
+                .next()
+                .ok_or_else(invalid)?
+                .ip(),
+        };
+        Ok(SocketAddr::new(ip, port))
+    }
+
     pub fn track(
         &self,
         announce_url: &str,
-        trp: TrackerRequestParameters,
-    ) -> Result<Vec<TrackerPeer>, TrackerResponseError> {
-        let request = self
-            .client
-            .get(announce_url)
-            .query(&[(
-                "event",
-                match trp.event {
-                    Event::Started => "started",
-                },
-            )])
-            .query(&[("port", trp.port)])
-            .query(&[("uploaded", trp.uploaded)])
-            .query(&[("downloaded", trp.downloaded)])
-            .query(&[("left", trp.left)])
-            .build()
-            .map_err(TrackerResponseError::HttpError)?;
+        tracker_request: &TrackerRequest,
+    ) -> Result<TrackerResponse, TrackerResponseError> {
+        let mut url = announce_url.to_string();
+        let mut redirected_to = None;
+        let mut redirects = 0;
+        let (status, body) = loop {
+            self.check_host(&url)?;
+            let client = self.client_for(&url)?;
+            let request = client
+                .get(tracker_request.url(&url))
+                .build()
+                .map_err(TrackerResponseError::HttpError)?;
 
-        println!("announce url {:?}", request.url());
+            println!("announce url {:?}", request.url());
 
-        self.client
-            .execute(request)
-            .map_err(TrackerResponseError::HttpError)
-            .and_then(|r: Response| {
-                let bytes = r.bytes().map_err(TrackerResponseError::HttpError)?;
-                bencode::bdecode(&bytes).map_err(TrackerResponseError::BdecodeFailure)
-            })
-            .and_then(|bencodable| match bencodable {
-                bencode::Bencodable::Dictionary(mut btm) => {
-                    let peers_bytes: Option<bencode::Bencodable> =
-                        btm.remove(&bencode::BencodableByteString::from("peers"));
-                    peers_bytes.ok_or(TrackerResponseError::NoPeerKey)
+            let response = client
+                .execute(request)
+                .map_err(TrackerResponseError::HttpError)?;
+            let status = response.status();
+            if status.is_redirection() {
+                redirects += 1;
+                if redirects > MAX_REDIRECTS {
+                    return Err(TrackerResponseError::TooManyRedirects);
+                }
+                url = redirect_location(&url, &response)?;
+                redirected_to = Some(url.clone());
+                continue;
+            }
+            let gzipped = response
+                .headers()
+                .get(reqwest::header::CONTENT_ENCODING)
+                .map(|v| v.as_bytes().eq_ignore_ascii_case(b"gzip"))
+                .unwrap_or(false);
+            let bytes = response.bytes().map_err(TrackerResponseError::HttpError)?;
+            break (status, decompress(&bytes, gzipped)?);
+        };
+
+        if !status.is_success() {
+            // trackers often explain a non-200 in a normal bencoded failure response
+            return Err(match failure_reason(&body) {
+                Some(reason) => TrackerResponseError::Failure(reason),
+                None => TrackerResponseError::HttpStatus(status.as_u16()),
+            });
+        }
+
+        bencode::bdecode(&body)
+            .map_err(TrackerResponseError::BdecodeFailure)
+            .and_then(|bencodable| {
+                // a refusal usually comes as a 200 with nothing but the reason in it
+                if let Ok(reason) = bencodable
+                    .get("failure reason")
+                    .and_then(bencode::Bencodable::as_str)
+                {
+                    return Err(TrackerResponseError::Failure(reason.to_string()));
                 }
-                _ => Err(TrackerResponseError::UnexpectedBencodable(bencodable)),
+                // IPv6 peers come separately, and are all an IPv6 only swarm has
+                let peers6 = match bencodable.get("peers6") {
+                    Ok(bencode::Bencodable::ByteString(bs)) => Some(compact_peers6(bs.as_bytes())?),
+                    Ok(peers6) => {
+                        return Err(TrackerResponseError::NoPeerByteString {
+                            original_string: peers6.clone(),
+                        })
+                    }
+                    Err(_) => None,
+                };
+                let peers = match bencodable.get("peers") {
+                    Ok(peers) => peers.clone(),
+                    Err(bencode::AccessError::MissingKey(_)) if peers6.is_some() => {
+                        bencode::Bencodable::List(vec![])
+                    }
+                    Err(bencode::AccessError::MissingKey(_)) => {
+                        return Err(TrackerResponseError::NoPeerKey)
+                    }
+                    Err(_) => return Err(TrackerResponseError::UnexpectedBencodable(bencodable)),
+                };
+                let response = TrackerResponse {
+                    peers: vec![],
+                    intervals: AnnounceIntervals {
+                        interval: seconds(&bencodable, "interval"),
+                        min_interval: seconds(&bencodable, "min interval"),
+                    },
+                    complete: count(&bencodable, "complete"),
+                    incomplete: count(&bencodable, "incomplete"),
+                    warning_message: bencodable
+                        .get("warning message")
+                        .and_then(bencode::Bencodable::as_str)
+                        .ok()
+                        .map(str::to_string),
+                    external_ip: external_ip(&bencodable),
+                    redirected_to,
+                };
+                Ok((peers, peers6.unwrap_or_default(), response))
             })
-            .and_then(|peers| match peers {
-                // A bytestring is one way to communicate a compact representation of peers
-                bencode::Bencodable::ByteString(bs) => Result::from(&bs),
-
-                // alternatively, get a bencodable that is more structured as a List of Dictionaries containing keys IP, peer id, and port with values
-                bencode::Bencodable::List(ld) => Result::from(BencodableList { list: &ld }),
-                _ => Err(TrackerResponseError::NoPeerByteString {
-                    original_string: peers,
-                }),
+            .and_then(|(peers, peers6, response)| {
+                match peers {
+                    // A bytestring is one way to communicate a compact representation of peers
+                    bencode::Bencodable::ByteString(bs) => Result::from(&bs),
+
+                    // alternatively, get a bencodable that is more structured as a List of Dictionaries containing keys IP, peer id, and port with values
+                    bencode::Bencodable::List(ld) => Result::from(BencodableList { list: &ld }),
+                    _ => Err(TrackerResponseError::NoPeerByteString {
+                        original_string: peers,
+                    }),
+                }
+                .map(|mut peers| {
+                    peers.extend(peers6);
+                    TrackerResponse { peers, ..response }
+                })
             })
     }
 }
 
+// BEP 3 announces, a GET with the request in the query string
+pub struct HttpTracker {
+    url: String,
+    tracker: Tracker,
+}
+
+impl Announce for HttpTracker {
+    fn announce(&self, request: &TrackerRequest) -> Result<TrackerResponse, TrackerResponseError> {
+        self.tracker.track(&self.url, request)
+    }
+}
+
+// BEP 15 announces: a connect for a connection id, then the announce itself, each a single
+// datagram tried a couple of times. The tracker's peers are the same family as its address.
+pub struct UdpTracker {
+    url: String,
+    tracker: Tracker,
+}
+
+impl Announce for UdpTracker {
+    fn announce(&self, request: &TrackerRequest) -> Result<TrackerResponse, TrackerResponseError> {
+        let addr = self.tracker.udp_addr(&self.url)?;
+        self.tracker.udp.announce(addr, request)
+    }
+}
+
+// The UDP side of every tracker a session announces to: one socket for each address family, shared
+// by all of them, with answers matched to requests by transaction id, and each tracker's
+// connection id kept for as long as BEP 15 says it's good. Announcing many torrents to one tracker
+// then costs one connect a minute rather than one per announce.
+#[derive(Clone, Default)]
+pub struct UdpClient {
+    inner: Arc<UdpClientInner>,
+}
+
+// the tracker a transaction's answer has to come from, and who's waiting for it
+type PendingUdpAnswer = (SocketAddr, Sender<Vec<u8>>);
+
+#[derive(Default)]
+struct UdpClientInner {
+    // IPv4 and IPv6, each bound when first needed
+    sockets: Mutex<[Option<Arc<UdpSocket>>; 2]>,
+    pending: Mutex<HashMap<u32, PendingUdpAnswer>>,
+    connection_ids: Mutex<HashMap<SocketAddr, (u64, Instant)>>,
+}
+
+impl UdpClient {
+    pub fn announce(
+        &self,
+        addr: SocketAddr,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse, TrackerResponseError> {
+        let connection_id = self.connection_id(addr)?;
+        let transaction_id = self.transaction_id();
+        let response = self.exchange(
+            addr,
+            &request.udp_announce(connection_id, transaction_id),
+            UDP_ACTION_ANNOUNCE,
+            transaction_id,
+            20,
+        );
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                // most likely the connection id expired early; the next announce connects again
+                self.inner.connection_ids.lock().remove(&addr);
+                return Err(e);
+            }
+        };
+        let word = |at: usize| u32::from_be_bytes(response[at..at + 4].try_into().unwrap());
+        let peers = match addr {
+            SocketAddr::V4(_) => compact_peers(&response[20..])?,
+            SocketAddr::V6(_) => compact_peers6(&response[20..])?,
+        };
+        Ok(TrackerResponse {
+            peers,
+            intervals: AnnounceIntervals {
+                interval: Some(Duration::from_secs(word(8) as u64)),
+                min_interval: None,
+            },
+            incomplete: Some(word(12)),
+            complete: Some(word(16)),
+            warning_message: None,
+            external_ip: None,
+            redirected_to: None,
+        })
+    }
+
+    // The tracker's connection id, connecting for a new one once the last has run out
+    fn connection_id(&self, addr: SocketAddr) -> Result<u64, TrackerResponseError> {
+        let now = Instant::now();
+        if let Some((id, issued)) = self.inner.connection_ids.lock().get(&addr) {
+            if now.duration_since(*issued) < UDP_CONNECTION_LIFETIME {
+                return Ok(*id);
+            }
+        }
+        let transaction_id = self.transaction_id();
+        let mut connect = UDP_PROTOCOL_ID.to_be_bytes().to_vec();
+        connect.extend_from_slice(&UDP_ACTION_CONNECT.to_be_bytes());
+        connect.extend_from_slice(&transaction_id.to_be_bytes());
+        let response = self.exchange(addr, &connect, UDP_ACTION_CONNECT, transaction_id, 16)?;
+        let id = u64::from_be_bytes(response[8..16].try_into().unwrap());
+        self.inner.connection_ids.lock().insert(addr, (id, now));
+        Ok(id)
+    }
+
+    fn transaction_id(&self) -> u32 {
+        let pending = self.inner.pending.lock();
+        loop {
+            let id = rand::random();
+            if !pending.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    // The socket for `addr`'s family, with a thread handing what arrives on it to whoever is
+    // waiting for that transaction. The thread ends once the client is dropped.
+    fn socket(&self, addr: SocketAddr) -> Result<Arc<UdpSocket>, TrackerResponseError> {
+        let family = addr.is_ipv6() as usize;
+        let mut sockets = self.inner.sockets.lock();
+        if let Some(socket) = &sockets[family] {
+            return Ok(Arc::clone(socket));
+        }
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = Arc::new(UdpSocket::bind(local).map_err(TrackerResponseError::Io)?);
+        socket
+            .set_read_timeout(Some(UDP_RECEIVE_POLL))
+            .map_err(TrackerResponseError::Io)?;
+        let receiving = Arc::clone(&socket);
+        let client = Arc::downgrade(&self.inner);
+        spawn(move || {
+            let mut buf = [0u8; 2048];
+            loop {
+                let received = receiving.recv_from(&mut buf);
+                let Some(client) = client.upgrade() else {
+                    break;
+                };
+                let Ok((n, from)) = received else {
+                    continue;
+                };
+                if n < 8 {
+                    continue;
+                }
+                let transaction_id = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+                let mut pending = client.pending.lock();
+                if matches!(pending.get(&transaction_id), Some((tracker, _)) if *tracker == from) {
+                    if let Some((_, waiting)) = pending.remove(&transaction_id) {
+                        let _ = waiting.send(buf[..n].to_vec());
+                    }
+                }
+            }
+        });
+        sockets[family] = Some(Arc::clone(&socket));
+        Ok(socket)
+    }
+
+    // Sends `packet` until an answer to it of at least `min_length` bytes comes back, or the
+    // attempts run out. An error answer is the tracker's failure reason.
+    fn exchange(
+        &self,
+        addr: SocketAddr,
+        packet: &[u8],
+        action: u32,
+        transaction_id: u32,
+        min_length: usize,
+    ) -> Result<Vec<u8>, TrackerResponseError> {
+        let socket = self.socket(addr)?;
+        let (sender, receiver) = channel();
+        self.inner
+            .pending
+            .lock()
+            .insert(transaction_id, (addr, sender));
+        let mut response = None;
+        for _ in 0..UDP_ATTEMPTS {
+            if let Err(e) = socket.send_to(packet, addr) {
+                self.inner.pending.lock().remove(&transaction_id);
+                return Err(TrackerResponseError::Io(e));
+            }
+            if let Ok(answer) = receiver.recv_timeout(UDP_TIMEOUT) {
+                response = Some(answer);
+                break;
+            }
+        }
+        self.inner.pending.lock().remove(&transaction_id);
+        let response = response.ok_or_else(|| {
+            TrackerResponseError::Io(std::io::Error::from(std::io::ErrorKind::TimedOut))
+        })?;
+        let answered = u32::from_be_bytes(response[..4].try_into().unwrap());
+        if answered == UDP_ACTION_ERROR {
+            return Err(TrackerResponseError::Failure(
+                String::from_utf8_lossy(&response[8..]).into_owned(),
+            ));
+        }
+        if answered != action || response.len() < min_length {
+            return Err(TrackerResponseError::BadUdpResponse);
+        }
+        Ok(response)
+    }
+}
+
+// Redirects are followed by hand so the new announce URL can be reported back
+fn client_builder() -> reqwest::blocking::ClientBuilder {
+    reqwest::blocking::Client::builder().redirect(reqwest::redirect::Policy::none())
+}
+
+// The new announce URL. Relative locations are resolved against the announce URL that was
+// redirected. Any query string is dropped, as it's usually ours echoed back and goes on again with
+// the next request.
+fn redirect_location(from: &str, response: &Response) -> Result<String, TrackerResponseError> {
+    let location = response
+        .headers()
+        .get(reqwest::header::LOCATION)
+        .and_then(|l| l.to_str().ok())
+        .ok_or(TrackerResponseError::BadRedirect)?;
+    let from = reqwest::Url::parse(from).map_err(|_| TrackerResponseError::BadRedirect)?;
+    let mut url = from
+        .join(location)
+        .map_err(|_| TrackerResponseError::BadRedirect)?;
+    url.set_query(None);
+    Ok(url.to_string())
+}
+
+// Some trackers gzip responses without saying so in the headers, so the magic bytes count too
+fn decompress(bytes: &[u8], gzipped: bool) -> Result<Vec<u8>, TrackerResponseError> {
+    if gzipped || bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut decoded = vec![];
+        flate2::read::GzDecoder::new(bytes)
+            .read_to_end(&mut decoded)
+            .map_err(TrackerResponseError::Decompress)?;
+        Ok(decoded)
+    } else {
+        Ok(bytes.to_vec())
+    }
+}
+
+// The top level `failure reason` of a response, found without decoding the rest of it: an error
+// page can be any size and is often not bencoded at all
+fn failure_reason(body: &[u8]) -> Option<String> {
+    let mut events = bencode::EventParser::new(body);
+    if events.next()?.ok()? != bencode::BencodeEvent::DictStart {
+        return None;
+    }
+    while let bencode::BencodeEvent::Key(key) = events.next()?.ok()? {
+        let value = events.next()?.ok()?;
+        match value {
+            bencode::BencodeEvent::Bytes(reason) if key == b"failure reason" => {
+                return std::str::from_utf8(reason).ok().map(str::to_string)
+            }
+            _ => events.skip_value(value).ok()?,
+        }
+    }
+    None
+}
+
+// Four or sixteen raw bytes; anything else isn't an address
+fn external_ip(bencodable: &bencode::Bencodable) -> Option<IpAddr> {
+    let bytes = bencodable
+        .get("external ip")
+        .and_then(bencode::Bencodable::as_bytes)
+        .ok()?;
+    match bytes.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => None,
+    }
+}
+
+fn count(bencodable: &bencode::Bencodable, key: &str) -> Option<u32> {
+    let count = bencodable
+        .get(key)
+        .and_then(bencode::Bencodable::as_int)
+        .ok()?;
+    u32::try_from(count).ok()
+}
+
+fn seconds(bencodable: &bencode::Bencodable, key: &str) -> Option<Duration> {
+    let secs = bencodable
+        .get(key)
+        .and_then(bencode::Bencodable::as_int)
+        .ok()?;
+    u64::try_from(secs).ok().map(Duration::from_secs)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+
+    // Answers one request per canned response, in order, and returns the address to announce to
+    fn serve(responses: Vec<Vec<u8>>) -> String {
+        serve_and_record(responses).0
+    }
+
+    // `serve`, also handing back the request line of every request answered
+    fn serve_and_record(responses: Vec<Vec<u8>>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = channel();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let _ = sender.send(request.lines().next().unwrap_or("").to_string());
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (format!("http://{}", addr), receiver)
+    }
+
+    fn http(status: &str, headers: &[&str], body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n", status, body.len());
+        for header in headers {
+            response.push_str(header);
+            response.push_str("\r\n");
+        }
+        response.push_str("Connection: close\r\n\r\n");
+        let mut response = response.into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    fn announce_request() -> TrackerRequest {
+        TrackerRequest::new([7u8; 20], b"-BT0001-localpeer000", 8999)
+            .event(Event::Started)
+            .compact(true)
+            .no_peer_id(true)
+            .corrupt(32768)
+            .redundant(0)
+    }
+
+    const PEERS_BODY: &[u8] = b"d8:intervali900e5:peers6:\x49\x8c\xcd\x54\x23\x27e";
+
+    #[test]
+    fn it_renders_requests_for_either_transport() {
+        let request = TrackerRequest::new([0xab; 20], b"-BT0001-localpeer000", 6881)
+            .left(1000)
+            .uploaded(5)
+            .numwant(30)
+            .key(0xbeef)
+            .compact(true);
+        assert_eq!(
+            request.url("http://tracker.example/announce?passkey=x"),
+            format!(
+                "http://tracker.example/announce?passkey=x&info_hash={}&peer_id=%2DBT0001%2Dlocalpeer000&port=6881&uploaded=5&downloaded=0&left=1000&compact=1&numwant=30&key=0000beef",
+                "%AB".repeat(20)
+            )
+        );
+        assert!(request
+            .clone()
+            .event(Event::Completed)
+            .query_string()
+            .contains("&event=completed"));
+        let query = request.query_string();
+        for announce_url in [
+            "http://tracker.example/announce",
+            "http://tracker.example/announce?",
+            "http://tracker.example/announce#top",
+        ] {
+            assert_eq!(
+                request.url(announce_url),
+                format!("http://tracker.example/announce?{}", query)
+            );
+        }
+        assert_eq!(
+            request.url("http://tracker.example/a?passkey=x&"),
+            format!("http://tracker.example/a?passkey=x&{}", query)
+        );
+        assert!(request
+            .clone()
+            .ipv6("2001:db8::1".parse().unwrap())
+            .query_string()
+            .contains("&compact=1&ipv6=2001%3Adb8%3A%3A1&numwant=30"));
+
+        let packet = request.event(Event::Stopped).udp_announce(0x41727101980, 9);
+        assert_eq!(packet.len(), 98);
+        assert_eq!(&packet[8..16], &[0, 0, 0, 1, 0, 0, 0, 9]);
+        assert_eq!(&packet[16..36], &[0xab; 20]);
+        assert_eq!(&packet[36..56], b"-BT0001-localpeer000");
+        // downloaded, left, uploaded
+        assert_eq!(u64::from_be_bytes(packet[64..72].try_into().unwrap()), 1000);
+        assert_eq!(u64::from_be_bytes(packet[72..80].try_into().unwrap()), 5);
+        assert_eq!(&packet[80..84], &[0, 0, 0, 3]);
+        assert_eq!(
+            &packet[88..98],
+            &[0, 0, 0xbe, 0xef, 0, 0, 0, 30, 0x1a, 0xe1]
+        );
+    }
+
+    #[test]
+    fn it_follows_and_reports_redirects() {
+        let new_base = serve(vec![http("200 OK", &[], PEERS_BODY)]);
+        let old_base = serve(vec![http(
+            "301 Moved Permanently",
+            &[&format!("Location: {}/announce", new_base)],
+            b"",
+        )]);
+
+        let outcome = Tracker::new()
+            .track(
+                &format!("{}/announce?passkey=abc", old_base),
+                &announce_request(),
+            )
+            .unwrap();
+        assert_eq!(outcome.peers.len(), 1);
+        assert_eq!(
+            outcome.redirected_to,
+            Some(format!("{}/announce", new_base))
+        );
+    }
+
+    #[test]
+    fn it_only_contacts_allowed_hosts() {
+        let base = serve(vec![http(
+            "302 Found",
+            &[&format!(
+                "Location: {}/announce",
+                serve(vec![]).replace("127.0.0.1", "localhost")
+            )],
+            b"",
+        )]);
+        let announce_url = format!("{}/announce", base);
+
+        assert!(matches!(
+            Tracker::new()
+                .allowing_only(Some(vec!["tracker.example".to_string()]))
+                .track(&announce_url, &announce_request()),
+            Err(TrackerResponseError::HostNotAllowed(host)) if host == "127.0.0.1"
+        ));
+        assert!(matches!(
+            Tracker::new()
+                .allowing_only(Some(vec!["127.0.0.1".to_string()]))
+                .track(&announce_url, &announce_request()),
+            Err(TrackerResponseError::HostNotAllowed(host)) if host == "localhost"
+        ));
+    }
+
+    #[test]
+    fn it_resolves_tracker_hosts_with_the_sessions_resolver() {
+        struct Localhost;
+        impl crate::dns::Resolve for Localhost {
+            fn resolve(&self, _host: &str) -> Result<crate::dns::Resolved, DnsError> {
+                Ok(crate::dns::Resolved {
+                    addrs: vec!["127.0.0.1".parse().unwrap()],
+                    ttl: None,
+                })
+            }
+        }
+        let base = serve(vec![http("200 OK", &[], PEERS_BODY)]);
+        let announce_url = format!("{}/announce", base.replace("127.0.0.1", "tracker.test"));
+        let dns = DnsResolver::new(std::sync::Arc::new(Localhost));
+
+        let outcome = Tracker::new()
+            .resolving_with(dns.clone(), IpPreference::Ipv6First)
+            .track(&announce_url, &announce_request())
+            .unwrap();
+        assert_eq!(outcome.peers.len(), 1);
+        assert!(matches!(
+            Tracker::new()
+                .resolving_with(dns, IpPreference::Ipv6Only)
+                .track("http://other.test:1/announce", &announce_request()),
+            Err(TrackerResponseError::Dns(DnsError::NoAddresses(host))) if host == "other.test"
+        ));
+    }
+
+    #[test]
+    fn it_only_sends_the_optional_parameters_a_tracker_takes() {
+        let (base, requests) = serve_and_record(vec![
+            http("200 OK", &[], PEERS_BODY),
+            http("200 OK", &[], PEERS_BODY),
+        ]);
+        let tracker = Tracker::new();
+
+        tracker
+            .track(&format!("{}/announce", base), &announce_request())
+            .unwrap();
+        let request = requests.recv().unwrap();
+        assert!(
+            request.contains("&event=started&compact=1&no_peer_id=1&corrupt=32768&redundant=0 "),
+            "{}",
+            request
+        );
+
+        // and a tracker that only knows dictionary peer lists
+        let only_corrupt = OptionalParameters {
+            corrupt: true,
+            redundant: false,
+            compact: false,
+        };
+        tracker
+            .track(
+                &format!("{}/announce", base),
+                &announce_request().for_tracker(only_corrupt),
+            )
+            .unwrap();
+        let request = requests.recv().unwrap();
+        assert!(
+            request.contains("&event=started&corrupt=32768 "),
+            "{}",
+            request
+        );
+        assert!(!request.contains("redundant"), "{}", request);
+        assert!(!request.contains("compact"), "{}", request);
+    }
+
+    #[test]
+    fn it_decodes_gzipped_responses() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(PEERS_BODY).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let base = serve(vec![http("200 OK", &["Content-Encoding: gzip"], &gzipped)]);
+
+        let outcome = Tracker::new()
+            .track(&format!("{}/announce", base), &announce_request())
+            .unwrap();
+        assert_eq!(outcome.peers.len(), 1);
+        assert_eq!(outcome.intervals.interval, Some(Duration::from_secs(900)));
+        assert_eq!(outcome.redirected_to, None);
+    }
+
+    #[test]
+    fn it_keeps_the_swarm_counts_and_warnings_trackers_send() {
+        let base = serve(vec![http(
+            "200 OK",
+            &[],
+            b"d8:completei12e11:external ip4:\xcb\x00\x71\x0710:incompletei3e8:intervali900e12:min intervali60e5:peers0:15:warning message11:slow down!!e",
+        )]);
+        let response = Tracker::new()
+            .track(&format!("{}/announce", base), &announce_request())
+            .unwrap();
+        assert_eq!(
+            (response.complete, response.incomplete),
+            (Some(12), Some(3))
+        );
+        assert_eq!(response.warning_message.as_deref(), Some("slow down!!"));
+        assert_eq!(response.external_ip, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(
+            response.intervals.min_interval,
+            Some(Duration::from_secs(60))
+        );
+
+        let mut status = TrackerStatus::new("http://tracker.example/announce");
+        assert!(!status.is_working());
+        status.record_failure(&TrackerResponseError::HttpStatus(502));
+        status.record_failure(&TrackerResponseError::Failure("overloaded".to_string()));
+        assert_eq!(status.consecutive_failures, 2);
+        assert_eq!(status.last_error.as_deref(), Some("overloaded"));
+        assert_eq!(status.last_announce, None);
+
+        let now = Instant::now();
+        status.record_response(now, &response);
+        assert!(status.is_working());
+        assert_eq!((status.complete, status.incomplete), (Some(12), Some(3)));
+        assert_eq!(
+            (status.consecutive_failures, &status.last_error),
+            (0, &None)
+        );
+        assert_eq!(status.peers_returned, 0);
+        assert_eq!(
+            status.next_allowed_announce(),
+            Some(now + Duration::from_secs(60))
+        );
+        assert_eq!(status.next_announce(), Some(now + Duration::from_secs(900)));
+    }
+
+    #[test]
+    fn it_reports_failure_reasons_whatever_the_status() {
+        let base = serve(vec![
+            http(
+                "403 Forbidden",
+                &[],
+                b"d14:failure reason17:torrent not founde",
+            ),
+            http("500 Internal Server Error", &[], b"oops"),
+            http(
+                "200 OK",
+                &[],
+                b"d14:failure reason16:unregistered key8:intervali1800ee",
+            ),
+        ]);
+        let tracker = Tracker::new();
+
+        assert!(matches!(
+            tracker.track(&format!("{}/announce", base), &announce_request()),
+            Err(TrackerResponseError::Failure(reason)) if reason == "torrent not found"
+        ));
+        assert!(matches!(
+            tracker.track(&format!("{}/announce", base), &announce_request()),
+            Err(TrackerResponseError::HttpStatus(500))
+        ));
+        assert!(matches!(
+            tracker.track(&format!("{}/announce", base), &announce_request()),
+            Err(TrackerResponseError::Failure(reason)) if reason == "unregistered key"
+        ));
+    }
+
+    #[test]
+    fn it_takes_dictionary_peers_without_peer_ids() {
+        let body = b"d8:intervali900e5:peersld2:ip13:73.140.205.847:peer id20:-TR2940-k8hj0wgej6ch4:porti8999eed2:ip8:10.0.0.14:porti6881eeee";
+        let base = serve(vec![http("200 OK", &[], body)]);
+
+        let outcome = Tracker::new()
+            .track(&format!("{}/announce", base), &announce_request())
+            .unwrap();
+        assert_eq!(
+            outcome.peers,
+            vec![
+                TrackerPeer::Peer(Peer {
+                    socket_addr: "73.140.205.84:8999".parse().unwrap(),
+                    id: b"-TR2940-k8hj0wgej6ch".to_vec(),
+                }),
+                TrackerPeer::SocketAddr("10.0.0.1:6881".parse().unwrap())
+            ]
+        );
+    }
+
+    #[test]
+    fn it_takes_ipv6_peers_from_peers6() {
+        let mut peers6 = vec![0x20, 0x01, 0x0d, 0xb8];
+        peers6.extend([0; 11]);
+        peers6.extend([1, 0x1a, 0xe1]);
+        let body = |peers: &[u8]| {
+            let mut body = b"d8:intervali900e".to_vec();
+            body.extend_from_slice(peers);
+            body.extend_from_slice(format!("6:peers6{}:", peers6.len()).as_bytes());
+            body.extend_from_slice(&peers6);
+            body.push(b'e');
+            body
+        };
+        let base = serve(vec![
+            http("200 OK", &[], &body(b"5:peers6:\x49\x8c\xcd\x54\x23\x27")),
+            // an IPv6 only swarm
+            http("200 OK", &[], &body(b"")),
+        ]);
+        let tracker = Tracker::new();
+
+        let outcome = tracker
+            .track(&format!("{}/announce", base), &announce_request())
+            .unwrap();
+        assert_eq!(
+            outcome.peers,
+            vec![
+                TrackerPeer::SocketAddr("73.140.205.84:8999".parse().unwrap()),
+                TrackerPeer::SocketAddr("[2001:db8::1]:6881".parse().unwrap())
+            ]
+        );
+        let outcome = tracker
+            .track(&format!("{}/announce", base), &announce_request())
+            .unwrap();
+        assert_eq!(
+            outcome.peers,
+            vec![TrackerPeer::SocketAddr(
+                "[2001:db8::1]:6881".parse().unwrap()
+            )]
+        );
+
+        assert!(matches!(
+            compact_peers6(&[0; 17]),
+            Err(TrackerResponseError::MisalignedPeers)
+        ));
+        assert!(is_global_ipv6(&"2001:db8::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&"fe80::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&"fd00::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&Ipv6Addr::LOCALHOST));
+    }
 
     #[test]
     fn it_correctly_converts_bytes_to_ip_addrs() {
         let example: &[u8] = &[
-            0x49 as u8, 0x8C as u8, 0xCD as u8, 0x54 as u8, 0x23 as u8, 0x27 as u8, 0x49 as u8,
-            0x8C as u8, 0xCD as u8, 0x54 as u8, 0x23 as u8, 0x27 as u8,
+            0x49 as u8, 0x8C as u8, 0xCD as u8, 0x54 as u8, 0x23 as u8, 0x27 as u8, 0x0A as u8,
+            0x00 as u8, 0x00 as u8, 0x02 as u8, 0x1A as u8, 0xE1 as u8,
         ];
 
         let actual = Result::from(&bencode::BencodableByteString::from(example)).unwrap();
@@ -216,13 +1558,93 @@ mod tests {
                     .parse::<std::net::SocketAddr>()
                     .unwrap(),
             ),
-            TrackerPeer::SocketAddr(
-                "73.140.205.84:8999"
-                    .parse::<std::net::SocketAddr>()
-                    .unwrap(),
-            ),
+            TrackerPeer::SocketAddr("10.0.0.2:6881".parse::<std::net::SocketAddr>().unwrap()),
         ];
 
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn it_refuses_announces_inside_the_min_interval() {
+        let mut status = TrackerStatus::new("http://tracker.example/announce");
+        let start = Instant::now();
+        assert!(status.check_announce(start, false).is_ok());
+
+        status.record_announce(
+            start,
+            AnnounceIntervals {
+                interval: Some(Duration::from_secs(1800)),
+                min_interval: Some(Duration::from_secs(300)),
+            },
+        );
+        assert_eq!(
+            status.next_allowed_announce(),
+            Some(start + Duration::from_secs(300))
+        );
+        assert!(matches!(
+            status.check_announce(start + Duration::from_secs(299), false),
+            Err(TrackerResponseError::MinIntervalNotElapsed { .. })
+        ));
+        assert!(status
+            .check_announce(start + Duration::from_secs(299), true)
+            .is_ok());
+        assert!(status
+            .check_announce(start + Duration::from_secs(300), false)
+            .is_ok());
+    }
+
+    #[test]
+    fn it_orders_trackers_tier_by_tier_and_promotes_the_one_that_answered() {
+        let tiers = vec![
+            vec!["http://a1".to_string(), "http://a2".to_string()],
+            vec![
+                "http://b1".to_string(),
+                "http://b2".to_string(),
+                "http://b3".to_string(),
+            ],
+        ];
+        let mut trackers = TrackerStatus::tiered(&tiers);
+        assert_eq!(
+            trackers.iter().map(|t| t.tier).collect::<Vec<_>>(),
+            vec![0, 0, 1, 1, 1]
+        );
+        let mut first_tier: Vec<&str> = trackers[..2].iter().map(|t| t.url.as_str()).collect();
+        first_tier.sort();
+        assert_eq!(first_tier, vec!["http://a1", "http://a2"]);
+
+        let answered = trackers[4].url.clone();
+        let others: Vec<String> = trackers[2..4].iter().map(|t| t.url.clone()).collect();
+        let before: Vec<String> = trackers[..2].iter().map(|t| t.url.clone()).collect();
+        promote(&mut trackers, &answered);
+        let urls: Vec<String> = trackers.iter().map(|t| t.url.clone()).collect();
+        assert_eq!(urls[..2], before[..]);
+        assert_eq!(urls[2], answered);
+        assert_eq!(urls[3..], others[..]);
+        assert_eq!(trackers[2].tier, 1);
+
+        // already first, or not known at all: nothing moves
+        promote(&mut trackers, &answered);
+        promote(&mut trackers, "http://unknown");
+        assert_eq!(
+            trackers.iter().map(|t| t.url.clone()).collect::<Vec<_>>(),
+            urls
+        );
+    }
+
+    #[test]
+    fn it_falls_back_to_the_regular_interval() {
+        let mut status = TrackerStatus::new("http://tracker.example/announce");
+        let start = Instant::now();
+        status.record_announce(
+            start,
+            AnnounceIntervals {
+                interval: Some(Duration::from_secs(60)),
+                min_interval: None,
+            },
+        );
+        assert_eq!(
+            status.next_allowed_announce(),
+            Some(start + Duration::from_secs(60))
+        );
+    }
 }
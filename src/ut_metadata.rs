@@ -0,0 +1,436 @@
+use crate::bencode::{bdecode, bdecode_prefix, bencode, Bencodable, DictBuilder};
+use crate::connection::{PeerConnection, SendError};
+use crate::messages::{Message, MessageParseError};
+use sha1::{Digest, Sha1};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// BEP 9: the info dictionary is handed out in 16KiB pieces over the extension protocol
+pub const METADATA_PIECE_SIZE: usize = 16384;
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+// the id we ask peers to use when sending us ut_metadata messages
+pub const UT_METADATA_ID: u8 = 1;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+// Far beyond any real info dictionary, which keeps a peer from having us allocate whatever it likes
+pub const MAX_METADATA_SIZE: u32 = 8 * 1024 * 1024;
+
+// The parts of a BEP 10 extended handshake we care about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    pub ut_metadata: Option<u8>,
+    pub metadata_size: Option<u32>,
+}
+
+impl ExtendedHandshake {
+    pub fn to_payload(&self) -> Vec<u8> {
+        let m = DictBuilder::new()
+            .insert_some("ut_metadata", self.ut_metadata.map(u32::from))
+            .build();
+        let handshake = DictBuilder::new()
+            .insert("m", m)
+            .insert_some("metadata_size", self.metadata_size)
+            .build();
+        bencode(&handshake).unwrap()
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        let handshake = bdecode(payload).ok()?;
+        handshake.as_dict().ok()?;
+        // a zero id means the peer disabled the extension
+        let ut_metadata = match handshake.query("m.ut_metadata") {
+            Some(Bencodable::Integer(id)) if *id > 0 => u8::try_from(*id).ok(),
+            _ => None,
+        };
+        let metadata_size = match handshake.query("metadata_size") {
+            Some(Bencodable::Integer(size)) => u32::try_from(*size).ok(),
+            _ => None,
+        };
+        Some(ExtendedHandshake {
+            ut_metadata,
+            metadata_size,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request {
+        piece: u32,
+    },
+    Data {
+        piece: u32,
+        total_size: u32,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: u32,
+    },
+}
+
+impl MetadataMessage {
+    pub fn serialize(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request { piece } => (0_i64, piece),
+            MetadataMessage::Data { piece, .. } => (1, piece),
+            MetadataMessage::Reject { piece } => (2, piece),
+        };
+        let total_size = match self {
+            MetadataMessage::Data { total_size, .. } => Some(*total_size),
+            _ => None,
+        };
+        let dictionary = DictBuilder::new()
+            .insert("msg_type", msg_type)
+            .insert("piece", *piece)
+            .insert_some("total_size", total_size)
+            .build();
+        let mut bytes = bencode(&dictionary).unwrap();
+        // data messages carry the piece itself straight after the dictionary
+        if let MetadataMessage::Data { data, .. } = self {
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    // Data messages carry the piece straight after the dictionary; only they may have anything
+    // trailing it
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let (dictionary, consumed) = bdecode_prefix(payload).ok()?;
+        let dictionary = match dictionary {
+            Bencodable::Dictionary(dictionary) => dictionary,
+            _ => return None,
+        };
+        let integer = |key: &str| match dictionary.get(key.as_bytes()) {
+            Some(Bencodable::Integer(i)) => u32::try_from(*i).ok(),
+            _ => None,
+        };
+        let piece = integer("piece")?;
+        let msg_type = integer("msg_type")?;
+        if msg_type != 1 && consumed != payload.len() {
+            return None;
+        }
+        match msg_type {
+            0 => Some(MetadataMessage::Request { piece }),
+            1 => Some(MetadataMessage::Data {
+                piece,
+                total_size: integer("total_size")?,
+                data: payload[consumed..].to_vec(),
+            }),
+            2 => Some(MetadataMessage::Reject { piece }),
+            _ => None,
+        }
+    }
+}
+
+// Answers one peer's metadata requests from the bencoded info dictionary. Each peer may fetch
+// the whole thing twice a minute; anything beyond that is rejected so a misbehaving magnet client
+// can't turn us into a metadata mirror.
+#[derive(Debug)]
+pub struct MetadataServer {
+    info_dictionary: Arc<Vec<u8>>,
+    served: VecDeque<Instant>,
+}
+
+impl MetadataServer {
+    pub fn new(info_dictionary: Arc<Vec<u8>>) -> Self {
+        MetadataServer {
+            info_dictionary,
+            served: VecDeque::new(),
+        }
+    }
+
+    pub fn metadata_size(&self) -> u32 {
+        self.info_dictionary.len() as u32
+    }
+
+    fn number_of_pieces(&self) -> usize {
+        self.info_dictionary.len().div_ceil(METADATA_PIECE_SIZE)
+    }
+
+    pub fn respond(&mut self, now: Instant, piece: u32) -> MetadataMessage {
+        while matches!(self.served.front(), Some(t) if now.duration_since(*t) >= RATE_LIMIT_WINDOW)
+        {
+            self.served.pop_front();
+        }
+        let start = piece as usize * METADATA_PIECE_SIZE;
+        if start >= self.info_dictionary.len() || self.served.len() >= 2 * self.number_of_pieces() {
+            return MetadataMessage::Reject { piece };
+        }
+        self.served.push_back(now);
+        let end = (start + METADATA_PIECE_SIZE).min(self.info_dictionary.len());
+        MetadataMessage::Data {
+            piece,
+            total_size: self.metadata_size(),
+            data: self.info_dictionary[start..end].to_vec(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MetadataError {
+    // the peer never sent an extended handshake offering ut_metadata and the metadata's size
+    Unsupported,
+    TooLarge(u32),
+    // a piece of the wrong length, out of range, or from a peer that changed its mind on the size
+    BadPiece(u32),
+    Rejected(u32),
+    // every piece arrived but together they aren't the info dictionary asked for
+    HashMismatch,
+    TimedOut,
+    Read(MessageParseError),
+    Write(SendError),
+}
+
+// Puts an info dictionary back together from pieces fetched from peers, checking the result
+// against the info hash before handing it out
+#[derive(Debug)]
+pub struct MetadataAssembler {
+    info_hash: [u8; 20],
+    total_size: u32,
+    pieces: Vec<Option<Vec<u8>>>,
+}
+
+impl MetadataAssembler {
+    pub fn new(info_hash: [u8; 20], total_size: u32) -> Result<Self, MetadataError> {
+        if total_size == 0 || total_size > MAX_METADATA_SIZE {
+            return Err(MetadataError::TooLarge(total_size));
+        }
+        let number_of_pieces = (total_size as usize).div_ceil(METADATA_PIECE_SIZE);
+        Ok(MetadataAssembler {
+            info_hash,
+            total_size,
+            pieces: vec![None; number_of_pieces],
+        })
+    }
+
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.pieces.len() as u32)
+            .filter(|piece| self.pieces[*piece as usize].is_none())
+            .collect()
+    }
+
+    // The info dictionary once the last piece is in. If it doesn't hash to the info hash every
+    // piece is thrown away, since there's no telling which was wrong.
+    pub fn receive(
+        &mut self,
+        piece: u32,
+        total_size: u32,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, MetadataError> {
+        let start = piece as usize * METADATA_PIECE_SIZE;
+        let expected_length =
+            METADATA_PIECE_SIZE.min((self.total_size as usize).saturating_sub(start));
+        if total_size != self.total_size
+            || piece as usize >= self.pieces.len()
+            || data.len() != expected_length
+        {
+            return Err(MetadataError::BadPiece(piece));
+        }
+        self.pieces[piece as usize] = Some(data);
+        if self.pieces.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+        let metadata: Vec<u8> = self
+            .pieces
+            .iter_mut()
+            .flat_map(|p| p.take().unwrap())
+            .collect();
+        let hash = <[u8; 20]>::from(Sha1::digest(&metadata));
+        match hash == self.info_hash {
+            true => Ok(Some(metadata)),
+            false => Err(MetadataError::HashMismatch),
+        }
+    }
+}
+
+// Fetches the info dictionary from a peer we've just finished the handshake with: extended
+// handshakes first, to learn the peer's ut_metadata id and the size, then every piece, asked for
+// all at once. Anything else the peer sends meanwhile is ignored.
+pub fn fetch_metadata(
+    connection: &mut PeerConnection,
+    info_hash: [u8; 20],
+    timeout: Duration,
+) -> Result<Vec<u8>, MetadataError> {
+    let deadline = Instant::now() + timeout;
+    let ours = ExtendedHandshake {
+        ut_metadata: Some(UT_METADATA_ID),
+        metadata_size: None,
+    };
+    connection
+        .write_message(Message::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: ours.to_payload(),
+        })
+        .map_err(MetadataError::Write)?;
+
+    let mut assembler: Option<MetadataAssembler> = None;
+    while Instant::now() < deadline {
+        let payload = match connection.read_message() {
+            Ok(Message::Extended { id, payload }) => (id, payload),
+            Ok(_) | Err(MessageParseError::WouldBlock) | Err(MessageParseError::TimedOut) => {
+                continue
+            }
+            Err(e) => return Err(MetadataError::Read(e)),
+        };
+        match (payload.0, &mut assembler) {
+            (EXTENDED_HANDSHAKE_ID, None) => {
+                let theirs = ExtendedHandshake::from_payload(&payload.1);
+                let (id, size) = match theirs {
+                    Some(ExtendedHandshake {
+                        ut_metadata: Some(id),
+                        metadata_size: Some(size),
+                    }) => (id, size),
+                    _ => return Err(MetadataError::Unsupported),
+                };
+                let fetching = MetadataAssembler::new(info_hash, size)?;
+                for piece in fetching.missing() {
+                    connection
+                        .write_message(Message::Extended {
+                            id,
+                            payload: MetadataMessage::Request { piece }.serialize(),
+                        })
+                        .map_err(MetadataError::Write)?;
+                }
+                assembler = Some(fetching);
+            }
+            (UT_METADATA_ID, Some(fetching)) => match MetadataMessage::parse(&payload.1) {
+                Some(MetadataMessage::Data {
+                    piece,
+                    total_size,
+                    data,
+                }) => {
+                    if let Some(metadata) = fetching.receive(piece, total_size, data)? {
+                        return Ok(metadata);
+                    }
+                }
+                Some(MetadataMessage::Reject { piece }) => {
+                    return Err(MetadataError::Rejected(piece))
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    Err(MetadataError::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_extended_handshakes() {
+        let ours = ExtendedHandshake {
+            ut_metadata: Some(UT_METADATA_ID),
+            metadata_size: Some(31235),
+        };
+        assert_eq!(
+            ours.to_payload(),
+            b"d1:md11:ut_metadatai1ee13:metadata_sizei31235ee"
+        );
+        assert_eq!(
+            ExtendedHandshake::from_payload(&ours.to_payload()),
+            Some(ours)
+        );
+        assert_eq!(
+            ExtendedHandshake::from_payload(b"d1:md11:ut_metadatai0eee"),
+            Some(ExtendedHandshake {
+                ut_metadata: None,
+                metadata_size: None
+            })
+        );
+    }
+
+    #[test]
+    fn it_serves_metadata_pieces() {
+        let info = Arc::new(vec![7u8; METADATA_PIECE_SIZE + 10]);
+        let mut server = MetadataServer::new(info);
+        let now = Instant::now();
+
+        let request = MetadataMessage::parse(b"d8:msg_typei0e5:piecei1ee").unwrap();
+        assert_eq!(request, MetadataMessage::Request { piece: 1 });
+        match server.respond(now, 1) {
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                assert_eq!((piece, total_size), (1, METADATA_PIECE_SIZE as u32 + 10));
+                assert_eq!(data, vec![7u8; 10]);
+            }
+            m => panic!("expected data, got {:?}", m),
+        }
+        let data = server.respond(now, 0);
+        assert_eq!(MetadataMessage::parse(&data.serialize()), Some(data));
+        assert_eq!(
+            MetadataMessage::parse(b"d8:msg_typei0e5:piecei1eejunk"),
+            None
+        );
+        assert_eq!(server.respond(now, 2), MetadataMessage::Reject { piece: 2 });
+        assert_eq!(
+            MetadataMessage::Reject { piece: 2 }.serialize(),
+            b"d8:msg_typei2e5:piecei2ee"
+        );
+    }
+
+    #[test]
+    fn it_assembles_metadata_and_checks_it_against_the_info_hash() {
+        let info: Vec<u8> = (0..METADATA_PIECE_SIZE + 10).map(|i| i as u8).collect();
+        let info_hash = <[u8; 20]>::from(Sha1::digest(&info));
+        let total_size = info.len() as u32;
+        let mut server = MetadataServer::new(Arc::new(info.clone()));
+        let mut assembler = MetadataAssembler::new(info_hash, total_size).unwrap();
+        let piece = |server: &mut MetadataServer, piece| match server.respond(Instant::now(), piece)
+        {
+            MetadataMessage::Data { data, .. } => data,
+            m => panic!("expected data, got {:?}", m),
+        };
+
+        assert_eq!(assembler.missing(), vec![0, 1]);
+        assert!(matches!(
+            assembler.receive(1, total_size, vec![0; 11]),
+            Err(MetadataError::BadPiece(1))
+        ));
+        assert!(matches!(
+            assembler.receive(1, total_size, piece(&mut server, 1)),
+            Ok(None)
+        ));
+        assert_eq!(assembler.missing(), vec![0]);
+        assert_eq!(
+            assembler
+                .receive(0, total_size, piece(&mut server, 0))
+                .unwrap(),
+            Some(info)
+        );
+
+        let mut wrong = MetadataAssembler::new([0u8; 20], 10).unwrap();
+        assert!(matches!(
+            wrong.receive(0, 10, vec![1; 10]),
+            Err(MetadataError::HashMismatch)
+        ));
+        assert_eq!(wrong.missing(), vec![0]);
+        assert!(matches!(
+            MetadataAssembler::new(info_hash, MAX_METADATA_SIZE + 1),
+            Err(MetadataError::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn it_rate_limits_metadata_requests() {
+        let mut server = MetadataServer::new(Arc::new(vec![1u8; 100]));
+        let now = Instant::now();
+        assert!(matches!(
+            server.respond(now, 0),
+            MetadataMessage::Data { .. }
+        ));
+        assert!(matches!(
+            server.respond(now, 0),
+            MetadataMessage::Data { .. }
+        ));
+        assert_eq!(server.respond(now, 0), MetadataMessage::Reject { piece: 0 });
+        assert!(matches!(
+            server.respond(now + RATE_LIMIT_WINDOW, 0),
+            MetadataMessage::Data { .. }
+        ));
+    }
+}
@@ -0,0 +1,172 @@
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+
+const VERIFICATION_THREADS: usize = 4;
+
+// How much a torrent trusts the pieces it downloads. Anything but `Full` is only for measuring the
+// engine on a local swarm where every peer is known to be honest: with it, a single bad peer can
+// get corrupt data written out and passed on to others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerificationMode {
+    // pieces are hashed before they're offered to peers or counted as verified
+    #[default]
+    Full,
+    // pieces are offered to peers as soon as they're in and hashed afterwards; one that fails is
+    // still downloaded again, but peers may already have been told we have it
+    Deferred,
+    // pieces are never hashed, as for content without piece hashes (UNSAFE)
+    Off,
+}
+
+struct Job {
+    sequence: u64,
+    index: u32,
+    data: Vec<u8>,
+    expected: [u8; 20],
+}
+
+// Hashes downloaded pieces on a small pool of worker threads. Workers finish in whatever order
+// they finish, but `completed` hands results back strictly in the order the pieces were submitted,
+// so whoever applies them (and announces them to peers) never sees a later piece before an
+// earlier one.
+#[derive(Debug)]
+pub struct VerificationQueue {
+    jobs: Sender<Job>,
+    results: Mutex<Receiver<(u64, u32, bool)>>,
+    next_sequence: u64,
+    next_to_apply: u64,
+    // finished out of order, waiting for everything submitted before them
+    pending: BTreeMap<u64, (u32, bool)>,
+}
+
+impl Default for VerificationQueue {
+    fn default() -> Self {
+        VerificationQueue::new()
+    }
+}
+
+impl VerificationQueue {
+    pub fn new() -> Self {
+        let (jobs, job_receiver) = channel::<Job>();
+        let (result_sender, results) = channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        for _ in 0..VERIFICATION_THREADS {
+            let job_receiver = Arc::clone(&job_receiver);
+            let result_sender = result_sender.clone();
+            // workers exit once the queue, and with it the job sender, is dropped
+            spawn(move || loop {
+                let job = match job_receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                let ok = <[u8; 20]>::from(Sha1::digest(&job.data)) == job.expected;
+                if result_sender.send((job.sequence, job.index, ok)).is_err() {
+                    return;
+                }
+            });
+        }
+        VerificationQueue {
+            jobs,
+            results: Mutex::new(results),
+            next_sequence: 0,
+            next_to_apply: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    pub fn submit(&mut self, index: u32, data: Vec<u8>, expected: [u8; 20]) {
+        let job = Job {
+            sequence: self.next_sequence,
+            index,
+            data,
+            expected,
+        };
+        self.next_sequence += 1;
+        self.jobs
+            .send(job)
+            .expect("verification workers exited while the queue was still in use");
+    }
+
+    pub fn in_flight(&self) -> u64 {
+        self.next_sequence - self.next_to_apply
+    }
+
+    // (piece index, hash matched) for every piece that can be applied now, in submission order
+    pub fn completed(&mut self) -> Vec<(u32, bool)> {
+        let results = self.results.lock().unwrap();
+        while let Ok((sequence, index, ok)) = results.try_recv() {
+            self.pending.insert(sequence, (index, ok));
+        }
+        drop(results);
+        self.drain_in_order()
+    }
+
+    // Blocks until every submitted piece has been hashed
+    pub fn finish(&mut self) -> Vec<(u32, bool)> {
+        let results = self.results.lock().unwrap();
+        while self.next_to_apply + (self.pending.len() as u64) < self.next_sequence {
+            match results.recv() {
+                Ok((sequence, index, ok)) => {
+                    self.pending.insert(sequence, (index, ok));
+                }
+                Err(_) => break,
+            }
+        }
+        drop(results);
+        self.drain_in_order()
+    }
+
+    fn drain_in_order(&mut self) -> Vec<(u32, bool)> {
+        let mut ready = vec![];
+        while let Some(result) = self.pending.remove(&self.next_to_apply) {
+            ready.push(result);
+            self.next_to_apply += 1;
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(data: &[u8]) -> [u8; 20] {
+        <[u8; 20]>::from(Sha1::digest(data))
+    }
+
+    #[test]
+    fn it_applies_many_simultaneous_completions_in_submission_order() {
+        let mut queue = VerificationQueue::new();
+        // large pieces first so later, smaller ones are likely to finish before them
+        let pieces: Vec<(u32, Vec<u8>)> = (0..64u32)
+            .map(|i| (i, vec![i as u8; (64 - i as usize) * 4096]))
+            .collect();
+        for (index, data) in &pieces {
+            let expected = if index % 10 == 3 {
+                [0u8; 20]
+            } else {
+                hash(data)
+            };
+            queue.submit(*index, data.clone(), expected);
+        }
+        assert_eq!(queue.in_flight(), 64);
+
+        let mut results = queue.completed();
+        results.extend(queue.finish());
+        assert_eq!(queue.in_flight(), 0);
+        assert_eq!(
+            results,
+            (0..64u32).map(|i| (i, i % 10 != 3)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn it_has_nothing_to_apply_before_anything_is_submitted() {
+        let mut queue = VerificationQueue::new();
+        assert!(queue.completed().is_empty());
+        assert!(queue.finish().is_empty());
+    }
+}
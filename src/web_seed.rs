@@ -0,0 +1,358 @@
+use crate::dns::{DnsError, DnsResolver, IpPreference};
+use crate::meta_info_file::{Info, MetaInfoFile};
+use parking_lot::Mutex;
+use reqwest::header::RANGE;
+use reqwest::{StatusCode, Url};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum WebSeedError {
+    HttpError(reqwest::Error),
+    // anything but 206 Partial Content, or 200 for a request covering a whole file
+    Status(StatusCode),
+    // the server sent a different number of bytes than were asked for
+    Length { expected: u64, received: u64 },
+    Dns(DnsError),
+}
+
+// A BEP 19 web seed: an HTTP server with the torrent's files laid out under one URL, from which
+// any range of the content can be fetched with Range requests. For a single file torrent the URL
+// is the file itself unless it ends in `/`; for a multi-file one it's the directory holding the
+// torrent's own directory.
+pub struct WebSeed {
+    pub url: Url,
+    // every file's URL and length, in the order their data is laid out; padding files have no URL,
+    // servers not being expected to have them
+    files: Vec<(Option<Url>, u64)>,
+    client: reqwest::blocking::Client,
+    dns: Option<(DnsResolver, IpPreference)>,
+    // with a `DnsResolver`, a client per host made for the addresses it last gave
+    clients: Mutex<HashMap<String, (Vec<SocketAddr>, reqwest::blocking::Client)>>,
+}
+
+impl std::fmt::Debug for WebSeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSeed")
+            .field("url", &self.url)
+            .field("files", &self.files)
+            .finish()
+    }
+}
+
+impl WebSeed {
+    pub fn new(url: Url, meta_info: &MetaInfoFile) -> Self {
+        let files = match &meta_info.info {
+            Info::SingleFile { name, file, .. } => {
+                let file_url = if url.path().ends_with('/') {
+                    with_segments(&url, [name])
+                } else {
+                    url.clone()
+                };
+                vec![(Some(file_url), file.length)]
+            }
+            // the torrent's directory is the first component of every file's path
+            Info::MultiFile { files, .. } => files
+                .iter()
+                .map(|file| {
+                    let segments = file.path.iter().map(|c| c.to_string_lossy());
+                    let file_url = (!file.padding).then(|| with_segments(&url, segments));
+                    (file_url, file.length)
+                })
+                .collect(),
+        };
+        WebSeed {
+            url,
+            files,
+            client: reqwest::blocking::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            dns: None,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Looks the seed's host up through `dns`, as trackers do, instead of leaving it to the HTTP
+    // client
+    pub fn resolving_with(mut self, dns: DnsResolver, preference: IpPreference) -> Self {
+        self.dns = Some((dns, preference));
+        self
+    }
+
+    // The shared client, or with a `DnsResolver` one that connects to the addresses it gave for
+    // `url`'s host. The lookup is made every time, the resolver's cache keeping it cheap, so a
+    // seed that moves is followed.
+    fn client_for(&self, url: &Url) -> Result<reqwest::blocking::Client, WebSeedError> {
+        let (Some((dns, preference)), Some(host)) = (&self.dns, url.domain()) else {
+            return Ok(self.client.clone());
+        };
+        // the port is taken from the URL whatever is given here
+        let addrs: Vec<SocketAddr> = dns
+            .lookup(host, *preference)
+            .map_err(WebSeedError::Dns)?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, 0))
+            .collect();
+        let mut clients = self.clients.lock();
+        if let Some((resolved, client)) = clients.get(host) {
+            if *resolved == addrs {
+                return Ok(client.clone());
+            }
+        }
+        let client = reqwest::blocking::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .resolve_to_addrs(host, &addrs)
+            .build()
+            .map_err(WebSeedError::HttpError)?;
+        clients.insert(host.to_string(), (addrs, client.clone()));
+        Ok(client)
+    }
+
+    // `length` bytes of content from `start`, in as many requests as there are files it spans
+    pub fn fetch(&self, start: u64, length: u64) -> Result<Vec<u8>, WebSeedError> {
+        let mut data = Vec::with_capacity(length as usize);
+        for (url, offset, part_length, file_length) in ranges(&self.files, start, length) {
+            let Some(url) = url else {
+                // padding is all zeros
+                data.resize(data.len() + part_length as usize, 0);
+                continue;
+            };
+            let response = self
+                .client_for(url)?
+                .get(url.clone())
+                .header(
+                    RANGE,
+                    format!("bytes={}-{}", offset, offset + part_length - 1),
+                )
+                .send()
+                .map_err(WebSeedError::HttpError)?;
+            let whole_file = offset == 0 && part_length == file_length;
+            match response.status() {
+                StatusCode::PARTIAL_CONTENT => {}
+                StatusCode::OK if whole_file => {}
+                status => return Err(WebSeedError::Status(status)),
+            }
+            let body = response.bytes().map_err(WebSeedError::HttpError)?;
+            if body.len() as u64 != part_length {
+                return Err(WebSeedError::Length {
+                    expected: part_length,
+                    received: body.len() as u64,
+                });
+            }
+            data.extend_from_slice(&body);
+        }
+        Ok(data)
+    }
+}
+
+fn with_segments<S: AsRef<str>>(url: &Url, segments: impl IntoIterator<Item = S>) -> Url {
+    let mut url = url.clone();
+    if let Ok(mut path) = url.path_segments_mut() {
+        path.pop_if_empty().extend(segments);
+    }
+    url
+}
+
+// The (url, offset in the file, length, file length) of each piece of the `length` bytes of
+// content from `start`; zero length files never come up
+fn ranges(
+    files: &[(Option<Url>, u64)],
+    start: u64,
+    length: u64,
+) -> Vec<(Option<&Url>, u64, u64, u64)> {
+    let end = start + length;
+    let mut file_start = 0;
+    let mut ranges = vec![];
+    for (url, file_length) in files {
+        let file_end = file_start + file_length;
+        let from = start.max(file_start);
+        let to = end.min(file_end);
+        if from < to {
+            ranges.push((url.as_ref(), from - file_start, to - from, *file_length));
+        }
+        file_start = file_end;
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::{Bencodable, DictBuilder};
+
+    fn multi_file() -> MetaInfoFile {
+        let file = |length: i64, path: &[&str]| {
+            DictBuilder::new()
+                .insert("length", length)
+                .insert(
+                    "path",
+                    path.iter()
+                        .map(|c| Bencodable::from(*c))
+                        .collect::<Vec<Bencodable>>(),
+                )
+                .build()
+        };
+        let info = DictBuilder::new()
+            .insert(
+                "files",
+                vec![
+                    file(10, &["a.bin"]),
+                    file(0, &["empty"]),
+                    file(20, &["sub", "b c.bin"]),
+                    DictBuilder::new()
+                        .insert("attr", "p")
+                        .insert("length", 6_i64)
+                        .insert(
+                            "path",
+                            vec![Bencodable::from(".pad"), Bencodable::from("6")],
+                        )
+                        .build(),
+                    file(4, &["d.bin"]),
+                ],
+            )
+            .insert("name", "dir")
+            .insert("piece length", 16384_u32)
+            .insert("pieces", &[0u8; 20][..])
+            .build();
+        MetaInfoFile::from(
+            &DictBuilder::new()
+                .insert("announce", "http://tracker.example/announce")
+                .insert("info", info)
+                .build(),
+        )
+    }
+
+    #[test]
+    fn it_maps_content_ranges_onto_file_urls() {
+        let seed = WebSeed::new(
+            Url::parse("http://seed.example/files").unwrap(),
+            &multi_file(),
+        );
+        let urls: Vec<Option<&str>> = seed
+            .files
+            .iter()
+            .map(|(url, _)| url.as_ref().map(Url::as_str))
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                Some("http://seed.example/files/dir/a.bin"),
+                Some("http://seed.example/files/dir/empty"),
+                Some("http://seed.example/files/dir/sub/b%20c.bin"),
+                None,
+                Some("http://seed.example/files/dir/d.bin")
+            ]
+        );
+
+        let spans = |start, length| -> Vec<(Option<&str>, u64, u64, u64)> {
+            ranges(&seed.files, start, length)
+                .into_iter()
+                .map(|(url, offset, length, file_length)| {
+                    (url.map(Url::as_str), offset, length, file_length)
+                })
+                .collect()
+        };
+        assert_eq!(
+            spans(5, 10),
+            vec![
+                (Some("http://seed.example/files/dir/a.bin"), 5, 5, 10),
+                (
+                    Some("http://seed.example/files/dir/sub/b%20c.bin"),
+                    0,
+                    5,
+                    20
+                )
+            ]
+        );
+        // the padding is in the content like any file, it just isn't fetched
+        assert_eq!(
+            spans(28, 10),
+            vec![
+                (
+                    Some("http://seed.example/files/dir/sub/b%20c.bin"),
+                    18,
+                    2,
+                    20
+                ),
+                (None, 0, 6, 6),
+                (Some("http://seed.example/files/dir/d.bin"), 0, 2, 4)
+            ]
+        );
+        assert_eq!(ranges(&seed.files, 10, 20)[0].1, 0);
+        assert_eq!(ranges(&seed.files, 10, 20).len(), 1);
+    }
+
+    #[test]
+    fn it_looks_the_seed_up_through_the_session_resolver() {
+        use crate::dns::Resolved;
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::sync::Arc;
+
+        struct Localhost;
+        impl crate::dns::Resolve for Localhost {
+            fn resolve(&self, _host: &str) -> Result<Resolved, DnsError> {
+                Ok(Resolved {
+                    addrs: vec!["127.0.0.1".parse().unwrap()],
+                    ttl: None,
+                })
+            }
+        }
+        let content: Vec<u8> = (0..100).collect();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let served = content.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0u8; 1024];
+                let n = stream.read(&mut request).unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..n]).to_ascii_lowercase();
+                let (from, to) = request
+                    .split("range: bytes=")
+                    .nth(1)
+                    .and_then(|range| range.split("\r\n").next())
+                    .and_then(|range| range.split_once('-'))
+                    .map(|(from, to)| (from.parse().unwrap(), to.parse::<usize>().unwrap()))
+                    .unwrap();
+                let body = &served[from..=to];
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .as_bytes(),
+                );
+                let _ = stream.write_all(body);
+            }
+        });
+        let meta_info = MetaInfoFile::from(
+            &DictBuilder::new()
+                .insert(
+                    "info",
+                    DictBuilder::new()
+                        .insert("length", 100_i64)
+                        .insert("name", "a.bin")
+                        .insert("piece length", 16384_u32)
+                        .insert("pieces", &[0u8; 20][..])
+                        .build(),
+                )
+                .build(),
+        );
+        let url = Url::parse(&format!("http://seed.test:{}/a.bin", port)).unwrap();
+        let dns = DnsResolver::new(Arc::new(Localhost));
+
+        let seed = WebSeed::new(url.clone(), &meta_info)
+            .resolving_with(dns.clone(), IpPreference::Ipv4Only);
+        assert_eq!(seed.fetch(10, 20).unwrap(), content[10..30].to_vec());
+        assert!(matches!(
+            WebSeed::new(url, &meta_info)
+                .resolving_with(dns, IpPreference::Ipv6Only)
+                .fetch(0, 10),
+            Err(WebSeedError::Dns(DnsError::NoAddresses(host))) if host == "seed.test"
+        ));
+    }
+}